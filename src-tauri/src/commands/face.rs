@@ -0,0 +1,27 @@
+use tauri::State;
+use crate::services::face_service::FaceDetection;
+use crate::services::image_service::decode_base64_image;
+use crate::services::{FaceService, ImageService, ModeService, SessionService, Storage};
+
+/// Check a captured photo against the session's mode before generating
+#[tauri::command]
+pub fn detect_faces(
+    storage: State<Storage>,
+    face_service: State<FaceService>,
+    session_id: String,
+    photo_base64: String,
+) -> Result<FaceDetection, String> {
+    let photo = ImageService::new().normalize_photo(&photo_base64)?;
+    let detection = face_service.detect(&decode_base64_image(&photo.base64)?)?;
+
+    let conn = storage.get_connection()?;
+    let session = SessionService::new(&conn)
+        .get_session(&session_id)?
+        .ok_or_else(|| "Session not found".to_string())?;
+    let mode = ModeService::new(&conn)
+        .get_mode_by_id(&session.mode_id)?
+        .ok_or_else(|| "Mode not found".to_string())?;
+
+    detection.check_person_count(mode.min_persons, mode.max_persons)?;
+    Ok(detection)
+}
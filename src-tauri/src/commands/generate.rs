@@ -1,140 +1,261 @@
-use tauri::State;
-use crate::models::{PhotoSession, SessionStatus};
-use crate::services::{ModeService, MiniMaxService, SessionService, Storage, WeChatService};
+use tauri::{Emitter, State};
+use crate::models::{LatePayment, Order, PaymentProviderKind, PaymentQr, PhotoSession, SessionStatus};
+use crate::services::image_service::decode_base64_image;
+use crate::services::minimax_service::ProviderCall;
+use crate::services::payment_poller::apply_trade;
+use crate::services::payment_provider::create_provider;
+use crate::services::usage_service::CallOutcome;
+use crate::services::{FaceService, GenerationCache, ImageService, ModeService, MiniMaxService, QrService, SessionService, Storage, UsageService};
 
 #[tauri::command]
 pub async fn generate_photo(
     storage: State<'_, Storage>,
+    face_service: State<'_, FaceService>,
     session_id: String,
     photo_base64: String,
     style_id: Option<String>,
+    regenerate: Option<bool>,
 ) -> Result<PhotoSession, String> {
-    eprintln!("[Generate] START session_id={}", session_id);
+    tracing::info!("[Generate] Start session {}", session_id);
+
+    // Validate and normalize the captured photo before touching the session
+    let photo = ImageService::new().normalize_photo(&photo_base64)?;
+    tracing::info!("[Generate] Normalized photo: {}x{} from {}", photo.width, photo.height, photo.source_format);
+
+    let detection = face_service.detect(&decode_base64_image(&photo.base64)?)?;
 
     // Get session info, effect prompt
-    let (effect_prompt, final_prompt): (String, String) = {
-        eprintln!("[Generate] Getting connection...");
+    let (final_prompt, mode_id, effect_id): (String, String, String) = {
         let conn = storage.get_connection()?;
-        eprintln!("[Generate] Got connection, getting session...");
         let session_service = SessionService::new(&conn);
-        eprintln!("[Generate] Calling get_session...");
-        let session_result = session_service.get_session(&session_id);
-        eprintln!("[Generate] get_session returned: {:?}", session_result.as_ref().map(|s| s.is_some()));
-        let session = session_result
+        let session = session_service.get_session(&session_id)
             .map_err(|e| e.to_string())?
             .ok_or_else(|| "Session not found".to_string())?;
-        eprintln!("[Generate] Got session, status={:?}", session.status);
 
         // Check if already processing - fetch latest session data
         if session.status == SessionStatus::Processing {
-            eprintln!("[Generate] Already processing, fetching latest session...");
+            tracing::info!("[Generate] Session {} already processing", session_id);
             let latest_session = session_service.get_session(&session_id)
                 .map_err(|e| e.to_string())?
                 .ok_or_else(|| "Session not found".to_string())?;
-            return Ok(latest_session);
+            return super::session::with_preview(latest_session);
         }
 
-        eprintln!("[Generate] Getting mode...");
         let mode_service = ModeService::new(&conn);
         let mode = mode_service.get_mode_by_id(&session.mode_id)
             .map_err(|e| e.to_string())?
             .ok_or_else(|| "Mode not found".to_string())?;
 
-        eprintln!("[Generate] Got mode, getting effect...");
+        // Reject shots with no person or the wrong head count for this mode
+        detection.check_person_count(mode.min_persons, mode.max_persons)?;
+
         let effect = mode.effects
             .into_iter()
             .find(|e| e.id == session.effect_id)
             .ok_or_else(|| "Effect not found".to_string())?;
 
-        let effect_prompt = effect.prompt.clone();
-        let final_prompt = effect_prompt.clone();
+        let final_prompt = effect.prompt.clone();
 
-        eprintln!("[Generate] Updating status to Processing...");
         session_service.update_session_status(&session_id, SessionStatus::Processing)
             .map_err(|e| e.to_string())?;
 
-        eprintln!("[Generate] Done getting prompt, releasing connection");
-        (effect_prompt, final_prompt)
+        (final_prompt, session.mode_id, session.effect_id)
     };
 
-    eprintln!("[Generate] Calling MiniMax API...");
-    let minimax = MiniMaxService::new().map_err(|e| e.to_string())?;
-
-    let generated_photo = match tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        minimax.generate_image(&photo_base64, &final_prompt)
-    ).await {
-        Ok(result) => result.map_err(|e| format!("AI generation failed: {}", e))?,
-        Err(_) => {
-            eprintln!("[Generate] MiniMax timed out, using mock");
-            minimax.generate_placeholder_image()
-        }
-    };
+    // Any failure from here on puts the session back, so a retry is not taken for a generation in progress
+    let generated: Result<PhotoSession, String> = async {
+        let minimax = MiniMaxService::new().map_err(|e| e.to_string())?;
 
-    eprintln!("[Generate] Saving results...");
-    let final_session = {
-        let conn = storage.get_connection()?;
-        let session_service = SessionService::new(&conn);
-        session_service.save_original_photo(&session_id, &photo_base64)
-            .map_err(|e| e.to_string())?;
-        session_service.save_generated_photo(&session_id, &generated_photo)
-            .map_err(|e| e.to_string())?;
+        // Identical photo + prompt reuses the previous result unless the customer asked for a fresh one
+        let cache = GenerationCache::new(&storage.data_dir)?;
+        let cache_key = GenerationCache::key(&photo.base64, &final_prompt, &minimax.cache_params());
+        let cached = if regenerate.unwrap_or(false) {
+            None
+        } else {
+            cache.get(&cache_key)
+        };
 
-        if let Some(ref sid) = style_id {
-            session_service.update_session_style(&session_id, sid)
+        let ai_photo = match cached {
+            Some(cached) => {
+                tracing::info!("[Generate] Reusing cached result");
+                Some(cached)
+            }
+            None => {
+                // Stop calling the provider once the spend budget is used up
+                if !minimax.is_mock() {
+                    let conn = storage.get_connection()?;
+                    let status = UsageService::new(&conn).generation_status()?;
+                    if !status.available {
+                        let reason = status.reason.unwrap_or_default();
+                        return Err(format!("AI generation unavailable: {}", reason));
+                    }
+                }
+
+                tracing::debug!("[Generate] Calling MiniMax API");
+                let outcome = tokio::time::timeout(
+                    std::time::Duration::from_secs(30),
+                    minimax.generate_image(&photo.base64, &final_prompt)
+                ).await;
+
+                // One row per request: a text-to-image fallback is a second call
+                for call in minimax.take_calls() {
+                    record_usage(&storage, &session_id, &effect_id, &call);
+                }
+
+                let result = match outcome {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(e)) => {
+                        tracing::warn!("[Generate] MiniMax failed, using local fallback: {}", e);
+                        None
+                    }
+                    Err(_) => {
+                        tracing::warn!("[Generate] MiniMax timed out, using local fallback");
+                        None
+                    }
+                };
+                if let Some(ref generated) = result {
+                    if let Err(e) = cache.put(&cache_key, generated) {
+                        tracing::warn!("[Generate] Failed to cache result: {}", e);
+                    }
+                }
+                result
+            }
+        };
+
+        let (generated_photo, fallback) = match ai_photo {
+            Some(photo) => (photo, false),
+            None => {
+                let photo = ImageService::new().render_fallback(&photo.base64, &mode_id, &effect_id)?;
+                (photo, true)
+            }
+        };
+
+        let session = {
+            let conn = storage.get_connection()?;
+            let session_service = SessionService::new(&conn);
+            session_service.save_original_photo(&session_id, &photo.base64)
+                .map_err(|e| e.to_string())?;
+            session_service.save_photo_info(&session_id, photo.width, photo.height, &photo.source_format, Some(detection.face_count))
+                .map_err(|e| e.to_string())?;
+            session_service.save_generated_photo(&session_id, &generated_photo, fallback)
                 .map_err(|e| e.to_string())?;
-        }
 
-        session_service.update_session_status(&session_id, SessionStatus::Previewing)
-            .map_err(|e| e.to_string())?;
+            if let Some(ref sid) = style_id {
+                session_service.update_session_style(&session_id, sid)
+                    .map_err(|e| e.to_string())?;
+            }
 
-        session_service.get_session(&session_id)
-            .map_err(|e| e.to_string())?
-            .ok_or_else(|| "Session not found".to_string())?
+            session_service.update_session_status(&session_id, SessionStatus::Previewing)
+                .map_err(|e| e.to_string())?;
+
+            session_service.get_session(&session_id)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| "Session not found".to_string())?
+        };
+        Ok(session)
+    }
+    .await;
+    let final_session = match generated {
+        Ok(session) => session,
+        Err(e) => {
+            let reset = storage
+                .get_connection()
+                .and_then(|conn| SessionService::new(&conn).update_session_status(&session_id, SessionStatus::Capturing));
+            if let Err(reset) = reset {
+                tracing::warn!("[Generate] Failed to reset session {}: {}", session_id, reset);
+            }
+            return Err(e);
+        }
     };
 
-    eprintln!("[Generate] DONE");
-    Ok(final_session)
+    tracing::debug!("[Generate] Session {} ready for preview", session_id);
+    super::session::with_preview(final_session)
+}
+
+// Usage accounting must never break a customer's generation
+fn record_usage(storage: &Storage, session_id: &str, effect_id: &str, call: &ProviderCall) {
+    // Still in flight means the generation timed out waiting for it
+    let latency_ms = call.latency_ms.unwrap_or_else(|| call.started.elapsed().as_millis() as i64);
+    let outcome = call.outcome.clone().unwrap_or(CallOutcome::TimedOut);
+    let recorded = storage.get_connection().and_then(|conn| {
+        UsageService::new(&conn)
+            .record_call(session_id, effect_id, "minimax", latency_ms, &outcome)
+            .map(|_| ())
+    });
+    if let Err(e) = recorded {
+        tracing::warn!("[Generate] Failed to record AI usage: {}", e);
+    }
 }
 
 #[tauri::command]
 pub async fn create_payment(
     storage: State<'_, Storage>,
     session_id: String,
-    order_type: String,
-    amount: i32,
-) -> Result<(String, String), String> {
-    let order_type_model = match order_type.as_str() {
-        "download" => crate::models::OrderType::Download,
-        "print" => crate::models::OrderType::Print,
-        _ => return Err("Invalid order type".to_string()),
+    order_type: Option<String>,
+    provider: Option<String>,
+    items: Option<Vec<crate::models::LineItemRequest>>,
+) -> Result<PaymentQr, String> {
+    // Priced by the backend; the customer is charged what the order costs
+    let contents = super::order::order_contents(order_type, items, None)?;
+    let provider_kind: PaymentProviderKind = match provider {
+        Some(p) => p.parse()?,
+        None => PaymentProviderKind::Wechat,
     };
+    let payment_provider = create_provider(provider_kind)?;
 
     let order = {
         let conn = storage.get_connection()?;
         let session_service = SessionService::new(&conn);
-        session_service.create_order(&session_id, order_type_model, amount)
+        session_service.create_order_from(&session_id, contents, provider_kind)
             .map_err(|e| e.to_string())?
     };
 
-    let wechat = WeChatService::new().map_err(|e| e.to_string())?;
+    let code_url = payment_provider.create(&order.id, order.amount, order_description(&order.order_type)).await?;
 
-    let (_prepay_id, qr_code) = wechat.create_order(&order.id, amount, "AI Photo Download")
-        .await
-        .map_err(|e| e.to_string())?;
+    let qr = QrService::new();
+    let options = qr.options();
+    Ok(PaymentQr {
+        order_id: order.id,
+        png_base64: qr.render_png_base64(&code_url, &options)?,
+        svg: qr.render_svg(&code_url, &options)?,
+    })
+}
 
-    Ok((order.id, qr_code))
+/// Line shown on the customer's payment screen
+fn order_description(order_type: &crate::models::OrderType) -> &'static str {
+    match order_type {
+        crate::models::OrderType::Download => "AI Photo Download",
+        crate::models::OrderType::Print => "AI Photo Print",
+        crate::models::OrderType::Bundle => "AI Photo Bundle",
+    }
 }
 
 #[tauri::command]
 pub async fn query_payment(
+    app: tauri::AppHandle,
     storage: State<'_, Storage>,
     order_id: String,
-) -> Result<String, String> {
-    let wechat = WeChatService::new().map_err(|e| e.to_string())?;
-    let status = wechat.query_order(&order_id)
-        .await
-        .map_err(|e| e.to_string())?;
+) -> Result<Order, String> {
+    let order = {
+        let conn = storage.get_connection()?;
+        SessionService::new(&conn)
+            .get_order(&order_id)?
+            .ok_or_else(|| "Order not found".to_string())?
+    };
+
+    let provider = create_provider(order.provider)?;
+    let trade = provider.query(&order.id).await?;
+
+    let on_late_payment = |late: LatePayment| {
+        if let Err(e) = app.emit("late-payment", &late) {
+            tracing::warn!("Failed to emit late-payment: {}", e);
+        }
+    };
+    if apply_trade(&storage, provider.as_ref(), &order, &trade, "query", &on_late_payment).await? {
+        tracing::info!("[Payment] Order {} reconciled: {} -> {}", order.id, trade.state, trade.status);
+    }
 
-    Ok(status)
+    let conn = storage.get_connection()?;
+    SessionService::new(&conn).get_order(&order.id)?
+        .ok_or_else(|| "Order not found".to_string())
 }
@@ -3,9 +3,23 @@ pub mod effect;
 pub mod session;
 pub mod order;
 pub mod generate;
+pub mod face;
+pub mod usage;
+pub mod refund;
+pub mod reconciliation;
+pub mod voucher;
+pub mod print;
+pub mod operator;
 
 pub use mode::*;
 pub use effect::*;
 pub use session::*;
 pub use order::*;
 pub use generate::*;
+pub use face::*;
+pub use usage::*;
+pub use refund::*;
+pub use reconciliation::*;
+pub use voucher::*;
+pub use print::*;
+pub use operator::*;
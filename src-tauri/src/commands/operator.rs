@@ -0,0 +1,46 @@
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use std::env;
+use std::sync::Mutex;
+
+// Wrong PINs in a row before operator commands lock for a while
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT_SECS: i64 = 300;
+
+// (consecutive wrong PINs, locked until)
+static FAILURES: Lazy<Mutex<(u32, i64)>> = Lazy::new(|| Mutex::new((0, 0)));
+
+/// Check the operator PIN before an operator command runs. Customers share the
+/// webview, so these commands are disabled unless `OPERATOR_PIN` is set.
+pub(crate) fn require_operator(pin: &str) -> Result<(), String> {
+    let expected = env::var("OPERATOR_PIN")
+        .ok()
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| "Operator commands are disabled (OPERATOR_PIN not set)".to_string())?;
+
+    let mut failures = FAILURES.lock().map_err(|e| e.to_string())?;
+    let now = Utc::now().timestamp();
+    if failures.1 > now {
+        return Err("Too many wrong PINs, try again later".to_string());
+    }
+
+    // Compare without an early exit so timing does not leak the PIN
+    let matches = pin.len() == expected.len()
+        && pin.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        failures.0 += 1;
+        if failures.0 >= MAX_FAILURES {
+            *failures = (0, now + LOCKOUT_SECS);
+            tracing::warn!("[Operator] {} wrong PINs, operator commands locked for {}s", MAX_FAILURES, LOCKOUT_SECS);
+        }
+        return Err("Wrong operator PIN".to_string());
+    }
+    failures.0 = 0;
+    Ok(())
+}
+
+/// Unlock the operator screens
+#[tauri::command]
+pub fn verify_operator_pin(pin: String) -> Result<(), String> {
+    require_operator(&pin)
+}
@@ -1,23 +1,54 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use tauri::State;
-use crate::models::{Order, OrderStatus, OrderType};
-use crate::services::{SessionService, Storage};
+use crate::models::{
+    DownloadLink, Fulfillment, FulfillmentStatus, OrderLookup, LineItemRequest, Order, OrderEvent, OrderItem, OrderStatus, OrderType,
+    PaymentProviderKind, Product,
+};
+use crate::services::session_service::OrderContents;
+use crate::services::download_service::{base_url, download_filename};
+use crate::services::{CatalogService, DownloadService, FulfillmentService, QrService, SessionService, Storage, UpscaleService};
+
+use super::operator::require_operator;
+
+/// An order is either catalog `items` or a single download/print `order_type`,
+/// checked against the price the customer was shown when `amount` is given
+pub(crate) fn order_contents(
+    order_type: Option<String>,
+    items: Option<Vec<LineItemRequest>>,
+    amount: Option<i32>,
+) -> Result<OrderContents, String> {
+    match (items, order_type) {
+        (Some(items), _) => Ok(OrderContents::Items { items, amount }),
+        (None, Some(order_type)) => {
+            let order_type = match order_type.as_str() {
+                "download" => OrderType::Download,
+                "print" => OrderType::Print,
+                _ => return Err("Invalid order type".to_string()),
+            };
+            Ok(OrderContents::Single { order_type, amount })
+        }
+        (None, None) => Err("Order needs an order type or items".to_string()),
+    }
+}
 
 #[tauri::command]
 pub fn create_order(
     storage: State<Storage>,
     session_id: String,
-    order_type: String,
+    order_type: Option<String>,
     amount: i32,
+    provider: Option<String>,
+    items: Option<Vec<LineItemRequest>>,
 ) -> Result<Order, String> {
-    let order_type = match order_type.as_str() {
-        "download" => OrderType::Download,
-        "print" => OrderType::Print,
-        _ => return Err("Invalid order type".to_string()),
+    let contents = order_contents(order_type, items, Some(amount))?;
+    let provider: PaymentProviderKind = match provider {
+        Some(p) => p.parse()?,
+        None => PaymentProviderKind::Wechat,
     };
 
     let conn = storage.get_connection()?;
     let session_service = SessionService::new(&conn);
-    session_service.create_order(&session_id, order_type, amount)
+    session_service.create_order_from(&session_id, contents, provider)
 }
 
 #[tauri::command]
@@ -27,16 +58,20 @@ pub fn get_order(storage: State<Storage>, order_id: String) -> Result<Option<Ord
     session_service.get_order(&order_id)
 }
 
+/// Operator command: correct an order's status by hand. Orders only become
+/// paid through their payment.
 #[tauri::command]
 pub fn update_order_status(
     storage: State<Storage>,
+    pin: String,
     order_id: String,
     status: String,
     wechat_order_id: Option<String>,
 ) -> Result<(), String> {
+    require_operator(&pin)?;
     let status = match status.as_str() {
         "pending" => OrderStatus::Pending,
-        "paid" => OrderStatus::Paid,
+        "paid" => return Err("Orders are marked paid by their payment, not by hand".to_string()),
         "cancelled" => OrderStatus::Cancelled,
         "refunded" => OrderStatus::Refunded,
         _ => return Err("Invalid order status".to_string()),
@@ -46,3 +81,102 @@ pub fn update_order_status(
     let session_service = SessionService::new(&conn);
     session_service.update_order_status(&order_id, status, wechat_order_id)
 }
+
+#[tauri::command]
+pub fn get_order_events(storage: State<Storage>, order_id: String) -> Result<Vec<OrderEvent>, String> {
+    let conn = storage.get_connection()?;
+    let session_service = SessionService::new(&conn);
+    session_service.get_order_events(&order_id)
+}
+
+#[tauri::command]
+pub fn get_order_items(storage: State<Storage>, order_id: String) -> Result<Vec<OrderItem>, String> {
+    let conn = storage.get_connection()?;
+    SessionService::new(&conn).get_order_items(&order_id)
+}
+
+/// The photo of a paid order at print resolution, as base64 JPEG
+#[tauri::command]
+pub async fn get_order_photo(storage: State<'_, Storage>, order_id: String) -> Result<String, String> {
+    let photo = {
+        let conn = storage.get_connection()?;
+        DownloadService::new(&conn).paid_photo(&order_id)?
+    };
+
+    let jpeg = UpscaleService::new(&storage.data_dir)?.upscale(&photo).await?;
+    Ok(STANDARD.encode(jpeg))
+}
+
+/// Find an order by the code on the customer's receipt
+#[tauri::command]
+pub fn lookup_order_by_code(storage: State<Storage>, code: String) -> Result<Option<OrderLookup>, String> {
+    let conn = storage.get_connection()?;
+    let Some(order) = SessionService::new(&conn).get_order_by_code(&code)? else {
+        return Ok(None);
+    };
+
+    let downloadable = DownloadService::new(&conn).paid_photo(&order.id).is_ok();
+    Ok(Some(OrderLookup {
+        code: order.code.unwrap_or_default(),
+        order_id: order.id,
+        order_type: order.order_type,
+        status: order.status,
+        amount: order.amount,
+        created_at: order.created_at,
+        downloadable,
+    }))
+}
+
+/// A short-lived link to a paid order's photo for the customer's phone
+#[tauri::command]
+pub async fn create_download_link(storage: State<'_, Storage>, order_id: String) -> Result<DownloadLink, String> {
+    let (url, expires_at, photo, code) = {
+        let conn = storage.get_connection()?;
+        let download_service = DownloadService::new(&conn);
+        let (url, expires_at) = download_service.create_link(&order_id, &base_url()?)?;
+        let code = SessionService::new(&conn).get_order(&order_id)?.and_then(|order| order.code);
+        (url, expires_at, download_service.paid_photo(&order_id)?, code)
+    };
+
+    // Upscale now so the phone does not wait for it
+    UpscaleService::new(&storage.data_dir)?.upscale(&photo).await?;
+
+    let qr = QrService::new();
+    Ok(DownloadLink {
+        png_base64: qr.render_png_base64(&url, &qr.options())?,
+        filename: download_filename(&order_id),
+        order_id,
+        code,
+        url,
+        expires_at,
+    })
+}
+
+/// Products on sale, priced for the session's effect when given
+#[tauri::command]
+pub fn get_products(storage: State<Storage>, effect_id: Option<String>) -> Result<Vec<Product>, String> {
+    let conn = storage.get_connection()?;
+    CatalogService::new(&conn).get_available_products(effect_id.as_deref())
+}
+
+#[tauri::command]
+pub fn get_fulfillments(storage: State<Storage>, order_id: String) -> Result<Vec<Fulfillment>, String> {
+    let conn = storage.get_connection()?;
+    FulfillmentService::new(&conn).get_fulfillments(&order_id)
+}
+
+/// Operator command: mark a unit delivered or failed, e.g. a print handed over by hand
+#[tauri::command]
+pub fn update_fulfillment_status(
+    storage: State<Storage>,
+    pin: String,
+    fulfillment_id: i64,
+    status: String,
+    detail: Option<String>,
+) -> Result<(), String> {
+    require_operator(&pin)?;
+    let status: FulfillmentStatus = status.parse()?;
+
+    let conn = storage.get_connection()?;
+    FulfillmentService::new(&conn).update_status(fulfillment_id, status, detail.as_deref())
+}
@@ -0,0 +1,79 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use tauri::State;
+use crate::models::{MediaKind, MediaSupply, PrintJob, PrintTemplate, PrinterStatus};
+use crate::services::image_service::decode_base64_image;
+use crate::services::print_compositor::PrintContext;
+use crate::services::{ImageService, InventoryService, PrintCompositor, PrintService, SessionService, Storage};
+
+use super::operator::require_operator;
+
+/// Print jobs of one order, or the latest jobs for the operator
+#[tauri::command]
+pub fn get_print_jobs(storage: State<Storage>, order_id: Option<String>, limit: Option<u32>) -> Result<Vec<PrintJob>, String> {
+    let conn = storage.get_connection()?;
+    PrintService::new(&conn).get_jobs(order_id.as_deref(), limit.unwrap_or(50))
+}
+
+/// Last polled printer health; `None` when printing is not set up
+#[tauri::command]
+pub fn get_printer_status(storage: State<Storage>) -> Result<Option<PrinterStatus>, String> {
+    let conn = storage.get_connection()?;
+    PrintService::new(&conn).get_status()
+}
+
+/// Tracked print consumables
+#[tauri::command]
+pub fn get_media_inventory(storage: State<Storage>) -> Result<Vec<MediaSupply>, String> {
+    let conn = storage.get_connection()?;
+    InventoryService::new(&conn).get_supplies()
+}
+
+/// Set the count of a consumable after loading paper or a ribbon
+#[tauri::command]
+pub fn restock_media(
+    storage: State<Storage>,
+    pin: String,
+    kind: String,
+    remaining: i32,
+    low_threshold: Option<i32>,
+) -> Result<MediaSupply, String> {
+    require_operator(&pin)?;
+    let kind: MediaKind = kind.parse()?;
+
+    let conn = storage.get_connection()?;
+    InventoryService::new(&conn).restock(kind, remaining, low_threshold)
+}
+
+#[tauri::command]
+pub fn get_print_templates(storage: State<Storage>) -> Result<Vec<PrintTemplate>, String> {
+    Ok(PrintCompositor::new(&storage.data_dir).templates().to_vec())
+}
+
+/// The session's watermarked preview laid out on a template (default `PRINT_TEMPLATE`), as base64 JPEG
+#[tauri::command]
+pub fn render_print_preview(storage: State<Storage>, session_id: String, template_id: Option<String>) -> Result<String, String> {
+    let photo = {
+        let conn = storage.get_connection()?;
+        SessionService::new(&conn)
+            .get_session(&session_id)?
+            .and_then(|session| session.generated_photo)
+            .ok_or_else(|| "Session has no generated photo".to_string())?
+    };
+
+    let photo = ImageService::new().render_preview(&photo)?;
+    let compositor = PrintCompositor::new(&storage.data_dir);
+    let template_id = template_id.unwrap_or_else(PrintCompositor::default_template_id);
+    let template = compositor
+        .template(&template_id)
+        .ok_or_else(|| format!("Unknown print template: {}", template_id))?;
+    let context = PrintContext {
+        order_id: String::new(),
+        order_code: String::new(),
+        session_id: session_id.clone(),
+        printed_at: Utc::now().timestamp(),
+    };
+
+    let jpeg = compositor.render(template, &decode_base64_image(&photo)?, &context)?;
+    Ok(STANDARD.encode(jpeg))
+}
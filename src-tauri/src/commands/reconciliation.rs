@@ -0,0 +1,60 @@
+use chrono::NaiveDate;
+use tauri::State;
+use crate::models::{PaymentProviderKind, ReconciliationReport};
+use crate::services::payment_provider::{create_provider, parse_bill};
+use crate::services::reconciliation_job::DOWNLOAD_SOURCE;
+use crate::services::{ReconciliationService, Storage};
+
+use super::operator::require_operator;
+
+const DEFAULT_REPORT_LIMIT: u32 = 30;
+
+/// Operator command: download a day's bill (`YYYY-MM-DD`, Beijing time) and
+/// reconcile it against the orders table
+#[tauri::command]
+pub async fn reconcile_payments(
+    storage: State<'_, Storage>,
+    pin: String,
+    provider: String,
+    bill_date: String,
+    auto_correct: Option<bool>,
+) -> Result<ReconciliationReport, String> {
+    require_operator(&pin)?;
+    let provider: PaymentProviderKind = provider.parse()?;
+    let date = parse_bill_date(&bill_date)?;
+    let entries = create_provider(provider)?.download_bill(date).await?;
+
+    let conn = storage.get_connection()?;
+    ReconciliationService::new(&conn).reconcile(provider, date, DOWNLOAD_SOURCE, &entries, auto_correct.unwrap_or(false))
+}
+
+/// Operator command: reconcile a bill file exported from the merchant console
+#[tauri::command]
+pub fn import_bill(
+    storage: State<Storage>,
+    pin: String,
+    provider: String,
+    bill_date: String,
+    file_path: String,
+    auto_correct: Option<bool>,
+) -> Result<ReconciliationReport, String> {
+    require_operator(&pin)?;
+    let provider: PaymentProviderKind = provider.parse()?;
+    let date = parse_bill_date(&bill_date)?;
+    let data = std::fs::read(&file_path).map_err(|e| format!("Failed to read bill file: {}", e))?;
+    let entries = parse_bill(provider, &data)?;
+
+    let conn = storage.get_connection()?;
+    ReconciliationService::new(&conn).reconcile(provider, date, "import", &entries, auto_correct.unwrap_or(false))
+}
+
+#[tauri::command]
+pub fn get_reconciliation_reports(storage: State<Storage>, pin: String, limit: Option<u32>) -> Result<Vec<ReconciliationReport>, String> {
+    require_operator(&pin)?;
+    let conn = storage.get_connection()?;
+    ReconciliationService::new(&conn).get_reports(limit.unwrap_or(DEFAULT_REPORT_LIMIT))
+}
+
+fn parse_bill_date(bill_date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(bill_date, "%Y-%m-%d").map_err(|_| format!("Invalid bill date: {}", bill_date))
+}
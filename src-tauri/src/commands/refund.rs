@@ -0,0 +1,41 @@
+use tauri::State;
+use crate::models::Refund;
+use crate::services::payment_provider::create_provider;
+use crate::services::refund_service::submit_refund;
+use crate::services::{RefundService, SessionService, Storage};
+
+use super::operator::require_operator;
+
+/// Operator command: refund all (`amount` omitted) or part of a paid order.
+/// A refund the provider did not confirm comes back processing and is
+/// settled in the background.
+#[tauri::command]
+pub async fn refund_order(
+    storage: State<'_, Storage>,
+    pin: String,
+    order_id: String,
+    amount: Option<i32>,
+    reason: Option<String>,
+) -> Result<Refund, String> {
+    require_operator(&pin)?;
+    let reason = reason.unwrap_or_else(|| "Refund".to_string());
+    let order = {
+        let conn = storage.get_connection()?;
+        SessionService::new(&conn)
+            .get_order(&order_id)?
+            .ok_or_else(|| "Order not found".to_string())?
+    };
+    let provider = create_provider(order.provider)?;
+
+    let refund = {
+        let conn = storage.get_connection()?;
+        RefundService::new(&conn).create_refund(&order_id, amount, Some(&reason))?
+    };
+    submit_refund(&storage, provider.as_ref(), &refund.id).await
+}
+
+#[tauri::command]
+pub fn get_refunds(storage: State<Storage>, order_id: String) -> Result<Vec<Refund>, String> {
+    let conn = storage.get_connection()?;
+    RefundService::new(&conn).get_refunds(&order_id)
+}
@@ -1,6 +1,6 @@
 use tauri::State;
 use crate::models::PhotoSession;
-use crate::services::{SessionService, Storage};
+use crate::services::{ImageService, SessionService, Storage};
 
 #[tauri::command]
 pub fn create_session(storage: State<Storage>, mode_id: String, effect_id: String) -> Result<PhotoSession, String> {
@@ -13,7 +13,7 @@ pub fn create_session(storage: State<Storage>, mode_id: String, effect_id: Strin
 pub fn get_session(storage: State<Storage>, session_id: String) -> Result<Option<PhotoSession>, String> {
     let conn = storage.get_connection()?;
     let session_service = SessionService::new(&conn);
-    session_service.get_session(&session_id)
+    session_service.get_session(&session_id)?.map(with_preview).transpose()
 }
 
 #[tauri::command]
@@ -21,13 +21,16 @@ pub fn save_original_photo(storage: State<Storage>, session_id: String, photo_ba
     let conn = storage.get_connection()?;
     let session_service = SessionService::new(&conn);
     session_service.save_original_photo(&session_id, &photo_base64)?;
-    session_service.get_session(&session_id)?
-        .ok_or_else(|| "Session not found".to_string())
+    let session = session_service.get_session(&session_id)?
+        .ok_or_else(|| "Session not found".to_string())?;
+    with_preview(session)
 }
 
-#[tauri::command]
-pub fn save_generated_photo(storage: State<Storage>, session_id: String, photo_base64: String) -> Result<(), String> {
-    let conn = storage.get_connection()?;
-    let session_service = SessionService::new(&conn);
-    session_service.save_generated_photo(&session_id, &photo_base64)
+/// Sessions reach the UI with a watermarked preview in place of the generated
+/// photo; the clean image is only released for a paid order
+pub(crate) fn with_preview(mut session: PhotoSession) -> Result<PhotoSession, String> {
+    if let Some(photo) = session.generated_photo.take() {
+        session.generated_photo = Some(ImageService::new().render_preview(&photo)?);
+    }
+    Ok(session)
 }
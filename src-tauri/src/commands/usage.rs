@@ -0,0 +1,48 @@
+use tauri::State;
+use crate::models::{GenerationStatus, RevenueReport, UsageReport};
+use crate::services::{RevenueService, Storage, UsageService};
+
+use super::operator::require_operator;
+
+/// Operator command: AI calls, failures and estimated spend per period
+#[tauri::command]
+pub fn get_usage_report(
+    storage: State<Storage>,
+    pin: String,
+    period: String,
+    limit: Option<u32>,
+) -> Result<Vec<UsageReport>, String> {
+    require_operator(&pin)?;
+    let conn = storage.get_connection()?;
+    let usage_service = UsageService::new(&conn);
+    match period.as_str() {
+        "daily" => usage_service.daily_report(limit.unwrap_or(30)),
+        "monthly" => usage_service.monthly_report(limit.unwrap_or(12)),
+        _ => Err("Invalid report period".to_string()),
+    }
+}
+
+#[tauri::command]
+pub fn get_generation_status(storage: State<Storage>) -> Result<GenerationStatus, String> {
+    let conn = storage.get_connection()?;
+    UsageService::new(&conn).generation_status()
+}
+
+/// Operator command: settled orders per period and payment method; voucher
+/// orders get their own rows
+#[tauri::command]
+pub fn get_revenue_report(
+    storage: State<Storage>,
+    pin: String,
+    period: String,
+    limit: Option<u32>,
+) -> Result<Vec<RevenueReport>, String> {
+    require_operator(&pin)?;
+    let conn = storage.get_connection()?;
+    let revenue_service = RevenueService::new(&conn);
+    match period.as_str() {
+        "daily" => revenue_service.daily_report(limit.unwrap_or(30)),
+        "monthly" => revenue_service.monthly_report(limit.unwrap_or(12)),
+        _ => Err("Invalid report period".to_string()),
+    }
+}
@@ -0,0 +1,64 @@
+use tauri::State;
+use crate::models::{LineItemRequest, Order, Voucher, VoucherKind};
+use crate::services::voucher_service::VoucherTerms;
+use crate::services::{Storage, VoucherService};
+
+use super::operator::require_operator;
+
+/// Operator command: issue a batch of voucher codes
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub fn create_vouchers(
+    storage: State<Storage>,
+    pin: String,
+    count: u32,
+    kind: String,
+    value: Option<i32>,
+    max_uses: Option<u32>,
+    mode_id: Option<String>,
+    expires_at: Option<i64>,
+    note: Option<String>,
+) -> Result<Vec<Voucher>, String> {
+    require_operator(&pin)?;
+    let terms = VoucherTerms {
+        kind: kind.parse::<VoucherKind>()?,
+        value,
+        max_uses: max_uses.unwrap_or(1),
+        mode_id,
+        expires_at,
+        note,
+    };
+
+    let conn = storage.get_connection()?;
+    VoucherService::new(&conn).create_vouchers(count, &terms)
+}
+
+#[tauri::command]
+pub fn get_vouchers(storage: State<Storage>, pin: String) -> Result<Vec<Voucher>, String> {
+    require_operator(&pin)?;
+    let conn = storage.get_connection()?;
+    VoucherService::new(&conn).get_vouchers()
+}
+
+#[tauri::command]
+pub fn disable_voucher(storage: State<Storage>, pin: String, code: String) -> Result<(), String> {
+    require_operator(&pin)?;
+    let conn = storage.get_connection()?;
+    VoucherService::new(&conn).disable_voucher(&code)
+}
+
+/// Settle a new order with a voucher code instead of `create_payment`
+#[tauri::command]
+pub fn redeem_voucher(
+    storage: State<Storage>,
+    session_id: String,
+    order_type: Option<String>,
+    amount: i32,
+    code: String,
+    items: Option<Vec<LineItemRequest>>,
+) -> Result<Order, String> {
+    let contents = super::order::order_contents(order_type, items, Some(amount))?;
+
+    let conn = storage.get_connection()?;
+    VoucherService::new(&conn).redeem(&session_id, contents, &code)
+}
@@ -63,6 +63,218 @@ pub fn create_tables(conn: &Connection) -> Result<()> {
         [],
     )?;
 
+    // Product catalog; bundles list their components
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS products (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            description TEXT,
+            kind TEXT NOT NULL,
+            price INTEGER,
+            active INTEGER NOT NULL DEFAULT 1,
+            sort_order INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bundle_components (
+            bundle_id TEXT NOT NULL,
+            product_id TEXT NOT NULL,
+            quantity INTEGER NOT NULL,
+            PRIMARY KEY (bundle_id, product_id),
+            FOREIGN KEY (bundle_id) REFERENCES products(id),
+            FOREIGN KEY (product_id) REFERENCES products(id)
+        )",
+        [],
+    )?;
+
+    // Order line items and the delivery of each unit
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS order_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            parent_id INTEGER,
+            product_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            quantity INTEGER NOT NULL,
+            unit_price INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id),
+            FOREIGN KEY (parent_id) REFERENCES order_items(id)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_order_items_order_id ON order_items(order_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS fulfillments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            order_item_id INTEGER,
+            kind TEXT NOT NULL,
+            status TEXT NOT NULL,
+            detail TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id),
+            FOREIGN KEY (order_item_id) REFERENCES order_items(id)
+        )",
+        [],
+    )?;
+
+    // Print jobs; a failed unit may be printed again under a new job
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS print_jobs (
+            id TEXT PRIMARY KEY,
+            order_id TEXT NOT NULL,
+            fulfillment_id INTEGER NOT NULL,
+            printer TEXT NOT NULL,
+            status TEXT NOT NULL,
+            printer_job_id TEXT,
+            error TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id),
+            FOREIGN KEY (fulfillment_id) REFERENCES fulfillments(id)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_print_jobs_status ON print_jobs(status)",
+        [],
+    )?;
+
+    // Last polled printer health; a single row
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS printer_status (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            printer TEXT NOT NULL,
+            state TEXT NOT NULL,
+            message TEXT,
+            checked_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Print consumables; a kind without a row is not counted
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS media_inventory (
+            kind TEXT PRIMARY KEY,
+            remaining INTEGER NOT NULL,
+            low_threshold INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Download links handed out for paid orders; used_at is the first completed download
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS download_tokens (
+            nonce TEXT PRIMARY KEY,
+            order_id TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            used_at INTEGER,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )",
+        [],
+    )?;
+
+    // Order status history
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS order_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            from_status TEXT,
+            to_status TEXT NOT NULL,
+            source TEXT NOT NULL,
+            detail TEXT,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_order_events_order_id ON order_events(order_id)",
+        [],
+    )?;
+
+    // Refunds against paid orders
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS refunds (
+            id TEXT PRIMARY KEY,
+            order_id TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            reason TEXT,
+            status TEXT NOT NULL,
+            provider_refund_id TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )",
+        [],
+    )?;
+
+    // Voucher codes and the orders they settled
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS vouchers (
+            code TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            value INTEGER,
+            max_uses INTEGER NOT NULL DEFAULT 1,
+            used INTEGER NOT NULL DEFAULT 0,
+            mode_id TEXT,
+            expires_at INTEGER,
+            note TEXT,
+            disabled INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS voucher_redemptions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            code TEXT NOT NULL,
+            order_id TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (code) REFERENCES vouchers(code),
+            FOREIGN KEY (order_id) REFERENCES orders(id)
+        )",
+        [],
+    )?;
+
+    // Daily bill reconciliation runs and the discrepancies they found
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS reconciliation_reports (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            provider TEXT NOT NULL,
+            bill_date TEXT NOT NULL,
+            source TEXT NOT NULL,
+            transactions INTEGER NOT NULL,
+            matched INTEGER NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS reconciliation_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            report_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            out_trade_no TEXT NOT NULL,
+            order_id TEXT,
+            bill_amount INTEGER,
+            order_amount INTEGER,
+            order_status TEXT,
+            detail TEXT NOT NULL,
+            corrected INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (report_id) REFERENCES reconciliation_reports(id)
+        )",
+        [],
+    )?;
+
     // User sessions table
     conn.execute(
         "CREATE TABLE IF NOT EXISTS user_sessions (
@@ -87,18 +299,70 @@ pub fn create_tables(conn: &Connection) -> Result<()> {
         [],
     )?;
 
+    // AI provider usage table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS ai_usage (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id TEXT NOT NULL,
+            effect_id TEXT NOT NULL,
+            provider TEXT NOT NULL,
+            latency_ms INTEGER NOT NULL,
+            success INTEGER NOT NULL,
+            error TEXT,
+            estimated_cost REAL NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_ai_usage_created_at ON ai_usage(created_at)",
+        [],
+    )?;
+
     // Insert default modes
     insert_default_modes(conn)?;
 
     // Insert default styles
     insert_default_styles(conn)?;
 
+    // Insert default products
+    insert_default_products(conn)?;
+
     // Migration: Add style_id column if it doesn't exist
     conn.execute(
         "ALTER TABLE photo_sessions ADD COLUMN style_id TEXT",
         [],
     ).ok(); // ok() to ignore error if column already exists
 
+    // Migration: Flag sessions whose generated photo is a local fallback
+    conn.execute(
+        "ALTER TABLE photo_sessions ADD COLUMN is_fallback INTEGER NOT NULL DEFAULT 0",
+        [],
+    ).ok();
+
+    // Migration: Record dimensions and source format of the normalized photo
+    for column in ["photo_width INTEGER", "photo_height INTEGER", "photo_format TEXT"] {
+        conn.execute(&format!("ALTER TABLE photo_sessions ADD COLUMN {}", column), []).ok();
+    }
+
+    // Migration: Per-mode person limits and detected face count
+    let person_limits_added = conn
+        .execute("ALTER TABLE photo_modes ADD COLUMN min_persons INTEGER NOT NULL DEFAULT 1", [])
+        .is_ok();
+    conn.execute("ALTER TABLE photo_modes ADD COLUMN max_persons INTEGER NOT NULL DEFAULT 3", []).ok();
+    conn.execute("ALTER TABLE photo_sessions ADD COLUMN face_count INTEGER", []).ok();
+    // Only when the columns are new, so limits the operator changed are kept
+    if person_limits_added {
+        set_default_person_limits(conn)?;
+    }
+
+    // Migration: Payment provider per order; existing orders were all WeChat
+    conn.execute("ALTER TABLE orders ADD COLUMN provider TEXT NOT NULL DEFAULT 'wechat'", []).ok();
+
+    // Migration: Short code customers type to find their order; older orders have none
+    conn.execute("ALTER TABLE orders ADD COLUMN code TEXT", []).ok();
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_orders_code ON orders(code)", [])?;
+
     Ok(())
 }
 
@@ -151,6 +415,57 @@ fn insert_default_modes(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// Group modes take two or three people; portrait modes transform one face
+fn set_default_person_limits(conn: &Connection) -> Result<()> {
+    let limits = [
+        ("cartoon", 2, 3),
+        ("movie", 2, 3),
+        ("traditional", 2, 3),
+        ("anime", 1, 1),
+        ("cyberpunk", 1, 1),
+        ("age", 1, 1),
+    ];
+
+    for (id, min_persons, max_persons) in limits {
+        conn.execute(
+            "UPDATE photo_modes SET min_persons = ?1, max_persons = ?2 WHERE id = ?3",
+            rusqlite::params![min_persons, max_persons, id],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn insert_default_products(conn: &Connection) -> Result<()> {
+    // No price: the effect's download or print price applies
+    let products = vec![
+        ("download", "电子版", "高清电子版照片下载", "download", None, 1),
+        ("print", "冲印照片", "6寸相纸冲印", "print", None, 2),
+        ("download-2prints", "电子版+2张冲印", "电子版下载加两张冲印照片", "bundle", Some(2000), 3),
+    ];
+
+    for (id, name, description, kind, price, sort_order) in products {
+        conn.execute(
+            "INSERT OR IGNORE INTO products (id, name, description, kind, price, sort_order) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![id, name, description, kind, price, sort_order],
+        )?;
+    }
+
+    let components = vec![
+        ("download-2prints", "download", 1),
+        ("download-2prints", "print", 2),
+    ];
+
+    for (bundle_id, product_id, quantity) in components {
+        conn.execute(
+            "INSERT OR IGNORE INTO bundle_components (bundle_id, product_id, quantity) VALUES (?1, ?2, ?3)",
+            rusqlite::params![bundle_id, product_id, quantity],
+        )?;
+    }
+
+    Ok(())
+}
+
 fn insert_default_styles(conn: &Connection) -> Result<()> {
     let styles = vec![
         ("cartoon", "卡通", "将照片转换为可爱的卡通风格，使用鲜明的色彩和简洁的线条", "🎨", "将照片转换为卡通风格，{original_description}，使用鲜明的色彩和简洁的线条，呈现Disney风格的动画效果"),
@@ -170,3 +485,43 @@ fn insert_default_styles(conn: &Connection) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(conn: &Connection, mode_id: &str) -> (u32, u32) {
+        conn.query_row(
+            "SELECT min_persons, max_persons FROM photo_modes WHERE id = ?1",
+            [mode_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn person_limits_are_seeded_per_mode() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        assert_eq!(limits(&conn, "movie"), (2, 3));
+        assert_eq!(limits(&conn, "age"), (1, 1));
+
+        // Operator changes survive later starts
+        conn.execute("UPDATE photo_modes SET min_persons = 1 WHERE id = 'movie'", []).unwrap();
+        create_tables(&conn).unwrap();
+        assert_eq!(limits(&conn, "movie"), (1, 3));
+    }
+
+    #[test]
+    fn person_limits_are_seeded_into_an_older_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Modes table of builds before person limits
+        conn.execute_batch(
+            "CREATE TABLE photo_modes (id TEXT PRIMARY KEY, name TEXT NOT NULL, description TEXT, icon TEXT);
+             INSERT INTO photo_modes (id, name) VALUES ('cartoon', '卡通模式');",
+        )
+        .unwrap();
+        create_tables(&conn).unwrap();
+        assert_eq!(limits(&conn, "cartoon"), (2, 3));
+    }
+}
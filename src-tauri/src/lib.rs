@@ -3,7 +3,9 @@ pub mod db;
 pub mod models;
 pub mod services;
 
-use services::Storage;
+use models::{LatePayment, MediaSupply, PaymentStatusEvent, PrintStatusEvent, PrinterStatus};
+use services::{DownloadServer, FaceService, NotifyServer, PaymentPoller, PrintQueue, ReconciliationJob, Storage};
+use tauri::Emitter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -25,9 +27,70 @@ pub fn run() {
     // Initialize storage
     let storage = Storage::new().expect("Failed to initialize storage");
 
+    // Empty shots and person counts are checked before every generation
+    let face_service = FaceService::new(&storage.data_dir);
+
+    let background_storage = storage.clone();
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(storage)
+        .manage(face_service)
+        .setup(move |app| {
+            // Push WeChat payment callbacks to the UI as they arrive
+            let handle = app.handle().clone();
+            let late_handle = app.handle().clone();
+            let started = NotifyServer::start(
+                background_storage.clone(),
+                move |event| emit_payment_status(&handle, event),
+                move |late| emit_late_payment(&late_handle, late),
+            );
+            if let Err(e) = started {
+                tracing::error!("[Notify] Payment notification listener not started: {}", e);
+            }
+
+            // Paid photos for customers' phones on the LAN
+            if let Err(e) = DownloadServer::start(background_storage.clone()) {
+                tracing::error!("[Download] Download server not started: {}", e);
+            }
+
+            // Settle or expire pending orders the notifications missed
+            match PaymentPoller::new(background_storage.clone()) {
+                Ok(poller) => {
+                    let handle = app.handle().clone();
+                    let late_handle = app.handle().clone();
+                    tauri::async_runtime::spawn(poller.run(
+                        move |event| emit_payment_status(&handle, event),
+                        move |late| emit_late_payment(&late_handle, late),
+                    ));
+                }
+                Err(e) => tracing::error!("[Poller] Payment poller not started: {}", e),
+            }
+
+            // Print paid print units as they come in
+            match PrintQueue::new(background_storage.clone()) {
+                Ok(queue) => {
+                    let handle = app.handle().clone();
+                    let printer_handle = app.handle().clone();
+                    let media_handle = app.handle().clone();
+                    tauri::async_runtime::spawn(queue.run(
+                        move |event| emit_print_status(&handle, event),
+                        move |status| emit_printer_status(&printer_handle, status),
+                        move |supply| emit_media_low(&media_handle, supply),
+                    ));
+                }
+                Err(e) => tracing::info!("[Print] Printing disabled: {}", e),
+            }
+
+            // Check yesterday's provider bills against the orders table
+            match ReconciliationJob::new(background_storage) {
+                Ok(job) => {
+                    tauri::async_runtime::spawn(job.run());
+                }
+                Err(e) => tracing::info!("[Reconcile] Daily reconciliation disabled: {}", e),
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::get_modes,
             commands::get_mode,
@@ -35,14 +98,71 @@ pub fn run() {
             commands::create_session,
             commands::get_session,
             commands::save_original_photo,
-            commands::save_generated_photo,
             commands::create_order,
             commands::get_order,
             commands::update_order_status,
+            commands::get_order_events,
+            commands::get_order_items,
+            commands::get_products,
+            commands::get_order_photo,
+            commands::create_download_link,
+            commands::lookup_order_by_code,
+            commands::get_fulfillments,
+            commands::update_fulfillment_status,
             commands::generate_photo,
             commands::create_payment,
             commands::query_payment,
+            commands::detect_faces,
+            commands::get_usage_report,
+            commands::get_generation_status,
+            commands::get_revenue_report,
+            commands::refund_order,
+            commands::get_refunds,
+            commands::reconcile_payments,
+            commands::import_bill,
+            commands::get_reconciliation_reports,
+            commands::create_vouchers,
+            commands::get_vouchers,
+            commands::disable_voucher,
+            commands::redeem_voucher,
+            commands::get_print_jobs,
+            commands::get_print_templates,
+            commands::render_print_preview,
+            commands::get_printer_status,
+            commands::get_media_inventory,
+            commands::restock_media,
+            commands::verify_operator_pin,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+fn emit_payment_status(handle: &tauri::AppHandle, event: PaymentStatusEvent) {
+    if let Err(e) = handle.emit("payment-status", &event) {
+        tracing::warn!("Failed to emit payment-status: {}", e);
+    }
+}
+
+fn emit_late_payment(handle: &tauri::AppHandle, late: LatePayment) {
+    if let Err(e) = handle.emit("late-payment", &late) {
+        tracing::warn!("Failed to emit late-payment: {}", e);
+    }
+}
+
+fn emit_print_status(handle: &tauri::AppHandle, event: PrintStatusEvent) {
+    if let Err(e) = handle.emit("print-status", &event) {
+        tracing::warn!("Failed to emit print-status: {}", e);
+    }
+}
+
+fn emit_printer_status(handle: &tauri::AppHandle, status: PrinterStatus) {
+    if let Err(e) = handle.emit("printer-status", &status) {
+        tracing::warn!("Failed to emit printer-status: {}", e);
+    }
+}
+
+fn emit_media_low(handle: &tauri::AppHandle, supply: MediaSupply) {
+    if let Err(e) = handle.emit("media-low", &supply) {
+        tracing::warn!("Failed to emit media-low: {}", e);
+    }
+}
@@ -6,6 +6,8 @@ pub struct PhotoMode {
     pub name: String,
     pub description: String,
     pub icon: String,
+    pub min_persons: u32,
+    pub max_persons: u32,
     pub effects: Vec<Effect>,
 }
 
@@ -28,6 +30,11 @@ pub struct PhotoSession {
     pub style_id: Option<String>,
     pub original_photo: Option<String>,  // Base64 encoded
     pub generated_photo: Option<String>, // Base64 encoded
+    pub fallback: bool,                  // generated_photo is a local fallback, not an AI result
+    pub photo_width: Option<u32>,        // Normalized original photo
+    pub photo_height: Option<u32>,
+    pub photo_format: Option<String>,    // Format the camera actually produced
+    pub face_count: Option<u32>,         // None when face detection is unavailable
     pub status: SessionStatus,
     pub created_at: i64,
     pub updated_at: i64,
@@ -88,9 +95,44 @@ pub struct Order {
     pub order_type: OrderType,
     pub amount: i32,
     pub status: OrderStatus,
+    /// Provider transaction id (WeChat `transaction_id` or Alipay `trade_no`)
     pub wechat_order_id: Option<String>,
     pub payment_time: Option<i64>,
     pub created_at: i64,
+    pub provider: PaymentProviderKind,
+    /// Short code printed on receipts for customers to look the order up
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentProviderKind {
+    Wechat,
+    Alipay,
+    /// Settled with a prepaid voucher code; no money moves at the kiosk
+    Voucher,
+}
+
+impl std::fmt::Display for PaymentProviderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentProviderKind::Wechat => write!(f, "wechat"),
+            PaymentProviderKind::Alipay => write!(f, "alipay"),
+            PaymentProviderKind::Voucher => write!(f, "voucher"),
+        }
+    }
+}
+
+impl std::str::FromStr for PaymentProviderKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wechat" => Ok(PaymentProviderKind::Wechat),
+            "alipay" => Ok(PaymentProviderKind::Alipay),
+            "voucher" => Ok(PaymentProviderKind::Voucher),
+            _ => Err(format!("Unknown payment provider: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -98,6 +140,8 @@ pub struct Order {
 pub enum OrderType {
     Download,
     Print,
+    /// Several line items or a catalog bundle; see the order's items
+    Bundle,
 }
 
 impl std::fmt::Display for OrderType {
@@ -105,6 +149,7 @@ impl std::fmt::Display for OrderType {
         match self {
             OrderType::Download => write!(f, "download"),
             OrderType::Print => write!(f, "print"),
+            OrderType::Bundle => write!(f, "bundle"),
         }
     }
 }
@@ -115,6 +160,7 @@ impl std::str::FromStr for OrderType {
         match s {
             "download" => Ok(OrderType::Download),
             "print" => Ok(OrderType::Print),
+            "bundle" => Ok(OrderType::Bundle),
             _ => Err(format!("Unknown order type: {}", s)),
         }
     }
@@ -129,6 +175,19 @@ pub enum OrderStatus {
     Refunded,
 }
 
+impl OrderStatus {
+    /// Legal order lifecycle: pending -> paid | cancelled, paid -> refunded.
+    /// Cancelled and refunded are final.
+    pub fn can_transition_to(&self, next: &OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::Pending, OrderStatus::Paid)
+                | (OrderStatus::Pending, OrderStatus::Cancelled)
+                | (OrderStatus::Paid, OrderStatus::Refunded)
+        )
+    }
+}
+
 impl std::fmt::Display for OrderStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -153,6 +212,583 @@ impl std::str::FromStr for OrderStatus {
     }
 }
 
+/// Something the kiosk sells. Bundles combine other products at their own price.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Product {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub kind: OrderType,
+    pub price: i32, // 分, resolved against the effect when one is given
+    pub components: Vec<BundleComponent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleComponent {
+    pub product_id: String,
+    pub quantity: u32,
+}
+
+/// A product and quantity the customer picked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LineItemRequest {
+    pub product_id: String,
+    pub quantity: u32,
+}
+
+/// One line of an order. Bundle lines carry the price; their components are
+/// child lines at zero so each can be fulfilled on its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderItem {
+    pub id: i64,
+    pub order_id: String,
+    pub parent_id: Option<i64>,
+    pub product_id: String,
+    pub name: String,
+    pub kind: OrderType,
+    pub quantity: u32,
+    pub unit_price: i32,
+}
+
+/// Delivery of one unit (one download, one print) of a paid order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fulfillment {
+    pub id: i64,
+    pub order_id: String,
+    pub order_item_id: Option<i64>, // None for orders from before line items
+    pub kind: OrderType,
+    pub status: FulfillmentStatus,
+    pub detail: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FulfillmentStatus {
+    Pending,
+    Fulfilled,
+    Failed,
+}
+
+impl std::fmt::Display for FulfillmentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FulfillmentStatus::Pending => write!(f, "pending"),
+            FulfillmentStatus::Fulfilled => write!(f, "fulfilled"),
+            FulfillmentStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl std::str::FromStr for FulfillmentStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(FulfillmentStatus::Pending),
+            "fulfilled" => Ok(FulfillmentStatus::Fulfilled),
+            "failed" => Ok(FulfillmentStatus::Failed),
+            _ => Err(format!("Unknown fulfillment status: {}", s)),
+        }
+    }
+}
+
+/// A print of one fulfillment unit, as submitted to the printer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintJob {
+    pub id: String,
+    pub order_id: String,
+    pub fulfillment_id: i64,
+    pub printer: String,
+    pub status: PrintJobStatus,
+    pub printer_job_id: Option<String>, // the printer's own id, e.g. the CUPS job id
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrintJobStatus {
+    Queued,
+    Printing,
+    Completed,
+    Failed,
+}
+
+impl std::fmt::Display for PrintJobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrintJobStatus::Queued => write!(f, "queued"),
+            PrintJobStatus::Printing => write!(f, "printing"),
+            PrintJobStatus::Completed => write!(f, "completed"),
+            PrintJobStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl std::str::FromStr for PrintJobStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(PrintJobStatus::Queued),
+            "printing" => Ok(PrintJobStatus::Printing),
+            "completed" => Ok(PrintJobStatus::Completed),
+            "failed" => Ok(PrintJobStatus::Failed),
+            _ => Err(format!("Unknown print job status: {}", s)),
+        }
+    }
+}
+
+/// One entry in an order's status history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderEvent {
+    pub id: i64,
+    pub order_id: String,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub source: String,
+    pub detail: Option<String>,
+    pub created_at: i64,
+}
+
+/// A full or partial refund of a paid order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Refund {
+    pub id: String,
+    pub order_id: String,
+    pub amount: i32,
+    pub reason: Option<String>,
+    pub status: RefundStatus,
+    pub provider_refund_id: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundStatus {
+    Processing,
+    Success,
+    Closed,
+    Abnormal,
+    Failed,
+}
+
+impl std::fmt::Display for RefundStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefundStatus::Processing => write!(f, "processing"),
+            RefundStatus::Success => write!(f, "success"),
+            RefundStatus::Closed => write!(f, "closed"),
+            RefundStatus::Abnormal => write!(f, "abnormal"),
+            RefundStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl std::str::FromStr for RefundStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "processing" => Ok(RefundStatus::Processing),
+            "success" => Ok(RefundStatus::Success),
+            "closed" => Ok(RefundStatus::Closed),
+            "abnormal" => Ok(RefundStatus::Abnormal),
+            "failed" => Ok(RefundStatus::Failed),
+            _ => Err(format!("Unknown refund status: {}", s)),
+        }
+    }
+}
+
+/// Operator-issued redemption code, e.g. prepaid by the host of an event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Voucher {
+    pub code: String,
+    pub kind: VoucherKind,
+    pub value: Option<i32>, // 分, for value vouchers
+    pub max_uses: u32,
+    pub used: u32,
+    pub mode_id: Option<String>, // None: valid for every mode
+    pub expires_at: Option<i64>,
+    pub note: Option<String>,
+    pub disabled: bool,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoucherKind {
+    /// Covers any order
+    Free,
+    /// Covers orders up to its value
+    Value,
+}
+
+impl std::fmt::Display for VoucherKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoucherKind::Free => write!(f, "free"),
+            VoucherKind::Value => write!(f, "value"),
+        }
+    }
+}
+
+impl std::str::FromStr for VoucherKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free" => Ok(VoucherKind::Free),
+            "value" => Ok(VoucherKind::Value),
+            _ => Err(format!("Unknown voucher kind: {}", s)),
+        }
+    }
+}
+
+/// Settled orders of one payment method in one period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevenueReport {
+    pub period: String, // YYYY-MM-DD or YYYY-MM, local time
+    pub provider: PaymentProviderKind,
+    pub orders: i64,
+    pub amount: i64,   // 分; list price covered for vouchers, not money received
+    pub refunded: i64, // 分
+}
+
+/// Result of matching one provider's daily bill against the orders table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconciliationReport {
+    pub id: i64,
+    pub provider: PaymentProviderKind,
+    pub bill_date: String, // YYYY-MM-DD, Beijing time
+    pub source: String,    // download or import
+    pub transactions: i64, // Lines in the bill
+    pub matched: i64,      // Trades that agree with their order
+    pub items: Vec<ReconciliationItem>,
+    pub created_at: i64,
+}
+
+/// One disagreement between a bill and the orders table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconciliationItem {
+    pub kind: DiscrepancyKind,
+    pub out_trade_no: String,
+    pub order_id: Option<String>,
+    pub bill_amount: Option<i32>,
+    pub order_amount: Option<i32>,
+    pub order_status: Option<OrderStatus>, // Before any correction
+    pub detail: String,
+    pub corrected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    /// Money moved at the provider but the orders table does not show it
+    Missing,
+    /// The orders table shows money the bill does not have
+    Extra,
+    /// Both sides have the trade but disagree on amount, id or status
+    Mismatched,
+    /// Paid at the provider for an order we cancelled; the money has to go back
+    RefundRequired,
+}
+
+impl std::fmt::Display for DiscrepancyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscrepancyKind::Missing => write!(f, "missing"),
+            DiscrepancyKind::Extra => write!(f, "extra"),
+            DiscrepancyKind::Mismatched => write!(f, "mismatched"),
+            DiscrepancyKind::RefundRequired => write!(f, "refund_required"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiscrepancyKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "missing" => Ok(DiscrepancyKind::Missing),
+            "extra" => Ok(DiscrepancyKind::Extra),
+            "mismatched" => Ok(DiscrepancyKind::Mismatched),
+            "refund_required" => Ok(DiscrepancyKind::RefundRequired),
+            _ => Err(format!("Unknown discrepancy kind: {}", s)),
+        }
+    }
+}
+
+/// Payment QR code rendered by the backend, so the payment URL itself
+/// never reaches the webview
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentQr {
+    pub order_id: String,
+    pub png_base64: String,
+    pub svg: String,
+}
+
+/// What a customer sees after typing an order code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderLookup {
+    pub order_id: String,
+    pub code: String,
+    pub order_type: OrderType,
+    pub status: OrderStatus,
+    pub amount: i32,
+    pub created_at: i64,
+    /// Paid with a photo to hand out, so a download link can be issued
+    pub downloadable: bool,
+}
+
+/// Short-lived link to a paid photo on the kiosk's download server, with its
+/// QR code for the customer's phone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadLink {
+    pub order_id: String,
+    /// Shown next to the QR code so download-only customers still get their code
+    pub code: Option<String>,
+    pub url: String,
+    pub filename: String,
+    pub expires_at: i64,
+    pub png_base64: String,
+}
+
+/// Payload of the `payment-status` event sent to the UI
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentStatusEvent {
+    pub order_id: String,
+    pub status: OrderStatus,
+}
+
+/// A payment that arrived for an order we had already cancelled. The order
+/// stays cancelled and the full amount is refunded automatically.
+/// Payload of the `late-payment` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatePayment {
+    pub order_id: String,
+    pub code: Option<String>,
+    pub amount: i32,
+    pub transaction_id: Option<String>,
+    pub refund_id: String,
+}
+
+/// A print layout loaded from a template file. Sizes and positions are in
+/// inches from the top-left corner of the trimmed page; text sizes in points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintTemplate {
+    pub id: String,
+    pub name: String,
+    pub width: f32,
+    pub height: f32,
+    /// Extra paper on every side that is cut off; backgrounds extend into it
+    #[serde(default)]
+    pub bleed: f32,
+    #[serde(default = "default_print_dpi")]
+    pub dpi: u32,
+    #[serde(default = "default_print_background")]
+    pub background: String,
+    pub border: Option<PrintBorder>,
+    /// The photo is placed, cropped to fill, in every slot
+    pub photos: Vec<PrintBox>,
+    /// Box the brand logo is fitted into
+    pub logo: Option<PrintBox>,
+    pub caption: Option<PrintText>,
+    pub date: Option<PrintText>,
+    /// `{code}` in the text is replaced with the order code
+    pub order_code: Option<PrintText>,
+    pub qr: Option<PrintQr>,
+}
+
+fn default_print_dpi() -> u32 {
+    300
+}
+
+fn default_print_background() -> String {
+    "#ffffff".to_string()
+}
+
+/// Frame from the trim edge inward; `brand` as the color uses `BRAND_COLOR`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintBorder {
+    pub width: f32,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// A line of text; it is shrunk to fit `width` when too long
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintText {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub size: f32,
+    #[serde(default = "default_print_text_color")]
+    pub color: String,
+    #[serde(default)]
+    pub align: PrintTextAlign,
+    /// Caption text
+    pub text: Option<String>,
+    /// strftime format for the date
+    pub format: Option<String>,
+}
+
+fn default_print_text_color() -> String {
+    "#000000".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrintTextAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// QR code linking to the digital copy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintQr {
+    pub x: f32,
+    pub y: f32,
+    pub size: f32,
+}
+
+/// Health of the printer as last polled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrinterStatus {
+    pub printer: String,
+    pub state: PrinterState,
+    pub message: Option<String>,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrinterState {
+    Ready,
+    OutOfPaper,
+    OutOfRibbon,
+    Offline,
+    Jammed,
+    /// Any other fault the printer reports
+    Error,
+}
+
+impl std::fmt::Display for PrinterState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrinterState::Ready => write!(f, "ready"),
+            PrinterState::OutOfPaper => write!(f, "out_of_paper"),
+            PrinterState::OutOfRibbon => write!(f, "out_of_ribbon"),
+            PrinterState::Offline => write!(f, "offline"),
+            PrinterState::Jammed => write!(f, "jammed"),
+            PrinterState::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl std::str::FromStr for PrinterState {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ready" => Ok(PrinterState::Ready),
+            "out_of_paper" => Ok(PrinterState::OutOfPaper),
+            "out_of_ribbon" => Ok(PrinterState::OutOfRibbon),
+            "offline" => Ok(PrinterState::Offline),
+            "jammed" => Ok(PrinterState::Jammed),
+            "error" => Ok(PrinterState::Error),
+            _ => Err(format!("Unknown printer state: {}", s)),
+        }
+    }
+}
+
+/// A print consumable counted down by one on every completed print.
+/// Also the payload of the `media-low` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaSupply {
+    pub kind: MediaKind,
+    pub remaining: i32,
+    /// `media-low` is raised when `remaining` drops to this
+    pub low_threshold: i32,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Paper,
+    Ribbon,
+}
+
+impl std::fmt::Display for MediaKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaKind::Paper => write!(f, "paper"),
+            MediaKind::Ribbon => write!(f, "ribbon"),
+        }
+    }
+}
+
+impl std::str::FromStr for MediaKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "paper" => Ok(MediaKind::Paper),
+            "ribbon" => Ok(MediaKind::Ribbon),
+            _ => Err(format!("Unknown media kind: {}", s)),
+        }
+    }
+}
+
+/// Payload of the `print-status` event sent to the UI when a print finishes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrintStatusEvent {
+    pub job_id: String,
+    pub order_id: String,
+    pub status: PrintJobStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AiUsage {
+    pub id: i64,
+    pub session_id: String,
+    pub effect_id: String,
+    pub provider: String,
+    pub latency_ms: i64,
+    pub success: bool,
+    pub error: Option<String>,
+    pub estimated_cost: f64, // 分
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub period: String, // YYYY-MM-DD or YYYY-MM, local time
+    pub calls: i64,
+    pub successes: i64,
+    pub failures: i64,
+    pub avg_latency_ms: i64,
+    pub estimated_cost: f64, // 分
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationStatus {
+    pub available: bool,
+    pub reason: Option<String>,
+    pub spent_today: f64,       // 分
+    pub daily_budget: Option<f64>,
+    pub spent_this_month: f64,  // 分
+    pub monthly_budget: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserSession {
     pub session_id: String,
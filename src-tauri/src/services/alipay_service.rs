@@ -0,0 +1,553 @@
+use crate::models::{OrderStatus, PaymentProviderKind, RefundStatus};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::Client;
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use chrono::NaiveDate;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Read;
+
+use super::payment_provider::{
+    beijing, notify_path_from_url, order_timeout_secs, parse_beijing_time, parse_cents, BillEntry, BillEntryKind,
+    NotifyReply, NotifyRequest, PaymentNotice, PaymentProvider, ProviderRefund, ProviderTrade, RefundError,
+};
+use super::wechat_service::out_trade_no;
+
+const DEFAULT_GATEWAY: &str = "https://openapi.alipay.com/gateway.do";
+const DEFAULT_NOTIFY_PATH: &str = "/alipay/notify";
+const SUCCESS_CODE: &str = "10000";
+// Precreated orders only exist at Alipay once the customer has scanned the code
+const TRADE_NOT_EXIST: &str = "ACQ.TRADE_NOT_EXIST";
+const BILL_NOT_EXIST: &str = "isp.bill_not_exist";
+
+struct AlipayKeys {
+    signing_key: SigningKey<Sha256>,
+    alipay_key: VerifyingKey<Sha256>,
+}
+
+/// Alipay face-to-face payment (当面付): precreate returns a QR code URL the
+/// customer scans with the Alipay app. Requests are RSA2-signed form posts.
+pub struct AlipayService {
+    client: Client,
+    app_id: String,
+    notify_url: String,
+    gateway: String,
+    // None in mock mode
+    keys: Option<AlipayKeys>,
+}
+
+impl AlipayService {
+    pub fn new() -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+        // Local stand-in provider, like WECHAT_MOCK
+        if env::var("ALIPAY_MOCK").unwrap_or_default() == "true" {
+            tracing::info!("Alipay service initialized: use_mock=true");
+            return Ok(Self {
+                client,
+                app_id: String::new(),
+                notify_url: String::new(),
+                gateway: String::new(),
+                keys: None,
+            });
+        }
+
+        let app_id = env::var("ALIPAY_APP_ID").map_err(|_| "ALIPAY_APP_ID not set")?;
+        let private_key_path = env::var("ALIPAY_PRIVATE_KEY_PATH").map_err(|_| "ALIPAY_PRIVATE_KEY_PATH not set")?;
+        let public_key_path = env::var("ALIPAY_PUBLIC_KEY_PATH").map_err(|_| "ALIPAY_PUBLIC_KEY_PATH not set")?;
+
+        let private_key = std::fs::read_to_string(&private_key_path)
+            .map_err(|e| format!("Failed to read Alipay app private key: {}", e))
+            .and_then(|key| load_private_key(&key))?;
+        let alipay_key = std::fs::read_to_string(&public_key_path)
+            .map_err(|e| format!("Failed to read Alipay public key: {}", e))
+            .and_then(|key| load_public_key(&key))?;
+
+        Ok(Self {
+            client,
+            app_id,
+            notify_url: env::var("ALIPAY_NOTIFY_URL").unwrap_or_default(),
+            // Overridable for the Alipay sandbox or a local mock server
+            gateway: env::var("ALIPAY_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
+            keys: Some(AlipayKeys {
+                signing_key: SigningKey::<Sha256>::new(private_key),
+                alipay_key: VerifyingKey::<Sha256>::new(alipay_key),
+            }),
+        })
+    }
+
+    pub fn is_mock(&self) -> bool {
+        self.keys.is_none()
+    }
+
+    /// Call one OpenAPI method and return its verified `<method>_response` node
+    async fn call(&self, method: &str, biz_content: Value) -> Result<Value, String> {
+        let keys = self.keys.as_ref().ok_or("Alipay is in mock mode")?;
+
+        let mut params = BTreeMap::new();
+        params.insert("app_id".to_string(), self.app_id.clone());
+        params.insert("method".to_string(), method.to_string());
+        params.insert("format".to_string(), "JSON".to_string());
+        params.insert("charset".to_string(), "utf-8".to_string());
+        params.insert("sign_type".to_string(), "RSA2".to_string());
+        params.insert("timestamp".to_string(), beijing_now());
+        params.insert("version".to_string(), "1.0".to_string());
+        params.insert("notify_url".to_string(), self.notify_url.clone());
+        params.insert("biz_content".to_string(), biz_content.to_string());
+
+        let signature = keys.signing_key.sign(sign_content(&params, &["sign"]).as_bytes());
+        params.insert("sign".to_string(), STANDARD.encode(signature.to_bytes()));
+
+        let response = self
+            .client
+            .post(&self.gateway)
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach Alipay: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Alipay HTTP error: {}", response.status()));
+        }
+        let body = response.text().await.map_err(|e| format!("Failed to read Alipay response: {}", e))?;
+
+        let node_name = format!("{}_response", method.replace('.', "_"));
+        let raw_node = raw_json_field(&body, &node_name).ok_or("Alipay response missing result")?;
+        let node: Value = serde_json::from_str(raw_node).map_err(|e| format!("Failed to parse Alipay response: {}", e))?;
+
+        // Alipay signs the raw text of the response node; some errors come back unsigned
+        let parsed: Value = serde_json::from_str(&body).map_err(|e| format!("Failed to parse Alipay response: {}", e))?;
+        match parsed.get("sign").and_then(|s| s.as_str()) {
+            Some(sign) => verify(&keys.alipay_key, raw_node, sign)
+                .map_err(|_| "Alipay response signature verification failed".to_string())?,
+            None if field(&node, "code") == SUCCESS_CODE => return Err("Alipay response is not signed".to_string()),
+            None => {}
+        }
+
+        Ok(node)
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for AlipayService {
+    fn kind(&self) -> PaymentProviderKind {
+        PaymentProviderKind::Alipay
+    }
+
+    async fn create(&self, order_id: &str, amount: i32, description: &str) -> Result<String, String> {
+        let out_trade_no = out_trade_no(order_id);
+        if self.is_mock() {
+            tracing::info!("Mock Alipay order created: {} for {} cents", order_id, amount);
+            return Ok(format!("https://qr.alipay.com/mock{}", out_trade_no));
+        }
+
+        // Let the QR code expire together with our own order timeout
+        let timeout_minutes = (order_timeout_secs() + 59) / 60;
+        let node = self
+            .call(
+                "alipay.trade.precreate",
+                json!({
+                    "out_trade_no": out_trade_no,
+                    "total_amount": yuan(amount),
+                    "subject": description,
+                    "timeout_express": format!("{}m", timeout_minutes.max(1)),
+                }),
+            )
+            .await?;
+        check(&node, "Alipay precreate failed")?;
+
+        tracing::info!("Alipay order created: {} for {} cents", order_id, amount);
+        node.get("qr_code")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| "Alipay response missing qr_code".to_string())
+    }
+
+    async fn query(&self, order_id: &str) -> Result<ProviderTrade, String> {
+        let not_scanned = || ProviderTrade {
+            status: OrderStatus::Pending,
+            state: "WAIT_BUYER_SCAN".to_string(),
+            transaction_id: None,
+            paid_at: None,
+            amount: None,
+            needs_close: false,
+        };
+        if self.is_mock() {
+            return Ok(not_scanned());
+        }
+
+        let node = self.call("alipay.trade.query", json!({ "out_trade_no": out_trade_no(order_id) })).await?;
+        if field(&node, "sub_code") == TRADE_NOT_EXIST {
+            return Ok(not_scanned());
+        }
+        check(&node, "Alipay order query failed")?;
+
+        let state = field(&node, "trade_status");
+        Ok(ProviderTrade {
+            status: trade_status(&state),
+            transaction_id: node.get("trade_no").and_then(|v| v.as_str()).map(|v| v.to_string()),
+            paid_at: parse_beijing_time(&field(&node, "send_pay_date")),
+            amount: parse_cents(&field(&node, "total_amount")),
+            needs_close: false,
+            state,
+        })
+    }
+
+    async fn close(&self, order_id: &str) -> Result<(), String> {
+        if self.is_mock() {
+            return Ok(());
+        }
+
+        let node = self.call("alipay.trade.close", json!({ "out_trade_no": out_trade_no(order_id) })).await?;
+        // Never scanned: nothing to close, and the QR code expires by itself
+        if field(&node, "sub_code") == TRADE_NOT_EXIST {
+            return Ok(());
+        }
+        check(&node, "Alipay close order failed")
+    }
+
+    async fn refund(
+        &self,
+        order_id: &str,
+        refund_id: &str,
+        refund_amount: i32,
+        _total_amount: i32,
+        reason: &str,
+    ) -> Result<ProviderRefund, RefundError> {
+        if self.is_mock() {
+            tracing::info!("Mock Alipay refund {} of {} cents on order {} ({})", refund_id, refund_amount, order_id, reason);
+            return Ok(ProviderRefund {
+                refund_id: Some(format!("mock_{}", out_trade_no(refund_id))),
+                status: RefundStatus::Success,
+            });
+        }
+
+        let node = self
+            .call(
+                "alipay.trade.refund",
+                json!({
+                    "out_trade_no": out_trade_no(order_id),
+                    "refund_amount": yuan(refund_amount),
+                    "out_request_no": out_trade_no(refund_id),
+                    "refund_reason": reason,
+                }),
+            )
+            .await?;
+        if let Err(e) = check(&node, "Alipay refund failed") {
+            // Service unavailable or a system error: Alipay asks for a retry with the same out_request_no
+            let unknown = field(&node, "code") == "20000" || field(&node, "sub_code") == "ACQ.SYSTEM_ERROR";
+            return Err(if unknown { RefundError::Unknown(e) } else { RefundError::Rejected(e) });
+        }
+
+        // Alipay refunds settle synchronously; a repeated request returns the earlier result
+        Ok(ProviderRefund {
+            refund_id: node.get("trade_no").and_then(|v| v.as_str()).map(|v| v.to_string()),
+            status: RefundStatus::Success,
+        })
+    }
+
+    fn verify_notification(&self, request: &NotifyRequest) -> Result<Option<PaymentNotice>, String> {
+        let keys = self.keys.as_ref().ok_or("Mock Alipay does not send notifications")?;
+
+        let fields: BTreeMap<String, String> = url::form_urlencoded::parse(request.body.as_bytes())
+            .into_owned()
+            .collect();
+        let sign = fields.get("sign").ok_or("Alipay notification is not signed")?;
+        verify(&keys.alipay_key, &sign_content(&fields, &["sign", "sign_type"]), sign)
+            .map_err(|_| "Alipay notification signature verification failed".to_string())?;
+        if fields.get("app_id") != Some(&self.app_id) {
+            return Err("Alipay notification for another app".to_string());
+        }
+
+        // Refund notifications carry out_biz_no; refunds are settled by the API call
+        if fields.contains_key("out_biz_no") {
+            return Ok(None);
+        }
+
+        let out_trade_no = fields.get("out_trade_no").cloned().ok_or("Alipay notification missing out_trade_no")?;
+        let state = fields.get("trade_status").cloned().unwrap_or_default();
+        Ok(Some(PaymentNotice::Payment {
+            out_trade_no,
+            trade: ProviderTrade {
+                status: trade_status(&state),
+                transaction_id: fields.get("trade_no").cloned(),
+                paid_at: fields.get("gmt_payment").and_then(|t| parse_beijing_time(t)),
+                amount: fields.get("total_amount").and_then(|a| parse_cents(a)),
+                needs_close: false,
+                state,
+            },
+        }))
+    }
+
+    fn notification_reply(&self, error: Option<&str>) -> NotifyReply {
+        NotifyReply {
+            status: 200,
+            content_type: "text/plain",
+            body: if error.is_some() { "failure" } else { "success" }.to_string(),
+        }
+    }
+
+    fn notify_path(&self) -> String {
+        notify_path_from_url(&self.notify_url, DEFAULT_NOTIFY_PATH)
+    }
+
+    async fn download_bill(&self, date: NaiveDate) -> Result<Vec<BillEntry>, String> {
+        if self.is_mock() {
+            return Err("Mock Alipay has no bills".to_string());
+        }
+
+        let node = self
+            .call(
+                "alipay.data.dataservice.bill.downloadurl.query",
+                json!({ "bill_type": "trade", "bill_date": date.format("%Y-%m-%d").to_string() }),
+            )
+            .await?;
+        // No transactions that day
+        if field(&node, "sub_code") == BILL_NOT_EXIST {
+            return Ok(Vec::new());
+        }
+        check(&node, "Alipay bill query failed")?;
+
+        // Short-lived pre-signed URL; the zip itself carries no signature
+        let url = field(&node, "bill_download_url");
+        let response = self
+            .client
+            .get(&url)
+            .timeout(std::time::Duration::from_secs(60))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to download Alipay bill: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Alipay bill download error: {}", response.status()));
+        }
+        let bytes = response.bytes().await.map_err(|e| format!("Failed to download Alipay bill: {}", e))?;
+        parse_bill(&bytes)
+    }
+}
+
+fn trade_status(state: &str) -> OrderStatus {
+    match state {
+        "TRADE_SUCCESS" | "TRADE_FINISHED" => OrderStatus::Paid,
+        "TRADE_CLOSED" => OrderStatus::Cancelled,
+        // WAIT_BUYER_PAY
+        _ => OrderStatus::Pending,
+    }
+}
+
+fn check(node: &Value, context: &str) -> Result<(), String> {
+    if field(node, "code") == SUCCESS_CODE {
+        return Ok(());
+    }
+    Err(format!(
+        "{}: {} {} {}",
+        context,
+        field(node, "code"),
+        field(node, "sub_code"),
+        field(node, "sub_msg")
+    ))
+}
+
+fn field(node: &Value, name: &str) -> String {
+    node.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string()
+}
+
+/// Sorted non-empty `k=v` pairs joined with `&`
+fn sign_content(params: &BTreeMap<String, String>, exclude: &[&str]) -> String {
+    params
+        .iter()
+        .filter(|(k, v)| !exclude.contains(&k.as_str()) && !v.is_empty())
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn verify(key: &VerifyingKey<Sha256>, content: &str, sign: &str) -> Result<(), ()> {
+    let bytes = STANDARD.decode(sign).map_err(|_| ())?;
+    let signature = Signature::try_from(bytes.as_slice()).map_err(|_| ())?;
+    key.verify(content.as_bytes(), &signature).map_err(|_| ())
+}
+
+/// Raw text of the object value of `"name"` in a JSON document. Signatures cover
+/// the exact bytes Alipay sent, so the node cannot be re-serialized.
+fn raw_json_field<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("\"{}\"", name);
+    let after_key = body.find(&key)? + key.len();
+    let start = after_key + body[after_key..].find('{')?;
+
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in body[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&body[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Keys are PEM or the bare base64 the Alipay key tool produces, PKCS#8 or PKCS#1
+fn load_private_key(text: &str) -> Result<RsaPrivateKey, String> {
+    let text = text.trim();
+    let key = if text.starts_with("-----BEGIN") {
+        RsaPrivateKey::from_pkcs8_pem(text).or_else(|_| RsaPrivateKey::from_pkcs1_pem(text)).ok()
+    } else {
+        STANDARD.decode(text).ok().and_then(|der| {
+            RsaPrivateKey::from_pkcs8_der(&der).or_else(|_| RsaPrivateKey::from_pkcs1_der(&der)).ok()
+        })
+    };
+    key.ok_or_else(|| "Invalid Alipay app private key".to_string())
+}
+
+fn load_public_key(text: &str) -> Result<RsaPublicKey, String> {
+    let text = text.trim();
+    let key = if text.starts_with("-----BEGIN") {
+        RsaPublicKey::from_public_key_pem(text).or_else(|_| RsaPublicKey::from_pkcs1_pem(text)).ok()
+    } else {
+        STANDARD.decode(text).ok().and_then(|der| {
+            RsaPublicKey::from_public_key_der(&der).or_else(|_| RsaPublicKey::from_pkcs1_der(&der)).ok()
+        })
+    };
+    key.ok_or_else(|| "Invalid Alipay public key".to_string())
+}
+
+fn beijing_now() -> String {
+    chrono::Utc::now().with_timezone(&beijing()).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Alipay amounts are yuan strings with two decimals
+fn yuan(cents: i32) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+/// Parse an Alipay trade bill (业务明细): the zip from the download URL or one
+/// of its CSV files. Bills are GBK-encoded; `#` lines are comments and totals.
+pub fn parse_bill(data: &[u8]) -> Result<Vec<BillEntry>, String> {
+    if !data.starts_with(b"PK") {
+        return parse_bill_csv(&decode_bill_text(data));
+    }
+
+    // The zip also holds a summary (汇总) file; only the detail file parses.
+    // Entry names are GBK without the UTF-8 flag, so go by content instead.
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))
+        .map_err(|e| format!("Invalid Alipay bill archive: {}", e))?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| format!("Invalid Alipay bill archive: {}", e))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(|e| format!("Failed to read Alipay bill: {}", e))?;
+        if let Ok(entries) = parse_bill_csv(&decode_bill_text(&bytes)) {
+            return Ok(entries);
+        }
+    }
+    Err("Alipay bill archive has no transaction detail file".to_string())
+}
+
+fn decode_bill_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.trim_start_matches('\u{feff}').to_string(),
+        Err(_) => encoding_rs::GB18030.decode(bytes).0.into_owned(),
+    }
+}
+
+fn parse_bill_csv(text: &str) -> Result<Vec<BillEntry>, String> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'));
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').map(str::trim).collect();
+    // Money columns carry a unit suffix, e.g. 订单金额（元）
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.starts_with(name))
+            .ok_or_else(|| format!("Alipay bill has no {} column", name))
+    };
+    let trade_no = column("支付宝交易号")?;
+    let out_trade_no = column("商户订单号")?;
+    let business_type = column("业务类型")?;
+    let amount = column("订单金额")?;
+    let finished_at = column("完成时间")?;
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+        let kind = match field(business_type) {
+            "交易" => BillEntryKind::Payment,
+            "退款" => BillEntryKind::Refund,
+            _ => continue,
+        };
+        entries.push(BillEntry {
+            kind,
+            out_trade_no: field(out_trade_no).to_string(),
+            transaction_id: field(trade_no).to_string(),
+            amount: parse_cents(field(amount))
+                .ok_or_else(|| format!("Invalid amount in Alipay bill: {}", field(amount)))?
+                .abs(),
+            time: parse_beijing_time(field(finished_at)),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn check_entries(entries: &[BillEntry]) {
+        assert_eq!(entries.len(), 3);
+        let payment = &entries[0];
+        assert_eq!(payment.kind, BillEntryKind::Payment);
+        assert_eq!(payment.out_trade_no, "6f1c2a9e0b2d4c0e9a1b2c3d4e5f6a7b");
+        assert_eq!(payment.transaction_id, "2026101822001412345678901234");
+        assert_eq!(payment.amount, 300);
+        assert_eq!(payment.time, Some(chrono::Utc.with_ymd_and_hms(2026, 10, 18, 2, 15, 2).unwrap().timestamp()));
+
+        assert_eq!(entries[1].amount, 500);
+        // Refunds are negative in the bill
+        assert_eq!(entries[2].kind, BillEntryKind::Refund);
+        assert_eq!(entries[2].out_trade_no, payment.out_trade_no);
+        assert_eq!(entries[2].amount, 300);
+    }
+
+    #[test]
+    fn parse_bill_reads_downloaded_archive() {
+        // GBK detail and summary files as downloaded from the bill URL
+        check_entries(&parse_bill(include_bytes!("../../tests/fixtures/alipay_bill.zip")).unwrap());
+    }
+
+    #[test]
+    fn parse_bill_reads_exported_csv() {
+        // UTF-8 with BOM as exported by hand from the merchant console
+        check_entries(&parse_bill(include_bytes!("../../tests/fixtures/alipay_bill.csv")).unwrap());
+    }
+
+    #[test]
+    fn parse_bill_rejects_other_files() {
+        assert!(parse_bill(b"PK\x03\x04 truncated").is_err());
+        assert!(parse_bill("门店编号,门店名称\n合计,".as_bytes()).is_err());
+    }
+}
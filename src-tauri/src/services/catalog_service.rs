@@ -0,0 +1,188 @@
+use crate::models::{BundleComponent, OrderType, Product};
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::HashSet;
+
+use super::InventoryService;
+
+// Column defaults of the effects table
+const DEFAULT_DOWNLOAD_PRICE: i32 = 300;
+const DEFAULT_PRINT_PRICE: i32 = 1000;
+
+pub struct CatalogService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> CatalogService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Active products, priced for `effect_id` when given
+    pub fn get_products(&self, effect_id: Option<&str>) -> Result<Vec<Product>, String> {
+        let ids: Vec<String> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT id FROM products WHERE active = 1 ORDER BY sort_order, id"
+            ).map_err(|e| e.to_string())?;
+            let ids = stmt.query_map([], |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .filter_map(|id| id.ok())
+                .collect();
+            ids
+        };
+
+        let mut products = Vec::new();
+        for id in ids {
+            if let Some(product) = self.get_product(&id, effect_id)? {
+                products.push(product);
+            }
+        }
+        Ok(products)
+    }
+
+    /// Products on sale right now: anything needing more prints than the
+    /// media has left for sale is hidden
+    pub fn get_available_products(&self, effect_id: Option<&str>) -> Result<Vec<Product>, String> {
+        let mut products = self.get_products(effect_id)?;
+        if let Some(available) = InventoryService::new(self.conn).available_prints()? {
+            let prints: HashSet<String> = products
+                .iter()
+                .filter(|product| product.kind == OrderType::Print)
+                .map(|product| product.id.clone())
+                .collect();
+            products.retain(|product| {
+                let needed = match product.kind {
+                    OrderType::Print => 1,
+                    _ => product.components.iter().filter(|c| prints.contains(&c.product_id)).map(|c| c.quantity as i32).sum(),
+                };
+                needed == 0 || needed <= available
+            });
+        }
+        Ok(products)
+    }
+
+    /// An active product, priced for `effect_id` when given
+    pub fn get_product(&self, id: &str, effect_id: Option<&str>) -> Result<Option<Product>, String> {
+        let row = self.conn.query_row(
+            "SELECT id, name, description, kind, price FROM products WHERE id = ?1 AND active = 1",
+            [id],
+            |row| {
+                let kind: String = row.get(3)?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    kind.parse().unwrap_or(OrderType::Download),
+                    row.get::<_, Option<i32>>(4)?,
+                ))
+            },
+        ).optional().map_err(|e| e.to_string())?;
+        let Some((id, name, description, kind, price)) = row else {
+            return Ok(None);
+        };
+
+        let components = if kind == OrderType::Bundle { self.get_components(&id)? } else { Vec::new() };
+        let price = match price {
+            Some(price) => price,
+            None => self.default_price(&kind, &components, effect_id)?,
+        };
+
+        Ok(Some(Product {
+            id,
+            name,
+            description: description.unwrap_or_default(),
+            kind,
+            price,
+            components,
+        }))
+    }
+
+    fn get_components(&self, bundle_id: &str) -> Result<Vec<BundleComponent>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT product_id, quantity FROM bundle_components WHERE bundle_id = ?1 ORDER BY product_id"
+        ).map_err(|e| e.to_string())?;
+
+        let components = stmt.query_map([bundle_id], |row| {
+            Ok(BundleComponent {
+                product_id: row.get(0)?,
+                quantity: row.get(1)?,
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|c| c.ok()).collect();
+
+        Ok(components)
+    }
+
+    /// Downloads and prints cost what the effect says; a bundle without its own
+    /// price costs the sum of its parts
+    fn default_price(&self, kind: &OrderType, components: &[BundleComponent], effect_id: Option<&str>) -> Result<i32, String> {
+        match kind {
+            OrderType::Download | OrderType::Print => {
+                let (download, print) = self.effect_prices(effect_id)?;
+                Ok(if *kind == OrderType::Download { download } else { print })
+            }
+            OrderType::Bundle => {
+                let mut total = 0;
+                for component in components {
+                    let product = self
+                        .get_product(&component.product_id, effect_id)?
+                        .ok_or_else(|| format!("Bundle component {} is not available", component.product_id))?;
+                    total += product.price * component.quantity as i32;
+                }
+                Ok(total)
+            }
+        }
+    }
+
+    fn effect_prices(&self, effect_id: Option<&str>) -> Result<(i32, i32), String> {
+        let prices = match effect_id {
+            Some(effect_id) => self.conn.query_row(
+                "SELECT price_download, price_print FROM effects WHERE id = ?1",
+                [effect_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).optional().map_err(|e| e.to_string())?,
+            None => None,
+        };
+        Ok(prices.unwrap_or((DEFAULT_DOWNLOAD_PRICE, DEFAULT_PRINT_PRICE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LineItemRequest, MediaKind, OrderStatus, PaymentProviderKind};
+    use crate::services::{ModeService, SessionService, Storage};
+
+    fn on_sale(conn: &Connection) -> Vec<String> {
+        CatalogService::new(conn).get_available_products(None).unwrap().into_iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn products_need_enough_prints_left() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        // Untracked media: everything is on sale
+        assert!(on_sale(&conn).contains(&"download-2prints".to_string()));
+
+        // One sheet left: the two-print bundle goes, the single print stays
+        InventoryService::new(&conn).restock(MediaKind::Paper, 1, None).unwrap();
+        let products = on_sale(&conn);
+        assert!(products.contains(&"download".to_string()));
+        assert!(products.contains(&"print".to_string()));
+        assert!(!products.contains(&"download-2prints".to_string()));
+
+        // An unpaid print order holds the last sheet
+        let mode = ModeService::new(&conn).get_all_modes().unwrap().remove(0);
+        let session_service = SessionService::new(&conn);
+        let session = session_service.create_session(&mode.id, &mode.effects[0].id).unwrap();
+        let print = CatalogService::new(&conn).get_product("print", Some(&session.effect_id)).unwrap().unwrap();
+        let items = [LineItemRequest { product_id: "print".to_string(), quantity: 1 }];
+        let order = session_service
+            .create_order_with_items(&session.id, &items, Some(print.price), PaymentProviderKind::Wechat)
+            .unwrap();
+        assert!(!on_sale(&conn).contains(&"print".to_string()));
+        assert!(session_service.create_order_with_items(&session.id, &items, Some(print.price), PaymentProviderKind::Wechat).is_err());
+
+        // Released once the order is cancelled
+        session_service.transition_order(&order.id, OrderStatus::Cancelled, "timeout", None, None, None).unwrap();
+        assert!(on_sale(&conn).contains(&"print".to_string()));
+    }
+}
@@ -0,0 +1,124 @@
+use std::env;
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::runtime::Handle;
+
+use super::download_service::download_filename;
+use super::{DownloadService, Storage, UpscaleService};
+
+const EXPIRED_PAGE: &str = "<!doctype html><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\">\
+<p style=\"font-family:sans-serif;text-align:center;margin-top:40vh\">下载链接已失效，请在自助机上重新获取二维码</p>";
+
+/// Embedded HTTP server on the kiosk's LAN that hands paid photos to
+/// customers' phones through links from `DownloadService`.
+///
+/// Listens on `DOWNLOAD_LISTEN` (e.g. `0.0.0.0:8089`).
+pub struct DownloadServer;
+
+impl DownloadServer {
+    /// Start the listener thread. Does nothing when no listen address is set.
+    pub fn start(storage: Storage) -> Result<(), String> {
+        let Ok(addr) = env::var("DOWNLOAD_LISTEN") else {
+            tracing::info!("[Download] DOWNLOAD_LISTEN not set, phone downloads disabled");
+            return Ok(());
+        };
+
+        let upscaler = Arc::new(UpscaleService::new(&storage.data_dir)?);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let server = Server::http(&addr).map_err(|e| format!("Failed to bind download listener on {}: {}", addr, e))?;
+        tracing::info!("[Download] Serving paid photos on {}", addr);
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let path = request.url().split('?').next().unwrap_or_default().to_string();
+                match path.strip_prefix("/download/") {
+                    Some(token) if *request.method() == Method::Get => {
+                        // Upscaling and sending to a slow phone must not hold up the next customer
+                        let token = token.to_string();
+                        let storage = storage.clone();
+                        let upscaler = upscaler.clone();
+                        let runtime = runtime.handle().clone();
+                        std::thread::spawn(move || handle_download(&storage, &upscaler, &runtime, &token, request));
+                    }
+                    _ => {
+                        request.respond(Response::empty(404)).ok();
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn handle_download(storage: &Storage, upscaler: &UpscaleService, runtime: &Handle, token: &str, request: Request) {
+    let outcome = serve(storage, upscaler, runtime, token);
+    let order_id = outcome.as_ref().ok().map(|(order_id, _)| order_id.clone());
+    let sent = respond(request, outcome);
+
+    match order_id {
+        Some(order_id) if sent => tracing::info!("[Download] Order {} downloaded", order_id),
+        Some(order_id) => release(storage, token, &order_id),
+        None => {}
+    }
+}
+
+/// The upscaled photo a token grants. Uses the token up.
+fn serve(storage: &Storage, upscaler: &UpscaleService, runtime: &Handle, token: &str) -> Result<(String, Vec<u8>), String> {
+    let (order_id, photo) = {
+        let conn = storage.get_connection()?;
+        DownloadService::new(&conn).redeem(token)?
+    };
+
+    match runtime.block_on(upscaler.upscale(&photo)) {
+        Ok(jpeg) => Ok((order_id, jpeg)),
+        Err(e) => {
+            release(storage, token, &order_id);
+            Err(e)
+        }
+    }
+}
+
+// The customer can retry with the same link when nothing reached the phone
+fn release(storage: &Storage, token: &str, order_id: &str) {
+    let released = storage
+        .get_connection()
+        .and_then(|conn| DownloadService::new(&conn).release(token));
+    if let Err(e) = released {
+        tracing::warn!("[Download] Link for order {} not released: {}", order_id, e);
+    }
+}
+
+/// Returns whether the whole response reached the phone
+fn respond(request: Request, outcome: Result<(String, Vec<u8>), String>) -> bool {
+    let response = match outcome {
+        Ok((order_id, jpeg)) => {
+            let disposition = format!("attachment; filename=\"{}\"", download_filename(&order_id));
+            Response::from_data(jpeg)
+                .with_header(header("Content-Type", "image/jpeg"))
+                .with_header(header("Content-Disposition", &disposition))
+                .with_header(header("Cache-Control", "no-store"))
+        }
+        Err(e) => {
+            tracing::warn!("[Download] Refused: {}", e);
+            Response::from_data(EXPIRED_PAGE.as_bytes().to_vec())
+                .with_status_code(410)
+                .with_header(header("Content-Type", "text/html; charset=utf-8"))
+        }
+    };
+    match request.respond(response) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("[Download] Response not delivered: {}", e);
+            false
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
@@ -0,0 +1,229 @@
+use crate::models::{OrderStatus, OrderType};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use rusqlite::{Connection, OptionalExtension};
+use sha2::Sha256;
+use std::env;
+
+use super::SessionService;
+
+const DEFAULT_TTL_SECS: i64 = 600;
+
+// Without DOWNLOAD_LINK_SECRET, links stop working when the app restarts
+static PROCESS_SECRET: Lazy<[u8; 32]> = Lazy::new(|| rand::thread_rng().gen());
+
+/// Signed download links for paid photos. A token reads
+/// `<order_id>.<expires_at>.<nonce>.<signature>` and is good for one download
+/// before it expires; the download consumes its nonce.
+pub struct DownloadService<'a> {
+    conn: &'a Connection,
+}
+
+struct Token<'t> {
+    order_id: &'t str,
+    expires_at: i64,
+    nonce: &'t str,
+    signature: &'t str,
+}
+
+impl<'a> DownloadService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// The generated photo of a paid order that includes a download, as base64.
+    /// Print-only orders never release the clean file.
+    pub fn paid_photo(&self, order_id: &str) -> Result<String, String> {
+        let session_service = SessionService::new(self.conn);
+        let order = session_service.get_order(order_id)?.ok_or_else(|| "Order not found".to_string())?;
+        if order.status != OrderStatus::Paid {
+            return Err("Order is not paid".to_string());
+        }
+        let has_download = order.order_type == OrderType::Download
+            || session_service.get_order_items(order_id)?.iter().any(|item| item.kind == OrderType::Download);
+        if !has_download {
+            return Err("Order does not include a download".to_string());
+        }
+        session_service
+            .get_session(&order.session_id)?
+            .and_then(|session| session.generated_photo)
+            .ok_or_else(|| "Session has no generated photo".to_string())
+    }
+
+    /// Issue a link under `base_url` to a paid order's photo; returns the URL and its expiry
+    pub fn create_link(&self, order_id: &str, base_url: &str) -> Result<(String, i64), String> {
+        self.paid_photo(order_id)?;
+
+        let ttl = env::var("DOWNLOAD_LINK_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_TTL_SECS);
+        let now = Utc::now().timestamp();
+        let expires_at = now + ttl;
+        let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+
+        self.conn.execute("DELETE FROM download_tokens WHERE expires_at < ?1", [now])
+            .map_err(|e| e.to_string())?;
+        self.conn.execute(
+            "INSERT INTO download_tokens (nonce, order_id, expires_at, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![nonce, order_id, expires_at, now],
+        ).map_err(|e| e.to_string())?;
+
+        let payload = format!("{}.{}.{}", order_id, expires_at, nonce);
+        let token = format!("{}.{}", payload, hex::encode(sign(&payload)));
+        tracing::info!("[Download] Link issued for order {}", order_id);
+        Ok((format!("{}/download/{}", base_url.trim_end_matches('/'), token), expires_at))
+    }
+
+    /// Use up a token from a download request; returns the order it grants
+    /// and its photo. A second request with the same token is refused.
+    pub fn redeem(&self, token: &str) -> Result<(String, String), String> {
+        let token = parse(token).ok_or_else(|| "Malformed download token".to_string())?;
+        let signature = hex::decode(token.signature).map_err(|_| "Malformed download token".to_string())?;
+        let payload = format!("{}.{}.{}", token.order_id, token.expires_at, token.nonce);
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret()).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "Invalid download signature".to_string())?;
+
+        if Utc::now().timestamp() > token.expires_at {
+            return Err("Download link expired".to_string());
+        }
+
+        // Consumed and looked up together, so two requests racing on one link cannot both win
+        let tx = self.conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        let used_at: Option<Option<i64>> = tx.query_row(
+            "SELECT used_at FROM download_tokens WHERE nonce = ?1 AND order_id = ?2",
+            [token.nonce, token.order_id],
+            |row| row.get(0),
+        ).optional().map_err(|e| e.to_string())?;
+        match used_at {
+            None => return Err("Unknown download link".to_string()),
+            Some(Some(_)) => return Err("Download link already used".to_string()),
+            Some(None) => {}
+        }
+        tx.execute(
+            "UPDATE download_tokens SET used_at = ?1 WHERE nonce = ?2",
+            rusqlite::params![Utc::now().timestamp(), token.nonce],
+        ).map_err(|e| e.to_string())?;
+        let photo = self.paid_photo(token.order_id)?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok((token.order_id.to_string(), photo))
+    }
+
+    /// Make a redeemed token usable again after its download failed to reach the phone
+    pub fn release(&self, token: &str) -> Result<(), String> {
+        let token = parse(token).ok_or_else(|| "Malformed download token".to_string())?;
+        self.conn.execute("UPDATE download_tokens SET used_at = NULL WHERE nonce = ?1", [token.nonce])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Name the photo of an order is saved under
+pub fn download_filename(order_id: &str) -> String {
+    format!("ai-photo-{}.jpg", order_id)
+}
+
+/// Address phones use to reach the download server: `DOWNLOAD_BASE_URL`, or
+/// `DOWNLOAD_LISTEN` when it names a concrete LAN address
+pub fn base_url() -> Result<String, String> {
+    if let Ok(url) = env::var("DOWNLOAD_BASE_URL") {
+        return Ok(url);
+    }
+    let listen = env::var("DOWNLOAD_LISTEN").map_err(|_| "Phone download is not set up (DOWNLOAD_LISTEN)".to_string())?;
+    if listen.starts_with("0.0.0.0") || listen.starts_with("[::]") {
+        return Err("DOWNLOAD_BASE_URL must be set when DOWNLOAD_LISTEN is a wildcard address".to_string());
+    }
+    Ok(format!("http://{}", listen))
+}
+
+fn parse(token: &str) -> Option<Token<'_>> {
+    let mut parts = token.split('.');
+    let parsed = Token {
+        order_id: parts.next()?,
+        expires_at: parts.next()?.parse().ok()?,
+        nonce: parts.next()?,
+        signature: parts.next()?,
+    };
+    parts.next().is_none().then_some(parsed)
+}
+
+fn secret() -> Vec<u8> {
+    env::var("DOWNLOAD_LINK_SECRET")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(String::into_bytes)
+        .unwrap_or_else(|| PROCESS_SECRET.to_vec())
+}
+
+fn sign(payload: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Order, PaymentProviderKind};
+    use crate::services::mock_provider::create_order;
+    use crate::services::session_service::OrderContents;
+    use crate::services::{ModeService, Storage};
+
+    fn print_order(storage: &Storage) -> Order {
+        let conn = storage.get_connection().unwrap();
+        let mode = ModeService::new(&conn).get_all_modes().unwrap().remove(0);
+        let session_service = SessionService::new(&conn);
+        let session = session_service.create_session(&mode.id, &mode.effects[0].id).unwrap();
+        let contents = OrderContents::Single { order_type: OrderType::Print, amount: None };
+        session_service.create_order_from(&session.id, contents, PaymentProviderKind::Wechat).unwrap()
+    }
+
+    const BASE_URL: &str = "http://192.168.1.20:8089";
+
+    #[test]
+    fn link_is_used_once() {
+        let storage = Storage::temp();
+        let order = create_order(&storage);
+        let conn = storage.get_connection().unwrap();
+        let session_service = SessionService::new(&conn);
+        session_service.save_generated_photo(&order.session_id, "aGVsbG8=", false).unwrap();
+        let download_service = DownloadService::new(&conn);
+        assert!(download_service.create_link(&order.id, BASE_URL).is_err());
+
+        session_service.transition_order(&order.id, OrderStatus::Paid, "test", Some("tx1"), None, None).unwrap();
+        let (url, _) = download_service.create_link(&order.id, BASE_URL).unwrap();
+        assert!(url.starts_with("http://192.168.1.20:8089/download/"), "{}", url);
+        let token = url.rsplit('/').next().unwrap();
+
+        assert_eq!(download_service.redeem(token).unwrap(), (order.id.clone(), "aGVsbG8=".to_string()));
+        let err = download_service.redeem(token).unwrap_err();
+        assert_eq!(err, "Download link already used");
+
+        // A download that never reached the phone gives the link back
+        download_service.release(token).unwrap();
+        assert_eq!(download_service.redeem(token).unwrap().0, order.id);
+
+        let (url, _) = download_service.create_link(&order.id, BASE_URL).unwrap();
+        let token = url.rsplit('/').next().unwrap();
+        let last = if token.ends_with('0') { '1' } else { '0' };
+        let forged = format!("{}{}", &token[..token.len() - 1], last);
+        assert!(download_service.redeem(&forged).is_err());
+    }
+
+    #[test]
+    fn print_only_order_releases_no_photo() {
+        let storage = Storage::temp();
+        let order = print_order(&storage);
+        let conn = storage.get_connection().unwrap();
+        let session_service = SessionService::new(&conn);
+        session_service.save_generated_photo(&order.session_id, "aGVsbG8=", false).unwrap();
+        session_service.transition_order(&order.id, OrderStatus::Paid, "test", Some("tx1"), None, None).unwrap();
+
+        let err = DownloadService::new(&conn).paid_photo(&order.id).unwrap_err();
+        assert_eq!(err, "Order does not include a download");
+    }
+}
@@ -0,0 +1,392 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::{Path, PathBuf};
+
+// Detection runs on a downscaled grayscale copy; faces in a booth shot are large
+const DETECT_MAX_SIDE: u32 = 640;
+const SHIFT_FACTOR: f32 = 0.1;
+const SCALE_FACTOR: f32 = 1.1;
+const CLUSTER_IOU: f32 = 0.2;
+const DEFAULT_SCORE_THRESHOLD: f32 = 10.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaceBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaceDetection {
+    pub face_count: u32,
+    pub faces: Vec<FaceBox>,
+}
+
+impl FaceDetection {
+    /// Enforce the mode's person limits on the detected face count
+    pub fn check_person_count(&self, min_persons: u32, max_persons: u32) -> Result<(), String> {
+        if self.face_count == 0 {
+            return Err("No person detected in the photo".to_string());
+        }
+        if self.face_count < min_persons {
+            return Err(format!(
+                "This mode needs at least {} people, detected {}",
+                min_persons, self.face_count
+            ));
+        }
+        if self.face_count > max_persons {
+            return Err(format!(
+                "This mode allows at most {} people, detected {}",
+                max_persons, self.face_count
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Pixel intensity comparison cascade (pico `facefinder` format)
+struct Cascade {
+    depth: u32,
+    codes: Vec<i8>,
+    preds: Vec<f32>,
+    thresholds: Vec<f32>,
+}
+
+impl Cascade {
+    fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut p = 8; // skip the training window size (tsr, tsc)
+        let read_i32 = |p: usize| -> Result<i32, String> {
+            bytes
+                .get(p..p + 4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| "Face cascade is truncated".to_string())
+        };
+        let read_f32 = |p: usize| read_i32(p).map(|v| f32::from_bits(v as u32));
+
+        let depth = read_i32(p)?;
+        p += 4;
+        let trees = read_i32(p)?;
+        p += 4;
+        if !(1..=16).contains(&depth) || trees <= 0 {
+            return Err("Face cascade header is invalid".to_string());
+        }
+
+        let leaves = 1usize << depth;
+        // Checked before allocating: a corrupt tree count must not size the buffers
+        let tree_len = 4 * leaves - 4 + 4 * leaves + 4;
+        if (trees as usize).checked_mul(tree_len).is_none_or(|len| len > bytes.len() - p) {
+            return Err("Face cascade is truncated".to_string());
+        }
+        let mut codes = Vec::with_capacity(trees as usize * leaves * 4);
+        let mut preds = Vec::with_capacity(trees as usize * leaves);
+        let mut thresholds = Vec::with_capacity(trees as usize);
+
+        for _ in 0..trees {
+            let code_len = 4 * leaves - 4;
+            let tree_codes = bytes
+                .get(p..p + code_len)
+                .ok_or_else(|| "Face cascade is truncated".to_string())?;
+            codes.extend_from_slice(&[0, 0, 0, 0]);
+            codes.extend(tree_codes.iter().map(|&b| b as i8));
+            p += code_len;
+
+            for _ in 0..leaves {
+                preds.push(read_f32(p)?);
+                p += 4;
+            }
+            thresholds.push(read_f32(p)?);
+            p += 4;
+        }
+
+        Ok(Self { depth: depth as u32, codes, preds, thresholds })
+    }
+
+    fn classify(&self, img: &GrayImage, r: i32, c: i32, s: i32) -> Option<f32> {
+        let (w, h) = (img.width() as i32, img.height() as i32);
+        let pixel = |rr: i32, cc: i32| -> u8 {
+            let y = (rr >> 8).clamp(0, h - 1);
+            let x = (cc >> 8).clamp(0, w - 1);
+            img.as_raw()[(y * w + x) as usize]
+        };
+
+        let (r, c) = (r * 256, c * 256);
+        let leaves = 1usize << self.depth;
+        let mut root = 0usize;
+        let mut score = 0.0f32;
+
+        for (i, threshold) in self.thresholds.iter().enumerate() {
+            let mut idx = 1usize;
+            for _ in 0..self.depth {
+                let code = &self.codes[root + 4 * idx..root + 4 * idx + 4];
+                let p1 = pixel(r + code[0] as i32 * s, c + code[1] as i32 * s);
+                let p2 = pixel(r + code[2] as i32 * s, c + code[3] as i32 * s);
+                idx = 2 * idx + (p1 <= p2) as usize;
+            }
+            score += self.preds[leaves * i + idx - leaves];
+            if score <= *threshold {
+                return None;
+            }
+            root += 4 * leaves;
+        }
+
+        Some(score - self.thresholds[self.thresholds.len() - 1])
+    }
+}
+
+pub struct FaceService {
+    /// Why detection is unavailable when no usable cascade was found
+    cascade: Result<Cascade, String>,
+    score_threshold: f32,
+}
+
+impl FaceService {
+    /// Load the face cascade from `FACE_CASCADE_PATH` or `<data_dir>/facefinder`.
+    /// Without a usable cascade the app still starts, but every detection fails.
+    pub fn new(data_dir: &Path) -> Self {
+        let path = env::var("FACE_CASCADE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| data_dir.join("facefinder"));
+        let score_threshold = env::var("FACE_SCORE_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_SCORE_THRESHOLD);
+
+        let cascade = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Cascade::parse(&bytes))
+            .map_err(|e| {
+                tracing::error!("[Face] Face cascade not usable at {:?}: {}; photos cannot be generated", path, e);
+                format!(
+                    "Face detection is not set up: no usable cascade at {} ({}). Set FACE_CASCADE_PATH to the pico facefinder file.",
+                    path.display(),
+                    e
+                )
+            });
+        if cascade.is_ok() {
+            tracing::info!("[Face] Cascade loaded from {:?}", path);
+        }
+
+        Self { cascade, score_threshold }
+    }
+
+    /// Detect faces. Boxes are in the coordinate space of `img`.
+    /// Errors when no usable cascade was loaded.
+    pub fn detect(&self, img: &DynamicImage) -> Result<FaceDetection, String> {
+        let cascade = self.cascade.as_ref().map_err(|e| e.clone())?;
+
+        let scale = (DETECT_MAX_SIDE as f32 / img.width().max(img.height()) as f32).min(1.0);
+        let gray = if scale < 1.0 {
+            img.resize(DETECT_MAX_SIDE, DETECT_MAX_SIDE, FilterType::Triangle).to_luma8()
+        } else {
+            img.to_luma8()
+        };
+
+        let short_side = gray.width().min(gray.height()) as f32;
+        let min_size = (short_side / 12.0).max(24.0);
+        let max_size = short_side;
+
+        let mut candidates = Vec::new();
+        let mut size = min_size;
+        while size <= max_size {
+            let step = (SHIFT_FACTOR * size).max(1.0) as i32;
+            let offset = (size / 2.0 + 1.0) as i32;
+            let s = size as i32;
+
+            let mut r = offset;
+            while r <= gray.height() as i32 - offset {
+                let mut c = offset;
+                while c <= gray.width() as i32 - offset {
+                    if let Some(q) = cascade.classify(&gray, r, c, s) {
+                        candidates.push((r as f32, c as f32, size, q));
+                    }
+                    c += step;
+                }
+                r += step;
+            }
+            size *= SCALE_FACTOR;
+        }
+
+        let faces: Vec<FaceBox> = cluster(candidates)
+            .into_iter()
+            .filter(|&(_, _, _, q)| q >= self.score_threshold)
+            .map(|(r, c, s, q)| {
+                let half = s / 2.0;
+                FaceBox {
+                    x: ((c - half).max(0.0) / scale) as u32,
+                    y: ((r - half).max(0.0) / scale) as u32,
+                    width: (s / scale) as u32,
+                    height: (s / scale) as u32,
+                    score: q,
+                }
+            })
+            .collect();
+
+        tracing::info!("[Face] Detected {} face(s)", faces.len());
+        Ok(FaceDetection { face_count: faces.len() as u32, faces })
+    }
+}
+
+/// Merge overlapping detections, averaging position and size and summing scores
+fn cluster(mut dets: Vec<(f32, f32, f32, f32)>) -> Vec<(f32, f32, f32, f32)> {
+    dets.sort_by(|a, b| b.3.total_cmp(&a.3));
+    let mut assigned = vec![false; dets.len()];
+    let mut clusters = Vec::new();
+
+    for i in 0..dets.len() {
+        if assigned[i] {
+            continue;
+        }
+        let (mut r, mut c, mut s, mut q, mut n) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for j in i..dets.len() {
+            if !assigned[j] && iou(dets[i], dets[j]) > CLUSTER_IOU {
+                assigned[j] = true;
+                r += dets[j].0;
+                c += dets[j].1;
+                s += dets[j].2;
+                q += dets[j].3;
+                n += 1.0;
+            }
+        }
+        clusters.push((r / n, c / n, s / n, q));
+    }
+
+    clusters
+}
+
+fn iou(a: (f32, f32, f32, f32), b: (f32, f32, f32, f32)) -> f32 {
+    let overlap = |ca: f32, cb: f32| {
+        ((ca + a.2 / 2.0).min(cb + b.2 / 2.0) - (ca - a.2 / 2.0).max(cb - b.2 / 2.0)).max(0.0)
+    };
+    let inter = overlap(a.0, b.0) * overlap(a.1, b.1);
+    inter / (a.2 * a.2 + b.2 * b.2 - inter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    /// Depth-1 cascade that fires on a dark blob about half the window wide:
+    /// dark a quarter window out from the centre, light at the window's edges
+    fn dark_blob_cascade() -> Vec<u8> {
+        // (code, leaf scores): leaf 0 is taken when p1 > p2
+        let edge = |r: i8, c: i8| ([r as u8, c as u8, 0, 0], [1.0f32, -1.0]);
+        let inner = |r: i8, c: i8| ([r as u8, c as u8, 0, 0], [-1.0f32, 1.0]);
+        let trees = [
+            edge(0, 127), edge(0, -127), edge(127, 0), edge(-127, 0),
+            inner(0, 64), inner(0, -64), inner(64, 0), inner(-64, 0),
+        ];
+
+        let mut bytes = Vec::new();
+        for v in [24i32, 24, 1, trees.len() as i32] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        // Every tree has to pass
+        for (i, (code, preds)) in trees.iter().enumerate() {
+            bytes.extend_from_slice(code);
+            for pred in preds {
+                bytes.extend_from_slice(&pred.to_le_bytes());
+            }
+            bytes.extend_from_slice(&(i as f32 + 0.5).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn service(cascade: &[u8]) -> FaceService {
+        FaceService { cascade: Ok(Cascade::parse(cascade).unwrap()), score_threshold: 1.0 }
+    }
+
+    fn white_with_squares(squares: &[(u32, u32)], side: u32) -> DynamicImage {
+        let mut img = GrayImage::from_pixel(400, 300, Luma([255]));
+        for &(x, y) in squares {
+            for dy in 0..side {
+                for dx in 0..side {
+                    img.put_pixel(x + dx, y + dy, Luma([0]));
+                }
+            }
+        }
+        DynamicImage::ImageLuma8(img)
+    }
+
+    #[test]
+    fn parses_cascade() {
+        let cascade = Cascade::parse(&dark_blob_cascade()).unwrap();
+        assert_eq!(cascade.depth, 1);
+        assert_eq!(cascade.codes.len(), 8 * 8);
+        assert_eq!(&cascade.codes[..8], &[0, 0, 0, 0, 0, 127, 0, 0]);
+        assert_eq!(&cascade.preds[..4], &[1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(cascade.thresholds, vec![0.5, 1.5, 2.5, 3.5, 4.5, 5.5, 6.5, 7.5]);
+    }
+
+    #[test]
+    fn truncated_cascade_is_an_error() {
+        let bytes = dark_blob_cascade();
+        for len in 0..bytes.len() {
+            assert!(Cascade::parse(&bytes[..len]).is_err(), "parsed {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn corrupt_header_is_an_error() {
+        let header = |depth: i32, trees: i32| {
+            let mut bytes = dark_blob_cascade();
+            bytes[8..12].copy_from_slice(&depth.to_le_bytes());
+            bytes[12..16].copy_from_slice(&trees.to_le_bytes());
+            Cascade::parse(&bytes)
+        };
+        assert!(header(0, 1).is_err());
+        assert!(header(17, 1).is_err());
+        assert!(header(1, 0).is_err());
+        assert!(header(1, -1).is_err());
+        // A huge tree count must not reach the allocator
+        assert!(header(16, i32::MAX).is_err());
+    }
+
+    #[test]
+    fn detects_each_dark_patch() {
+        let faces = service(&dark_blob_cascade());
+
+        let blank = faces.detect(&white_with_squares(&[], 0)).unwrap();
+        assert_eq!(blank.face_count, 0);
+
+        let one = faces.detect(&white_with_squares(&[(60, 60)], 60)).unwrap();
+        assert_eq!(one.face_count, 1);
+        let face = &one.faces[0];
+        assert!(face.x <= 90 && face.x + face.width >= 90 && face.y <= 90 && face.y + face.height >= 90);
+
+        let two = faces.detect(&white_with_squares(&[(40, 60), (280, 160)], 60)).unwrap();
+        assert_eq!(two.face_count, 2);
+    }
+
+    #[test]
+    fn group_mode_rejects_a_single_face() {
+        let storage = crate::services::Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let modes = crate::services::ModeService::new(&conn);
+        let group = modes.get_mode_by_id("movie").unwrap().unwrap();
+        let portrait = modes.get_mode_by_id("age").unwrap().unwrap();
+        let faces = service(&dark_blob_cascade());
+
+        let one = faces.detect(&white_with_squares(&[(60, 60)], 60)).unwrap();
+        let err = one.check_person_count(group.min_persons, group.max_persons).unwrap_err();
+        assert!(err.contains("at least 2 people"), "{}", err);
+        assert!(one.check_person_count(portrait.min_persons, portrait.max_persons).is_ok());
+
+        let two = faces.detect(&white_with_squares(&[(40, 60), (280, 160)], 60)).unwrap();
+        assert!(two.check_person_count(group.min_persons, group.max_persons).is_ok());
+        let err = two.check_person_count(portrait.min_persons, portrait.max_persons).unwrap_err();
+        assert!(err.contains("at most 1 people"), "{}", err);
+    }
+
+    #[test]
+    fn missing_cascade_fails_detection() {
+        let dir = std::env::temp_dir().join(format!("face-{}", uuid::Uuid::new_v4()));
+        let faces = FaceService::new(&dir);
+        let err = faces.detect(&white_with_squares(&[], 0)).unwrap_err();
+        assert!(err.contains("Face detection is not set up") && err.contains("FACE_CASCADE_PATH"), "{}", err);
+    }
+}
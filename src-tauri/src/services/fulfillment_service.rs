@@ -0,0 +1,127 @@
+use crate::models::{Fulfillment, FulfillmentStatus, OrderType};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+
+use super::SessionService;
+
+pub struct FulfillmentService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> FulfillmentService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Queue delivery of every unit of a freshly paid order. Downloads are
+    /// released at once; prints wait for the printer. Does nothing when the
+    /// order already has fulfillments.
+    pub fn create_for_order(&self, order_id: &str) -> Result<Vec<Fulfillment>, String> {
+        let existing: Option<i64> = self.conn.query_row(
+            "SELECT id FROM fulfillments WHERE order_id = ?1 LIMIT 1",
+            [order_id],
+            |row| row.get(0),
+        ).optional().map_err(|e| e.to_string())?;
+        if existing.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let session_service = SessionService::new(self.conn);
+        let mut units: Vec<(Option<i64>, OrderType)> = Vec::new();
+        for item in session_service.get_order_items(order_id)? {
+            // Bundle lines are delivered through their component lines
+            if item.kind == OrderType::Bundle {
+                continue;
+            }
+            units.extend((0..item.quantity).map(|_| (Some(item.id), item.kind.clone())));
+        }
+        // Orders from before line items are a single unit of their type
+        if units.is_empty() {
+            let order = session_service.get_order(order_id)?.ok_or_else(|| "Order not found".to_string())?;
+            units.push((None, order.order_type));
+        }
+
+        let now = Utc::now().timestamp();
+        let mut fulfillments = Vec::new();
+        for (order_item_id, kind) in units {
+            let (status, detail) = match kind {
+                OrderType::Download => (FulfillmentStatus::Fulfilled, Some("Released for download".to_string())),
+                _ => (FulfillmentStatus::Pending, None),
+            };
+            self.conn.execute(
+                "INSERT INTO fulfillments (order_id, order_item_id, kind, status, detail, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+                rusqlite::params![order_id, order_item_id, kind.to_string(), status.to_string(), detail, now],
+            ).map_err(|e| e.to_string())?;
+
+            fulfillments.push(Fulfillment {
+                id: self.conn.last_insert_rowid(),
+                order_id: order_id.to_string(),
+                order_item_id,
+                kind,
+                status,
+                detail,
+                created_at: now,
+                updated_at: now,
+            });
+        }
+
+        tracing::info!("[Fulfillment] Order {}: {} unit(s) queued", order_id, fulfillments.len());
+        Ok(fulfillments)
+    }
+
+    pub fn update_status(&self, id: i64, status: FulfillmentStatus, detail: Option<&str>) -> Result<(), String> {
+        let updated = self.conn.execute(
+            "UPDATE fulfillments SET status = ?1, detail = COALESCE(?2, detail), updated_at = ?3 WHERE id = ?4",
+            rusqlite::params![status.to_string(), detail, Utc::now().timestamp(), id],
+        ).map_err(|e| e.to_string())?;
+        if updated == 0 {
+            return Err("Fulfillment not found".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn get_fulfillments(&self, order_id: &str) -> Result<Vec<Fulfillment>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, order_item_id, kind, status, detail, created_at, updated_at
+             FROM fulfillments WHERE order_id = ?1 ORDER BY id"
+        ).map_err(|e| e.to_string())?;
+
+        let fulfillments = stmt.query_map([order_id], fulfillment_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|f| f.ok())
+            .collect();
+
+        Ok(fulfillments)
+    }
+
+    /// Units of one kind still waiting, oldest first
+    pub fn get_pending(&self, kind: OrderType) -> Result<Vec<Fulfillment>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, order_item_id, kind, status, detail, created_at, updated_at
+             FROM fulfillments WHERE kind = ?1 AND status = ?2 ORDER BY id"
+        ).map_err(|e| e.to_string())?;
+
+        let fulfillments = stmt.query_map([kind.to_string(), FulfillmentStatus::Pending.to_string()], fulfillment_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|f| f.ok())
+            .collect();
+
+        Ok(fulfillments)
+    }
+}
+
+fn fulfillment_from_row(row: &rusqlite::Row) -> rusqlite::Result<Fulfillment> {
+    let kind: String = row.get(3)?;
+    let status: String = row.get(4)?;
+    Ok(Fulfillment {
+        id: row.get(0)?,
+        order_id: row.get(1)?,
+        order_item_id: row.get(2)?,
+        kind: kind.parse().unwrap_or(OrderType::Download),
+        status: status.parse().unwrap_or(FulfillmentStatus::Pending),
+        detail: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
@@ -0,0 +1,159 @@
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_TTL_SECS: u64 = 24 * 3600;
+const DEFAULT_MAX_MB: u64 = 512;
+
+/// On-disk cache of AI results so repeated identical requests are not billed twice
+pub struct GenerationCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+}
+
+impl GenerationCache {
+    pub fn new(data_dir: &Path) -> Result<Self, String> {
+        let dir = data_dir.join("generation_cache");
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create cache dir: {}", e))?;
+
+        let ttl_secs = env::var("GENERATION_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+        let max_mb = env::var("GENERATION_CACHE_MAX_MB")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_MB);
+
+        Ok(Self {
+            dir,
+            ttl: Duration::from_secs(ttl_secs),
+            max_bytes: max_mb * 1024 * 1024,
+        })
+    }
+
+    /// Cache key over the normalized input image, the final prompt and provider params
+    pub fn key(photo_base64: &str, prompt: &str, params: &str) -> String {
+        let mut hasher = Sha256::new();
+        for part in [photo_base64, prompt, params] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let path = self.entry_path(key);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        if is_expired(modified, self.ttl) {
+            fs::remove_file(&path).ok();
+            return None;
+        }
+
+        let photo = fs::read_to_string(&path).ok()?;
+        tracing::info!("[Cache] Hit for generation {}", &key[..12]);
+        Some(photo)
+    }
+
+    pub fn put(&self, key: &str, photo_base64: &str) -> Result<(), String> {
+        // Write then rename so a concurrent reader never sees a partial entry
+        let tmp = self.dir.join(format!("{}.tmp", key));
+        fs::write(&tmp, photo_base64).map_err(|e| format!("Failed to write cache entry: {}", e))?;
+        fs::rename(&tmp, self.entry_path(key)).map_err(|e| format!("Failed to write cache entry: {}", e))?;
+        self.prune();
+        Ok(())
+    }
+
+    /// Drop expired entries, then the oldest ones until the cache fits its size cap
+    pub fn prune(&self) {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+
+        let mut entries: Vec<(PathBuf, SystemTime, u64)> = Vec::new();
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("b64") {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else { continue };
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            if is_expired(modified, self.ttl) {
+                fs::remove_file(&path).ok();
+            } else {
+                entries.push((path, modified, meta.len()));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, _, len)| len).sum();
+        entries.sort_by_key(|(_, modified, _)| *modified);
+        for (path, _, len) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.b64", key))
+    }
+}
+
+fn is_expired(modified: SystemTime, ttl: Duration) -> bool {
+    modified.elapsed().map(|age| age > ttl).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: &str = "model=image-01;aspect_ratio=3:4";
+
+    fn cache() -> GenerationCache {
+        GenerationCache::new(&env::temp_dir().join(format!("ai-photobooth-test-{}", uuid::Uuid::new_v4()))).unwrap()
+    }
+
+    #[test]
+    fn stored_result_is_returned() {
+        let cache = cache();
+        let key = GenerationCache::key("cGhvdG8=", "anime portrait", PARAMS);
+        assert_eq!(cache.get(&key), None);
+
+        cache.put(&key, "cmVzdWx0").unwrap();
+        assert_eq!(cache.get(&key).as_deref(), Some("cmVzdWx0"));
+        assert_eq!(cache.get(&GenerationCache::key("b3RoZXI=", "anime portrait", PARAMS)), None);
+    }
+
+    #[test]
+    fn styles_do_not_share_results() {
+        let cache = cache();
+        let anime = GenerationCache::key("cGhvdG8=", "anime portrait", PARAMS);
+        let cyberpunk = GenerationCache::key("cGhvdG8=", "cyberpunk portrait", PARAMS);
+        assert_ne!(anime, cyberpunk);
+        assert_ne!(anime, GenerationCache::key("cGhvdG8=", "anime portrait", "model=image-01;aspect_ratio=1:1"));
+        // Parts are length-prefixed, so moving text between them changes the key
+        assert_ne!(GenerationCache::key("ab", "c", PARAMS), GenerationCache::key("a", "bc", PARAMS));
+
+        cache.put(&anime, "YW5pbWU=").unwrap();
+        assert_eq!(cache.get(&cyberpunk), None);
+        cache.put(&cyberpunk, "Y3liZXJwdW5r").unwrap();
+        assert_eq!(cache.get(&anime).as_deref(), Some("YW5pbWU="));
+        assert_eq!(cache.get(&cyberpunk).as_deref(), Some("Y3liZXJwdW5r"));
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let cache = GenerationCache { ttl: Duration::ZERO, ..cache() };
+        let key = GenerationCache::key("cGhvdG8=", "anime portrait", PARAMS);
+        cache.put(&key, "cmVzdWx0").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&key), None);
+        assert!(!cache.entry_path(&key).exists());
+    }
+}
@@ -0,0 +1,525 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use std::env;
+use std::io::Cursor;
+
+// Output size of the fallback card, matching the 3:4 aspect ratio requested from MiniMax
+const FALLBACK_WIDTH: u32 = 768;
+const FALLBACK_HEIGHT: u32 = 1024;
+const FRAME_BORDER: u32 = 32;
+const FRAME_BAND: u32 = 112;
+const JPEG_QUALITY: u8 = 90;
+
+// Input limits for captured photos
+const MAX_INPUT_BYTES: usize = 20 * 1024 * 1024;
+const MAX_INPUT_DIMENSION: u32 = 12_000;
+const MIN_PHOTO_SHORT_SIDE: u32 = 320;
+
+// Watermarked preview shown before payment
+const DEFAULT_PREVIEW_LONG_SIDE: u32 = 640;
+const PREVIEW_JPEG_QUALITY: u8 = 75;
+const WATERMARK_SPACING: u32 = 56;
+const WATERMARK_BAND: u32 = 12;
+const WATERMARK_OPACITY: f32 = 0.35;
+
+// MiniMax subject_reference limits: long side and encoded size after normalization
+const PROVIDER_MAX_LONG_SIDE: u32 = 2048;
+const PROVIDER_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// Captured photo after decoding, orientation and re-encoding for the provider
+#[derive(Debug, Clone)]
+pub struct NormalizedPhoto {
+    pub base64: String,      // JPEG, no data URL prefix
+    pub width: u32,
+    pub height: u32,
+    pub source_format: String,
+}
+
+/// Local look applied to the customer's photo when no AI result is available
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LocalStyle {
+    Cartoon,
+    Poster,
+    Anime,
+    Neon,
+    Sepia,
+    Youthful,
+    Aged,
+}
+
+impl LocalStyle {
+    fn for_effect(mode_id: &str, effect_id: &str) -> Self {
+        match mode_id {
+            "cartoon" => LocalStyle::Cartoon,
+            "movie" => LocalStyle::Poster,
+            "anime" => LocalStyle::Anime,
+            "cyberpunk" => LocalStyle::Neon,
+            "traditional" => LocalStyle::Sepia,
+            "age" if effect_id == "age-01" => LocalStyle::Youthful,
+            "age" => LocalStyle::Aged,
+            _ => LocalStyle::Poster,
+        }
+    }
+}
+
+pub struct ImageService {
+    brand_color: Rgb<u8>,
+    logo_path: Option<String>,
+}
+
+impl ImageService {
+    pub fn new() -> Self {
+        let brand_color = env::var("BRAND_COLOR")
+            .ok()
+            .and_then(|c| parse_hex_color(&c))
+            .unwrap_or(Rgb([124, 58, 237]));
+        let logo_path = env::var("BRAND_LOGO_PATH").ok().filter(|p| !p.is_empty());
+
+        Self { brand_color, logo_path }
+    }
+
+    /// Render the local fallback shown when AI generation is unavailable:
+    /// the customer's photo with a style filter inside the branded frame.
+    /// Returns base64 JPEG without a data URL prefix.
+    pub fn render_fallback(&self, photo_base64: &str, mode_id: &str, effect_id: &str) -> Result<String, String> {
+        let style = LocalStyle::for_effect(mode_id, effect_id);
+        let inner_width = FALLBACK_WIDTH - FRAME_BORDER * 2;
+        let inner_height = FALLBACK_HEIGHT - FRAME_BORDER - FRAME_BAND;
+
+        let photo = match decode_base64_image(photo_base64) {
+            Ok(img) => {
+                let mut rgb = img
+                    .resize_to_fill(inner_width, inner_height, FilterType::Lanczos3)
+                    .to_rgb8();
+                apply_style(&mut rgb, style);
+                rgb
+            }
+            Err(e) => {
+                // Still hand back a branded card rather than nothing
+                tracing::warn!("[Fallback] Could not decode captured photo ({}), rendering empty frame", e);
+                RgbImage::from_pixel(inner_width, inner_height, mix(self.brand_color, Rgb([255, 255, 255]), 0.8))
+            }
+        };
+
+        let mut canvas = RgbImage::from_pixel(FALLBACK_WIDTH, FALLBACK_HEIGHT, self.brand_color);
+        imageops::replace(&mut canvas, &photo, FRAME_BORDER as i64, FRAME_BORDER as i64);
+        self.draw_logo(&mut canvas);
+
+        tracing::info!("[Fallback] Rendered local fallback with style {:?}", style);
+        encode_jpeg_base64(&canvas)
+    }
+
+    /// Render what the customer sees before paying: the photo downscaled to
+    /// `PREVIEW_LONG_SIDE` under diagonal bands and the brand logo, so the
+    /// preview is not worth keeping. Returns base64 JPEG.
+    pub fn render_preview(&self, photo_base64: &str) -> Result<String, String> {
+        let long_side = env::var("PREVIEW_LONG_SIDE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_PREVIEW_LONG_SIDE);
+
+        let img = decode_base64_image(photo_base64)?;
+        let mut preview = if img.width().max(img.height()) > long_side {
+            img.resize(long_side, long_side, FilterType::Triangle).to_rgb8()
+        } else {
+            img.to_rgb8()
+        };
+
+        let white = Rgb([255, 255, 255]);
+        for (x, y, px) in preview.enumerate_pixels_mut() {
+            if (x + y) % WATERMARK_SPACING < WATERMARK_BAND {
+                *px = mix(*px, white, WATERMARK_OPACITY);
+            }
+        }
+        self.draw_watermark_logo(&mut preview);
+
+        Ok(STANDARD.encode(encode_jpeg(&preview, PREVIEW_JPEG_QUALITY)?))
+    }
+
+    /// Validate a captured photo and prepare it for generation: detect the real
+    /// format, apply EXIF orientation, enforce size limits, downsize to the
+    /// provider's maximum and re-encode as JPEG.
+    pub fn normalize_photo(&self, photo_base64: &str) -> Result<NormalizedPhoto, String> {
+        let bytes = decode_base64_bytes(photo_base64)?;
+        if bytes.is_empty() {
+            return Err("Photo is empty".to_string());
+        }
+        if bytes.len() > MAX_INPUT_BYTES {
+            return Err(format!(
+                "Photo is too large ({} bytes, max {})",
+                bytes.len(),
+                MAX_INPUT_BYTES
+            ));
+        }
+
+        let format = image::guess_format(&bytes)
+            .map_err(|_| "Unrecognized photo format".to_string())?;
+        if !matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) {
+            return Err(format!("Unsupported photo format: {:?}", format));
+        }
+
+        // The JPEG decoder pads truncated scans with grey instead of failing
+        if format == ImageFormat::Jpeg && !has_jpeg_eoi(&bytes) {
+            return Err("Corrupt photo: JPEG data is truncated".to_string());
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_INPUT_DIMENSION);
+        limits.max_image_height = Some(MAX_INPUT_DIMENSION);
+
+        let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+        reader.limits(limits);
+        let mut decoder = reader
+            .into_decoder()
+            .map_err(|e| format!("Corrupt photo: {}", e))?;
+        let orientation = decoder
+            .orientation()
+            .map_err(|e| format!("Corrupt photo metadata: {}", e))?;
+        let mut img = DynamicImage::from_decoder(decoder)
+            .map_err(|e| format!("Corrupt photo: {}", e))?;
+        img.apply_orientation(orientation);
+
+        if img.width().min(img.height()) < MIN_PHOTO_SHORT_SIDE {
+            return Err(format!(
+                "Photo is too small ({}x{}, short side must be at least {}px)",
+                img.width(),
+                img.height(),
+                MIN_PHOTO_SHORT_SIDE
+            ));
+        }
+
+        if img.width().max(img.height()) > PROVIDER_MAX_LONG_SIDE {
+            img = img.resize(PROVIDER_MAX_LONG_SIDE, PROVIDER_MAX_LONG_SIDE, FilterType::Lanczos3);
+        }
+
+        let rgb = img.to_rgb8();
+        let mut quality = JPEG_QUALITY;
+        let encoded = loop {
+            let encoded = encode_jpeg(&rgb, quality)?;
+            if encoded.len() <= PROVIDER_MAX_BYTES || quality <= 50 {
+                break encoded;
+            }
+            quality -= 10;
+        };
+        if encoded.len() > PROVIDER_MAX_BYTES {
+            return Err("Photo cannot be compressed to the provider's size limit".to_string());
+        }
+
+        tracing::info!(
+            "[Image] Normalized {:?} photo to {}x{} JPEG ({} bytes, orientation {:?})",
+            format,
+            rgb.width(),
+            rgb.height(),
+            encoded.len(),
+            orientation
+        );
+
+        Ok(NormalizedPhoto {
+            base64: STANDARD.encode(encoded),
+            width: rgb.width(),
+            height: rgb.height(),
+            source_format: format.extensions_str()[0].to_string(),
+        })
+    }
+
+    fn draw_logo(&self, canvas: &mut RgbImage) {
+        let Some(path) = &self.logo_path else {
+            return;
+        };
+
+        let logo = match image::open(path) {
+            Ok(logo) => logo,
+            Err(e) => {
+                tracing::warn!("[Fallback] Failed to load brand logo {}: {}", path, e);
+                return;
+            }
+        };
+
+        let max_height = FRAME_BAND - 32;
+        let max_width = FALLBACK_WIDTH - FRAME_BORDER * 2;
+        let logo = logo.resize(max_width, max_height, FilterType::Lanczos3).to_rgba8();
+        let x = (FALLBACK_WIDTH - logo.width()) / 2;
+        let y = FALLBACK_HEIGHT - FRAME_BAND + (FRAME_BAND - logo.height()) / 2;
+
+        let mut rgba = DynamicImage::ImageRgb8(canvas.clone()).to_rgba8();
+        imageops::overlay(&mut rgba, &logo, x as i64, y as i64);
+        *canvas = DynamicImage::ImageRgba8(rgba).to_rgb8();
+    }
+
+    /// The brand logo, translucent, across the middle of a preview
+    fn draw_watermark_logo(&self, canvas: &mut RgbImage) {
+        let Some(path) = &self.logo_path else {
+            return;
+        };
+        let logo = match image::open(path) {
+            Ok(logo) => logo,
+            Err(e) => {
+                tracing::warn!("[Preview] Failed to load brand logo {}: {}", path, e);
+                return;
+            }
+        };
+
+        let mut logo = logo.resize(canvas.width() * 2 / 3, canvas.height() / 3, FilterType::Lanczos3).to_rgba8();
+        for px in logo.pixels_mut() {
+            px[3] = (px[3] as f32 * WATERMARK_OPACITY) as u8;
+        }
+        let x = (canvas.width() - logo.width()) / 2;
+        let y = (canvas.height() - logo.height()) / 2;
+
+        let mut rgba = DynamicImage::ImageRgb8(canvas.clone()).to_rgba8();
+        imageops::overlay(&mut rgba, &logo, x as i64, y as i64);
+        *canvas = DynamicImage::ImageRgba8(rgba).to_rgb8();
+    }
+}
+
+impl Default for ImageService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn has_jpeg_eoi(bytes: &[u8]) -> bool {
+    // Some encoders pad after the end-of-image marker
+    let tail = &bytes[bytes.len().saturating_sub(64)..];
+    tail.windows(2).any(|w| w == [0xFF, 0xD9])
+}
+
+pub fn decode_base64_bytes(data: &str) -> Result<Vec<u8>, String> {
+    // Accept both raw base64 and data URLs
+    let payload = match data.find("base64,") {
+        Some(idx) => &data[idx + "base64,".len()..],
+        None => data,
+    };
+    STANDARD
+        .decode(payload.trim())
+        .map_err(|e| format!("Invalid base64 image: {}", e))
+}
+
+pub fn decode_base64_image(data: &str) -> Result<DynamicImage, String> {
+    let bytes = decode_base64_bytes(data)?;
+    image::load_from_memory(&bytes).map_err(|e| format!("Failed to decode image: {}", e))
+}
+
+pub fn encode_jpeg(img: &RgbImage, quality: u8) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    JpegEncoder::new_with_quality(&mut buf, quality)
+        .encode_image(img)
+        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+    Ok(buf)
+}
+
+pub fn encode_jpeg_base64(img: &RgbImage) -> Result<String, String> {
+    Ok(STANDARD.encode(encode_jpeg(img, JPEG_QUALITY)?))
+}
+
+fn apply_style(img: &mut RgbImage, style: LocalStyle) {
+    for px in img.pixels_mut() {
+        *px = match style {
+            LocalStyle::Cartoon => posterize(saturate(*px, 1.5), 6),
+            LocalStyle::Poster => {
+                // Teal shadows, warm highlights
+                split_tone(contrast(*px, 1.35), Rgb([0, 90, 110]), Rgb([255, 170, 90]), 0.25)
+            }
+            LocalStyle::Anime => posterize(brighten(saturate(*px, 1.3), 18), 8),
+            LocalStyle::Neon => {
+                split_tone(contrast(saturate(*px, 1.4), 1.2), Rgb([60, 0, 110]), Rgb([0, 240, 255]), 0.4)
+            }
+            LocalStyle::Sepia => sepia(*px),
+            LocalStyle::Youthful => brighten(saturate(*px, 1.2), 20),
+            LocalStyle::Aged => {
+                let l = luma(*px) as u8;
+                contrast(mix(Rgb([l, l, l]), Rgb([112, 96, 80]), 0.15), 1.15)
+            }
+        };
+    }
+
+    if style != LocalStyle::Cartoon && style != LocalStyle::Anime {
+        vignette(img);
+    }
+}
+
+fn vignette(img: &mut RgbImage) {
+    let (w, h) = img.dimensions();
+    let cx = w as f32 / 2.0;
+    let cy = h as f32 / 2.0;
+    let max_dist = (cx * cx + cy * cy).sqrt();
+    for (x, y, px) in img.enumerate_pixels_mut() {
+        let dx = x as f32 - cx;
+        let dy = y as f32 - cy;
+        let falloff = 1.0 - 0.45 * ((dx * dx + dy * dy).sqrt() / max_dist).powi(2);
+        *px = Rgb(px.0.map(|c| clamp(c as f32 * falloff)));
+    }
+}
+
+fn luma(px: Rgb<u8>) -> f32 {
+    0.299 * px[0] as f32 + 0.587 * px[1] as f32 + 0.114 * px[2] as f32
+}
+
+fn clamp(v: f32) -> u8 {
+    v.round().clamp(0.0, 255.0) as u8
+}
+
+fn mix(a: Rgb<u8>, b: Rgb<u8>, t: f32) -> Rgb<u8> {
+    Rgb([0, 1, 2].map(|i| clamp(a[i] as f32 * (1.0 - t) + b[i] as f32 * t)))
+}
+
+fn split_tone(px: Rgb<u8>, shadows: Rgb<u8>, highlights: Rgb<u8>, strength: f32) -> Rgb<u8> {
+    let t = luma(px) / 255.0;
+    mix(mix(px, shadows, (1.0 - t) * strength), highlights, t * strength)
+}
+
+fn saturate(px: Rgb<u8>, factor: f32) -> Rgb<u8> {
+    let l = luma(px);
+    Rgb(px.0.map(|c| clamp(l + (c as f32 - l) * factor)))
+}
+
+fn contrast(px: Rgb<u8>, factor: f32) -> Rgb<u8> {
+    Rgb(px.0.map(|c| clamp((c as f32 - 128.0) * factor + 128.0)))
+}
+
+fn brighten(px: Rgb<u8>, amount: i16) -> Rgb<u8> {
+    Rgb(px.0.map(|c| (c as i16 + amount).clamp(0, 255) as u8))
+}
+
+fn posterize(px: Rgb<u8>, levels: u8) -> Rgb<u8> {
+    let step = 255.0 / (levels - 1) as f32;
+    Rgb(px.0.map(|c| clamp((c as f32 / step).round() * step)))
+}
+
+fn sepia(px: Rgb<u8>) -> Rgb<u8> {
+    let (r, g, b) = (px[0] as f32, px[1] as f32, px[2] as f32);
+    Rgb([
+        clamp(0.393 * r + 0.769 * g + 0.189 * b),
+        clamp(0.349 * r + 0.686 * g + 0.168 * b),
+        clamp(0.272 * r + 0.534 * g + 0.131 * b),
+    ])
+}
+
+pub fn parse_hex_color(s: &str) -> Option<Rgb<u8>> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRAND: Rgb<u8> = Rgb([124, 58, 237]);
+
+    fn service() -> ImageService {
+        ImageService { brand_color: BRAND, logo_path: None }
+    }
+
+    fn encode(img: &RgbImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    fn grey(width: u32, height: u32) -> String {
+        STANDARD.encode(encode(&RgbImage::from_pixel(width, height, Rgb([100, 100, 100])), ImageFormat::Png))
+    }
+
+    fn close(a: Rgb<u8>, b: Rgb<u8>) -> bool {
+        (0..3).all(|i| a[i].abs_diff(b[i]) <= 12)
+    }
+
+    #[test]
+    fn preview_is_downscaled_and_watermarked() {
+        let preview = decode_base64_image(&service().render_preview(&grey(1280, 960)).unwrap()).unwrap().to_rgb8();
+        assert_eq!(preview.dimensions(), (640, 480));
+
+        // Bands are lightened, the photo between them is left alone
+        assert!(close(*preview.get_pixel(3, 3), Rgb([154, 154, 154])), "{:?}", preview.get_pixel(3, 3));
+        assert!(close(*preview.get_pixel(30, 3), Rgb([100, 100, 100])), "{:?}", preview.get_pixel(30, 3));
+        let marked = preview.pixels().filter(|px| px[0] > 130).count();
+        assert!(marked > preview.len() / 3 / 8, "{} watermarked pixels", marked);
+    }
+
+    #[test]
+    fn small_photo_keeps_its_size_in_preview() {
+        let preview = decode_base64_image(&service().render_preview(&grey(400, 300)).unwrap()).unwrap();
+        assert_eq!((preview.width(), preview.height()), (400, 300));
+    }
+
+    #[test]
+    fn fallback_is_framed() {
+        let fallback = decode_base64_image(&service().render_fallback(&grey(1280, 960), "movie", "movie-01").unwrap())
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(fallback.dimensions(), (FALLBACK_WIDTH, FALLBACK_HEIGHT));
+        assert!(close(*fallback.get_pixel(4, 4), BRAND));
+        assert!(close(*fallback.get_pixel(FALLBACK_WIDTH / 2, FALLBACK_HEIGHT - FRAME_BAND / 2), BRAND));
+        assert!(!close(*fallback.get_pixel(FALLBACK_WIDTH / 2, FALLBACK_HEIGHT / 2), BRAND));
+
+        // An unreadable photo still gets a card of the same size
+        let empty = decode_base64_image(&service().render_fallback("bm90IGFuIGltYWdl", "movie", "movie-01").unwrap()).unwrap();
+        assert_eq!((empty.width(), empty.height()), (FALLBACK_WIDTH, FALLBACK_HEIGHT));
+    }
+
+    /// JPEG of a `width`x`height` photo, red on the left half, tagged with
+    /// EXIF orientation 6 (rotate 90° clockwise to display)
+    fn rotated_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, _| if x < width / 2 { Rgb([220, 30, 30]) } else { Rgb([30, 30, 220]) });
+        let jpeg = encode(&img, ImageFormat::Jpeg);
+
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+        exif.extend([0x00, 0x01]); // one IFD entry
+        exif.extend([0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00]); // Orientation = 6
+        exif.extend([0x00, 0x00, 0x00, 0x00]); // no next IFD
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend([0xFF, 0xE1]);
+        bytes.extend(((exif.len() + 2) as u16).to_be_bytes());
+        bytes.extend(exif);
+        bytes.extend(&jpeg[2..]);
+        bytes
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() {
+        let photo = service().normalize_photo(&STANDARD.encode(rotated_jpeg(480, 360))).unwrap();
+        assert_eq!(photo.source_format, "jpg");
+        assert_eq!((photo.width, photo.height), (360, 480));
+
+        // The left half ends up on top
+        let img = decode_base64_image(&photo.base64).unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (360, 480));
+        assert!(close(*img.get_pixel(180, 60), Rgb([220, 30, 30])), "{:?}", img.get_pixel(180, 60));
+        assert!(close(*img.get_pixel(180, 420), Rgb([30, 30, 220])), "{:?}", img.get_pixel(180, 420));
+    }
+
+    #[test]
+    fn large_photo_is_downsized_for_the_provider() {
+        let photo = service().normalize_photo(&grey(3000, 2000)).unwrap();
+        assert_eq!((photo.width, photo.height), (PROVIDER_MAX_LONG_SIDE, 1365));
+        assert_eq!(photo.source_format, "png");
+    }
+
+    #[test]
+    fn unusable_photos_are_rejected() {
+        let service = service();
+        let err = service.normalize_photo(&STANDARD.encode(b"%PDF-1.7 not a photo")).unwrap_err();
+        assert_eq!(err, "Unrecognized photo format");
+
+        let err = service.normalize_photo("not base64!").unwrap_err();
+        assert!(err.starts_with("Invalid base64 image"), "{}", err);
+
+        // Past the decoder's dimension limit
+        let err = service.normalize_photo(&grey(MAX_INPUT_DIMENSION + 1, 1)).unwrap_err();
+        assert!(err.starts_with("Corrupt photo"), "{}", err);
+
+        let err = service.normalize_photo(&grey(400, 200)).unwrap_err();
+        assert!(err.starts_with("Photo is too small"), "{}", err);
+
+        let jpeg = rotated_jpeg(480, 360);
+        let err = service.normalize_photo(&STANDARD.encode(&jpeg[..jpeg.len() / 2])).unwrap_err();
+        assert!(err.contains("truncated"), "{}", err);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::models::{FulfillmentStatus, MediaKind, MediaSupply, OrderStatus, OrderType};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use std::env;
+
+use super::payment_provider::order_timeout_secs;
+
+const DEFAULT_LOW_THRESHOLD: i32 = 20;
+
+pub struct InventoryService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> InventoryService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    pub fn get_supplies(&self) -> Result<Vec<MediaSupply>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT kind, remaining, low_threshold, updated_at FROM media_inventory ORDER BY kind"
+        ).map_err(|e| e.to_string())?;
+
+        let supplies = stmt.query_map([], supply_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(supplies)
+    }
+
+    pub fn get_supply(&self, kind: MediaKind) -> Result<Option<MediaSupply>, String> {
+        self.conn.query_row(
+            "SELECT kind, remaining, low_threshold, updated_at FROM media_inventory WHERE kind = ?1",
+            [kind.to_string()],
+            supply_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    /// Set the count after loading new media. The threshold is kept when not
+    /// given, or taken from `MEDIA_LOW_THRESHOLD` the first time.
+    pub fn restock(&self, kind: MediaKind, remaining: i32, low_threshold: Option<i32>) -> Result<MediaSupply, String> {
+        if remaining < 0 {
+            return Err("Remaining count cannot be negative".to_string());
+        }
+        let low_threshold = match low_threshold {
+            Some(threshold) if threshold < 0 => return Err("Low-stock threshold cannot be negative".to_string()),
+            Some(threshold) => threshold,
+            None => match self.get_supply(kind)? {
+                Some(supply) => supply.low_threshold,
+                None => env::var("MEDIA_LOW_THRESHOLD")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_LOW_THRESHOLD),
+            },
+        };
+        let supply = MediaSupply { kind, remaining, low_threshold, updated_at: Utc::now().timestamp() };
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO media_inventory (kind, remaining, low_threshold, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![kind.to_string(), supply.remaining, supply.low_threshold, supply.updated_at],
+        ).map_err(|e| e.to_string())?;
+
+        tracing::info!("[Inventory] {} restocked to {}", kind, remaining);
+        Ok(supply)
+    }
+
+    /// Count one completed print against every tracked supply. Returns the
+    /// supplies that just reached their low-stock threshold or ran out.
+    pub fn consume_print(&self) -> Result<Vec<MediaSupply>, String> {
+        let now = Utc::now().timestamp();
+        let mut alerts = Vec::new();
+        for before in self.get_supplies()? {
+            let remaining = (before.remaining - 1).max(0);
+            self.conn.execute(
+                "UPDATE media_inventory SET remaining = ?1, updated_at = ?2 WHERE kind = ?3",
+                rusqlite::params![remaining, now, before.kind.to_string()],
+            ).map_err(|e| e.to_string())?;
+
+            let went_low = before.remaining > before.low_threshold && remaining <= before.low_threshold;
+            let ran_out = before.remaining > 0 && remaining == 0;
+            if went_low || ran_out {
+                tracing::warn!("[Inventory] {} low: {} left", before.kind, remaining);
+                alerts.push(MediaSupply { remaining, updated_at: now, ..before });
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Prints that can still be sold: the scarcest supply less the prints
+    /// already paid for but not printed, and less those held by unpaid orders
+    /// that can still be paid. `None` when no media is tracked.
+    pub fn available_prints(&self) -> Result<Option<i32>, String> {
+        let Some(remaining) = self.get_supplies()?.iter().map(|supply| supply.remaining).min() else {
+            return Ok(None);
+        };
+        // Refunded orders keep their pending units but will never be printed
+        let owed: i32 = self.conn.query_row(
+            "SELECT COUNT(*) FROM fulfillments f JOIN orders o ON o.id = f.order_id
+             WHERE f.kind = ?1 AND f.status = ?2 AND o.status = ?3",
+            [OrderType::Print.to_string(), FulfillmentStatus::Pending.to_string(), OrderStatus::Paid.to_string()],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())?;
+        // Bundle components are their own print lines, already multiplied out
+        let reserved: i32 = self.conn.query_row(
+            "SELECT COALESCE(SUM(i.quantity), 0) FROM order_items i JOIN orders o ON o.id = i.order_id
+             WHERE i.kind = ?1 AND o.status = ?2 AND o.created_at > ?3",
+            rusqlite::params![
+                OrderType::Print.to_string(),
+                OrderStatus::Pending.to_string(),
+                Utc::now().timestamp() - order_timeout_secs()
+            ],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())?;
+        Ok(Some(remaining - owed - reserved))
+    }
+
+    pub fn ensure_in_stock(&self, prints: u32) -> Result<(), String> {
+        match self.available_prints()? {
+            Some(available) if available < prints as i32 => Err(format!(
+                "Out of print media: {} print(s) left",
+                available.max(0)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn supply_from_row(row: &rusqlite::Row) -> rusqlite::Result<MediaSupply> {
+    let kind: String = row.get(0)?;
+    Ok(MediaSupply {
+        kind: kind.parse().unwrap_or(MediaKind::Paper),
+        remaining: row.get(1)?,
+        low_threshold: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
@@ -0,0 +1,414 @@
+use crate::models::PrinterState;
+use async_trait::async_trait;
+use reqwest::Client;
+use std::env;
+
+use super::printer::{PrintDocument, Printer, PrinterJobState};
+
+// RFC 8010/8011 operation ids
+const PRINT_JOB: u16 = 0x0002;
+const GET_JOB_ATTRIBUTES: u16 = 0x0009;
+const GET_PRINTER_ATTRIBUTES: u16 = 0x000b;
+
+// printer-state enum
+const PRINTER_STOPPED: i32 = 5;
+
+// Delimiter tags
+const OPERATION_ATTRIBUTES: u8 = 0x01;
+const END_OF_ATTRIBUTES: u8 = 0x03;
+
+// Value tags
+const INTEGER: u8 = 0x21;
+const BOOLEAN: u8 = 0x22;
+const ENUM: u8 = 0x23;
+const TEXT: u8 = 0x41;
+const NAME: u8 = 0x42;
+const KEYWORD: u8 = 0x44;
+const URI: u8 = 0x45;
+const CHARSET: u8 = 0x47;
+const NATURAL_LANGUAGE: u8 = 0x48;
+const MIME_MEDIA_TYPE: u8 = 0x49;
+
+const DEFAULT_IPP_PORT: u16 = 631;
+
+/// A CUPS queue or any IPP Everywhere printer, e.g.
+/// `PRINTER_URI=ipp://localhost:631/printers/Canon_SELPHY`
+pub struct IppPrinter {
+    printer_uri: String,
+    endpoint: String,
+    user: String,
+    client: Client,
+}
+
+impl IppPrinter {
+    pub fn new() -> Result<Self, String> {
+        let printer_uri = env::var("PRINTER_URI").map_err(|_| "PRINTER_URI not set".to_string())?;
+        let endpoint = http_endpoint(&printer_uri)?;
+        let user = env::var("PRINTER_USER").unwrap_or_else(|_| "photobooth".to_string());
+
+        Ok(Self {
+            printer_uri,
+            endpoint,
+            user,
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()
+                .map_err(|e| e.to_string())?,
+        })
+    }
+
+    async fn send(&self, request: Vec<u8>) -> Result<IppResponse, String> {
+        let response = self.client
+            .post(&self.endpoint)
+            .header("Content-Type", "application/ipp")
+            .body(request)
+            .send()
+            .await
+            .map_err(|e| format!("Printer unreachable: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Printer HTTP error: {}", response.status()));
+        }
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+
+        let response = IppResponse::parse(&body)?;
+        // 0x0000-0x00ff are successful-ok and its variants
+        if response.status > 0x00ff {
+            let message = response.text("status-message").unwrap_or_default();
+            return Err(format!("Printer refused the request (0x{:04x}) {}", response.status, message));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl Printer for IppPrinter {
+    fn name(&self) -> String {
+        self.printer_uri.clone()
+    }
+
+    async fn submit(&self, document: &PrintDocument) -> Result<String, String> {
+        let mut request = IppRequest::new(PRINT_JOB, &self.printer_uri);
+        request.attribute(NAME, "requesting-user-name", self.user.as_bytes());
+        request.attribute(NAME, "job-name", document.job_id.as_bytes());
+        request.attribute(MIME_MEDIA_TYPE, "document-format", document.mime_type.as_bytes());
+        let response = self.send(request.finish(&document.data)).await?;
+
+        response
+            .integer("job-id")
+            .map(|id| id.to_string())
+            .ok_or_else(|| "Printer did not return a job id".to_string())
+    }
+
+    async fn job_state(&self, printer_job_id: &str) -> Result<PrinterJobState, String> {
+        let job_id: i32 = printer_job_id.parse().map_err(|_| format!("Invalid IPP job id: {}", printer_job_id))?;
+
+        let mut request = IppRequest::new(GET_JOB_ATTRIBUTES, &self.printer_uri);
+        request.attribute(INTEGER, "job-id", &job_id.to_be_bytes());
+        request.attribute(NAME, "requesting-user-name", self.user.as_bytes());
+        request.attribute(KEYWORD, "requested-attributes", b"job-state");
+        request.value(KEYWORD, b"job-state-message");
+        request.value(KEYWORD, b"job-state-reasons");
+        let response = self.send(request.finish(&[])).await?;
+
+        let state = response.integer("job-state").ok_or_else(|| "Printer did not report the job state".to_string())?;
+        Ok(match state {
+            // pending, pending-held, processing, processing-stopped
+            3..=6 => PrinterJobState::Processing,
+            9 => PrinterJobState::Completed,
+            // canceled, aborted
+            _ => {
+                let reason = response
+                    .text("job-state-message")
+                    .filter(|m| !m.is_empty())
+                    .or_else(|| response.text("job-state-reasons"))
+                    .unwrap_or_else(|| format!("job-state {}", state));
+                PrinterJobState::Failed(reason)
+            }
+        })
+    }
+
+    async fn status(&self) -> (PrinterState, Option<String>) {
+        let mut request = IppRequest::new(GET_PRINTER_ATTRIBUTES, &self.printer_uri);
+        request.attribute(NAME, "requesting-user-name", self.user.as_bytes());
+        request.attribute(KEYWORD, "requested-attributes", b"printer-state");
+        request.value(KEYWORD, b"printer-state-reasons");
+        request.value(KEYWORD, b"printer-state-message");
+        request.value(KEYWORD, b"printer-is-accepting-jobs");
+        let response = match self.send(request.finish(&[])).await {
+            Ok(response) => response,
+            Err(e) => return (PrinterState::Offline, Some(e)),
+        };
+
+        let message = response.text("printer-state-message").filter(|m| !m.is_empty());
+        let reasons = response.texts("printer-state-reasons");
+        if let Some(state) = reasons.iter().find_map(|reason| state_for_reason(reason)) {
+            return (state, message.or_else(|| Some(reasons.join(", "))));
+        }
+        // A stopped queue (e.g. disabled in CUPS) or one refusing jobs prints nothing
+        let stopped = response.integer("printer-state") == Some(PRINTER_STOPPED);
+        let accepting = response.boolean("printer-is-accepting-jobs").unwrap_or(true);
+        if stopped || !accepting {
+            return (PrinterState::Error, message.or_else(|| Some("Printer is stopped".to_string())));
+        }
+        (PrinterState::Ready, message)
+    }
+}
+
+/// Map an RFC 8011 `printer-state-reasons` keyword onto a health state.
+/// Warnings and reports (e.g. `media-low-warning`) do not stop printing.
+fn state_for_reason(reason: &str) -> Option<PrinterState> {
+    if reason == "none" || reason.ends_with("-warning") || (reason.ends_with("-report") && reason != "offline-report") {
+        return None;
+    }
+    let keyword = reason.strip_suffix("-error").unwrap_or(reason);
+    Some(match keyword {
+        "media-empty" | "media-needed" | "input-tray-missing" => PrinterState::OutOfPaper,
+        // Dye-sub printers report the ribbon as a marker supply
+        "marker-supply-empty" | "toner-empty" | "marker-supply-missing" => PrinterState::OutOfRibbon,
+        "media-jam" | "jam" => PrinterState::Jammed,
+        "offline" | "offline-report" | "shutdown" | "connecting-to-device" => PrinterState::Offline,
+        "door-open" | "cover-open" | "other" | "paused" | "moving-to-paused" => PrinterState::Error,
+        // Unknown reasons without the -error suffix are informational
+        _ if reason.ends_with("-error") => PrinterState::Error,
+        _ => return None,
+    })
+}
+
+/// `ipp://` and `ipps://` are HTTP(S) on port 631 unless a port is given
+fn http_endpoint(printer_uri: &str) -> Result<String, String> {
+    let (scheme, rest) = if let Some(rest) = printer_uri.strip_prefix("ipp://") {
+        ("http", rest)
+    } else if let Some(rest) = printer_uri.strip_prefix("ipps://") {
+        ("https", rest)
+    } else if printer_uri.starts_with("http://") || printer_uri.starts_with("https://") {
+        return Ok(printer_uri.to_string());
+    } else {
+        return Err(format!("Unsupported printer URI: {}", printer_uri));
+    };
+
+    let mut url = url::Url::parse(&format!("{}://{}", scheme, rest)).map_err(|e| format!("Invalid printer URI: {}", e))?;
+    if !rest.split('/').next().unwrap_or_default().contains(':') {
+        url.set_port(Some(DEFAULT_IPP_PORT)).map_err(|_| "Invalid printer URI".to_string())?;
+    }
+    Ok(url.to_string())
+}
+
+struct IppRequest {
+    buf: Vec<u8>,
+}
+
+impl IppRequest {
+    fn new(operation: u16, printer_uri: &str) -> Self {
+        let mut request = Self { buf: Vec::new() };
+        request.buf.extend_from_slice(&[0x02, 0x00]); // IPP 2.0
+        request.buf.extend_from_slice(&operation.to_be_bytes());
+        request.buf.extend_from_slice(&1u32.to_be_bytes()); // request id
+        request.buf.push(OPERATION_ATTRIBUTES);
+        // These two must come first, in this order
+        request.attribute(CHARSET, "attributes-charset", b"utf-8");
+        request.attribute(NATURAL_LANGUAGE, "attributes-natural-language", b"en");
+        request.attribute(URI, "printer-uri", printer_uri.as_bytes());
+        request
+    }
+
+    fn attribute(&mut self, tag: u8, name: &str, value: &[u8]) {
+        self.buf.push(tag);
+        self.buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+    }
+
+    /// Another value of the previous attribute
+    fn value(&mut self, tag: u8, value: &[u8]) {
+        self.attribute(tag, "", value);
+    }
+
+    fn finish(mut self, document: &[u8]) -> Vec<u8> {
+        self.buf.push(END_OF_ATTRIBUTES);
+        self.buf.extend_from_slice(document);
+        self.buf
+    }
+}
+
+struct IppResponse {
+    status: u16,
+    /// Every value of every attribute in every group, additional values
+    /// repeated under their attribute's name
+    attributes: Vec<(String, u8, Vec<u8>)>,
+}
+
+impl IppResponse {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let truncated = || "Truncated IPP response".to_string();
+        if data.len() < 8 {
+            return Err(truncated());
+        }
+        let status = u16::from_be_bytes([data[2], data[3]]);
+
+        let mut attributes: Vec<(String, u8, Vec<u8>)> = Vec::new();
+        let mut pos = 8;
+        while pos < data.len() {
+            let tag = data[pos];
+            pos += 1;
+            if tag == END_OF_ATTRIBUTES {
+                break;
+            }
+            // Group delimiters carry no name or value
+            if tag < 0x10 {
+                continue;
+            }
+
+            let mut field = || -> Result<&[u8], String> {
+                let len = data.get(pos..pos + 2).ok_or_else(truncated)?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                let value = data.get(pos + 2..pos + 2 + len).ok_or_else(truncated)?;
+                pos += 2 + len;
+                Ok(value)
+            };
+            let mut name = String::from_utf8_lossy(field()?).to_string();
+            let value = field()?.to_vec();
+            if name.is_empty() {
+                match attributes.last() {
+                    Some((previous, _, _)) => name = previous.clone(),
+                    None => continue,
+                }
+            }
+            attributes.push((name, tag, value));
+        }
+
+        Ok(Self { status, attributes })
+    }
+
+    fn get(&self, name: &str) -> Option<(u8, &[u8])> {
+        self.attributes
+            .iter()
+            .find(|(n, _, _)| n == name)
+            .map(|(_, tag, value)| (*tag, value.as_slice()))
+    }
+
+    fn integer(&self, name: &str) -> Option<i32> {
+        match self.get(name)? {
+            (INTEGER | ENUM, [a, b, c, d]) => Some(i32::from_be_bytes([*a, *b, *c, *d])),
+            _ => None,
+        }
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        match self.get(name)? {
+            (TEXT | NAME | KEYWORD, value) => Some(String::from_utf8_lossy(value).to_string()),
+            _ => None,
+        }
+    }
+
+    fn texts(&self, name: &str) -> Vec<String> {
+        self.attributes
+            .iter()
+            .filter(|(n, tag, _)| n == name && matches!(*tag, TEXT | NAME | KEYWORD))
+            .map(|(_, _, value)| String::from_utf8_lossy(value).to_string())
+            .collect()
+    }
+
+    fn boolean(&self, name: &str) -> Option<bool> {
+        match self.get(name)? {
+            (BOOLEAN, [value]) => Some(*value != 0),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRINTER_URI: &str = "ipp://localhost/printers/selphy";
+
+    /// One attribute as it appears on the wire
+    fn encoded(tag: u8, name: &str, value: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    fn print_job_request_bytes() {
+        let mut request = IppRequest::new(PRINT_JOB, PRINTER_URI);
+        request.attribute(NAME, "requesting-user-name", b"photobooth");
+        request.attribute(MIME_MEDIA_TYPE, "document-format", b"image/jpeg");
+        let bytes = request.finish(b"\xff\xd8JPEG");
+
+        let mut expected = vec![0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x01];
+        expected.extend(b"\x47\x00\x12attributes-charset\x00\x05utf-8");
+        expected.extend(b"\x48\x00\x1battributes-natural-language\x00\x02en");
+        expected.extend(b"\x45\x00\x0bprinter-uri\x00\x1fipp://localhost/printers/selphy");
+        expected.extend(b"\x42\x00\x14requesting-user-name\x00\x0aphotobooth");
+        expected.extend(b"\x49\x00\x0fdocument-format\x00\x0aimage/jpeg");
+        expected.extend(b"\x03\xff\xd8JPEG");
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn get_job_attributes_response_is_parsed() {
+        // successful-ok, request 1: operation group, then a job group whose
+        // job-state-reasons has a second value under an empty name
+        let mut data = vec![0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01];
+        data.extend(encoded(CHARSET, "attributes-charset", b"utf-8"));
+        data.extend(encoded(NATURAL_LANGUAGE, "attributes-natural-language", b"en"));
+        data.push(0x02);
+        data.extend(encoded(ENUM, "job-state", &8i32.to_be_bytes()));
+        data.extend(encoded(KEYWORD, "job-state-reasons", b"aborted-by-system"));
+        data.extend(encoded(KEYWORD, "", b"media-jam"));
+        data.extend(encoded(TEXT, "job-state-message", b"Paper jam"));
+        data.extend(encoded(BOOLEAN, "printer-is-accepting-jobs", &[0]));
+        data.push(END_OF_ATTRIBUTES);
+
+        let response = IppResponse::parse(&data).unwrap();
+        assert_eq!(response.status, 0x0000);
+        assert_eq!(response.integer("job-state"), Some(8));
+        assert_eq!(response.texts("job-state-reasons"), ["aborted-by-system", "media-jam"]);
+        assert_eq!(response.text("job-state-message").as_deref(), Some("Paper jam"));
+        assert_eq!(response.boolean("printer-is-accepting-jobs"), Some(false));
+        // Wrong syntax for the accessor
+        assert_eq!(response.integer("job-state-message"), None);
+
+        assert!(IppResponse::parse(&data[..4]).is_err());
+        let cut = data.len() - 6;
+        assert!(IppResponse::parse(&data[..cut]).is_err());
+    }
+
+    #[test]
+    fn printer_state_reasons() {
+        let cases = [
+            ("none", None),
+            ("media-empty", Some(PrinterState::OutOfPaper)),
+            ("media-empty-error", Some(PrinterState::OutOfPaper)),
+            ("media-needed", Some(PrinterState::OutOfPaper)),
+            ("media-low-warning", None),
+            ("marker-supply-empty-error", Some(PrinterState::OutOfRibbon)),
+            ("marker-supply-low-warning", None),
+            ("toner-empty", Some(PrinterState::OutOfRibbon)),
+            ("media-jam", Some(PrinterState::Jammed)),
+            ("media-jam-error", Some(PrinterState::Jammed)),
+            ("offline-report", Some(PrinterState::Offline)),
+            ("shutdown", Some(PrinterState::Offline)),
+            ("cover-open-error", Some(PrinterState::Error)),
+            ("paused", Some(PrinterState::Error)),
+            ("fuser-over-temp-error", Some(PrinterState::Error)),
+            ("fuser-over-temp-report", None),
+            ("spool-area-full", None),
+        ];
+        for (reason, state) in cases {
+            assert_eq!(state_for_reason(reason), state, "{}", reason);
+        }
+    }
+
+    #[test]
+    fn ipp_uris_map_to_http() {
+        assert_eq!(http_endpoint(PRINTER_URI).unwrap(), "http://localhost:631/printers/selphy");
+        assert_eq!(http_endpoint("ipps://10.0.0.5:8631/ipp/print").unwrap(), "https://10.0.0.5:8631/ipp/print");
+        assert!(http_endpoint("lpd://10.0.0.5/queue").is_err());
+    }
+}
@@ -1,6 +1,13 @@
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::usage_service::CallOutcome;
+
+const MODEL: &str = "image-01";
+const ASPECT_RATIO: &str = "3:4";
 
 // Request struct without subject_reference (text-to-image)
 #[derive(Debug, Serialize)]
@@ -45,11 +52,21 @@ struct MiniMaxData {
     image_base64: Vec<String>,
 }
 
+/// One request sent to the MiniMax API; `outcome` is `None` while it is in flight
+#[derive(Debug, Clone)]
+pub struct ProviderCall {
+    pub kind: &'static str,
+    pub started: Instant,
+    pub latency_ms: Option<i64>,
+    pub outcome: Option<CallOutcome>,
+}
+
 pub struct MiniMaxService {
     client: Client,
     api_key: String,
     base_url: String,
     use_mock: bool,
+    calls: Mutex<Vec<ProviderCall>>,
 }
 
 impl MiniMaxService {
@@ -75,37 +92,84 @@ impl MiniMaxService {
             api_key,
             base_url: "https://api.minimaxi.com".to_string(),
             use_mock,
+            calls: Mutex::new(Vec::new()),
         })
     }
 
+    pub fn is_mock(&self) -> bool {
+        self.use_mock
+    }
+
+    /// Every request sent since the last call, including any still in flight
+    /// when the caller gave up waiting
+    pub fn take_calls(&self) -> Vec<ProviderCall> {
+        std::mem::take(&mut *self.calls.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    async fn send<T: Serialize>(&self, kind: &'static str, body: &T) -> Result<reqwest::Response, String> {
+        let started = Instant::now();
+        let index = {
+            let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+            calls.push(ProviderCall { kind, started, latency_ms: None, outcome: None });
+            calls.len() - 1
+        };
+
+        let result = self
+            .client
+            .post(format!("{}/v1/image_generation", self.base_url))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(body)
+            .send()
+            .await;
+
+        let outcome = match &result {
+            Ok(response) if response.status().is_success() => CallOutcome::Success,
+            Ok(response) => CallOutcome::Failed(format!("HTTP {}", response.status())),
+            Err(e) => CallOutcome::Failed(e.to_string()),
+        };
+        if let Some(call) = self.calls.lock().unwrap_or_else(|e| e.into_inner()).get_mut(index) {
+            call.latency_ms = Some(started.elapsed().as_millis() as i64);
+            call.outcome = Some(outcome);
+        }
+
+        result.map_err(|e| format!("Failed to send request: {}", e))
+    }
+
+    /// Request parameters that affect the output, used in generation cache keys
+    pub fn cache_params(&self) -> String {
+        format!("model={};aspect_ratio={}", MODEL, ASPECT_RATIO)
+    }
+
+    /// Generate an image from the user's photo. Returns `Ok(None)` when the
+    /// generator is unavailable (mock mode or both API calls rejected), so the
+    /// caller can render a local fallback instead.
     pub async fn generate_image(
         &self,
         user_photo_base64: &str,
         prompt: &str,
-    ) -> Result<String, String> {
+    ) -> Result<Option<String>, String> {
         tracing::info!("[MiniMax] ===== START generate_image =====");
         tracing::info!("[MiniMax] use_mock={}, prompt={}", self.use_mock, prompt);
         tracing::info!("[MiniMax] User photo base64 length: {}", user_photo_base64.len());
 
         // Use mock mode if configured
         if self.use_mock {
-            tracing::warn!("[MiniMax] Using MOCK photo (use_mock=true)");
-            let mock_image = self.generate_placeholder_image();
-            tracing::info!("[MiniMax] Mock image generated, length: {}", mock_image.len());
+            tracing::warn!("[MiniMax] Mock mode, no AI image generated (use_mock=true)");
             tracing::info!("[MiniMax] ===== END generate_image (mock) =====");
-            return Ok(mock_image);
+            return Ok(None);
         }
 
         tracing::info!("[MiniMax] Calling MiniMax API (image-to-image)...");
         tracing::info!("[MiniMax] API URL: {}/v1/image_generation", self.base_url);
 
-        // Image-to-image: add data URL prefix to base64
+        // Image-to-image: add data URL prefix to base64 (photos are normalized to JPEG before this call)
         let image_with_prefix = format!("data:image/jpeg;base64,{}", user_photo_base64);
 
         let i2i_request = MiniMaxRequestWithRef {
-            model: "image-01".to_string(),
+            model: MODEL.to_string(),
             prompt: prompt.to_string(),
-            aspect_ratio: "3:4".to_string(),
+            aspect_ratio: ASPECT_RATIO.to_string(),
             response_format: "base64".to_string(),
             subject_reference: vec![SubjectReference {
                 r#type: "character".to_string(),
@@ -115,15 +179,7 @@ impl MiniMaxService {
 
         tracing::info!("[MiniMax] Sending image-to-image request...");
 
-        let response = self
-            .client
-            .post(format!("{}/v1/image_generation", self.base_url))
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&i2i_request)
-            .send()
-            .await
-            .map_err(|e| format!("Failed to send request: {}", e))?;
+        let response = self.send("image-to-image", &i2i_request).await?;
 
         tracing::info!("[MiniMax] Response received, status: {}", response.status());
 
@@ -135,28 +191,19 @@ impl MiniMaxService {
             tracing::warn!("[MiniMax] Image-to-image failed, trying text-to-image...");
 
             let text_request = MiniMaxRequest {
-                model: "image-01".to_string(),
+                model: MODEL.to_string(),
                 prompt: prompt.to_string(),
-                aspect_ratio: "3:4".to_string(),
+                aspect_ratio: ASPECT_RATIO.to_string(),
                 response_format: "base64".to_string(),
             };
 
-            let response = self
-                .client
-                .post(format!("{}/v1/image_generation", self.base_url))
-                .header("Authorization", format!("Bearer {}", self.api_key))
-                .header("Content-Type", "application/json")
-                .json(&text_request)
-                .send()
-                .await
-                .map_err(|e| format!("Failed to send request: {}", e))?;
+            let response = self.send("text-to-image", &text_request).await?;
 
             if !response.status().is_success() {
                 let status = response.status();
                 let body = response.text().await.unwrap_or_default();
                 tracing::warn!("Text-to-image also failed {}: {}", status, body);
-                let mock_image = self.generate_placeholder_image();
-                return Ok(mock_image);
+                return Ok(None);
             }
 
             let result: MiniMaxResponse = response
@@ -172,7 +219,7 @@ impl MiniMaxService {
                 .ok_or_else(|| "No image in response".to_string())?;
 
             tracing::info!("[MiniMax] Text-to-image fallback success, length: {}", generated_image.len());
-            return Ok(generated_image);
+            return Ok(Some(generated_image));
         }
 
         let result: MiniMaxResponse = response
@@ -189,15 +236,7 @@ impl MiniMaxService {
 
         tracing::info!("[MiniMax] API response parsed, generated image length: {}", generated_image.len());
         tracing::info!("[MiniMax] ===== END generate_image (success) =====");
-        Ok(generated_image)
-    }
-
-    /// Generate a simple placeholder image directly (no network request)
-    pub fn generate_placeholder_image(&self) -> String {
-        // Use a hardcoded small valid base64 image (1x1 purple pixel JPEG)
-        // This is a minimal valid JPEG that browsers can display
-        // Strip the "data:image/jpeg;base64," prefix
-        "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==".to_string()
+        Ok(Some(generated_image))
     }
 }
 
@@ -208,6 +247,7 @@ impl Default for MiniMaxService {
             api_key: String::new(),
             base_url: "https://api.minimaxi.com".to_string(),
             use_mock: true,
+            calls: Mutex::new(Vec::new()),
         }
     }
 }
@@ -0,0 +1,131 @@
+use crate::models::{Order, OrderStatus, OrderType, PaymentProviderKind, RefundStatus};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::payment_provider::{
+    BillEntry, NotifyReply, NotifyRequest, PaymentNotice, PaymentProvider, ProviderRefund, ProviderTrade, RefundError,
+};
+use super::session_service::OrderContents;
+use super::{ModeService, SessionService, Storage};
+
+/// Scripted stand-in for a payment provider in tests. Clones share their
+/// state, so a test keeps one handle and hands another to the code under test.
+#[derive(Clone, Default)]
+pub struct MockProvider {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+pub struct MockState {
+    /// Query answers per order id; orders not listed are unpaid
+    pub trades: HashMap<String, Result<ProviderTrade, String>>,
+    pub close_error: Option<String>,
+    /// Refund answers, used in turn; refunds succeed once they run out
+    pub refund_replies: VecDeque<Result<ProviderRefund, RefundError>>,
+    pub bill: Vec<BillEntry>,
+    /// Order ids closed, in order
+    pub closed: Vec<String>,
+    /// Refund ids sent, in order
+    pub refunds: Vec<String>,
+}
+
+impl MockProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn boxed(&self) -> Box<dyn PaymentProvider> {
+        Box::new(self.clone())
+    }
+}
+
+pub fn trade(status: OrderStatus, state: &str, amount: Option<i32>) -> ProviderTrade {
+    let paid = status == OrderStatus::Paid;
+    ProviderTrade {
+        status,
+        state: state.to_string(),
+        transaction_id: paid.then(|| "mock_transaction".to_string()),
+        paid_at: paid.then(|| chrono::Utc::now().timestamp()),
+        amount,
+        needs_close: false,
+    }
+}
+
+/// A pending download order for a new session, at the catalog price
+pub fn create_order(storage: &Storage) -> Order {
+    let conn = storage.get_connection().unwrap();
+    let mode = ModeService::new(&conn).get_all_modes().unwrap().remove(0);
+    let session_service = SessionService::new(&conn);
+    let session = session_service.create_session(&mode.id, &mode.effects[0].id).unwrap();
+    let contents = OrderContents::Single { order_type: OrderType::Download, amount: None };
+    session_service.create_order_from(&session.id, contents, PaymentProviderKind::Wechat).unwrap()
+}
+
+#[async_trait]
+impl PaymentProvider for MockProvider {
+    fn kind(&self) -> PaymentProviderKind {
+        PaymentProviderKind::Wechat
+    }
+
+    async fn create(&self, order_id: &str, _amount: i32, _description: &str) -> Result<String, String> {
+        Ok(format!("mock://pay/{}", order_id))
+    }
+
+    async fn query(&self, order_id: &str) -> Result<ProviderTrade, String> {
+        self.state()
+            .trades
+            .get(order_id)
+            .cloned()
+            .unwrap_or_else(|| Ok(trade(OrderStatus::Pending, "NOTPAY", None)))
+    }
+
+    async fn close(&self, order_id: &str) -> Result<(), String> {
+        let mut state = self.state();
+        if let Some(e) = state.close_error.clone() {
+            return Err(e);
+        }
+        state.closed.push(order_id.to_string());
+        Ok(())
+    }
+
+    async fn refund(
+        &self,
+        _order_id: &str,
+        refund_id: &str,
+        _refund_amount: i32,
+        _total_amount: i32,
+        _reason: &str,
+    ) -> Result<ProviderRefund, RefundError> {
+        let mut state = self.state();
+        state.refunds.push(refund_id.to_string());
+        state.refund_replies.pop_front().unwrap_or_else(|| {
+            Ok(ProviderRefund { refund_id: Some(format!("mock_{}", refund_id)), status: RefundStatus::Success })
+        })
+    }
+
+    fn verify_notification(&self, _request: &NotifyRequest) -> Result<Option<PaymentNotice>, String> {
+        Ok(None)
+    }
+
+    fn notification_reply(&self, error: Option<&str>) -> NotifyReply {
+        NotifyReply {
+            status: if error.is_some() { 500 } else { 200 },
+            content_type: "text/plain",
+            body: error.unwrap_or("OK").to_string(),
+        }
+    }
+
+    fn notify_path(&self) -> String {
+        "/mock/notify".to_string()
+    }
+
+    async fn download_bill(&self, _date: NaiveDate) -> Result<Vec<BillEntry>, String> {
+        Ok(self.state().bill.clone())
+    }
+}
@@ -2,10 +2,63 @@ pub mod mode_service;
 pub mod session_service;
 pub mod minimax_service;
 pub mod wechat_service;
+pub mod wechat_v3;
 pub mod storage;
+pub mod image_service;
+pub mod face_service;
+pub mod generation_cache;
+pub mod usage_service;
+pub mod notify_server;
+pub mod payment_poller;
+pub mod refund_service;
+pub mod qr_service;
+pub mod payment_provider;
+pub mod alipay_service;
+pub mod reconciliation_service;
+pub mod reconciliation_job;
+pub mod voucher_service;
+pub mod revenue_service;
+pub mod catalog_service;
+pub mod fulfillment_service;
+pub mod printer;
+pub mod ipp_printer;
+pub mod print_service;
+pub mod print_queue;
+pub mod print_compositor;
+pub mod inventory_service;
+pub mod upscale_service;
+pub mod download_service;
+pub mod download_server;
+#[cfg(test)]
+pub mod mock_provider;
 
 pub use mode_service::ModeService;
 pub use session_service::SessionService;
 pub use minimax_service::MiniMaxService;
 pub use wechat_service::WeChatService;
 pub use storage::Storage;
+pub use image_service::ImageService;
+pub use face_service::FaceService;
+pub use generation_cache::GenerationCache;
+pub use usage_service::UsageService;
+pub use notify_server::NotifyServer;
+pub use payment_poller::PaymentPoller;
+pub use refund_service::RefundService;
+pub use qr_service::QrService;
+pub use payment_provider::PaymentProvider;
+pub use alipay_service::AlipayService;
+pub use reconciliation_service::ReconciliationService;
+pub use reconciliation_job::ReconciliationJob;
+pub use voucher_service::VoucherService;
+pub use revenue_service::RevenueService;
+pub use catalog_service::CatalogService;
+pub use fulfillment_service::FulfillmentService;
+pub use printer::Printer;
+pub use ipp_printer::IppPrinter;
+pub use print_service::PrintService;
+pub use print_queue::PrintQueue;
+pub use print_compositor::PrintCompositor;
+pub use inventory_service::InventoryService;
+pub use upscale_service::UpscaleService;
+pub use download_service::DownloadService;
+pub use download_server::DownloadServer;
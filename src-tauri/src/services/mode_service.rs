@@ -12,7 +12,7 @@ impl<'a> ModeService<'a> {
 
     pub fn get_all_modes(&self) -> Result<Vec<PhotoMode>, String> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, icon FROM photo_modes"
+            "SELECT id, name, description, icon, min_persons, max_persons FROM photo_modes"
         ).map_err(|e| e.to_string())?;
 
         let modes: Vec<PhotoMode> = stmt.query_map([], |row| {
@@ -21,6 +21,8 @@ impl<'a> ModeService<'a> {
                 name: row.get(1).unwrap_or_default(),
                 description: row.get(2).unwrap_or_default(),
                 icon: row.get(3).unwrap_or_default(),
+                min_persons: row.get::<_, u32>(4).unwrap_or(1),
+                max_persons: row.get::<_, u32>(5).unwrap_or(3),
                 effects: vec![],
             })
         }).map_err(|e| e.to_string())?.filter_map(|m| m.ok()).collect();
@@ -36,7 +38,7 @@ impl<'a> ModeService<'a> {
 
     pub fn get_mode_by_id(&self, id: &str) -> Result<Option<PhotoMode>, String> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, icon FROM photo_modes WHERE id = ?1"
+            "SELECT id, name, description, icon, min_persons, max_persons FROM photo_modes WHERE id = ?1"
         ).map_err(|e| e.to_string())?;
 
         let mut rows = stmt.query([id]).map_err(|e| e.to_string())?;
@@ -46,6 +48,8 @@ impl<'a> ModeService<'a> {
                 name: row.get(1).unwrap_or_default(),
                 description: row.get(2).unwrap_or_default(),
                 icon: row.get(3).unwrap_or_default(),
+                min_persons: row.get::<_, u32>(4).unwrap_or(1),
+                max_persons: row.get::<_, u32>(5).unwrap_or(3),
                 effects: vec![],
             };
             mode.effects = self.get_effects_by_mode(&mode.id)?;
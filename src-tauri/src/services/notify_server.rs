@@ -0,0 +1,295 @@
+use crate::models::{LatePayment, OrderStatus, PaymentStatusEvent};
+use std::env;
+use std::io::Read;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::runtime::Handle;
+
+use super::payment_poller::apply_trade;
+use super::payment_provider::{configured_providers, NotifyRequest, PaymentNotice, ProviderRefund, ProviderTrade};
+use super::{PaymentProvider, RefundService, SessionService, Storage};
+
+// Provider notifications are a few KB at most
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// Embedded HTTP endpoint for payment and refund notifications from every
+/// configured provider, each on the path of its notify URL.
+///
+/// Listens on `PAYMENT_NOTIFY_LISTEN` (e.g. `0.0.0.0:8088`); the public notify
+/// URLs must be forwarded here by a reverse proxy or tunnel.
+pub struct NotifyServer;
+
+impl NotifyServer {
+    /// Start the listener thread. Does nothing when no listen address is set.
+    /// Payments for cancelled orders are reported through `on_late_payment`
+    /// and refunded right away.
+    pub fn start<F, L>(storage: Storage, on_status: F, on_late_payment: L) -> Result<(), String>
+    where
+        F: Fn(PaymentStatusEvent) + Send + 'static,
+        L: Fn(LatePayment) + Send + Sync + 'static,
+    {
+        // WECHAT_NOTIFY_LISTEN predates Alipay support
+        let Ok(addr) = env::var("PAYMENT_NOTIFY_LISTEN").or_else(|_| env::var("WECHAT_NOTIFY_LISTEN")) else {
+            tracing::info!("[Notify] PAYMENT_NOTIFY_LISTEN not set, payment notifications disabled");
+            return Ok(());
+        };
+        let providers = configured_providers();
+        if providers.is_empty() {
+            return Err("No payment provider configured".to_string());
+        }
+
+        // Payments are applied like a status query, which may call the provider
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let server = Server::http(&addr).map_err(|e| format!("Failed to bind notify listener on {}: {}", addr, e))?;
+        let routes: Vec<(String, Box<dyn PaymentProvider>)> = providers
+            .into_iter()
+            .map(|provider| {
+                let path = provider.notify_path();
+                tracing::info!("[Notify] Listening for {} notifications on {}{}", provider.kind(), addr, path);
+                (path, provider)
+            })
+            .collect();
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let path = request.url().split('?').next().unwrap_or_default().to_string();
+                let route = routes.iter().find(|(p, _)| *p == path);
+                match route {
+                    Some((_, provider)) if *request.method() == Method::Post => {
+                        handle_request(provider.as_ref(), &storage, runtime.handle(), request, &on_status, &on_late_payment)
+                    }
+                    _ => {
+                        request.respond(Response::empty(404)).ok();
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn handle_request<F, L>(
+    provider: &dyn PaymentProvider,
+    storage: &Storage,
+    runtime: &Handle,
+    mut request: Request,
+    on_status: &F,
+    on_late_payment: &L,
+) where
+    F: Fn(PaymentStatusEvent),
+    L: Fn(LatePayment) + Sync,
+{
+    let mut notify = NotifyRequest {
+        headers: request
+            .headers()
+            .iter()
+            .map(|h| (h.field.to_string(), h.value.to_string()))
+            .collect(),
+        body: String::new(),
+    };
+
+    let outcome = request
+        .as_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut notify.body)
+        .map_err(|e| format!("Failed to read notification: {}", e))
+        .and_then(|_| apply_notification(provider, storage, runtime, &notify, on_late_payment));
+
+    let error = match outcome {
+        Ok(Some(event)) => {
+            tracing::info!("[Notify] Order {} marked {}", event.order_id, event.status);
+            on_status(event);
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("[Notify] Rejected {} notification: {}", provider.kind(), e);
+            Some(e)
+        }
+    };
+
+    let reply = provider.notification_reply(error.as_deref());
+    let mut response = Response::from_string(reply.body).with_status_code(reply.status);
+    if let Ok(header) = Header::from_bytes("Content-Type", reply.content_type) {
+        response = response.with_header(header);
+    }
+    request.respond(response).ok();
+}
+
+/// Verify a notification and settle its order or refund. Returns the event to
+/// publish when the order changed; duplicates and unrelated events return `None`.
+fn apply_notification(
+    provider: &dyn PaymentProvider,
+    storage: &Storage,
+    runtime: &Handle,
+    request: &NotifyRequest,
+    on_late_payment: &(dyn Fn(LatePayment) + Sync),
+) -> Result<Option<PaymentStatusEvent>, String> {
+    match provider.verify_notification(request)? {
+        Some(PaymentNotice::Payment { out_trade_no, trade }) => {
+            apply_payment(provider, storage, runtime, &out_trade_no, &trade, on_late_payment)
+        }
+        Some(PaymentNotice::Refund { out_refund_no, refund }) => apply_refund(storage, &out_refund_no, &refund),
+        None => Ok(None),
+    }
+}
+
+fn apply_payment(
+    provider: &dyn PaymentProvider,
+    storage: &Storage,
+    runtime: &Handle,
+    out_trade_no: &str,
+    trade: &ProviderTrade,
+    on_late_payment: &(dyn Fn(LatePayment) + Sync),
+) -> Result<Option<PaymentStatusEvent>, String> {
+    if trade.status != OrderStatus::Paid {
+        return Ok(None);
+    }
+
+    let order = {
+        let conn = storage.get_connection()?;
+        SessionService::new(&conn)
+            .get_order_by_out_trade_no(out_trade_no)?
+            .filter(|order| order.provider == provider.kind())
+            .ok_or_else(|| format!("Unknown order {}", out_trade_no))?
+    };
+
+    if !runtime.block_on(apply_trade(storage, provider, &order, trade, "notify", on_late_payment))? {
+        // Already settled by an earlier delivery or a status query, or paid after cancellation
+        return Ok(None);
+    }
+
+    Ok(Some(PaymentStatusEvent {
+        order_id: order.id,
+        status: OrderStatus::Paid,
+    }))
+}
+
+fn apply_refund(storage: &Storage, out_refund_no: &str, refund: &ProviderRefund) -> Result<Option<PaymentStatusEvent>, String> {
+    let conn = storage.get_connection()?;
+    let refund_service = RefundService::new(&conn);
+    let record = refund_service
+        .get_refund_by_out_refund_no(out_refund_no)?
+        .ok_or_else(|| format!("Unknown refund {}", out_refund_no))?;
+
+    if !refund_service.apply_refund_status(&record.id, refund.status.clone(), refund.refund_id.as_deref())? {
+        return Ok(None);
+    }
+
+    Ok(Some(PaymentStatusEvent {
+        order_id: record.order_id,
+        status: OrderStatus::Refunded,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Order;
+    use crate::services::mock_provider::create_order;
+    use crate::services::wechat_service::{out_trade_no, parse_xml, to_xml, SignType, WeChatService};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    // Merchant key of the WeChat Pay v2 signing guide
+    const KEY: &str = "192006250b4c09247ec02edce69f6a2d";
+
+    /// Notify endpoint of a v2 WeChat merchant on a local port. Returns its URL
+    /// and the status events it publishes.
+    fn notify_endpoint(storage: &Storage) -> (String, Arc<Mutex<Vec<PaymentStatusEvent>>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/wechat/notify", server.server_addr().to_ip().unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let published = events.clone();
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            let provider = WeChatService::test_v2("", KEY, SignType::Md5);
+            let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+            let on_status = |event| published.lock().unwrap().push(event);
+            for request in server.incoming_requests() {
+                handle_request(&provider, &storage, runtime.handle(), request, &on_status, &|_| {});
+            }
+        });
+        (url, events)
+    }
+
+    /// v2 payment notification for `order`, signed with `key`
+    fn payment_notice(order: &Order, total_fee: i32, key: &str) -> String {
+        let mut params: BTreeMap<String, String> = [
+            ("appid", "wxd930ea5d5a258f4f"),
+            ("mch_id", "10000100"),
+            ("nonce_str", "5K8264ILTKCH16CQ2502SI8ZNMTM67VS"),
+            ("return_code", "SUCCESS"),
+            ("result_code", "SUCCESS"),
+            ("trade_type", "NATIVE"),
+            ("transaction_id", "4200002301202610181234567890"),
+            ("time_end", "20261018101502"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        params.insert("out_trade_no".to_string(), out_trade_no(&order.id));
+        params.insert("total_fee".to_string(), total_fee.to_string());
+        let sign = WeChatService::test_v2("", key, SignType::Md5).sign(&params);
+        params.insert("sign".to_string(), sign);
+        to_xml(&params)
+    }
+
+    /// Deliver a notification; returns WeChat's view of our reply
+    async fn deliver(url: &str, body: String) -> BTreeMap<String, String> {
+        let reply = reqwest::Client::new().post(url).body(body).send().await.unwrap().text().await.unwrap();
+        parse_xml(&reply).unwrap()
+    }
+
+    fn order_status(storage: &Storage, order_id: &str) -> OrderStatus {
+        let conn = storage.get_connection().unwrap();
+        SessionService::new(&conn).get_order(order_id).unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn signed_payment_settles_the_order_once() {
+        let storage = Storage::temp();
+        let (url, events) = notify_endpoint(&storage);
+        let order = create_order(&storage);
+
+        let reply = deliver(&url, payment_notice(&order, order.amount, KEY)).await;
+        assert_eq!(reply.get("return_code").map(String::as_str), Some("SUCCESS"));
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Paid);
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        // WeChat redelivers until it sees SUCCESS; repeats are acknowledged, not republished
+        let reply = deliver(&url, payment_notice(&order, order.amount, KEY)).await;
+        assert_eq!(reply.get("return_code").map(String::as_str), Some("SUCCESS"));
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forged_notification_is_refused() {
+        let storage = Storage::temp();
+        let (url, events) = notify_endpoint(&storage);
+        let order = create_order(&storage);
+
+        let reply = deliver(&url, payment_notice(&order, order.amount, "forged_key")).await;
+        assert_eq!(reply.get("return_code").map(String::as_str), Some("FAIL"));
+        assert!(reply["return_msg"].contains("signature verification failed"), "{:?}", reply);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Pending);
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn payment_of_another_amount_is_refused() {
+        let storage = Storage::temp();
+        let (url, events) = notify_endpoint(&storage);
+        let order = create_order(&storage);
+
+        let reply = deliver(&url, payment_notice(&order, order.amount - 1, KEY)).await;
+        assert_eq!(reply.get("return_code").map(String::as_str), Some("FAIL"));
+        assert!(reply["return_msg"].contains("Amount mismatch"), "{:?}", reply);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Pending);
+        assert!(events.lock().unwrap().is_empty());
+    }
+}
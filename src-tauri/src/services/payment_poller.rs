@@ -0,0 +1,410 @@
+use crate::models::{LatePayment, Order, OrderStatus, PaymentProviderKind, PaymentStatusEvent, RefundStatus};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::payment_provider::{configured_providers, order_timeout_secs, ProviderTrade};
+use super::refund_service::submit_refund;
+use super::{PaymentProvider, RefundService, SessionService, Storage};
+
+const TICK: Duration = Duration::from_secs(1);
+// Unconfirmed refunds are sent again this often until the provider answers
+const REFUND_RETRY_SECS: i64 = 60;
+
+/// Watches pending orders in the background: polls the provider on a backoff
+/// schedule and closes orders nobody paid within `PAYMENT_ORDER_TIMEOUT_SECS`.
+/// Also settles refunds left processing by re-sending them under the same id.
+pub struct PaymentPoller {
+    storage: Storage,
+    providers: HashMap<PaymentProviderKind, Box<dyn PaymentProvider>>,
+    order_timeout: i64,
+    // order id -> (unix time of next check, consecutive provider errors)
+    schedule: HashMap<String, (i64, u32)>,
+    // refund id -> unix time of next attempt
+    refund_schedule: HashMap<String, i64>,
+}
+
+impl PaymentPoller {
+    pub fn new(storage: Storage) -> Result<Self, String> {
+        Self::with_providers(storage, configured_providers())
+    }
+
+    pub fn with_providers(storage: Storage, providers: Vec<Box<dyn PaymentProvider>>) -> Result<Self, String> {
+        let providers: HashMap<_, _> = providers
+            .into_iter()
+            .map(|provider| (provider.kind(), provider))
+            .collect();
+        if providers.is_empty() {
+            return Err("No payment provider configured".to_string());
+        }
+
+        Ok(Self {
+            storage,
+            providers,
+            order_timeout: order_timeout_secs(),
+            schedule: HashMap::new(),
+            refund_schedule: HashMap::new(),
+        })
+    }
+
+    /// Run forever, reporting every status change through `on_status` and
+    /// payments for cancelled orders through `on_late_payment`
+    pub async fn run<F, L>(mut self, on_status: F, on_late_payment: L)
+    where
+        F: Fn(PaymentStatusEvent) + Send + Sync + 'static,
+        L: Fn(LatePayment) + Send + Sync + 'static,
+    {
+        tracing::info!("[Poller] Watching pending orders, timeout {}s", self.order_timeout);
+        loop {
+            if let Err(e) = self.poll_due(&on_status, &on_late_payment).await {
+                tracing::warn!("[Poller] {}", e);
+            }
+            if let Err(e) = self.retry_refunds(&on_status).await {
+                tracing::warn!("[Poller] {}", e);
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+
+    async fn poll_due<F, L>(&mut self, on_status: &F, on_late_payment: &L) -> Result<(), String>
+    where
+        F: Fn(PaymentStatusEvent),
+        L: Fn(LatePayment) + Sync,
+    {
+        let pending = {
+            let conn = self.storage.get_connection()?;
+            SessionService::new(&conn).get_orders_by_status(OrderStatus::Pending)?
+        };
+
+        // Forget orders that were settled elsewhere (notification, manual change)
+        self.schedule.retain(|id, _| pending.iter().any(|o| &o.id == id));
+
+        let now = Utc::now().timestamp();
+        for order in pending {
+            // Orders of a provider that is no longer configured cannot be checked
+            if !self.providers.contains_key(&order.provider) {
+                continue;
+            }
+            let (due, errors) = *self.schedule.entry(order.id.clone()).or_insert((now, 0));
+            if due > now {
+                continue;
+            }
+
+            let age = now - order.created_at;
+            let outcome = match self.check(&order, age >= self.order_timeout, on_late_payment).await {
+                Ok(Some(status)) => {
+                    on_status(PaymentStatusEvent { order_id: order.id.clone(), status });
+                    self.schedule.remove(&order.id);
+                    continue;
+                }
+                Ok(None) => 0,
+                Err(e) => {
+                    tracing::warn!("[Poller] Order {}: {}", order.id, e);
+                    errors + 1
+                }
+            };
+
+            let next = now + poll_interval(age, outcome).as_secs() as i64;
+            self.schedule.insert(order.id, (next, outcome));
+        }
+        Ok(())
+    }
+
+    /// Send processing refunds again; the provider answers a repeat with the
+    /// refund's current state instead of refunding twice
+    async fn retry_refunds<F>(&mut self, on_status: &F) -> Result<(), String>
+    where
+        F: Fn(PaymentStatusEvent),
+    {
+        let processing = {
+            let conn = self.storage.get_connection()?;
+            let refund_service = RefundService::new(&conn);
+            let session_service = SessionService::new(&conn);
+            let mut processing = Vec::new();
+            for refund in refund_service.get_refunds_by_status(RefundStatus::Processing)? {
+                if let Some(order) = session_service.get_order(&refund.order_id)? {
+                    processing.push((refund, order));
+                }
+            }
+            processing
+        };
+        self.refund_schedule.retain(|id, _| processing.iter().any(|(r, _)| &r.id == id));
+
+        let now = Utc::now().timestamp();
+        for (refund, order) in processing {
+            let Some(provider) = self.providers.get(&order.provider) else {
+                continue;
+            };
+            // Leave the first attempt and its notification time to land
+            let due = *self.refund_schedule.entry(refund.id.clone()).or_insert(refund.updated_at + REFUND_RETRY_SECS);
+            if due > now {
+                continue;
+            }
+            self.refund_schedule.insert(refund.id.clone(), now + REFUND_RETRY_SECS);
+
+            match submit_refund(&self.storage, provider.as_ref(), &refund.id).await {
+                Ok(settled) if settled.status != RefundStatus::Processing => {
+                    tracing::info!("[Poller] Refund {} settled: {}", refund.id, settled.status);
+                    let conn = self.storage.get_connection()?;
+                    let status = SessionService::new(&conn).get_order(&order.id)?.map(|o| o.status);
+                    if let Some(status) = status.filter(|s| *s != order.status) {
+                        on_status(PaymentStatusEvent { order_id: order.id.clone(), status });
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("[Poller] Refund {}: {}", refund.id, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Query the provider and apply the result; past the timeout, close the order.
+    /// An expired order is closed even when the query fails: the close itself
+    /// is refused for a trade that was paid. Returns the new status when the order changed.
+    async fn check(
+        &self,
+        order: &Order,
+        expired: bool,
+        on_late_payment: &(dyn Fn(LatePayment) + Sync),
+    ) -> Result<Option<OrderStatus>, String> {
+        let provider = self.providers.get(&order.provider).ok_or("Payment provider not configured")?;
+        match provider.query(&order.id).await {
+            Ok(trade) => {
+                if apply_trade(&self.storage, provider.as_ref(), order, &trade, "poller", on_late_payment).await? {
+                    return Ok(Some(trade.status));
+                }
+                if trade.status != OrderStatus::Pending {
+                    return Ok(None);
+                }
+            }
+            Err(e) if expired => tracing::warn!("[Poller] Order {} query failed, closing anyway: {}", order.id, e),
+            Err(e) => return Err(e),
+        }
+        if !expired {
+            return Ok(None);
+        }
+
+        // Close at the provider first so a late scan cannot pay a cancelled order
+        provider.close(&order.id).await?;
+        let conn = self.storage.get_connection()?;
+        let cancelled = SessionService::new(&conn).transition_order(
+            &order.id,
+            OrderStatus::Cancelled,
+            "timeout",
+            None,
+            None,
+            Some("Unpaid order closed after timeout"),
+        )?;
+        Ok(cancelled.then_some(OrderStatus::Cancelled))
+    }
+}
+
+/// Apply a queried trade to its order. A failed trade still open at the
+/// provider is closed there first, so it cannot be paid once we cancel it;
+/// a payment for a cancelled order is reported and refunded right away.
+/// A payment for another amount than the order's is an error and changes nothing.
+/// Returns true when the order changed.
+pub async fn apply_trade(
+    storage: &Storage,
+    provider: &dyn PaymentProvider,
+    order: &Order,
+    trade: &ProviderTrade,
+    source: &str,
+    on_late_payment: &(dyn Fn(LatePayment) + Sync),
+) -> Result<bool, String> {
+    if trade.needs_close && order.status == OrderStatus::Pending {
+        provider.close(&order.id).await?;
+    }
+    if trade.status == OrderStatus::Paid {
+        if let Some(amount) = trade.amount.filter(|amount| *amount != order.amount) {
+            return Err(format!("Amount mismatch for order {}: paid {} expected {}", order.id, amount, order.amount));
+        }
+        let late = {
+            let conn = storage.get_connection()?;
+            SessionService::new(&conn).record_late_payment(&order.id, source, trade.transaction_id.as_deref())?
+        };
+        if let Some(late) = late {
+            // Left processing on failure; the poller sends it again
+            if let Err(e) = submit_refund(storage, provider, &late.refund_id).await {
+                tracing::warn!("[Payment] Late payment refund {}: {}", late.refund_id, e);
+            }
+            on_late_payment(late);
+            return Ok(false);
+        }
+    }
+
+    let conn = storage.get_connection()?;
+    SessionService::new(&conn).reconcile_order(
+        &order.id,
+        trade.status.clone(),
+        source,
+        trade.transaction_id.as_deref(),
+        trade.paid_at,
+    )
+}
+
+/// Poll often while the customer is likely scanning, then back off;
+/// provider errors back off further
+fn poll_interval(age_secs: i64, errors: u32) -> Duration {
+    let base = match age_secs {
+        0..=60 => 2,
+        61..=180 => 5,
+        _ => 15,
+    };
+    Duration::from_secs((base << errors.min(4)).min(60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::mock_provider::{create_order, trade, MockProvider};
+    use crate::services::payment_provider::RefundError;
+    use std::sync::{Arc, Mutex};
+
+    fn order_status(storage: &Storage, order_id: &str) -> OrderStatus {
+        let conn = storage.get_connection().unwrap();
+        SessionService::new(&conn).get_order(order_id).unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn expired_order_is_closed_when_query_fails() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+        let order = create_order(&storage);
+        provider.state().trades.insert(order.id.clone(), Err("WeChat Pay HTTP error: 502".to_string()));
+
+        // Not yet expired: the error is reported and the order stays open
+        assert!(poller.check(&order, false, &|_| {}).await.is_err());
+        assert!(provider.state().closed.is_empty());
+
+        assert_eq!(poller.check(&order, true, &|_| {}).await.unwrap(), Some(OrderStatus::Cancelled));
+        assert_eq!(provider.state().closed, vec![order.id.clone()]);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn order_stays_pending_when_close_fails() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+        let order = create_order(&storage);
+        provider.state().trades.insert(order.id.clone(), Err("timed out".to_string()));
+        provider.state().close_error = Some("ORDERPAID".to_string());
+
+        assert!(poller.check(&order, true, &|_| {}).await.is_err());
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn unknown_trade_is_closed_on_expiry() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+        let order = create_order(&storage);
+        provider.state().trades.insert(order.id.clone(), Ok(crate::services::wechat_service::WeChatTrade::not_exist().into()));
+
+        assert_eq!(poller.check(&order, false, &|_| {}).await.unwrap(), None);
+        assert_eq!(poller.check(&order, true, &|_| {}).await.unwrap(), Some(OrderStatus::Cancelled));
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn failed_payment_is_closed_before_cancelling() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+        let order = create_order(&storage);
+        let payerror = ProviderTrade { needs_close: true, ..trade(OrderStatus::Cancelled, "PAYERROR", None) };
+        provider.state().trades.insert(order.id.clone(), Ok(payerror));
+
+        // Close refused: the order stays open
+        provider.state().close_error = Some("SYSTEMERROR".to_string());
+        assert!(poller.check(&order, false, &|_| {}).await.is_err());
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Pending);
+
+        provider.state().close_error = None;
+        assert_eq!(poller.check(&order, false, &|_| {}).await.unwrap(), Some(OrderStatus::Cancelled));
+        assert_eq!(provider.state().closed, vec![order.id.clone()]);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn payment_of_another_amount_is_not_applied() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+        let order = create_order(&storage);
+        provider.state().trades.insert(order.id.clone(), Ok(trade(OrderStatus::Paid, "SUCCESS", Some(order.amount - 1))));
+
+        let err = poller.check(&order, false, &|_| {}).await.unwrap_err();
+        assert!(err.starts_with("Amount mismatch"), "{}", err);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn late_payment_is_refunded_and_reported() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let order = create_order(&storage);
+        let cancelled = {
+            let conn = storage.get_connection().unwrap();
+            let session_service = SessionService::new(&conn);
+            session_service.transition_order(&order.id, OrderStatus::Cancelled, "timeout", None, None, None).unwrap();
+            session_service.get_order(&order.id).unwrap().unwrap()
+        };
+        let paid = trade(OrderStatus::Paid, "SUCCESS", Some(order.amount));
+
+        let reported = Mutex::new(Vec::new());
+        let on_late_payment = |late: LatePayment| reported.lock().unwrap().push(late);
+        assert!(!apply_trade(&storage, &provider, &cancelled, &paid, "query", &on_late_payment).await.unwrap());
+        // Reported again by the next query: recorded once
+        assert!(!apply_trade(&storage, &provider, &cancelled, &paid, "query", &on_late_payment).await.unwrap());
+
+        let reported = reported.into_inner().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].amount, order.amount);
+        assert_eq!(provider.state().refunds, vec![reported[0].refund_id.clone()]);
+
+        let conn = storage.get_connection().unwrap();
+        let refund = RefundService::new(&conn).get_refund(&reported[0].refund_id).unwrap().unwrap();
+        assert_eq!(refund.status, RefundStatus::Success);
+        assert_eq!(order_status(&storage, &order.id), OrderStatus::Cancelled);
+        let events = SessionService::new(&conn).get_order_events(&order.id).unwrap();
+        assert!(events.last().unwrap().detail.as_deref().unwrap().starts_with("Late payment mock_transaction"));
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_refunds_are_retried() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let order = create_order(&storage);
+        let refund = {
+            let conn = storage.get_connection().unwrap();
+            SessionService::new(&conn).update_order_status(&order.id, OrderStatus::Paid, None).unwrap();
+            RefundService::new(&conn).create_refund(&order.id, None, None).unwrap()
+        };
+        provider.state().refund_replies.push_back(Err(RefundError::Unknown("connection reset".to_string())));
+        let pending = submit_refund(&storage, &provider, &refund.id).await.unwrap();
+        assert_eq!(pending.status, RefundStatus::Processing);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_status = |event: PaymentStatusEvent| events.lock().unwrap().push(event);
+        let mut poller = PaymentPoller::with_providers(storage.clone(), vec![provider.boxed()]).unwrap();
+
+        // Too early: the first attempt was just made
+        poller.retry_refunds(&on_status).await.unwrap();
+        assert_eq!(provider.state().refunds.len(), 1);
+
+        let conn = storage.get_connection().unwrap();
+        conn.execute("UPDATE refunds SET updated_at = updated_at - ?1", [REFUND_RETRY_SECS]).unwrap();
+        poller.refund_schedule.clear();
+        poller.retry_refunds(&on_status).await.unwrap();
+
+        assert_eq!(provider.state().refunds, vec![refund.id.clone(), refund.id.clone()]);
+        assert_eq!(RefundService::new(&conn).get_refund(&refund.id).unwrap().unwrap().status, RefundStatus::Success);
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].order_id.as_str(), &events[0].status), (order.id.as_str(), &OrderStatus::Refunded));
+    }
+}
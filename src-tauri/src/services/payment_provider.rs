@@ -0,0 +1,226 @@
+use crate::models::{OrderStatus, PaymentProviderKind, RefundStatus};
+use async_trait::async_trait;
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime};
+use std::env;
+
+use super::{alipay_service, wechat_service, AlipayService, WeChatService};
+
+/// Provider-side state of an order, already mapped onto our statuses
+#[derive(Debug, Clone)]
+pub struct ProviderTrade {
+    pub status: OrderStatus,
+    /// Raw provider state, e.g. `NOTPAY` or `WAIT_BUYER_PAY`, for logs
+    pub state: String,
+    pub transaction_id: Option<String>,
+    pub paid_at: Option<i64>,
+    pub amount: Option<i32>,
+    /// Failed for us but still open at the provider (WeChat `PAYERROR`);
+    /// close it before cancelling the order
+    pub needs_close: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProviderRefund {
+    pub refund_id: Option<String>,
+    pub status: RefundStatus,
+}
+
+/// Why a refund request did not go through
+#[derive(Debug, Clone)]
+pub enum RefundError {
+    /// The provider refused the refund; no money moved
+    Rejected(String),
+    /// No definite answer (timeout, dropped connection, provider system error).
+    /// The refund may have been executed; send it again with the same id to find out.
+    Unknown(String),
+}
+
+impl std::fmt::Display for RefundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefundError::Rejected(e) => write!(f, "rejected: {}", e),
+            RefundError::Unknown(e) => write!(f, "outcome unknown: {}", e),
+        }
+    }
+}
+
+// Anything not classified as a rejection may have reached the provider
+impl From<String> for RefundError {
+    fn from(e: String) -> Self {
+        RefundError::Unknown(e)
+    }
+}
+
+/// A verified asynchronous notification from a provider
+#[derive(Debug, Clone)]
+pub enum PaymentNotice {
+    Payment { out_trade_no: String, trade: ProviderTrade },
+    Refund { out_refund_no: String, refund: ProviderRefund },
+}
+
+/// Raw HTTP callback as received by the notification listener
+#[derive(Debug, Clone, Default)]
+pub struct NotifyRequest {
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl NotifyRequest {
+    pub fn header(&self, name: &str) -> String {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BillEntryKind {
+    Payment,
+    Refund,
+}
+
+/// One transaction line of a provider's daily bill
+#[derive(Debug, Clone)]
+pub struct BillEntry {
+    pub kind: BillEntryKind,
+    pub out_trade_no: String,
+    pub transaction_id: String,
+    /// Cents; refunds are positive too
+    pub amount: i32,
+    pub time: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NotifyReply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+/// A scan-to-pay provider. Orders and refunds are identified by our own ids;
+/// each provider derives its trade numbers from them.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    fn kind(&self) -> PaymentProviderKind;
+
+    /// Create a scan-to-pay order and return the URL to render as a QR code
+    async fn create(&self, order_id: &str, amount: i32, description: &str) -> Result<String, String>;
+
+    async fn query(&self, order_id: &str) -> Result<ProviderTrade, String>;
+
+    /// Close an unpaid order so it can no longer be paid
+    async fn close(&self, order_id: &str) -> Result<(), String>;
+
+    /// Refund all or part of a paid order. Providers deduplicate on `refund_id`,
+    /// so sending the same refund again is safe and returns its current state.
+    async fn refund(
+        &self,
+        order_id: &str,
+        refund_id: &str,
+        refund_amount: i32,
+        total_amount: i32,
+        reason: &str,
+    ) -> Result<ProviderRefund, RefundError>;
+
+    /// Verify and decode a callback. `None` for notifications with nothing to apply.
+    fn verify_notification(&self, request: &NotifyRequest) -> Result<Option<PaymentNotice>, String>;
+
+    /// Reply the provider expects; anything but success makes it retry
+    fn notification_reply(&self, error: Option<&str>) -> NotifyReply;
+
+    /// Listener path for this provider's callbacks
+    fn notify_path(&self) -> String;
+
+    /// Every transaction of `date` (Beijing time) from the provider's bill.
+    /// Bills for a day are available from about 10:00 the next morning.
+    async fn download_bill(&self, date: NaiveDate) -> Result<Vec<BillEntry>, String>;
+}
+
+pub fn create_provider(kind: PaymentProviderKind) -> Result<Box<dyn PaymentProvider>, String> {
+    Ok(match kind {
+        PaymentProviderKind::Wechat => Box::new(WeChatService::new()?),
+        PaymentProviderKind::Alipay => Box::new(AlipayService::new()?),
+        PaymentProviderKind::Voucher => return Err("Voucher orders are settled by redeeming a code".to_string()),
+    })
+}
+
+/// Parse a bill file exported by hand from the provider's merchant console
+pub fn parse_bill(kind: PaymentProviderKind, data: &[u8]) -> Result<Vec<BillEntry>, String> {
+    match kind {
+        PaymentProviderKind::Wechat => wechat_service::parse_bill(data),
+        PaymentProviderKind::Alipay => alipay_service::parse_bill(data),
+        PaymentProviderKind::Voucher => Err("Vouchers have no provider bill".to_string()),
+    }
+}
+
+/// Every provider whose configuration is complete
+pub fn configured_providers() -> Vec<Box<dyn PaymentProvider>> {
+    [PaymentProviderKind::Wechat, PaymentProviderKind::Alipay]
+        .into_iter()
+        .filter_map(|kind| match create_provider(kind) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                tracing::info!("[Payment] {} not configured: {}", kind, e);
+                None
+            }
+        })
+        .collect()
+}
+
+const DEFAULT_ORDER_TIMEOUT_SECS: i64 = 300;
+
+/// How long a scan-to-pay order stays payable (`PAYMENT_ORDER_TIMEOUT_SECS`)
+pub fn order_timeout_secs() -> i64 {
+    env::var("PAYMENT_ORDER_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_ORDER_TIMEOUT_SECS)
+}
+
+/// Path component of a notify URL, or `default` when unset
+pub fn notify_path_from_url(url: &str, default: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .map(|url| url.path().to_string())
+        .filter(|path| path != "/")
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Both providers keep time and cut bills in Beijing time
+pub fn beijing() -> FixedOffset {
+    FixedOffset::east_opt(8 * 3600).expect("valid offset")
+}
+
+/// `yyyy-MM-dd HH:mm:ss` in Beijing time
+pub fn parse_beijing_time(s: &str) -> Option<i64> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok()?;
+    naive.and_local_timezone(beijing()).single().map(|t| t.timestamp())
+}
+
+/// Unix time range `[start, end)` of a Beijing calendar day
+pub fn beijing_day_range(date: NaiveDate) -> (i64, i64) {
+    let start = date
+        .and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(beijing()).single())
+        .map(|t| t.timestamp())
+        .unwrap_or_default();
+    (start, start + 24 * 3600)
+}
+
+/// Amounts in bills and the Alipay API are yuan strings, e.g. `3.00` or `-3.00`
+pub fn parse_cents(s: &str) -> Option<i32> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (whole, frac) = s.split_once('.').unwrap_or((s, "0"));
+    let frac = format!("{:0<2}", frac);
+    if frac.len() != 2 {
+        return None;
+    }
+    let cents = whole.parse::<i32>().ok()? * 100 + frac.parse::<i32>().ok()?;
+    Some(if negative { -cents } else { cents })
+}
@@ -0,0 +1,449 @@
+use crate::models::{PrintBox, PrintTemplate, PrintText, PrintTextAlign};
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use chrono::{Local, TimeZone};
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgb, Rgba, RgbaImage};
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use super::image_service::parse_hex_color;
+use super::QrService;
+
+const DEFAULT_TEMPLATE: &str = "4x6";
+const JPEG_QUALITY: u8 = 95;
+
+// Shipped templates; files in the template directory with the same id replace them
+const BUILTIN_TEMPLATES: &[&str] = &[
+    include_str!("../../print_templates/4x6.json"),
+    include_str!("../../print_templates/2x6.json"),
+    include_str!("../../print_templates/5x7.json"),
+];
+
+// Tried in order when PRINT_FONT_PATH is unset; the CJK fonts come first for Chinese captions
+const FONT_CANDIDATES: &[&str] = &[
+    "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/noto-cjk/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/truetype/wqy/wqy-microhei.ttc",
+    "/System/Library/Fonts/PingFang.ttc",
+    "C:\\Windows\\Fonts\\msyh.ttc",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+];
+
+/// What a print is of; fills in the date, order code and QR link
+#[derive(Debug, Clone)]
+pub struct PrintContext {
+    pub order_id: String,
+    /// Empty for previews, which leave the order code out
+    pub order_code: String,
+    pub session_id: String,
+    pub printed_at: i64,
+}
+
+/// Lays paid photos out on print templates: photo slots, branded border,
+/// logo, caption, date, order code and a QR code linking to the digital copy.
+pub struct PrintCompositor {
+    templates: Vec<PrintTemplate>,
+    brand_color: Rgb<u8>,
+    logo: Option<DynamicImage>,
+    font: Option<FontVec>,
+    qr_url: Option<String>,
+}
+
+impl PrintCompositor {
+    /// Built-in templates plus every `*.json` in `PRINT_TEMPLATE_DIR`
+    /// (default `<data_dir>/print_templates`)
+    pub fn new(data_dir: &Path) -> Self {
+        let mut templates: Vec<PrintTemplate> = BUILTIN_TEMPLATES
+            .iter()
+            .map(|json| serde_json::from_str(json).expect("built-in print template is valid"))
+            .collect();
+
+        let dir = env::var("PRINT_TEMPLATE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| data_dir.join("print_templates"));
+        for template in load_templates(&dir) {
+            templates.retain(|t| t.id != template.id);
+            templates.push(template);
+        }
+
+        let brand_color = env::var("BRAND_COLOR")
+            .ok()
+            .and_then(|c| parse_hex_color(&c))
+            .unwrap_or(Rgb([124, 58, 237]));
+        let logo = env::var("BRAND_LOGO_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .and_then(|path| match image::open(&path) {
+                Ok(logo) => Some(logo),
+                Err(e) => {
+                    tracing::warn!("[PrintLayout] Failed to load brand logo {}: {}", path, e);
+                    None
+                }
+            });
+        let qr_url = env::var("PRINT_QR_URL").ok().filter(|u| !u.is_empty());
+
+        Self {
+            templates,
+            brand_color,
+            logo,
+            font: load_font(),
+            qr_url,
+        }
+    }
+
+    pub fn templates(&self) -> &[PrintTemplate] {
+        &self.templates
+    }
+
+    pub fn template(&self, id: &str) -> Option<&PrintTemplate> {
+        self.templates.iter().find(|t| t.id == id)
+    }
+
+    /// The template used for print orders (`PRINT_TEMPLATE`, default 4x6)
+    pub fn default_template_id() -> String {
+        env::var("PRINT_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string())
+    }
+
+    /// Render a full page including bleed as a JPEG tagged with the template's DPI
+    pub fn render(&self, template: &PrintTemplate, photo: &DynamicImage, context: &PrintContext) -> Result<Vec<u8>, String> {
+        let page = self.render_page(template, photo, context)?;
+        let rgb = DynamicImage::ImageRgba8(page).to_rgb8();
+
+        let mut jpeg = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY);
+        encoder.set_pixel_density(PixelDensity::dpi(template.dpi as u16));
+        encoder.encode_image(&rgb).map_err(|e| format!("Failed to encode print: {}", e))?;
+        Ok(jpeg)
+    }
+
+    fn render_page(&self, template: &PrintTemplate, photo: &DynamicImage, context: &PrintContext) -> Result<RgbaImage, String> {
+        validate(template)?;
+        let px = |inches: f32| (inches * template.dpi as f32).round() as i64;
+        let bleed = px(template.bleed);
+        let width = (px(template.width) + bleed * 2) as u32;
+        let height = (px(template.height) + bleed * 2) as u32;
+        let background = self.color(&template.background)?;
+
+        // The border color runs out into the bleed so trimming never shows white
+        let mut canvas = match &template.border {
+            Some(border) => {
+                let mut canvas = RgbaImage::from_pixel(width, height, self.color(&border.color)?);
+                let inset = bleed + px(border.width);
+                let inner = RgbaImage::from_pixel(width - inset as u32 * 2, height - inset as u32 * 2, background);
+                imageops::replace(&mut canvas, &inner, inset, inset);
+                canvas
+            }
+            None => RgbaImage::from_pixel(width, height, background),
+        };
+        let place = |b: &PrintBox| (bleed + px(b.x), bleed + px(b.y), px(b.width).max(1) as u32, px(b.height).max(1) as u32);
+
+        for slot in &template.photos {
+            let (x, y, w, h) = place(slot);
+            let fitted = photo.resize_to_fill(w, h, FilterType::Lanczos3).to_rgba8();
+            imageops::replace(&mut canvas, &fitted, x, y);
+        }
+
+        if let (Some(slot), Some(logo)) = (&template.logo, &self.logo) {
+            let (x, y, w, h) = place(slot);
+            let logo = logo.resize(w, h, FilterType::Lanczos3).to_rgba8();
+            imageops::overlay(&mut canvas, &logo, x, y + (h - logo.height()) as i64 / 2);
+        }
+
+        if let Some(caption) = &template.caption {
+            if let Some(text) = caption.text.as_deref().filter(|t| !t.is_empty()) {
+                self.draw_text(&mut canvas, template, caption, text)?;
+            }
+        }
+        if let Some(date) = &template.date {
+            let format = date.format.as_deref().unwrap_or("%Y-%m-%d");
+            if let Some(printed_at) = Local.timestamp_opt(context.printed_at, 0).single() {
+                // An invalid format is an error here rather than a panic in to_string()
+                let mut text = String::new();
+                write!(text, "{}", printed_at.format(format))
+                    .map_err(|_| format!("Invalid date format in print template {}: {}", template.id, format))?;
+                self.draw_text(&mut canvas, template, date, &text)?;
+            }
+        }
+
+        if let Some(slot) = &template.order_code {
+            if !context.order_code.is_empty() {
+                let text = slot.text.as_deref().unwrap_or("{code}").replace("{code}", &context.order_code);
+                self.draw_text(&mut canvas, template, slot, &text)?;
+            }
+        }
+
+        if let (Some(slot), Some(url)) = (&template.qr, &self.qr_url) {
+            let url = url
+                .replace("{order_id}", &context.order_id)
+                .replace("{order_code}", &context.order_code)
+                .replace("{session_id}", &context.session_id);
+            let size = px(slot.size).max(1) as u32;
+            let qr = render_qr(&url, size)?;
+            imageops::replace(&mut canvas, &qr, bleed + px(slot.x), bleed + px(slot.y));
+        }
+
+        Ok(canvas)
+    }
+
+    fn draw_text(&self, canvas: &mut RgbaImage, template: &PrintTemplate, slot: &PrintText, text: &str) -> Result<(), String> {
+        let Some(font) = &self.font else {
+            return Ok(());
+        };
+        let dpi = template.dpi as f32;
+        let bleed = template.bleed * dpi;
+        let box_width = slot.width * dpi;
+        let color = self.color(&slot.color)?;
+
+        // Points to pixels, shrunk until the line fits its box
+        let mut scale = slot.size * dpi / 72.0;
+        let mut line_width = text_width(font, scale, text);
+        if line_width > box_width {
+            scale *= box_width / line_width;
+            line_width = text_width(font, scale, text);
+        }
+        let offset = match slot.align {
+            PrintTextAlign::Left => 0.0,
+            PrintTextAlign::Center => (box_width - line_width) / 2.0,
+            PrintTextAlign::Right => box_width - line_width,
+        };
+
+        let scaled = font.as_scaled(PxScale::from(scale));
+        let origin_x = bleed + slot.x * dpi + offset.max(0.0);
+        let baseline = bleed + slot.y * dpi + scaled.ascent();
+        let mut caret = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += scaled.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(scale, point(origin_x + caret, baseline));
+            caret += scaled.h_advance(id);
+            previous = Some(id);
+
+            let Some(outline) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let x = bounds.min.x as i64 + gx as i64;
+                let y = bounds.min.y as i64 + gy as i64;
+                if x < 0 || y < 0 || x >= canvas.width() as i64 || y >= canvas.height() as i64 {
+                    return;
+                }
+                let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+                for i in 0..3 {
+                    pixel[i] = (pixel[i] as f32 * (1.0 - coverage) + color[i] as f32 * coverage).round() as u8;
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn color(&self, value: &str) -> Result<Rgba<u8>, String> {
+        let rgb = if value.eq_ignore_ascii_case("brand") {
+            self.brand_color
+        } else {
+            parse_hex_color(value).ok_or_else(|| format!("Invalid color in print template: {}", value))?
+        };
+        Ok(Rgba([rgb[0], rgb[1], rgb[2], 255]))
+    }
+}
+
+fn validate(template: &PrintTemplate) -> Result<(), String> {
+    if template.width <= 0.0 || template.height <= 0.0 || template.bleed < 0.0 {
+        return Err(format!("Print template {} has an invalid page size", template.id));
+    }
+    if !(72..=1200).contains(&template.dpi) {
+        return Err(format!("Print template {} needs a DPI between 72 and 1200", template.id));
+    }
+    if template.photos.is_empty() {
+        return Err(format!("Print template {} has no photo slot", template.id));
+    }
+    let inside = |b: &PrintBox| {
+        b.x >= 0.0 && b.y >= 0.0 && b.width > 0.0 && b.height > 0.0
+            && b.x + b.width <= template.width + 0.001 && b.y + b.height <= template.height + 0.001
+    };
+    if !template.photos.iter().chain(template.logo.as_ref()).all(inside) {
+        return Err(format!("Print template {} has a box outside the page", template.id));
+    }
+    if let Some(border) = &template.border {
+        if border.width < 0.0 || border.width * 2.0 >= template.width.min(template.height) {
+            return Err(format!("Print template {} has an invalid border", template.id));
+        }
+    }
+    Ok(())
+}
+
+fn load_templates(dir: &Path) -> Vec<PrintTemplate> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let template = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_str::<PrintTemplate>(&json).map_err(|e| e.to_string()))
+                .and_then(|template| validate(&template).map(|_| template));
+            match template {
+                Ok(template) => {
+                    tracing::info!("[PrintLayout] Loaded template {} from {}", template.id, path.display());
+                    Some(template)
+                }
+                Err(e) => {
+                    tracing::warn!("[PrintLayout] Skipping template {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// `PRINT_FONT_PATH`, or the first common system font found
+fn load_font() -> Option<FontVec> {
+    let configured = env::var("PRINT_FONT_PATH").ok().filter(|p| !p.is_empty());
+    let candidates: Vec<String> = match configured {
+        Some(path) => vec![path],
+        None => FONT_CANDIDATES.iter().map(|p| p.to_string()).collect(),
+    };
+
+    for path in candidates {
+        let Ok(data) = std::fs::read(&path) else {
+            continue;
+        };
+        match FontVec::try_from_vec_and_index(data, 0) {
+            Ok(font) => return Some(font),
+            Err(e) => tracing::warn!("[PrintLayout] Failed to load font {}: {}", path, e),
+        }
+    }
+    tracing::warn!("[PrintLayout] No font found, prints will have no caption or date");
+    None
+}
+
+fn text_width(font: &FontVec, scale: f32, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(scale));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// A QR symbol in a `size` pixel square. Modules stay whole pixels; the
+/// leftover is white padding around the symbol.
+fn render_qr(data: &str, size: u32) -> Result<RgbaImage, String> {
+    let qr = QrService::new();
+    let mut options = qr.options();
+    options.module_px = 1;
+    let modules = qr.render_image(data, &options)?.width();
+    if modules > size {
+        return Err(format!("QR code does not fit in {} pixels", size));
+    }
+    options.module_px = size / modules;
+
+    let symbol = qr.render_image(data, &options)?;
+    let mut square = RgbaImage::from_pixel(size, size, Rgba([255, 255, 255, 255]));
+    let offset = ((size - symbol.width()) / 2) as i64;
+    imageops::replace(&mut square, &symbol, offset, offset);
+    Ok(square)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRAND: Rgb<u8> = Rgb([200, 30, 60]);
+
+    fn compositor() -> PrintCompositor {
+        PrintCompositor {
+            templates: BUILTIN_TEMPLATES.iter().map(|json| serde_json::from_str(json).unwrap()).collect(),
+            brand_color: BRAND,
+            logo: None,
+            font: None,
+            qr_url: None,
+        }
+    }
+
+    fn context() -> PrintContext {
+        PrintContext {
+            order_id: "order-1".to_string(),
+            order_code: "A1B2".to_string(),
+            session_id: "session-1".to_string(),
+            printed_at: 1_790_000_000,
+        }
+    }
+
+    fn photo() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(800, 600, Rgb([40, 160, 90])))
+    }
+
+    /// Units and X/Y density from the JFIF APP0 segment that opens the file
+    fn jfif_density(jpeg: &[u8]) -> (u8, u16, u16) {
+        assert_eq!(&jpeg[..4], &[0xFF, 0xD8, 0xFF, 0xE0]);
+        assert_eq!(&jpeg[6..11], b"JFIF\0");
+        let density = |i: usize| u16::from_be_bytes([jpeg[i], jpeg[i + 1]]);
+        (jpeg[13], density(14), density(16))
+    }
+
+    #[test]
+    fn page_includes_bleed_at_template_dpi() {
+        let compositor = compositor();
+        // The 0.125" bleed is 37.5px at 300 DPI and rounds up to 38px a side
+        for (id, width, height) in [("4x6", 1276, 1876), ("2x6", 676, 1876), ("5x7", 1576, 2176)] {
+            let template = compositor.template(id).unwrap();
+            let jpeg = compositor.render(template, &photo(), &context()).unwrap();
+
+            let page = image::load_from_memory(&jpeg).unwrap();
+            assert_eq!((page.width(), page.height()), (width, height), "{}", id);
+            // Units 1 is dots per inch
+            assert_eq!(jfif_density(&jpeg), (1, 300, 300), "{}", id);
+        }
+    }
+
+    #[test]
+    fn density_follows_a_custom_dpi() {
+        let compositor = compositor();
+        let mut template = compositor.template("4x6").unwrap().clone();
+        template.dpi = 150;
+        let jpeg = compositor.render(&template, &photo(), &context()).unwrap();
+
+        let page = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((page.width(), page.height()), (638, 938));
+        assert_eq!(jfif_density(&jpeg), (1, 150, 150));
+    }
+
+    #[test]
+    fn border_runs_into_the_bleed_around_the_photo() {
+        let compositor = compositor();
+        let template = compositor.template("4x6").unwrap();
+        let page = compositor.render_page(template, &photo(), &context()).unwrap();
+
+        assert_eq!(page.get_pixel(0, 0), &Rgba([BRAND[0], BRAND[1], BRAND[2], 255]));
+        // Center of the photo slot: 0.125" bleed + 0.3" + half of 3.4" across
+        assert_eq!(page.get_pixel(638, 808), &Rgba([40, 160, 90, 255]));
+    }
+
+    #[test]
+    fn out_of_range_dpi_is_rejected() {
+        let compositor = compositor();
+        let mut template = compositor.template("4x6").unwrap().clone();
+        template.dpi = 50;
+        let err = compositor.render(&template, &photo(), &context()).unwrap_err();
+        assert!(err.contains("DPI"), "{}", err);
+    }
+}
@@ -0,0 +1,371 @@
+use crate::models::{MediaSupply, OrderStatus, PrintJob, PrintJobStatus, PrintStatusEvent, PrintTemplate, PrinterState, PrinterStatus};
+use chrono::Utc;
+use std::env;
+use std::time::Duration;
+
+use super::print_compositor::{PrintCompositor, PrintContext};
+use super::printer::{create_printer, PrintDocument, Printer, PrinterJobState};
+use super::{InventoryService, PrintService, SessionService, Storage, UpscaleService};
+
+const TICK: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Prints paid print units in the background: queues a job for each pending
+/// print fulfillment, submits it and follows it until the printer is done.
+/// Watches printer health on every tick; jobs wait while the printer is down
+/// and failed units are printed again once it recovers.
+pub struct PrintQueue {
+    storage: Storage,
+    printer: Box<dyn Printer>,
+    compositor: PrintCompositor,
+    template: PrintTemplate,
+    upscaler: UpscaleService,
+    /// Print attempts per unit before it is left for the operator (`PRINT_MAX_ATTEMPTS`)
+    max_attempts: u32,
+    last_state: Option<PrinterState>,
+}
+
+impl PrintQueue {
+    pub fn new(storage: Storage) -> Result<Self, String> {
+        let printer = create_printer(&storage.data_dir)?;
+        let compositor = PrintCompositor::new(&storage.data_dir);
+        let upscaler = UpscaleService::new(&storage.data_dir)?;
+        let template_id = PrintCompositor::default_template_id();
+        let template = compositor
+            .template(&template_id)
+            .cloned()
+            .ok_or_else(|| format!("Unknown print template: {}", template_id))?;
+
+        let max_attempts = env::var("PRINT_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+
+        Ok(Self { storage, printer, compositor, template, upscaler, max_attempts, last_state: None })
+    }
+
+    /// Run forever, reporting every finished job through `on_status`, every
+    /// change of printer health through `on_printer` and media running low
+    /// through `on_media`
+    pub async fn run<F, G, H>(mut self, on_status: F, on_printer: G, on_media: H)
+    where
+        F: Fn(PrintStatusEvent) + Send + Sync + 'static,
+        G: Fn(PrinterStatus) + Send + Sync + 'static,
+        H: Fn(MediaSupply) + Send + Sync + 'static,
+    {
+        tracing::info!("[Print] Printing {} to {}", self.template.id, self.printer.name());
+        loop {
+            if let Err(e) = self.process(&on_status, &on_printer, &on_media).await {
+                tracing::warn!("[Print] {}", e);
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+
+    async fn process<F, G, H>(&mut self, on_status: &F, on_printer: &G, on_media: &H) -> Result<(), String>
+    where
+        F: Fn(PrintStatusEvent),
+        G: Fn(PrinterStatus),
+        H: Fn(MediaSupply),
+    {
+        let ready = self.check_printer(on_printer).await?;
+
+        let (queued, printing) = {
+            let conn = self.storage.get_connection()?;
+            let print_service = PrintService::new(&conn);
+            print_service.enqueue_pending(&self.printer.name())?;
+            (
+                print_service.get_jobs_by_status(PrintJobStatus::Queued)?,
+                print_service.get_jobs_by_status(PrintJobStatus::Printing)?,
+            )
+        };
+
+        // Queued jobs wait for the printer rather than fail against it
+        if ready {
+            for job in queued {
+                if let Err(e) = self.submit(&job).await {
+                    tracing::error!("[Print] Job {} not submitted: {}", job.id, e);
+                    self.finish(&job, PrintJobStatus::Failed, Some(&e), on_status, on_media)?;
+                }
+            }
+        }
+
+        for job in printing {
+            let Some(printer_job_id) = job.printer_job_id.as_deref() else {
+                continue;
+            };
+            // The printer being unreachable is not the job failing; ask again next tick
+            match self.printer.job_state(printer_job_id).await {
+                Ok(PrinterJobState::Processing) => {}
+                Ok(PrinterJobState::Completed) => self.finish(&job, PrintJobStatus::Completed, None, on_status, on_media)?,
+                Ok(PrinterJobState::Failed(reason)) => self.finish(&job, PrintJobStatus::Failed, Some(&reason), on_status, on_media)?,
+                Err(e) => tracing::warn!("[Print] Job {} state unknown: {}", job.id, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll and record printer health; returns whether jobs can be submitted
+    async fn check_printer<G>(&mut self, on_printer: &G) -> Result<bool, String>
+    where
+        G: Fn(PrinterStatus),
+    {
+        let (state, message) = self.printer.status().await;
+        let status = PrinterStatus {
+            printer: self.printer.name(),
+            state,
+            message,
+            checked_at: Utc::now().timestamp(),
+        };
+
+        let conn = self.storage.get_connection()?;
+        let print_service = PrintService::new(&conn);
+        print_service.save_status(&status)?;
+
+        let previous = self.last_state.replace(state);
+        if previous == Some(state) {
+            return Ok(state == PrinterState::Ready);
+        }
+        match state {
+            PrinterState::Ready => tracing::info!("[Print] Printer ready"),
+            _ => tracing::warn!("[Print] Printer {}: {}", state, status.message.as_deref().unwrap_or("")),
+        }
+        on_printer(status);
+
+        // Back from a fault, or first seen ready after a restart
+        if state == PrinterState::Ready {
+            let requeued = print_service.requeue_failed(&self.printer.name(), self.max_attempts)?;
+            if !requeued.is_empty() {
+                tracing::info!("[Print] Requeued {} failed print(s)", requeued.len());
+            }
+        }
+        Ok(state == PrinterState::Ready)
+    }
+
+    async fn submit(&self, job: &PrintJob) -> Result<(), String> {
+        let document = self.document(job).await?;
+        let printer_job_id = self.printer.submit(&document).await?;
+
+        let conn = self.storage.get_connection()?;
+        PrintService::new(&conn).mark_printing(&job.id, &printer_job_id)?;
+        tracing::info!("[Print] Job {} submitted as {}", job.id, printer_job_id);
+        Ok(())
+    }
+
+    /// The session's generated photo, upscaled and laid out on the print template
+    async fn document(&self, job: &PrintJob) -> Result<PrintDocument, String> {
+        let (order, photo) = {
+            let conn = self.storage.get_connection()?;
+            let session_service = SessionService::new(&conn);
+            let order = session_service.get_order(&job.order_id)?.ok_or_else(|| "Order not found".to_string())?;
+            // Refunded after the job was queued
+            if order.status != OrderStatus::Paid {
+                return Err(format!("Order is {}, not printing", order.status));
+            }
+            let photo = session_service
+                .get_session(&order.session_id)?
+                .and_then(|session| session.generated_photo)
+                .ok_or_else(|| "Session has no generated photo".to_string())?;
+            (order, photo)
+        };
+        let photo = self.upscaler.upscale(&photo).await?;
+        let photo = image::load_from_memory(&photo).map_err(|e| format!("Failed to decode image: {}", e))?;
+
+        let context = PrintContext {
+            order_id: order.id,
+            order_code: order.code.unwrap_or_default(),
+            session_id: order.session_id,
+            printed_at: Utc::now().timestamp(),
+        };
+        let data = self.compositor.render(&self.template, &photo, &context)?;
+        Ok(PrintDocument { job_id: job.id.clone(), mime_type: "image/jpeg", data })
+    }
+
+    fn finish<F, H>(&self, job: &PrintJob, status: PrintJobStatus, error: Option<&str>, on_status: &F, on_media: &H) -> Result<(), String>
+    where
+        F: Fn(PrintStatusEvent),
+        H: Fn(MediaSupply),
+    {
+        let conn = self.storage.get_connection()?;
+        PrintService::new(&conn).finish(&job.id, status.clone(), error)?;
+        if status == PrintJobStatus::Completed {
+            for supply in InventoryService::new(&conn).consume_print()? {
+                on_media(supply);
+            }
+        }
+        on_status(PrintStatusEvent {
+            job_id: job.id.clone(),
+            order_id: job.order_id.clone(),
+            status,
+            error: error.map(|e| e.to_string()),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{FulfillmentStatus, MediaKind, OrderType, PaymentProviderKind};
+    use crate::services::printer::FilePrinter;
+    use crate::services::session_service::OrderContents;
+    use crate::services::{FulfillmentService, ModeService};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::sync::Mutex;
+
+    fn file_queue(storage: &Storage, max_attempts: u32) -> PrintQueue {
+        let compositor = PrintCompositor::new(&storage.data_dir);
+        let template = compositor.template(&PrintCompositor::default_template_id()).cloned().unwrap();
+        PrintQueue {
+            storage: storage.clone(),
+            printer: Box::new(FilePrinter::new(&storage.data_dir).unwrap()),
+            compositor,
+            template,
+            upscaler: UpscaleService::new(&storage.data_dir).unwrap(),
+            max_attempts,
+            last_state: None,
+        }
+    }
+
+    /// A paid print order for a session with a generated photo
+    fn paid_print_order(storage: &Storage) -> String {
+        let conn = storage.get_connection().unwrap();
+        let mode = ModeService::new(&conn).get_all_modes().unwrap().remove(0);
+        let session_service = SessionService::new(&conn);
+        let session = session_service.create_session(&mode.id, &mode.effects[0].id).unwrap();
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(300, 200)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        session_service.save_generated_photo(&session.id, &STANDARD.encode(&png), false).unwrap();
+
+        let contents = OrderContents::Single { order_type: OrderType::Print, amount: None };
+        let order = session_service.create_order_from(&session.id, contents, PaymentProviderKind::Wechat).unwrap();
+        session_service.transition_order(&order.id, OrderStatus::Paid, "test", Some("tx1"), None, None).unwrap();
+        order.id
+    }
+
+    /// One pass of the queue; returns the finished jobs it reported
+    async fn tick(queue: &mut PrintQueue) -> Vec<PrintStatusEvent> {
+        let events = Mutex::new(Vec::new());
+        queue.process(&|event| events.lock().unwrap().push(event), &|_| {}, &|_| {}).await.unwrap();
+        events.into_inner().unwrap()
+    }
+
+    fn jobs(storage: &Storage, order_id: &str) -> Vec<PrintJob> {
+        let conn = storage.get_connection().unwrap();
+        PrintService::new(&conn).get_jobs(Some(order_id), 10).unwrap()
+    }
+
+    fn fulfillment_status(storage: &Storage, order_id: &str) -> FulfillmentStatus {
+        let conn = storage.get_connection().unwrap();
+        FulfillmentService::new(&conn).get_fulfillments(order_id).unwrap().remove(0).status
+    }
+
+    /// Lose the printed page so the printer reports the job failed
+    fn lose_output(job: &PrintJob) {
+        std::fs::remove_file(job.printer_job_id.as_deref().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn paid_print_is_printed_and_fulfilled() {
+        let storage = Storage::temp();
+        let mut queue = file_queue(&storage, DEFAULT_MAX_ATTEMPTS);
+        let order_id = paid_print_order(&storage);
+
+        // Queued and submitted in one pass
+        assert!(tick(&mut queue).await.is_empty());
+        let job = jobs(&storage, &order_id).remove(0);
+        assert_eq!(job.status, PrintJobStatus::Printing);
+        assert!(std::path::Path::new(job.printer_job_id.as_deref().unwrap()).exists());
+
+        let events = tick(&mut queue).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status, PrintJobStatus::Completed);
+        assert_eq!(jobs(&storage, &order_id)[0].status, PrintJobStatus::Completed);
+        assert_eq!(fulfillment_status(&storage, &order_id), FulfillmentStatus::Fulfilled);
+    }
+
+    #[tokio::test]
+    async fn failed_print_is_retried_up_to_max_attempts() {
+        let storage = Storage::temp();
+        let mut queue = file_queue(&storage, 2);
+        let order_id = paid_print_order(&storage);
+
+        tick(&mut queue).await;
+        lose_output(&jobs(&storage, &order_id)[0]);
+        let events = tick(&mut queue).await;
+        assert_eq!(events[0].status, PrintJobStatus::Failed);
+        assert_eq!(fulfillment_status(&storage, &order_id), FulfillmentStatus::Failed);
+
+        // Printer back from a fault: the unit goes out again under a new job
+        queue.last_state = Some(PrinterState::Offline);
+        tick(&mut queue).await;
+        let attempts = jobs(&storage, &order_id);
+        assert_eq!(attempts.len(), 2);
+        let retry = attempts.iter().find(|j| j.status == PrintJobStatus::Printing).unwrap();
+        assert_eq!(fulfillment_status(&storage, &order_id), FulfillmentStatus::Pending);
+
+        lose_output(retry);
+        tick(&mut queue).await;
+        assert_eq!(fulfillment_status(&storage, &order_id), FulfillmentStatus::Failed);
+
+        // Out of attempts: left for the operator
+        queue.last_state = Some(PrinterState::Offline);
+        tick(&mut queue).await;
+        let attempts = jobs(&storage, &order_id);
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts.iter().all(|j| j.status == PrintJobStatus::Failed));
+        assert_eq!(fulfillment_status(&storage, &order_id), FulfillmentStatus::Failed);
+    }
+
+    fn refund(storage: &Storage, order_id: &str) {
+        let conn = storage.get_connection().unwrap();
+        SessionService::new(&conn).transition_order(order_id, OrderStatus::Refunded, "test", None, None, None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refunded_print_is_not_printed() {
+        let storage = Storage::temp();
+        let mut queue = file_queue(&storage, DEFAULT_MAX_ATTEMPTS);
+        let order_id = paid_print_order(&storage);
+        let available = || {
+            let conn = storage.get_connection().unwrap();
+            InventoryService::new(&conn).available_prints().unwrap()
+        };
+        {
+            let conn = storage.get_connection().unwrap();
+            InventoryService::new(&conn).restock(MediaKind::Paper, 10, None).unwrap();
+        }
+        assert_eq!(available(), Some(9));
+
+        refund(&storage, &order_id);
+        assert_eq!(available(), Some(10));
+        assert!(tick(&mut queue).await.is_empty());
+        assert!(jobs(&storage, &order_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_print_of_refunded_order_is_dropped() {
+        let storage = Storage::temp();
+        let mut queue = file_queue(&storage, DEFAULT_MAX_ATTEMPTS);
+        let order_id = paid_print_order(&storage);
+        {
+            let conn = storage.get_connection().unwrap();
+            PrintService::new(&conn).enqueue_pending(&queue.printer.name()).unwrap();
+        }
+
+        refund(&storage, &order_id);
+        let events = tick(&mut queue).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status, PrintJobStatus::Failed);
+        assert_eq!(events[0].error.as_deref(), Some("Order is refunded, not printing"));
+
+        // Not retried once the printer recovers
+        queue.last_state = Some(PrinterState::Offline);
+        tick(&mut queue).await;
+        assert_eq!(jobs(&storage, &order_id).len(), 1);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::models::{FulfillmentStatus, OrderStatus, OrderType, PrintJob, PrintJobStatus, PrinterState, PrinterStatus};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use uuid::Uuid;
+
+use super::FulfillmentService;
+
+// A status older than this means nobody is watching the printer
+const STATUS_STALE_SECS: i64 = 120;
+
+pub struct PrintService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> PrintService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Queue a job for every print fulfillment of a paid order that has never been printed
+    pub fn enqueue_pending(&self, printer: &str) -> Result<Vec<PrintJob>, String> {
+        let fulfillments: Vec<(i64, String)> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT f.id, f.order_id FROM fulfillments f JOIN orders o ON o.id = f.order_id
+                 WHERE f.kind = ?1 AND f.status = ?2 AND o.status = ?3
+                   AND NOT EXISTS (SELECT 1 FROM print_jobs j WHERE j.fulfillment_id = f.id)
+                 ORDER BY f.id"
+            ).map_err(|e| e.to_string())?;
+            let rows = stmt.query_map(
+                [OrderType::Print.to_string(), FulfillmentStatus::Pending.to_string(), OrderStatus::Paid.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+            rows
+        };
+
+        fulfillments
+            .into_iter()
+            .map(|(fulfillment_id, order_id)| self.create_job(&order_id, fulfillment_id, printer))
+            .collect()
+    }
+
+    pub fn create_job(&self, order_id: &str, fulfillment_id: i64, printer: &str) -> Result<PrintJob, String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now().timestamp();
+
+        self.conn.execute(
+            "INSERT INTO print_jobs (id, order_id, fulfillment_id, printer, status, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            rusqlite::params![id, order_id, fulfillment_id, printer, PrintJobStatus::Queued.to_string(), now],
+        ).map_err(|e| e.to_string())?;
+
+        tracing::info!("[Print] Queued job {} for order {}", id, order_id);
+        Ok(PrintJob {
+            id,
+            order_id: order_id.to_string(),
+            fulfillment_id,
+            printer: printer.to_string(),
+            status: PrintJobStatus::Queued,
+            printer_job_id: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    /// Record that the printer accepted the job
+    pub fn mark_printing(&self, id: &str, printer_job_id: &str) -> Result<(), String> {
+        self.conn.execute(
+            "UPDATE print_jobs SET status = ?1, printer_job_id = ?2, updated_at = ?3 WHERE id = ?4",
+            rusqlite::params![PrintJobStatus::Printing.to_string(), printer_job_id, Utc::now().timestamp(), id],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Finish a job and its fulfillment together
+    pub fn finish(&self, id: &str, status: PrintJobStatus, error: Option<&str>) -> Result<(), String> {
+        let job = self.get_job(id)?.ok_or_else(|| "Print job not found".to_string())?;
+        let fulfillment_status = match status {
+            PrintJobStatus::Completed => FulfillmentStatus::Fulfilled,
+            PrintJobStatus::Failed => FulfillmentStatus::Failed,
+            _ => return Err(format!("A print job cannot finish as {}", status)),
+        };
+
+        let tx = self.conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        tx.execute(
+            "UPDATE print_jobs SET status = ?1, error = ?2, updated_at = ?3 WHERE id = ?4",
+            rusqlite::params![status.to_string(), error, Utc::now().timestamp(), id],
+        ).map_err(|e| e.to_string())?;
+        let detail = match &job.printer_job_id {
+            Some(printer_job_id) => format!("Print job {} on {}", printer_job_id, job.printer),
+            None => format!("Print job {}", job.id),
+        };
+        FulfillmentService::new(self.conn).update_status(job.fulfillment_id, fulfillment_status, Some(error.unwrap_or(&detail)))?;
+        tx.commit().map_err(|e| e.to_string())?;
+
+        tracing::info!("[Print] Job {} {}", id, status);
+        Ok(())
+    }
+
+    /// Put failed print units of paid orders back in the queue under new jobs,
+    /// skipping units that have already been tried `max_attempts` times
+    pub fn requeue_failed(&self, printer: &str, max_attempts: u32) -> Result<Vec<PrintJob>, String> {
+        let fulfillments: Vec<(i64, String)> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT f.id, f.order_id FROM fulfillments f JOIN orders o ON o.id = f.order_id
+                 WHERE f.kind = ?1 AND f.status = ?2 AND o.status = ?3
+                   AND (SELECT COUNT(*) FROM print_jobs j WHERE j.fulfillment_id = f.id) BETWEEN 1 AND ?4 - 1
+                 ORDER BY f.id"
+            ).map_err(|e| e.to_string())?;
+            let rows = stmt.query_map(
+                rusqlite::params![
+                    OrderType::Print.to_string(),
+                    FulfillmentStatus::Failed.to_string(),
+                    OrderStatus::Paid.to_string(),
+                    max_attempts
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+            rows
+        };
+
+        let fulfillment_service = FulfillmentService::new(self.conn);
+        let mut jobs = Vec::with_capacity(fulfillments.len());
+        for (fulfillment_id, order_id) in fulfillments {
+            let tx = self.conn.unchecked_transaction().map_err(|e| e.to_string())?;
+            fulfillment_service.update_status(fulfillment_id, FulfillmentStatus::Pending, Some("Requeued for printing"))?;
+            jobs.push(self.create_job(&order_id, fulfillment_id, printer)?);
+            tx.commit().map_err(|e| e.to_string())?;
+        }
+        Ok(jobs)
+    }
+
+    pub fn save_status(&self, status: &PrinterStatus) -> Result<(), String> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO printer_status (id, printer, state, message, checked_at) VALUES (1, ?1, ?2, ?3, ?4)",
+            rusqlite::params![status.printer, status.state.to_string(), status.message, status.checked_at],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Last polled printer health; `None` when printing is not set up
+    pub fn get_status(&self) -> Result<Option<PrinterStatus>, String> {
+        self.conn.query_row(
+            "SELECT printer, state, message, checked_at FROM printer_status WHERE id = 1",
+            [],
+            |row| {
+                let state: String = row.get(1)?;
+                Ok(PrinterStatus {
+                    printer: row.get(0)?,
+                    state: state.parse().unwrap_or(PrinterState::Error),
+                    message: row.get(2)?,
+                    checked_at: row.get(3)?,
+                })
+            },
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    /// Refuse to sell prints while the watched printer cannot print. Without a
+    /// recent status, printing is either not set up or handled by hand.
+    pub fn ensure_printer_available(&self) -> Result<(), String> {
+        let Some(status) = self.get_status()? else {
+            return Ok(());
+        };
+        if status.state == PrinterState::Ready || Utc::now().timestamp() - status.checked_at > STATUS_STALE_SECS {
+            return Ok(());
+        }
+        Err(format!("Printer unavailable ({}), prints cannot be sold right now", status.state))
+    }
+
+    pub fn get_job(&self, id: &str) -> Result<Option<PrintJob>, String> {
+        self.conn.query_row(
+            "SELECT id, order_id, fulfillment_id, printer, status, printer_job_id, error, created_at, updated_at
+             FROM print_jobs WHERE id = ?1",
+            [id],
+            print_job_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    pub fn get_jobs_by_status(&self, status: PrintJobStatus) -> Result<Vec<PrintJob>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, fulfillment_id, printer, status, printer_job_id, error, created_at, updated_at
+             FROM print_jobs WHERE status = ?1 ORDER BY created_at, id"
+        ).map_err(|e| e.to_string())?;
+
+        let jobs = stmt.query_map([status.to_string()], print_job_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|j| j.ok())
+            .collect();
+
+        Ok(jobs)
+    }
+
+    /// Jobs of one order, or the most recent jobs when no order is given
+    pub fn get_jobs(&self, order_id: Option<&str>, limit: u32) -> Result<Vec<PrintJob>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, fulfillment_id, printer, status, printer_job_id, error, created_at, updated_at
+             FROM print_jobs WHERE ?1 IS NULL OR order_id = ?1 ORDER BY created_at DESC, id LIMIT ?2"
+        ).map_err(|e| e.to_string())?;
+
+        let jobs = stmt.query_map(rusqlite::params![order_id, limit], print_job_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|j| j.ok())
+            .collect();
+
+        Ok(jobs)
+    }
+}
+
+fn print_job_from_row(row: &rusqlite::Row) -> rusqlite::Result<PrintJob> {
+    let status: String = row.get(4)?;
+    Ok(PrintJob {
+        id: row.get(0)?,
+        order_id: row.get(1)?,
+        fulfillment_id: row.get(2)?,
+        printer: row.get(3)?,
+        status: status.parse().unwrap_or(PrintJobStatus::Queued),
+        printer_job_id: row.get(5)?,
+        error: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+    })
+}
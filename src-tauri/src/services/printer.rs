@@ -0,0 +1,101 @@
+use crate::models::PrinterState;
+use async_trait::async_trait;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use super::IppPrinter;
+
+/// A ready-to-print page
+#[derive(Debug, Clone)]
+pub struct PrintDocument {
+    /// Our print job id, used as the job name at the printer
+    pub job_id: String,
+    pub mime_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// Printer-side state of a submitted job
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrinterJobState {
+    /// Accepted but not finished, including jobs held or stopped at the printer
+    Processing,
+    Completed,
+    Failed(String),
+}
+
+#[async_trait]
+pub trait Printer: Send + Sync {
+    /// Name recorded on each print job
+    fn name(&self) -> String;
+
+    /// Hand a page to the printer and return the printer's job id
+    async fn submit(&self, document: &PrintDocument) -> Result<String, String>;
+
+    async fn job_state(&self, printer_job_id: &str) -> Result<PrinterJobState, String>;
+
+    /// Current health with the printer's own message, if any. A printer that
+    /// cannot be reached is `Offline`, not an error.
+    async fn status(&self) -> (PrinterState, Option<String>);
+}
+
+/// The printer selected by `PRINTER_BACKEND`: `ipp` for a CUPS/IPP queue,
+/// `file` to write pages to `PRINT_OUTPUT_DIR` instead of printing
+pub fn create_printer(data_dir: &Path) -> Result<Box<dyn Printer>, String> {
+    let backend = env::var("PRINTER_BACKEND").map_err(|_| "PRINTER_BACKEND not set".to_string())?;
+    Ok(match backend.as_str() {
+        "ipp" => Box::new(IppPrinter::new()?),
+        "file" => Box::new(FilePrinter::new(data_dir)?),
+        other => return Err(format!("Unknown PRINTER_BACKEND: {}", other)),
+    })
+}
+
+/// Writes every page to a directory; each job completes as soon as it is written
+pub struct FilePrinter {
+    dir: PathBuf,
+}
+
+impl FilePrinter {
+    pub fn new(data_dir: &Path) -> Result<Self, String> {
+        let dir = env::var("PRINT_OUTPUT_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| data_dir.join("print_output"));
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create print output dir: {}", e))?;
+        Ok(Self { dir })
+    }
+}
+
+#[async_trait]
+impl Printer for FilePrinter {
+    fn name(&self) -> String {
+        format!("file:{}", self.dir.display())
+    }
+
+    async fn submit(&self, document: &PrintDocument) -> Result<String, String> {
+        let extension = match document.mime_type {
+            "image/png" => "png",
+            "application/pdf" => "pdf",
+            _ => "jpg",
+        };
+        let path = self.dir.join(format!("{}.{}", document.job_id, extension));
+        tokio::fs::write(&path, &document.data)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(path.display().to_string())
+    }
+
+    async fn job_state(&self, printer_job_id: &str) -> Result<PrinterJobState, String> {
+        if Path::new(printer_job_id).exists() {
+            Ok(PrinterJobState::Completed)
+        } else {
+            Ok(PrinterJobState::Failed("Output file is missing".to_string()))
+        }
+    }
+
+    async fn status(&self) -> (PrinterState, Option<String>) {
+        if self.dir.is_dir() {
+            (PrinterState::Ready, None)
+        } else {
+            (PrinterState::Offline, Some(format!("{} is missing", self.dir.display())))
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::imageops::{overlay, FilterType};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use qrcode::{Color, QrCode};
+use std::env;
+use std::io::Cursor;
+
+pub use qrcode::EcLevel;
+
+// Logo may cover at most this share of the symbol width; level H tolerates it
+const LOGO_SCALE: f32 = 0.22;
+
+#[derive(Debug, Clone)]
+pub struct QrOptions {
+    pub ec_level: EcLevel,
+    /// Light border around the symbol, in modules (the spec asks for 4)
+    pub quiet_zone: u32,
+    /// PNG pixels per module; SVG output is unitless
+    pub module_px: u32,
+    /// Draw the configured logo in the center; forces level H
+    pub with_logo: bool,
+}
+
+impl Default for QrOptions {
+    fn default() -> Self {
+        Self {
+            ec_level: EcLevel::M,
+            quiet_zone: 4,
+            module_px: 10,
+            with_logo: false,
+        }
+    }
+}
+
+/// Renders QR codes for payment links, download links and printed receipts
+pub struct QrService {
+    defaults: QrOptions,
+    logo: Option<DynamicImage>,
+}
+
+impl QrService {
+    pub fn new() -> Self {
+        let mut defaults = QrOptions::default();
+        if let Some(level) = env::var("QR_EC_LEVEL").ok().and_then(|v| parse_ec_level(&v)) {
+            defaults.ec_level = level;
+        }
+        if let Some(zone) = env::var("QR_QUIET_ZONE").ok().and_then(|v| v.parse().ok()) {
+            defaults.quiet_zone = zone;
+        }
+        if let Some(px) = env::var("QR_MODULE_PX").ok().and_then(|v| v.parse().ok()).filter(|px| *px > 0) {
+            defaults.module_px = px;
+        }
+
+        let logo = env::var("QR_LOGO_PATH")
+            .or_else(|_| env::var("BRAND_LOGO_PATH"))
+            .ok()
+            .filter(|p| !p.is_empty())
+            .and_then(|path| match image::open(&path) {
+                Ok(logo) => Some(logo),
+                Err(e) => {
+                    tracing::warn!("[QR] Failed to load logo {}: {}", path, e);
+                    None
+                }
+            });
+        defaults.with_logo = logo.is_some();
+
+        Self { defaults, logo }
+    }
+
+    /// Options from `QR_*` env vars; the logo is on whenever one is configured
+    pub fn options(&self) -> QrOptions {
+        self.defaults.clone()
+    }
+
+    pub fn render_png(&self, data: &str, options: &QrOptions) -> Result<Vec<u8>, String> {
+        let canvas = self.render_image(data, options)?;
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(canvas)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(|e| format!("Failed to encode QR code: {}", e))?;
+        Ok(png)
+    }
+
+    /// The symbol as pixels, for drawing onto other images
+    pub fn render_image(&self, data: &str, options: &QrOptions) -> Result<RgbaImage, String> {
+        let (modules, width) = self.encode(data, options)?;
+        let px = options.module_px;
+        let size = (width as u32 + options.quiet_zone * 2) * px;
+
+        let mut canvas = RgbaImage::from_pixel(size, size, Rgba([255, 255, 255, 255]));
+        for (i, color) in modules.iter().enumerate() {
+            if *color != Color::Dark {
+                continue;
+            }
+            let x0 = ((i % width) as u32 + options.quiet_zone) * px;
+            let y0 = ((i / width) as u32 + options.quiet_zone) * px;
+            for y in y0..y0 + px {
+                for x in x0..x0 + px {
+                    canvas.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+                }
+            }
+        }
+
+        if let Some(logo) = self.logo.as_ref().filter(|_| options.with_logo) {
+            let symbol = width as u32 * px;
+            let max = (symbol as f32 * LOGO_SCALE) as u32;
+            let logo = logo.resize(max, max, FilterType::Lanczos3).to_rgba8();
+
+            // White pad keeps the logo from merging into neighbouring modules
+            let pad = px;
+            let backing = RgbaImage::from_pixel(logo.width() + pad * 2, logo.height() + pad * 2, Rgba([255, 255, 255, 255]));
+            let bx = (size - backing.width()) / 2;
+            let by = (size - backing.height()) / 2;
+            overlay(&mut canvas, &backing, bx as i64, by as i64);
+            overlay(&mut canvas, &logo, (bx + pad) as i64, (by + pad) as i64);
+        }
+
+        Ok(canvas)
+    }
+
+    pub fn render_png_base64(&self, data: &str, options: &QrOptions) -> Result<String, String> {
+        self.render_png(data, options).map(|png| STANDARD.encode(png))
+    }
+
+    /// One module per user unit, so the SVG scales cleanly to any size
+    pub fn render_svg(&self, data: &str, options: &QrOptions) -> Result<String, String> {
+        let (modules, width) = self.encode(data, options)?;
+        let q = options.quiet_zone as usize;
+        let size = width + q * 2;
+
+        // Merge horizontal runs of dark modules into single path segments
+        let mut path = String::new();
+        for y in 0..width {
+            let mut x = 0;
+            while x < width {
+                if modules[y * width + x] != Color::Dark {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < width && modules[y * width + x] == Color::Dark {
+                    x += 1;
+                }
+                path.push_str(&format!("M{},{}h{}v1h-{}z", start + q, y + q, x - start, x - start));
+            }
+        }
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {0} {0}" shape-rendering="crispEdges"><rect width="{0}" height="{0}" fill="white"/><path fill="black" d="{1}"/>"#,
+            size, path
+        );
+
+        if let Some(logo) = self.logo.as_ref().filter(|_| options.with_logo) {
+            let side = width as f32 * LOGO_SCALE;
+            let offset = (size as f32 - side) / 2.0;
+            let mut png = Vec::new();
+            logo.resize(256, 256, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(|e| format!("Failed to encode QR logo: {}", e))?;
+            svg.push_str(&format!(
+                r#"<rect x="{0:.2}" y="{0:.2}" width="{1:.2}" height="{1:.2}" fill="white"/><image x="{2:.2}" y="{2:.2}" width="{3:.2}" height="{3:.2}" href="data:image/png;base64,{4}"/>"#,
+                offset - 1.0,
+                side + 2.0,
+                offset,
+                side,
+                STANDARD.encode(png)
+            ));
+        }
+
+        svg.push_str("</svg>");
+        Ok(svg)
+    }
+
+    fn encode(&self, data: &str, options: &QrOptions) -> Result<(Vec<Color>, usize), String> {
+        let ec_level = if options.with_logo && self.logo.is_some() { EcLevel::H } else { options.ec_level };
+        let code = QrCode::with_error_correction_level(data, ec_level)
+            .map_err(|e| format!("Failed to encode QR code: {}", e))?;
+        Ok((code.to_colors(), code.width()))
+    }
+}
+
+impl Default for QrService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_ec_level(s: &str) -> Option<EcLevel> {
+    match s.to_ascii_uppercase().as_str() {
+        "L" => Some(EcLevel::L),
+        "M" => Some(EcLevel::M),
+        "Q" => Some(EcLevel::Q),
+        "H" => Some(EcLevel::H),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "weixin://wxpay/bizpayurl?pr=8cJ3kQ2zz";
+
+    fn service() -> QrService {
+        QrService { defaults: QrOptions::default(), logo: None }
+    }
+
+    /// Reads the modules back out of a rendered PNG by sampling each module's center
+    fn read_modules(png: &[u8], options: &QrOptions) -> Vec<Color> {
+        let image = image::load_from_memory(png).unwrap().to_luma8();
+        let px = options.module_px;
+        let width = image.width() / px - options.quiet_zone * 2;
+        let mut modules = Vec::new();
+        for y in 0..width {
+            for x in 0..width {
+                let cx = (x + options.quiet_zone) * px + px / 2;
+                let cy = (y + options.quiet_zone) * px + px / 2;
+                modules.push(if image.get_pixel(cx, cy)[0] < 128 { Color::Dark } else { Color::Light });
+            }
+        }
+        modules
+    }
+
+    fn symbol(data: &str, ec_level: EcLevel) -> Vec<Color> {
+        QrCode::with_error_correction_level(data, ec_level).unwrap().to_colors()
+    }
+
+    #[test]
+    fn png_carries_the_encoded_url() {
+        let options = QrOptions { module_px: 4, ..QrOptions::default() };
+        let png = service().render_png(URL, &options).unwrap();
+
+        let modules = read_modules(&png, &options);
+        assert_eq!(modules, symbol(URL, options.ec_level));
+        assert_ne!(modules, symbol("weixin://wxpay/bizpayurl?pr=8cJ3kQ2zy", options.ec_level));
+    }
+
+    #[test]
+    fn png_has_a_light_quiet_zone() {
+        let options = QrOptions::default();
+        let png = service().render_png(URL, &options).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+
+        let border = options.quiet_zone * options.module_px;
+        assert!(image.enumerate_pixels().filter(|(x, y, _)| *x < border || *y < border).all(|(_, _, p)| p[0] == 255));
+    }
+
+    #[test]
+    fn svg_draws_every_dark_module() {
+        let options = QrOptions::default();
+        let svg = service().render_svg(URL, &options).unwrap();
+        let modules = symbol(URL, options.ec_level);
+        let width = (modules.len() as f64).sqrt() as usize;
+
+        let path = svg.split(" d=\"").nth(1).unwrap().split('"').next().unwrap();
+        let mut drawn = vec![Color::Light; modules.len()];
+        for segment in path.split('z').filter(|s| !s.is_empty()) {
+            let (start, run) = segment[1..].split_once('h').unwrap();
+            let (x, y) = start.split_once(',').unwrap();
+            let (x, y): (usize, usize) = (x.parse().unwrap(), y.parse().unwrap());
+            let run: usize = run.split('v').next().unwrap().parse().unwrap();
+            let q = options.quiet_zone as usize;
+            for dx in 0..run {
+                drawn[(y - q) * width + x - q + dx] = Color::Dark;
+            }
+        }
+        assert_eq!(drawn, modules);
+    }
+}
@@ -0,0 +1,89 @@
+use chrono::{Duration as ChronoDuration, Timelike, Utc};
+use std::env;
+use std::time::Duration;
+
+use super::payment_provider::{beijing, configured_providers};
+use super::{PaymentProvider, ReconciliationService, Storage};
+
+// Bills show up in the morning; retry missing ones at this interval
+const CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+pub const DOWNLOAD_SOURCE: &str = "download";
+
+/// Reconciles yesterday's bill of every configured provider once a day, from
+/// `RECONCILE_HOUR` (Beijing time) on. `RECONCILE_AUTO_CORRECT=true` lets it
+/// fix order statuses the bill proves wrong.
+pub struct ReconciliationJob {
+    storage: Storage,
+    providers: Vec<Box<dyn PaymentProvider>>,
+    hour: u32,
+    auto_correct: bool,
+}
+
+impl ReconciliationJob {
+    pub fn new(storage: Storage) -> Result<Self, String> {
+        let hour = env::var("RECONCILE_HOUR")
+            .map_err(|_| "RECONCILE_HOUR not set".to_string())?
+            .parse::<u32>()
+            .ok()
+            .filter(|h| *h < 24)
+            .ok_or("RECONCILE_HOUR must be an hour between 0 and 23")?;
+        let providers = configured_providers();
+        if providers.is_empty() {
+            return Err("No payment provider configured".to_string());
+        }
+
+        Ok(Self {
+            storage,
+            providers,
+            hour,
+            auto_correct: env::var("RECONCILE_AUTO_CORRECT").unwrap_or_default() == "true",
+        })
+    }
+
+    pub async fn run(self) {
+        tracing::info!("[Reconcile] Daily reconciliation from {:02}:00, auto-correct {}", self.hour, self.auto_correct);
+        loop {
+            self.reconcile_due().await;
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn reconcile_due(&self) {
+        let now = Utc::now().with_timezone(&beijing());
+        if now.hour() < self.hour {
+            return;
+        }
+        let bill_date = now.date_naive() - ChronoDuration::days(1);
+
+        for provider in &self.providers {
+            let done = self
+                .storage
+                .get_connection()
+                .and_then(|conn| ReconciliationService::new(&conn).has_report(provider.kind(), bill_date, DOWNLOAD_SOURCE));
+            match done {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => {
+                    tracing::warn!("[Reconcile] {}", e);
+                    continue;
+                }
+            }
+
+            let outcome = match provider.download_bill(bill_date).await {
+                Ok(entries) => self.storage.get_connection().and_then(|conn| {
+                    ReconciliationService::new(&conn).reconcile(
+                        provider.kind(),
+                        bill_date,
+                        DOWNLOAD_SOURCE,
+                        &entries,
+                        self.auto_correct,
+                    )
+                }),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = outcome {
+                tracing::warn!("[Reconcile] {} bill for {} not reconciled yet: {}", provider.kind(), bill_date, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::models::{DiscrepancyKind, Order, OrderStatus, PaymentProviderKind, ReconciliationItem, ReconciliationReport};
+use chrono::{NaiveDate, Utc};
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::BTreeMap;
+
+use super::payment_provider::{beijing_day_range, BillEntry, BillEntryKind};
+use super::wechat_service::out_trade_no;
+use super::{RefundService, SessionService};
+
+// Order event source for corrections
+const SOURCE: &str = "reconciliation";
+
+/// One trade of a bill: its payment line and the refunds booked that day
+#[derive(Default)]
+struct BillTrade<'e> {
+    payment: Option<&'e BillEntry>,
+    refunded: i32,
+}
+
+pub struct ReconciliationService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> ReconciliationService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Match one day's bill against the orders table and store the report.
+    /// With `auto_correct`, orders the bill proves paid or fully refunded are
+    /// moved there; everything else is only reported.
+    pub fn reconcile(
+        &self,
+        provider: PaymentProviderKind,
+        bill_date: NaiveDate,
+        source: &str,
+        entries: &[BillEntry],
+        auto_correct: bool,
+    ) -> Result<ReconciliationReport, String> {
+        let session_service = SessionService::new(self.conn);
+        let refund_service = RefundService::new(self.conn);
+
+        let mut trades: BTreeMap<&str, BillTrade> = BTreeMap::new();
+        for entry in entries {
+            let trade = trades.entry(entry.out_trade_no.as_str()).or_default();
+            match entry.kind {
+                BillEntryKind::Payment => trade.payment = Some(entry),
+                BillEntryKind::Refund => trade.refunded += entry.amount,
+            }
+        }
+
+        let mut items = Vec::new();
+        let mut matched = 0;
+        for (&trade_no, trade) in &trades {
+            let found = items.len();
+            let order = session_service
+                .get_order_by_out_trade_no(trade_no)?
+                .filter(|order| order.provider == provider);
+            let Some(order) = order else {
+                items.push(item(
+                    DiscrepancyKind::Missing,
+                    trade_no,
+                    None,
+                    Some(trade.payment.map(|p| p.amount).unwrap_or(trade.refunded)),
+                    "No order for this transaction".to_string(),
+                ));
+                continue;
+            };
+
+            if let Some(payment) = trade.payment {
+                let known_id = order.wechat_order_id.as_deref().filter(|id| !id.is_empty());
+                if payment.amount != order.amount {
+                    items.push(item(
+                        DiscrepancyKind::Mismatched,
+                        trade_no,
+                        Some(&order),
+                        Some(payment.amount),
+                        format!("Paid {} but the order is for {}", payment.amount, order.amount),
+                    ));
+                } else if known_id.is_some_and(|id| id != payment.transaction_id) {
+                    items.push(item(
+                        DiscrepancyKind::Mismatched,
+                        trade_no,
+                        Some(&order),
+                        Some(payment.amount),
+                        format!("Bill transaction {} differs from the order's {}", payment.transaction_id, known_id.unwrap_or_default()),
+                    ));
+                } else if order.status == OrderStatus::Cancelled {
+                    // A cancelled order cannot become paid again; it needs a refund instead
+                    let recorded = refund_service.refunded_amount(&order.id)?;
+                    let detail = if recorded >= payment.amount {
+                        format!("Paid at the provider after cancellation, refund of {} recorded", recorded)
+                    } else {
+                        "Paid at the provider after cancellation, refund required".to_string()
+                    };
+                    items.push(item(DiscrepancyKind::RefundRequired, trade_no, Some(&order), Some(payment.amount), detail));
+                } else if order.status == OrderStatus::Pending {
+                    let mut missing = item(
+                        DiscrepancyKind::Missing,
+                        trade_no,
+                        Some(&order),
+                        Some(payment.amount),
+                        "Paid at the provider but the order is pending".to_string(),
+                    );
+                    if auto_correct {
+                        missing.corrected = session_service.reconcile_order(
+                            &order.id,
+                            OrderStatus::Paid,
+                            SOURCE,
+                            Some(&payment.transaction_id),
+                            payment.time,
+                        )?;
+                    }
+                    items.push(missing);
+                }
+            }
+
+            if trade.refunded > 0 {
+                let recorded = refund_service.refunded_amount(&order.id)?;
+                if order.status == OrderStatus::Paid && trade.refunded >= order.amount {
+                    let mut mismatch = item(
+                        DiscrepancyKind::Mismatched,
+                        trade_no,
+                        Some(&order),
+                        Some(trade.refunded),
+                        "Refunded in full at the provider but the order is paid".to_string(),
+                    );
+                    if auto_correct {
+                        mismatch.corrected =
+                            session_service.reconcile_order(&order.id, OrderStatus::Refunded, SOURCE, None, None)?;
+                    }
+                    items.push(mismatch);
+                } else if recorded < trade.refunded {
+                    items.push(item(
+                        DiscrepancyKind::Mismatched,
+                        trade_no,
+                        Some(&order),
+                        Some(trade.refunded),
+                        format!("Refunded {} at the provider but only {} is recorded", trade.refunded, recorded),
+                    ));
+                }
+            }
+
+            if items.len() == found {
+                matched += 1;
+            }
+        }
+
+        // The other direction: money the orders table has for that day but the bill does not
+        let (start, end) = beijing_day_range(bill_date);
+        for order in session_service.get_orders_paid_between(provider, start, end)? {
+            let trade_no = out_trade_no(&order.id);
+            if trades.get(trade_no.as_str()).is_some_and(|t| t.payment.is_some()) {
+                continue;
+            }
+            items.push(item(
+                DiscrepancyKind::Extra,
+                &trade_no,
+                Some(&order),
+                None,
+                "Paid in the orders table but not in the bill".to_string(),
+            ));
+        }
+        for (order_id, amount) in refund_service.refunded_between(provider, start, end)? {
+            let trade_no = out_trade_no(&order_id);
+            if trades.get(trade_no.as_str()).is_some_and(|t| t.refunded > 0) {
+                continue;
+            }
+            let order = session_service.get_order(&order_id)?;
+            items.push(item(
+                DiscrepancyKind::Extra,
+                &trade_no,
+                order.as_ref(),
+                None,
+                format!("Refund of {} recorded but not in the bill", amount),
+            ));
+        }
+
+        let report = ReconciliationReport {
+            id: 0,
+            provider,
+            bill_date: bill_date.format("%Y-%m-%d").to_string(),
+            source: source.to_string(),
+            transactions: entries.len() as i64,
+            matched,
+            items,
+            created_at: Utc::now().timestamp(),
+        };
+        let report = self.save_report(report)?;
+
+        let corrected = report.items.iter().filter(|i| i.corrected).count();
+        tracing::info!(
+            "[Reconcile] {} {}: {} transactions, {} matched, {} discrepancies, {} corrected",
+            provider,
+            report.bill_date,
+            report.transactions,
+            report.matched,
+            report.items.len(),
+            corrected
+        );
+        Ok(report)
+    }
+
+    fn save_report(&self, mut report: ReconciliationReport) -> Result<ReconciliationReport, String> {
+        let tx = self.conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        tx.execute(
+            "INSERT INTO reconciliation_reports (provider, bill_date, source, transactions, matched, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                report.provider.to_string(),
+                report.bill_date,
+                report.source,
+                report.transactions,
+                report.matched,
+                report.created_at
+            ],
+        ).map_err(|e| e.to_string())?;
+        report.id = tx.last_insert_rowid();
+
+        for item in &report.items {
+            tx.execute(
+                "INSERT INTO reconciliation_items
+                 (report_id, kind, out_trade_no, order_id, bill_amount, order_amount, order_status, detail, corrected)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                rusqlite::params![
+                    report.id,
+                    item.kind.to_string(),
+                    item.out_trade_no,
+                    item.order_id,
+                    item.bill_amount,
+                    item.order_amount,
+                    item.order_status.as_ref().map(|s| s.to_string()),
+                    item.detail,
+                    item.corrected
+                ],
+            ).map_err(|e| e.to_string())?;
+        }
+
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(report)
+    }
+
+    /// Whether a day's bill has already been reconciled from the given source
+    pub fn has_report(&self, provider: PaymentProviderKind, bill_date: NaiveDate, source: &str) -> Result<bool, String> {
+        self.conn.query_row(
+            "SELECT 1 FROM reconciliation_reports WHERE provider = ?1 AND bill_date = ?2 AND source = ?3 LIMIT 1",
+            rusqlite::params![provider.to_string(), bill_date.format("%Y-%m-%d").to_string(), source],
+            |_| Ok(()),
+        ).optional().map(|found| found.is_some()).map_err(|e| e.to_string())
+    }
+
+    /// Most recent reports first
+    pub fn get_reports(&self, limit: u32) -> Result<Vec<ReconciliationReport>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, provider, bill_date, source, transactions, matched, created_at
+             FROM reconciliation_reports ORDER BY id DESC LIMIT ?1"
+        ).map_err(|e| e.to_string())?;
+
+        let reports: Vec<ReconciliationReport> = stmt.query_map([limit], |row| {
+            let provider: String = row.get(1)?;
+            Ok(ReconciliationReport {
+                id: row.get(0)?,
+                provider: provider.parse().unwrap_or(PaymentProviderKind::Wechat),
+                bill_date: row.get(2)?,
+                source: row.get(3)?,
+                transactions: row.get(4)?,
+                matched: row.get(5)?,
+                items: Vec::new(),
+                created_at: row.get(6)?,
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+
+        reports
+            .into_iter()
+            .map(|mut report| {
+                report.items = self.get_items(report.id)?;
+                Ok(report)
+            })
+            .collect()
+    }
+
+    fn get_items(&self, report_id: i64) -> Result<Vec<ReconciliationItem>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT kind, out_trade_no, order_id, bill_amount, order_amount, order_status, detail, corrected
+             FROM reconciliation_items WHERE report_id = ?1 ORDER BY id"
+        ).map_err(|e| e.to_string())?;
+
+        let items = stmt.query_map([report_id], |row| {
+            let kind: String = row.get(0)?;
+            let order_status: Option<String> = row.get(5)?;
+            Ok(ReconciliationItem {
+                kind: kind.parse().unwrap_or(DiscrepancyKind::Mismatched),
+                out_trade_no: row.get(1)?,
+                order_id: row.get(2)?,
+                bill_amount: row.get(3)?,
+                order_amount: row.get(4)?,
+                order_status: order_status.and_then(|s| s.parse().ok()),
+                detail: row.get(6)?,
+                corrected: row.get(7)?,
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|i| i.ok()).collect();
+
+        Ok(items)
+    }
+}
+
+fn item(
+    kind: DiscrepancyKind,
+    out_trade_no: &str,
+    order: Option<&Order>,
+    bill_amount: Option<i32>,
+    detail: String,
+) -> ReconciliationItem {
+    ReconciliationItem {
+        kind,
+        out_trade_no: out_trade_no.to_string(),
+        order_id: order.map(|o| o.id.clone()),
+        bill_amount,
+        order_amount: order.map(|o| o.amount),
+        order_status: order.map(|o| o.status.clone()),
+        detail,
+        corrected: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::mock_provider::create_order;
+    use crate::services::Storage;
+
+    fn bill_payment(order: &Order) -> BillEntry {
+        BillEntry {
+            kind: BillEntryKind::Payment,
+            out_trade_no: out_trade_no(&order.id),
+            transaction_id: "4200002301202610181234567890".to_string(),
+            amount: order.amount,
+            time: Some(Utc::now().timestamp()),
+        }
+    }
+
+    #[test]
+    fn paid_cancelled_order_requires_refund() {
+        let storage = Storage::temp();
+        let pending = create_order(&storage);
+        let cancelled = create_order(&storage);
+        let conn = storage.get_connection().unwrap();
+        let session_service = SessionService::new(&conn);
+        session_service.transition_order(&cancelled.id, OrderStatus::Cancelled, "timeout", None, None, None).unwrap();
+
+        let entries = [bill_payment(&pending), bill_payment(&cancelled)];
+        let date = Utc::now().date_naive();
+        let report = ReconciliationService::new(&conn)
+            .reconcile(PaymentProviderKind::Wechat, date, "test", &entries, true)
+            .unwrap();
+
+        let missing = report.items.iter().find(|i| i.order_id.as_deref() == Some(pending.id.as_str())).unwrap();
+        assert_eq!(missing.kind, DiscrepancyKind::Missing);
+        assert!(missing.corrected);
+
+        // Reported only: no status change is attempted
+        let refund = report.items.iter().find(|i| i.order_id.as_deref() == Some(cancelled.id.as_str())).unwrap();
+        assert_eq!(refund.kind, DiscrepancyKind::RefundRequired);
+        assert!(!refund.corrected);
+        let status = |id: &str| session_service.get_order(id).unwrap().unwrap().status;
+        assert_eq!(status(&pending.id), OrderStatus::Paid);
+        assert_eq!(status(&cancelled.id), OrderStatus::Cancelled);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::models::{OrderStatus, PaymentProviderKind, Refund, RefundStatus};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use uuid::Uuid;
+
+use super::payment_provider::RefundError;
+use super::storage::Tx;
+use super::{PaymentProvider, SessionService, Storage};
+
+pub struct RefundService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> RefundService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Record a refund about to be sent to the provider. `amount` defaults to
+    /// whatever has not been refunded yet.
+    pub fn create_refund(&self, order_id: &str, amount: Option<i32>, reason: Option<&str>) -> Result<Refund, String> {
+        // The checks and the insert commit together, so concurrent refunds cannot exceed the order
+        let tx = Tx::begin(self.conn)?;
+        let order = SessionService::new(self.conn)
+            .get_order(order_id)?
+            .ok_or_else(|| "Order not found".to_string())?;
+        if order.status != OrderStatus::Paid {
+            return Err(format!("Only paid orders can be refunded, order is {}", order.status));
+        }
+        if order.provider == PaymentProviderKind::Voucher {
+            return Err("Voucher orders took no payment to refund".to_string());
+        }
+
+        let remaining = order.amount - self.refunded_amount(order_id)?;
+        let amount = amount.unwrap_or(remaining);
+        if amount <= 0 || amount > remaining {
+            return Err(format!("Refund amount must be between 1 and {}", remaining));
+        }
+        let refund = self.insert_refund(order_id, amount, reason)?;
+        tx.commit()?;
+        Ok(refund)
+    }
+
+    /// Record a processing refund without checking the order
+    pub fn insert_refund(&self, order_id: &str, amount: i32, reason: Option<&str>) -> Result<Refund, String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now().timestamp();
+        self.conn.execute(
+            "INSERT INTO refunds (id, order_id, amount, reason, status, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            rusqlite::params![id, order_id, amount, reason, RefundStatus::Processing.to_string(), now],
+        ).map_err(|e| e.to_string())?;
+
+        Ok(Refund {
+            id,
+            order_id: order_id.to_string(),
+            amount,
+            reason: reason.map(|r| r.to_string()),
+            status: RefundStatus::Processing,
+            provider_refund_id: None,
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    /// Amount refunded or still being refunded
+    pub fn refunded_amount(&self, order_id: &str) -> Result<i32, String> {
+        self.conn.query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE order_id = ?1 AND status IN (?2, ?3)",
+            rusqlite::params![order_id, RefundStatus::Processing.to_string(), RefundStatus::Success.to_string()],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())
+    }
+
+    /// Apply a provider result (API response or callback) to a refund. Only processing
+    /// refunds change; once everything has been refunded the order becomes refunded.
+    /// Returns whether the order changed status.
+    pub fn apply_refund_status(&self, refund_id: &str, status: RefundStatus, provider_refund_id: Option<&str>) -> Result<bool, String> {
+        let refund = self.get_refund(refund_id)?.ok_or_else(|| "Refund not found".to_string())?;
+        if refund.status != RefundStatus::Processing || status == RefundStatus::Processing {
+            if provider_refund_id.is_some() && refund.provider_refund_id.is_none() {
+                self.conn.execute(
+                    "UPDATE refunds SET provider_refund_id = ?1 WHERE id = ?2",
+                    rusqlite::params![provider_refund_id, refund_id],
+                ).map_err(|e| e.to_string())?;
+            }
+            return Ok(false);
+        }
+
+        self.conn.execute(
+            "UPDATE refunds SET status = ?1, provider_refund_id = COALESCE(?2, provider_refund_id), updated_at = ?3
+             WHERE id = ?4",
+            rusqlite::params![status.to_string(), provider_refund_id, Utc::now().timestamp(), refund_id],
+        ).map_err(|e| e.to_string())?;
+        tracing::info!("[Refund] {} for order {} -> {}", refund_id, refund.order_id, status);
+
+        if status != RefundStatus::Success {
+            return Ok(false);
+        }
+
+        let session_service = SessionService::new(self.conn);
+        let order = session_service
+            .get_order(&refund.order_id)?
+            .ok_or_else(|| "Order not found".to_string())?;
+        // A late payment's order stays cancelled
+        if order.status != OrderStatus::Paid {
+            return Ok(false);
+        }
+        let refunded: i32 = self.conn.query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE order_id = ?1 AND status = ?2",
+            rusqlite::params![refund.order_id, RefundStatus::Success.to_string()],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())?;
+        if refunded < order.amount {
+            return Ok(false);
+        }
+
+        let detail = format!("Refunded {} in total", refunded);
+        session_service.transition_order(&refund.order_id, OrderStatus::Refunded, "refund", None, None, Some(&detail))
+    }
+
+    pub fn get_refund(&self, refund_id: &str) -> Result<Option<Refund>, String> {
+        self.conn.query_row(
+            "SELECT id, order_id, amount, reason, status, provider_refund_id, created_at, updated_at
+             FROM refunds WHERE id = ?1",
+            [refund_id],
+            refund_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    /// Find the refund a provider `out_refund_no` (our id without dashes) refers to
+    pub fn get_refund_by_out_refund_no(&self, out_refund_no: &str) -> Result<Option<Refund>, String> {
+        self.conn.query_row(
+            "SELECT id, order_id, amount, reason, status, provider_refund_id, created_at, updated_at
+             FROM refunds WHERE REPLACE(id, '-', '') = ?1",
+            [out_refund_no],
+            refund_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    /// Successful refunds of one provider's orders settled within `[start, end)`,
+    /// summed per order
+    pub fn refunded_between(&self, provider: PaymentProviderKind, start: i64, end: i64) -> Result<Vec<(String, i32)>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT r.order_id, SUM(r.amount) FROM refunds r JOIN orders o ON o.id = r.order_id
+             WHERE o.provider = ?1 AND r.status = ?2 AND r.updated_at >= ?3 AND r.updated_at < ?4
+             GROUP BY r.order_id"
+        ).map_err(|e| e.to_string())?;
+
+        let params = rusqlite::params![provider.to_string(), RefundStatus::Success.to_string(), start, end];
+        let totals = stmt.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(totals)
+    }
+
+    pub fn get_refunds_by_status(&self, status: RefundStatus) -> Result<Vec<Refund>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, amount, reason, status, provider_refund_id, created_at, updated_at
+             FROM refunds WHERE status = ?1 ORDER BY created_at"
+        ).map_err(|e| e.to_string())?;
+
+        let refunds = stmt.query_map([status.to_string()], refund_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(refunds)
+    }
+
+    pub fn get_refunds(&self, order_id: &str) -> Result<Vec<Refund>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, amount, reason, status, provider_refund_id, created_at, updated_at
+             FROM refunds WHERE order_id = ?1 ORDER BY created_at"
+        ).map_err(|e| e.to_string())?;
+
+        let refunds = stmt.query_map([order_id], refund_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(refunds)
+    }
+}
+
+/// Send a recorded refund to the provider and apply the answer. Only a refusal
+/// fails the refund; without a definite answer it stays processing and is sent
+/// again later under the same id, so the customer is never refunded twice.
+pub async fn submit_refund(storage: &Storage, provider: &dyn PaymentProvider, refund_id: &str) -> Result<Refund, String> {
+    let (refund, order) = {
+        let conn = storage.get_connection()?;
+        let refund = RefundService::new(&conn)
+            .get_refund(refund_id)?
+            .ok_or_else(|| "Refund not found".to_string())?;
+        let order = SessionService::new(&conn)
+            .get_order(&refund.order_id)?
+            .ok_or_else(|| "Order not found".to_string())?;
+        (refund, order)
+    };
+    let reason = refund.reason.as_deref().unwrap_or("Refund");
+
+    let result = provider.refund(&order.id, &refund.id, refund.amount, order.amount, reason).await;
+
+    let conn = storage.get_connection()?;
+    let refund_service = RefundService::new(&conn);
+    match result {
+        Ok(reply) => {
+            refund_service.apply_refund_status(&refund.id, reply.status, reply.refund_id.as_deref())?;
+        }
+        Err(RefundError::Rejected(e)) => {
+            refund_service.apply_refund_status(&refund.id, RefundStatus::Failed, None)?;
+            return Err(format!("Refund rejected: {}", e));
+        }
+        Err(RefundError::Unknown(e)) => {
+            tracing::warn!("[Refund] {} for order {} unconfirmed, will retry: {}", refund.id, order.id, e);
+        }
+    }
+
+    refund_service.get_refund(&refund.id)?
+        .ok_or_else(|| "Refund not found".to_string())
+}
+
+fn refund_from_row(row: &rusqlite::Row) -> rusqlite::Result<Refund> {
+    let status: String = row.get(4)?;
+    Ok(Refund {
+        id: row.get(0)?,
+        order_id: row.get(1)?,
+        amount: row.get(2)?,
+        reason: row.get(3)?,
+        status: status.parse().unwrap_or(RefundStatus::Processing),
+        provider_refund_id: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::mock_provider::{create_order, MockProvider};
+    use crate::services::payment_provider::ProviderRefund;
+
+    fn paid_order(storage: &Storage) -> String {
+        let order = create_order(storage);
+        let conn = storage.get_connection().unwrap();
+        SessionService::new(&conn).update_order_status(&order.id, OrderStatus::Paid, Some("tx1".to_string())).unwrap();
+        order.id
+    }
+
+    fn order_status(storage: &Storage, order_id: &str) -> OrderStatus {
+        let conn = storage.get_connection().unwrap();
+        SessionService::new(&conn).get_order(order_id).unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn full_refund_settles_order() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        let order_id = paid_order(&storage);
+        let refund = RefundService::new(&storage.get_connection().unwrap()).create_refund(&order_id, None, None).unwrap();
+
+        let refund = submit_refund(&storage, &provider, &refund.id).await.unwrap();
+        assert_eq!(refund.status, RefundStatus::Success);
+        assert_eq!(refund.provider_refund_id, Some(format!("mock_{}", refund.id)));
+        assert_eq!(order_status(&storage, &order_id), OrderStatus::Refunded);
+    }
+
+    #[tokio::test]
+    async fn rejected_refund_fails_and_frees_amount() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        provider.state().refund_replies.push_back(Err(RefundError::Rejected("NOT_ENOUGH".to_string())));
+        let order_id = paid_order(&storage);
+        let refund = RefundService::new(&storage.get_connection().unwrap()).create_refund(&order_id, Some(100), None).unwrap();
+
+        let err = submit_refund(&storage, &provider, &refund.id).await.unwrap_err();
+        assert!(err.contains("NOT_ENOUGH"), "{}", err);
+        let conn = storage.get_connection().unwrap();
+        let refund_service = RefundService::new(&conn);
+        assert_eq!(refund_service.get_refund(&refund.id).unwrap().unwrap().status, RefundStatus::Failed);
+        assert_eq!(refund_service.refunded_amount(&order_id).unwrap(), 0);
+        assert_eq!(order_status(&storage, &order_id), OrderStatus::Paid);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_refund_is_resent_under_the_same_id() {
+        let storage = Storage::temp();
+        let provider = MockProvider::new();
+        provider.state().refund_replies.push_back(Err(RefundError::Unknown("operation timed out".to_string())));
+        let order_id = paid_order(&storage);
+        let refund = RefundService::new(&storage.get_connection().unwrap()).create_refund(&order_id, None, None).unwrap();
+
+        // The provider may have refunded: keep the amount reserved
+        let pending = submit_refund(&storage, &provider, &refund.id).await.unwrap();
+        assert_eq!(pending.status, RefundStatus::Processing);
+        let conn = storage.get_connection().unwrap();
+        assert!(RefundService::new(&conn).create_refund(&order_id, None, None).is_err());
+
+        // The repeat learns the refund went through the first time
+        provider.state().refund_replies.push_back(Ok(ProviderRefund {
+            refund_id: Some("50300000001".to_string()),
+            status: RefundStatus::Success,
+        }));
+        let settled = submit_refund(&storage, &provider, &refund.id).await.unwrap();
+        assert_eq!(settled.status, RefundStatus::Success);
+        assert_eq!(provider.state().refunds, vec![refund.id.clone(), refund.id.clone()]);
+        assert_eq!(RefundService::new(&conn).get_refunds(&order_id).unwrap().len(), 1);
+        assert_eq!(order_status(&storage, &order_id), OrderStatus::Refunded);
+    }
+
+    #[test]
+    fn refund_over_the_remaining_amount_is_refused() {
+        let storage = Storage::temp();
+        let order_id = paid_order(&storage);
+        let conn = storage.get_connection().unwrap();
+        let refund_service = RefundService::new(&conn);
+        let amount = SessionService::new(&conn).get_order(&order_id).unwrap().unwrap().amount;
+
+        refund_service.create_refund(&order_id, Some(100), None).unwrap();
+        let err = refund_service.create_refund(&order_id, Some(amount), None).unwrap_err();
+        assert!(err.contains(&format!("between 1 and {}", amount - 100)), "{}", err);
+        assert_eq!(refund_service.refunded_amount(&order_id).unwrap(), 100);
+
+        refund_service.create_refund(&order_id, None, None).unwrap();
+        assert!(refund_service.create_refund(&order_id, Some(1), None).is_err());
+        assert_eq!(refund_service.refunded_amount(&order_id).unwrap(), amount);
+    }
+}
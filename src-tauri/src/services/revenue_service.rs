@@ -0,0 +1,56 @@
+use crate::models::{OrderStatus, PaymentProviderKind, RefundStatus, RevenueReport};
+use rusqlite::Connection;
+
+pub struct RevenueService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> RevenueService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    pub fn daily_report(&self, days: u32) -> Result<Vec<RevenueReport>, String> {
+        self.report("%Y-%m-%d", days)
+    }
+
+    pub fn monthly_report(&self, months: u32) -> Result<Vec<RevenueReport>, String> {
+        self.report("%Y-%m", months)
+    }
+
+    /// One row per period and payment method, so voucher orders never mix
+    /// with money actually collected
+    fn report(&self, period_format: &str, limit: u32) -> Result<Vec<RevenueReport>, String> {
+        let mut stmt = self.conn.prepare(
+            "WITH settled AS (
+                 SELECT strftime(?1, payment_time, 'unixepoch', 'localtime') AS period, id, provider, amount
+                 FROM orders WHERE status IN (?2, ?3) AND payment_time IS NOT NULL
+             ),
+             periods AS (SELECT DISTINCT period FROM settled ORDER BY period DESC LIMIT ?4)
+             SELECT s.period, s.provider, COUNT(*), SUM(s.amount),
+                    COALESCE(SUM((SELECT SUM(r.amount) FROM refunds r WHERE r.order_id = s.id AND r.status = ?5)), 0)
+             FROM settled s WHERE s.period IN (SELECT period FROM periods)
+             GROUP BY s.period, s.provider ORDER BY s.period DESC, s.provider"
+        ).map_err(|e| e.to_string())?;
+
+        let params = rusqlite::params![
+            period_format,
+            OrderStatus::Paid.to_string(),
+            OrderStatus::Refunded.to_string(),
+            limit,
+            RefundStatus::Success.to_string()
+        ];
+        let reports = stmt.query_map(params, |row| {
+            let provider: String = row.get(1).unwrap_or_default();
+            Ok(RevenueReport {
+                period: row.get(0).unwrap_or_default(),
+                provider: provider.parse().unwrap_or(PaymentProviderKind::Wechat),
+                orders: row.get(2).unwrap_or(0),
+                amount: row.get(3).unwrap_or(0),
+                refunded: row.get(4).unwrap_or(0),
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+
+        Ok(reports)
+    }
+}
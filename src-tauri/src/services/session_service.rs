@@ -1,8 +1,25 @@
-use crate::models::{Order, OrderStatus, OrderType, PhotoSession, SessionStatus, Step};
+use crate::models::{LatePayment, LineItemRequest, Order, OrderEvent, OrderItem, OrderStatus, OrderType, PaymentProviderKind, PhotoSession, SessionStatus, Step};
 use chrono::Utc;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use uuid::Uuid;
 
+use super::storage::Tx;
+use super::voucher_service::{generate_code, normalize_code};
+use super::{CatalogService, FulfillmentService, InventoryService, PrintService, RefundService};
+
+// 32^6 codes; collisions are rare and retried
+const ORDER_CODE_LENGTH: usize = 6;
+const ORDER_CODE_ATTEMPTS: u32 = 10;
+
+/// What an order is for: a single download or print, or catalog line items.
+/// Both are priced from the catalog; `amount` is the total the customer was
+/// shown, and the order is refused if it differs.
+#[derive(Debug, Clone)]
+pub enum OrderContents {
+    Single { order_type: OrderType, amount: Option<i32> },
+    Items { items: Vec<LineItemRequest>, amount: Option<i32> },
+}
+
 pub struct SessionService<'a> {
     conn: &'a Connection,
 }
@@ -38,6 +55,11 @@ impl<'a> SessionService<'a> {
             style_id: None,
             original_photo: None,
             generated_photo: None,
+            fallback: false,
+            photo_width: None,
+            photo_height: None,
+            photo_format: None,
+            face_count: None,
             status: SessionStatus::SelectingMode,
             created_at: now,
             updated_at: now,
@@ -46,7 +68,8 @@ impl<'a> SessionService<'a> {
 
     pub fn get_session(&self, id: &str) -> Result<Option<PhotoSession>, String> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, mode_id, effect_id, style_id, original_photo, generated_photo, status, created_at, updated_at
+            "SELECT id, mode_id, effect_id, style_id, original_photo, generated_photo, status, created_at, updated_at, is_fallback,
+                    photo_width, photo_height, photo_format, face_count
              FROM photo_sessions WHERE id = ?1"
         ).map_err(|e| e.to_string())?;
 
@@ -60,6 +83,11 @@ impl<'a> SessionService<'a> {
                 style_id: row.get(3).ok(),
                 original_photo: row.get(4).ok(),
                 generated_photo: row.get(5).ok(),
+                fallback: row.get::<_, bool>(9).unwrap_or(false),
+                photo_width: row.get(10).ok(),
+                photo_height: row.get(11).ok(),
+                photo_format: row.get(12).ok(),
+                face_count: row.get(13).ok(),
                 status: status_str.parse().unwrap_or(SessionStatus::SelectingMode),
                 created_at: row.get::<_, i64>(7).unwrap_or(0),
                 updated_at: row.get::<_, i64>(8).unwrap_or(0),
@@ -87,11 +115,20 @@ impl<'a> SessionService<'a> {
         Ok(())
     }
 
-    pub fn save_generated_photo(&self, id: &str, photo_base64: &str) -> Result<(), String> {
+    pub fn save_generated_photo(&self, id: &str, photo_base64: &str, fallback: bool) -> Result<(), String> {
         let now = Utc::now().timestamp();
         self.conn.execute(
-            "UPDATE photo_sessions SET generated_photo = ?1, updated_at = ?2 WHERE id = ?3",
-            rusqlite::params![photo_base64, now, id],
+            "UPDATE photo_sessions SET generated_photo = ?1, is_fallback = ?2, updated_at = ?3 WHERE id = ?4",
+            rusqlite::params![photo_base64, fallback, now, id],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn save_photo_info(&self, id: &str, width: u32, height: u32, format: &str, face_count: Option<u32>) -> Result<(), String> {
+        let now = Utc::now().timestamp();
+        self.conn.execute(
+            "UPDATE photo_sessions SET photo_width = ?1, photo_height = ?2, photo_format = ?3, face_count = ?4, updated_at = ?5 WHERE id = ?6",
+            rusqlite::params![width, height, format, face_count, now, id],
         ).map_err(|e| e.to_string())?;
         Ok(())
     }
@@ -107,38 +144,182 @@ impl<'a> SessionService<'a> {
 
     pub fn get_orders(&self, session_id: &str) -> Result<Vec<Order>, String> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at
+            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at, provider, code
              FROM orders WHERE session_id = ?1 ORDER BY created_at DESC"
         ).map_err(|e| e.to_string())?;
 
-        let orders = stmt.query_map([session_id], |row| {
-            let order_type_str: String = row.get(2).unwrap_or_default();
-            let status_str: String = row.get(4).unwrap_or_default();
-            Ok(Order {
-                id: row.get(0).unwrap_or_default(),
-                session_id: row.get(1).unwrap_or_default(),
-                order_type: order_type_str.parse().unwrap_or(OrderType::Download),
-                amount: row.get::<_, i32>(3).unwrap_or(0),
-                status: status_str.parse().unwrap_or(OrderStatus::Pending),
-                wechat_order_id: row.get(5).ok(),
-                payment_time: row.get(6).ok(),
-                created_at: row.get::<_, i64>(7).unwrap_or(0),
-            })
-        }).map_err(|e| e.to_string())?.filter_map(|o| o.ok()).collect();
+        let orders = stmt.query_map([session_id], order_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|o| o.ok())
+            .collect();
+
+        Ok(orders)
+    }
+
+    pub fn get_orders_by_status(&self, status: OrderStatus) -> Result<Vec<Order>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at, provider, code
+             FROM orders WHERE status = ?1 ORDER BY created_at"
+        ).map_err(|e| e.to_string())?;
+
+        let orders = stmt.query_map([status.to_string()], order_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|o| o.ok())
+            .collect();
+
+        Ok(orders)
+    }
+
+    /// Orders of one provider paid within `[start, end)`, including those refunded since
+    pub fn get_orders_paid_between(&self, provider: PaymentProviderKind, start: i64, end: i64) -> Result<Vec<Order>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at, provider, code
+             FROM orders WHERE provider = ?1 AND status IN (?2, ?3) AND payment_time >= ?4 AND payment_time < ?5
+             ORDER BY payment_time"
+        ).map_err(|e| e.to_string())?;
+
+        let params = rusqlite::params![
+            provider.to_string(),
+            OrderStatus::Paid.to_string(),
+            OrderStatus::Refunded.to_string(),
+            start,
+            end
+        ];
+        let orders = stmt.query_map(params, order_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|o| o.ok())
+            .collect();
 
         Ok(orders)
     }
 
-    pub fn create_order(&self, session_id: &str, order_type: OrderType, amount: i32) -> Result<Order, String> {
+    pub fn create_order(&self, session_id: &str, order_type: OrderType, amount: i32, provider: PaymentProviderKind) -> Result<Order, String> {
+        self.create_order_from(session_id, OrderContents::Single { order_type, amount: Some(amount) }, provider)
+    }
+
+    pub fn create_order_from(&self, session_id: &str, contents: OrderContents, provider: PaymentProviderKind) -> Result<Order, String> {
+        match contents {
+            OrderContents::Single { order_type, amount } => {
+                if order_type == OrderType::Bundle {
+                    return Err("Bundle orders need line items".to_string());
+                }
+                // The catalog's single download and print products carry the kind as their id
+                let item = LineItemRequest { product_id: order_type.to_string(), quantity: 1 };
+                self.create_order_with_items(session_id, &[item], amount, provider)
+            }
+            OrderContents::Items { items, amount } => self.create_order_with_items(session_id, &items, amount, provider),
+        }
+    }
+
+    /// Create an order of catalog products at catalog prices. `amount` is the
+    /// total the customer was shown; if prices changed since, the order is refused.
+    pub fn create_order_with_items(
+        &self,
+        session_id: &str,
+        items: &[LineItemRequest],
+        amount: Option<i32>,
+        provider: PaymentProviderKind,
+    ) -> Result<Order, String> {
+        if items.is_empty() {
+            return Err("Order has no items".to_string());
+        }
+        let session = self.get_session(session_id)?.ok_or_else(|| "Session not found".to_string())?;
+        let catalog = CatalogService::new(self.conn);
+
+        let mut lines = Vec::with_capacity(items.len());
+        for item in items {
+            if item.quantity == 0 {
+                return Err("Item quantity must be at least 1".to_string());
+            }
+            let product = catalog
+                .get_product(&item.product_id, Some(&session.effect_id))?
+                .ok_or_else(|| format!("Product not available: {}", item.product_id))?;
+
+            let mut components = Vec::with_capacity(product.components.len());
+            for component in &product.components {
+                let part = catalog
+                    .get_product(&component.product_id, Some(&session.effect_id))?
+                    .ok_or_else(|| format!("Bundle component {} is not available", component.product_id))?;
+                components.push((part, component.quantity * item.quantity));
+            }
+
+            lines.push(NewLine {
+                product_id: product.id,
+                name: product.name,
+                kind: product.kind,
+                quantity: item.quantity,
+                unit_price: product.price,
+                components,
+            });
+        }
+
+        let total: i32 = lines.iter().map(|line| line.unit_price * line.quantity as i32).sum();
+        if let Some(amount) = amount.filter(|amount| *amount != total) {
+            return Err(format!(
+                "Price changed: the order costs ¥{:.2}, not ¥{:.2}",
+                total as f64 / 100.0,
+                amount as f64 / 100.0
+            ));
+        }
+
+        let order_type = match lines.as_slice() {
+            [line] if line.quantity == 1 && line.kind != OrderType::Bundle => line.kind.clone(),
+            _ => OrderType::Bundle,
+        };
+        self.insert_order(session_id, order_type, total, provider, &lines)
+    }
+
+    fn insert_order(
+        &self,
+        session_id: &str,
+        order_type: OrderType,
+        amount: i32,
+        provider: PaymentProviderKind,
+        lines: &[NewLine],
+    ) -> Result<Order, String> {
+        // Local fallbacks are previews only and must never be sold as an AI result
+        if let Some(session) = self.get_session(session_id)? {
+            if session.fallback {
+                return Err("Fallback photo cannot be purchased".to_string());
+            }
+        }
+        let prints: u32 = lines
+            .iter()
+            .map(|line| {
+                let parts: u32 = line.components
+                    .iter()
+                    .filter(|(part, _)| part.kind == OrderType::Print)
+                    .map(|(_, quantity)| quantity)
+                    .sum();
+                u32::from(line.kind == OrderType::Print) * line.quantity + parts
+            })
+            .sum();
+        if prints > 0 {
+            PrintService::new(self.conn).ensure_printer_available()?;
+            InventoryService::new(self.conn).ensure_in_stock(prints)?;
+        }
+
         let id = Uuid::new_v4().to_string();
+        let code = self.new_order_code()?;
         let now = Utc::now().timestamp();
 
+        let tx = Tx::begin(self.conn)?;
         self.conn.execute(
-            "INSERT INTO orders (id, session_id, order_type, amount, status, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            rusqlite::params![id, session_id, order_type.to_string(), amount, OrderStatus::Pending.to_string(), now],
+            "INSERT INTO orders (id, session_id, order_type, amount, status, created_at, provider, code)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![id, session_id, order_type.to_string(), amount, OrderStatus::Pending.to_string(), now, provider.to_string(), code],
         ).map_err(|e| e.to_string())?;
 
+        for line in lines {
+            let parent_id = self.insert_order_item(&id, None, &line.product_id, &line.name, &line.kind, line.quantity, line.unit_price)?;
+            for (part, quantity) in &line.components {
+                self.insert_order_item(&id, Some(parent_id), &part.id, &part.name, &part.kind, *quantity, 0)?;
+            }
+        }
+
+        self.record_order_event(&id, None, &OrderStatus::Pending, "created", None)?;
+        tx.commit()?;
+
         Ok(Order {
             id,
             session_id: session_id.to_string(),
@@ -148,45 +329,326 @@ impl<'a> SessionService<'a> {
             wechat_order_id: None,
             payment_time: None,
             created_at: now,
+            provider,
+            code: Some(code),
         })
     }
 
+    /// An order code no other order has
+    fn new_order_code(&self) -> Result<String, String> {
+        for _ in 0..ORDER_CODE_ATTEMPTS {
+            let code = generate_code(ORDER_CODE_LENGTH);
+            let taken = self.conn.query_row(
+                "SELECT 1 FROM orders WHERE code = ?1",
+                [&code],
+                |_| Ok(()),
+            ).optional().map_err(|e| e.to_string())?.is_some();
+            if !taken {
+                return Ok(code);
+            }
+        }
+        Err("Could not allocate an order code".to_string())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_order_item(
+        &self,
+        order_id: &str,
+        parent_id: Option<i64>,
+        product_id: &str,
+        name: &str,
+        kind: &OrderType,
+        quantity: u32,
+        unit_price: i32,
+    ) -> Result<i64, String> {
+        self.conn.execute(
+            "INSERT INTO order_items (order_id, parent_id, product_id, name, kind, quantity, unit_price)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![order_id, parent_id, product_id, name, kind.to_string(), quantity, unit_price],
+        ).map_err(|e| e.to_string())?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Line items of an order, bundle components right after their bundle
+    pub fn get_order_items(&self, order_id: &str) -> Result<Vec<OrderItem>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, order_id, parent_id, product_id, name, kind, quantity, unit_price
+             FROM order_items WHERE order_id = ?1 ORDER BY COALESCE(parent_id, id), id"
+        ).map_err(|e| e.to_string())?;
+
+        let items = stmt.query_map([order_id], |row| {
+            let kind: String = row.get(5)?;
+            Ok(OrderItem {
+                id: row.get(0)?,
+                order_id: row.get(1)?,
+                parent_id: row.get(2)?,
+                product_id: row.get(3)?,
+                name: row.get(4)?,
+                kind: kind.parse().unwrap_or(OrderType::Download),
+                quantity: row.get(6)?,
+                unit_price: row.get(7)?,
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|i| i.ok()).collect();
+
+        Ok(items)
+    }
+
+    /// Manual status change (operator or frontend); illegal transitions are rejected
     pub fn update_order_status(&self, order_id: &str, status: OrderStatus, wechat_order_id: Option<String>) -> Result<(), String> {
-        let payment_time = if status == OrderStatus::Paid {
-            Some(Utc::now().timestamp())
-        } else {
-            None
+        self.transition_order(order_id, status, "manual", wechat_order_id.as_deref(), None, None)
+            .map(|_| ())
+    }
+
+    /// Move an order to `to` and record the change in `order_events`.
+    /// Repeating the current status is a no-op and returns false; illegal transitions
+    /// are errors. `payment_time` is set once on entering paid and never cleared.
+    pub fn transition_order(
+        &self,
+        order_id: &str,
+        to: OrderStatus,
+        source: &str,
+        wechat_order_id: Option<&str>,
+        payment_time: Option<i64>,
+        detail: Option<&str>,
+    ) -> Result<bool, String> {
+        let tx = Tx::begin(self.conn)?;
+        let order = self.get_order(order_id)?.ok_or_else(|| "Order not found".to_string())?;
+
+        if order.status == to {
+            return Ok(false);
+        }
+        if !order.status.can_transition_to(&to) {
+            return Err(format!("Illegal order status change: {} -> {}", order.status, to));
+        }
+
+        let now = Utc::now().timestamp();
+        let payment_time = match to {
+            OrderStatus::Paid => Some(payment_time.unwrap_or(now)),
+            _ => None,
         };
 
+        // Guard on the status we read so a concurrent writer cannot be overwritten
+        let updated = self.conn.execute(
+            "UPDATE orders SET status = ?1, wechat_order_id = COALESCE(?2, wechat_order_id),
+                    payment_time = COALESCE(payment_time, ?3)
+             WHERE id = ?4 AND status = ?5",
+            rusqlite::params![to.to_string(), wechat_order_id, payment_time, order_id, order.status.to_string()],
+        ).map_err(|e| e.to_string())?;
+        if updated == 0 {
+            return Err("Order status changed concurrently".to_string());
+        }
+
+        self.record_order_event(order_id, Some(&order.status), &to, source, detail)?;
+        if to == OrderStatus::Paid {
+            FulfillmentService::new(self.conn).create_for_order(order_id)?;
+        }
+        tx.commit()?;
+
+        tracing::info!("[Order] {} {} -> {} ({})", order_id, order.status, to, source);
+        Ok(true)
+    }
+
+    /// Apply the status a payment provider reports for an order. Unlike manual
+    /// changes, stale or out-of-order reports are logged and ignored.
+    /// Returns whether the stored order changed.
+    pub fn reconcile_order(
+        &self,
+        order_id: &str,
+        status: OrderStatus,
+        source: &str,
+        wechat_order_id: Option<&str>,
+        payment_time: Option<i64>,
+    ) -> Result<bool, String> {
+        let order = self.get_order(order_id)?.ok_or_else(|| "Order not found".to_string())?;
+
+        // A refund we never saw the payment for: settle the payment first
+        if order.status == OrderStatus::Pending && status == OrderStatus::Refunded {
+            self.transition_order(order_id, OrderStatus::Paid, source, wechat_order_id, payment_time, None)?;
+            return self.transition_order(order_id, status, source, wechat_order_id, None, None);
+        }
+
+        if status == OrderStatus::Pending || order.status == status {
+            return Ok(false);
+        }
+        if !order.status.can_transition_to(&status) {
+            tracing::warn!("[Order] Ignoring {} report for {} order {}", status, order.status, order_id);
+            return Ok(false);
+        }
+
+        self.transition_order(order_id, status, source, wechat_order_id, payment_time, None)
+    }
+
+    /// Record a payment reported for an order we already cancelled and open a
+    /// refund of the full amount, since nothing will be delivered for it.
+    /// `None` when the order is not cancelled or the payment was already recorded.
+    pub fn record_late_payment(
+        &self,
+        order_id: &str,
+        source: &str,
+        transaction_id: Option<&str>,
+    ) -> Result<Option<LatePayment>, String> {
+        let tx = Tx::begin(self.conn)?;
+        let order = self.get_order(order_id)?.ok_or_else(|| "Order not found".to_string())?;
+        if order.status != OrderStatus::Cancelled {
+            return Ok(None);
+        }
+        // Cancelled orders are never refunded otherwise, so any refund is ours
+        let refund_service = RefundService::new(self.conn);
+        if !refund_service.get_refunds(order_id)?.is_empty() {
+            return Ok(None);
+        }
+
+        let refund = refund_service.insert_refund(order_id, order.amount, Some("Paid after the order was cancelled"))?;
+        self.conn.execute(
+            "UPDATE orders SET wechat_order_id = COALESCE(?1, wechat_order_id) WHERE id = ?2",
+            rusqlite::params![transaction_id, order_id],
+        ).map_err(|e| e.to_string())?;
+        let detail = format!("Late payment {}, refund {} opened", transaction_id.unwrap_or("without transaction id"), refund.id);
+        self.record_order_event(order_id, Some(&order.status), &order.status, source, Some(&detail))?;
+        tx.commit()?;
+
+        tracing::warn!("[Order] {} paid after cancellation ({}), refunding {}", order_id, source, order.amount);
+        Ok(Some(LatePayment {
+            order_id: order.id,
+            code: order.code,
+            amount: order.amount,
+            transaction_id: transaction_id.map(|t| t.to_string()),
+            refund_id: refund.id,
+        }))
+    }
+
+    fn record_order_event(
+        &self,
+        order_id: &str,
+        from: Option<&OrderStatus>,
+        to: &OrderStatus,
+        source: &str,
+        detail: Option<&str>,
+    ) -> Result<(), String> {
         self.conn.execute(
-            "UPDATE orders SET status = ?1, wechat_order_id = ?2, payment_time = ?3 WHERE id = ?4",
-            rusqlite::params![status.to_string(), wechat_order_id, payment_time, order_id],
+            "INSERT INTO order_events (order_id, from_status, to_status, source, detail, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                order_id,
+                from.map(|s| s.to_string()),
+                to.to_string(),
+                source,
+                detail,
+                Utc::now().timestamp()
+            ],
         ).map_err(|e| e.to_string())?;
         Ok(())
     }
 
-    pub fn get_order(&self, order_id: &str) -> Result<Option<Order>, String> {
+    pub fn get_order_events(&self, order_id: &str) -> Result<Vec<OrderEvent>, String> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at
-             FROM orders WHERE id = ?1"
+            "SELECT id, order_id, from_status, to_status, source, detail, created_at
+             FROM order_events WHERE order_id = ?1 ORDER BY id"
         ).map_err(|e| e.to_string())?;
 
-        let mut rows = stmt.query([order_id]).map_err(|e| e.to_string())?;
-        if let Some(row) = rows.next().map_err(|e| e.to_string())? {
-            let order_type_str: String = row.get(2).unwrap_or_default();
-            let status_str: String = row.get(4).unwrap_or_default();
-            Ok(Some(Order {
-                id: row.get(0).unwrap_or_default(),
-                session_id: row.get(1).unwrap_or_default(),
-                order_type: order_type_str.parse().unwrap_or(OrderType::Download),
-                amount: row.get::<_, i32>(3).unwrap_or(0),
-                status: status_str.parse().unwrap_or(OrderStatus::Pending),
-                wechat_order_id: row.get(5).ok(),
-                payment_time: row.get(6).ok(),
-                created_at: row.get::<_, i64>(7).unwrap_or(0),
-            }))
-        } else {
-            Ok(None)
+        let events = stmt.query_map([order_id], |row| {
+            let from_status: Option<String> = row.get(2)?;
+            let to_status: String = row.get(3)?;
+            Ok(OrderEvent {
+                id: row.get(0)?,
+                order_id: row.get(1)?,
+                from_status: from_status.and_then(|s| s.parse().ok()),
+                to_status: to_status.parse().unwrap_or(OrderStatus::Pending),
+                source: row.get(4)?,
+                detail: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+
+        Ok(events)
+    }
+
+    /// Find the order a provider `out_trade_no` (our id without dashes) refers to
+    pub fn get_order_by_out_trade_no(&self, out_trade_no: &str) -> Result<Option<Order>, String> {
+        self.conn.query_row(
+            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at, provider, code
+             FROM orders WHERE REPLACE(id, '-', '') = ?1",
+            [out_trade_no],
+            order_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    /// Find an order by the code on its receipt, typed in any case with spaces or dashes
+    pub fn get_order_by_code(&self, code: &str) -> Result<Option<Order>, String> {
+        let order_id: Option<String> = self.conn.query_row(
+            "SELECT id FROM orders WHERE code = ?1",
+            [normalize_code(code)],
+            |row| row.get(0),
+        ).optional().map_err(|e| e.to_string())?;
+
+        match order_id {
+            Some(id) => self.get_order(&id),
+            None => Ok(None),
         }
     }
+
+    pub fn get_order(&self, order_id: &str) -> Result<Option<Order>, String> {
+        self.conn.query_row(
+            "SELECT id, session_id, order_type, amount, status, wechat_order_id, payment_time, created_at, provider, code
+             FROM orders WHERE id = ?1",
+            [order_id],
+            order_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+}
+
+fn order_from_row(row: &rusqlite::Row) -> rusqlite::Result<Order> {
+    let order_type: String = row.get(2)?;
+    let status: String = row.get(4)?;
+    let provider: String = row.get(8)?;
+    Ok(Order {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        order_type: order_type.parse().unwrap_or(OrderType::Download),
+        amount: row.get(3)?,
+        status: status.parse().unwrap_or(OrderStatus::Pending),
+        wechat_order_id: row.get(5)?,
+        payment_time: row.get(6)?,
+        created_at: row.get(7)?,
+        provider: provider.parse().unwrap_or(PaymentProviderKind::Wechat),
+        code: row.get(9)?,
+    })
+}
+
+/// A line to insert, with its bundle components already multiplied out
+struct NewLine {
+    product_id: String,
+    name: String,
+    kind: OrderType,
+    quantity: u32,
+    unit_price: i32,
+    components: Vec<(crate::models::Product, u32)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{ModeService, Storage};
+
+    #[test]
+    fn single_order_is_priced_from_the_catalog() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let mode = ModeService::new(&conn).get_all_modes().unwrap().remove(0);
+        let session_service = SessionService::new(&conn);
+        let session = session_service.create_session(&mode.id, &mode.effects[0].id).unwrap();
+        let price = CatalogService::new(&conn).get_product("download", Some(&session.effect_id)).unwrap().unwrap().price;
+
+        let err = session_service
+            .create_order(&session.id, OrderType::Download, 1, PaymentProviderKind::Wechat)
+            .unwrap_err();
+        assert!(err.starts_with("Price changed"), "{}", err);
+
+        let order = session_service
+            .create_order_from(&session.id, OrderContents::Single { order_type: OrderType::Download, amount: None }, PaymentProviderKind::Wechat)
+            .unwrap();
+        assert_eq!(order.amount, price);
+        let items = session_service.get_order_items(&order.id).unwrap();
+        assert_eq!((items.len(), items[0].unit_price), (1, price));
+    }
 }
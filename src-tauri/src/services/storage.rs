@@ -1,3 +1,4 @@
+use rusqlite::{Connection, Transaction};
 use std::path::PathBuf;
 
 pub struct Storage {
@@ -9,7 +10,11 @@ impl Storage {
         let data_dir = dirs::data_local_dir()
             .ok_or("Failed to get data directory")?
             .join("ai-photobooth");
+        Self::open(data_dir)
+    }
 
+    /// Storage rooted at `data_dir`, creating the directory and tables as needed
+    pub fn open(data_dir: PathBuf) -> Result<Self, String> {
         std::fs::create_dir_all(&data_dir).map_err(|e| format!("Failed to create data dir: {}", e))?;
 
         let db_path = data_dir.join("photobooth.db");
@@ -33,6 +38,35 @@ impl Storage {
     }
 }
 
+/// A transaction that joins the one already open on the connection, so a
+/// service method can run on its own or as part of a caller's larger unit of work
+pub struct Tx<'a>(Option<Transaction<'a>>);
+
+impl<'a> Tx<'a> {
+    pub fn begin(conn: &'a Connection) -> Result<Self, String> {
+        if !conn.is_autocommit() {
+            return Ok(Tx(None));
+        }
+        conn.unchecked_transaction().map(|tx| Tx(Some(tx))).map_err(|e| e.to_string())
+    }
+
+    /// A joined transaction is committed, or rolled back, by its owner
+    pub fn commit(self) -> Result<(), String> {
+        match self.0 {
+            Some(tx) => tx.commit().map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage {
+    /// Fresh storage in its own temporary directory
+    pub fn temp() -> Self {
+        Self::open(std::env::temp_dir().join(format!("ai-photobooth-test-{}", uuid::Uuid::new_v4()))).expect("temp storage")
+    }
+}
+
 impl Clone for Storage {
     fn clone(&self) -> Self {
         Self { data_dir: self.data_dir.clone() }
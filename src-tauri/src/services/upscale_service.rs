@@ -0,0 +1,235 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageError, ImageReader, Limits, RgbImage};
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use super::image_service::{decode_base64_bytes, encode_jpeg};
+
+// A 6-inch print at 300 DPI
+const DEFAULT_LONG_SIDE: u32 = 1800;
+const JPEG_QUALITY: u8 = 95;
+const UNSHARP_SIGMA: f32 = 1.2;
+const UNSHARP_THRESHOLD: i32 = 2;
+// Generated photos are ~1k; anything far beyond a print is refused before decoding
+const MAX_INPUT_DIMENSION: u32 = 8_000;
+
+/// Brings generated photos up to print resolution for paid outputs. Uses the
+/// AI upscaler at `UPSCALER_URL` when configured and falls back to Lanczos
+/// resampling plus an unsharp mask on the CPU. Results are cached on disk per
+/// generated photo.
+pub struct UpscaleService {
+    dir: PathBuf,
+    long_side: u32,
+    endpoint: Option<String>,
+    api_key: Option<String>,
+    client: Client,
+}
+
+impl UpscaleService {
+    pub fn new(data_dir: &Path) -> Result<Self, String> {
+        let dir = data_dir.join("upscaled");
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create upscale dir: {}", e))?;
+
+        let long_side = env::var("UPSCALE_LONG_SIDE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_LONG_SIDE);
+
+        Ok(Self {
+            dir,
+            long_side,
+            endpoint: env::var("UPSCALER_URL").ok().filter(|v| !v.is_empty()),
+            api_key: env::var("UPSCALER_API_KEY").ok().filter(|v| !v.is_empty()),
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(60))
+                .build()
+                .map_err(|e| e.to_string())?,
+        })
+    }
+
+    /// The photo as a JPEG whose long side is at least `UPSCALE_LONG_SIDE`
+    pub async fn upscale(&self, photo_base64: &str) -> Result<Vec<u8>, String> {
+        let path = self.entry_path(photo_base64);
+        if let Ok(cached) = fs::read(&path) {
+            return Ok(cached);
+        }
+
+        let bytes = decode_base64_bytes(photo_base64)?;
+        let img = decode(&bytes)?;
+        let (width, height) = img.dimensions();
+
+        let upscaled = match &self.endpoint {
+            Some(endpoint) if width.max(height) < self.long_side => match self.remote(endpoint, bytes).await {
+                Ok(remote) => Some(remote),
+                Err(e) => {
+                    tracing::warn!("[Upscale] {}; upscaling locally", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let long_side = self.long_side;
+        let rgb = match upscaled {
+            Some(remote) => fit(remote, long_side),
+            None => tokio::task::spawn_blocking(move || upscale_local(img, long_side))
+                .await
+                .map_err(|e| e.to_string())?,
+        };
+
+        let jpeg = encode_jpeg(&rgb, JPEG_QUALITY)?;
+        if let Err(e) = fs::write(&path, &jpeg) {
+            tracing::warn!("[Upscale] Failed to cache {}: {}", path.display(), e);
+        }
+        tracing::info!("[Upscale] {}x{} -> {}x{}", width, height, rgb.width(), rgb.height());
+        Ok(jpeg)
+    }
+
+    /// Send the photo to the AI upscaler; the reply body is the upscaled image
+    async fn remote(&self, endpoint: &str, photo: Vec<u8>) -> Result<DynamicImage, String> {
+        let mut request = self.client
+            .post(endpoint)
+            .header("Content-Type", "image/jpeg")
+            .body(photo);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = request.send().await.map_err(|e| format!("Upscaler unreachable: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Upscaler HTTP error: {}", response.status()));
+        }
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        decode(&body).map_err(|e| format!("Upscaler returned an invalid image: {}", e))
+    }
+
+    fn entry_path(&self, photo_base64: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(self.long_side.to_le_bytes());
+        hasher.update(photo_base64.as_bytes());
+        self.dir.join(format!("{}.jpg", hex::encode(hasher.finalize())))
+    }
+}
+
+/// Decode with a cap on each side, so a huge image fails before it is allocated
+fn decode(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_INPUT_DIMENSION);
+    limits.max_image_height = Some(MAX_INPUT_DIMENSION);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("Failed to decode image: {}", e))?;
+    reader.limits(limits);
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => format!("Image is too large to upscale (max {}px a side)", MAX_INPUT_DIMENSION),
+        e => format!("Failed to decode image: {}", e),
+    })
+}
+
+/// Lanczos resampling up to `long_side`, then an unsharp mask to restore edges
+fn upscale_local(img: DynamicImage, long_side: u32) -> RgbImage {
+    if img.width().max(img.height()) >= long_side {
+        return img.to_rgb8();
+    }
+    let resized = img.resize(long_side, long_side, FilterType::Lanczos3).to_rgb8();
+    imageops::unsharpen(&resized, UNSHARP_SIGMA, UNSHARP_THRESHOLD)
+}
+
+/// An upscaler may overshoot; bring its output down to `long_side`
+fn fit(img: DynamicImage, long_side: u32) -> RgbImage {
+    if img.width().max(img.height()) > long_side {
+        img.resize(long_side, long_side, FilterType::Lanczos3).to_rgb8()
+    } else {
+        img.to_rgb8()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use image::{ImageFormat, Rgb};
+    use tiny_http::{Header, Response, Server};
+
+    fn service(endpoint: Option<String>) -> UpscaleService {
+        let dir = env::temp_dir().join(format!("ai-photobooth-upscale-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        UpscaleService {
+            dir,
+            long_side: DEFAULT_LONG_SIDE,
+            endpoint,
+            api_key: None,
+            client: Client::new(),
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([90, 120, 200])));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        png
+    }
+
+    fn dimensions(jpeg: &[u8]) -> (u32, u32) {
+        image::load_from_memory(jpeg).unwrap().dimensions()
+    }
+
+    /// An AI upscaler that always answers with `reply`
+    fn upscaler(reply: Vec<u8>) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/upscale", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let header = Header::from_bytes("Content-Type", "image/png").unwrap();
+                let _ = request.respond(Response::from_data(reply.clone()).with_header(header));
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn small_photo_is_brought_up_to_print_size() {
+        let service = service(None);
+        let photo = STANDARD.encode(png(600, 400));
+
+        let jpeg = service.upscale(&photo).await.unwrap();
+        assert_eq!(dimensions(&jpeg), (1800, 1200));
+        assert_eq!(fs::read(service.entry_path(&photo)).unwrap(), jpeg);
+    }
+
+    #[tokio::test]
+    async fn photo_at_print_size_is_not_resized() {
+        let service = service(None);
+        let jpeg = service.upscale(&STANDARD.encode(png(1000, 2000))).await.unwrap();
+        assert_eq!(dimensions(&jpeg), (1000, 2000));
+    }
+
+    #[tokio::test]
+    async fn upscaler_overshoot_is_fitted_to_print_size() {
+        let service = service(Some(upscaler(png(2400, 4800))));
+        let jpeg = service.upscale(&STANDARD.encode(png(600, 1200))).await.unwrap();
+        assert_eq!(dimensions(&jpeg), (900, 1800));
+    }
+
+    #[tokio::test]
+    async fn oversized_photo_is_rejected() {
+        let service = service(None);
+        let photo = STANDARD.encode(png(MAX_INPUT_DIMENSION + 1, 8));
+
+        let err = service.upscale(&photo).await.unwrap_err();
+        assert!(err.contains("too large"), "{}", err);
+        assert!(!service.entry_path(&photo).exists());
+    }
+
+    #[tokio::test]
+    async fn oversized_upscaler_reply_falls_back_to_local() {
+        let service = service(Some(upscaler(png(MAX_INPUT_DIMENSION + 1, 8))));
+        let jpeg = service.upscale(&STANDARD.encode(png(600, 400))).await.unwrap();
+        assert_eq!(dimensions(&jpeg), (1800, 1200));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::models::{AiUsage, GenerationStatus, UsageReport};
+use chrono::{Datelike, Local, TimeZone, Utc};
+use rusqlite::Connection;
+use std::env;
+
+// MiniMax image-01 list price is about 0.025 元 per image
+const DEFAULT_COST_PER_CALL: f64 = 2.5;
+
+/// How a provider call ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallOutcome {
+    Success,
+    /// Rejected or unreachable; the provider does not bill these
+    Failed(String),
+    /// Given up on while in flight; the provider may still have generated and billed it
+    TimedOut,
+}
+
+pub struct UsageService<'a> {
+    conn: &'a Connection,
+    cost_per_call: f64,
+    daily_budget: Option<f64>,
+    monthly_budget: Option<f64>,
+}
+
+impl<'a> UsageService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        let read_amount = |name: &str| env::var(name).ok().and_then(|v| v.parse::<f64>().ok());
+
+        Self {
+            conn,
+            cost_per_call: read_amount("MINIMAX_COST_PER_CALL").unwrap_or(DEFAULT_COST_PER_CALL),
+            // Budgets in 分; unset or zero means unlimited
+            daily_budget: read_amount("AI_DAILY_BUDGET").filter(|b| *b > 0.0),
+            monthly_budget: read_amount("AI_MONTHLY_BUDGET").filter(|b| *b > 0.0),
+        }
+    }
+
+    /// Record one provider call. Successful and timed-out calls count against the budget.
+    pub fn record_call(
+        &self,
+        session_id: &str,
+        effect_id: &str,
+        provider: &str,
+        latency_ms: i64,
+        outcome: &CallOutcome,
+    ) -> Result<AiUsage, String> {
+        let now = Utc::now().timestamp();
+        let (success, error, estimated_cost) = match outcome {
+            CallOutcome::Success => (true, None, self.cost_per_call),
+            CallOutcome::Failed(error) => (false, Some(error.as_str()), 0.0),
+            CallOutcome::TimedOut => (false, Some("Timed out"), self.cost_per_call),
+        };
+
+        self.conn.execute(
+            "INSERT INTO ai_usage (session_id, effect_id, provider, latency_ms, success, error, estimated_cost, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![session_id, effect_id, provider, latency_ms, success, error, estimated_cost, now],
+        ).map_err(|e| e.to_string())?;
+
+        Ok(AiUsage {
+            id: self.conn.last_insert_rowid(),
+            session_id: session_id.to_string(),
+            effect_id: effect_id.to_string(),
+            provider: provider.to_string(),
+            latency_ms,
+            success,
+            error: error.map(|e| e.to_string()),
+            estimated_cost,
+            created_at: now,
+        })
+    }
+
+    pub fn daily_report(&self, days: u32) -> Result<Vec<UsageReport>, String> {
+        self.report("%Y-%m-%d", days)
+    }
+
+    pub fn monthly_report(&self, months: u32) -> Result<Vec<UsageReport>, String> {
+        self.report("%Y-%m", months)
+    }
+
+    fn report(&self, period_format: &str, limit: u32) -> Result<Vec<UsageReport>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT strftime(?1, created_at, 'unixepoch', 'localtime') AS period,
+                    COUNT(*), SUM(success), CAST(AVG(latency_ms) AS INTEGER), SUM(estimated_cost)
+             FROM ai_usage GROUP BY period ORDER BY period DESC LIMIT ?2"
+        ).map_err(|e| e.to_string())?;
+
+        let reports = stmt.query_map(rusqlite::params![period_format, limit], |row| {
+            let calls: i64 = row.get(1).unwrap_or(0);
+            let successes: i64 = row.get(2).unwrap_or(0);
+            Ok(UsageReport {
+                period: row.get(0).unwrap_or_default(),
+                calls,
+                successes,
+                failures: calls - successes,
+                avg_latency_ms: row.get(3).unwrap_or(0),
+                estimated_cost: row.get(4).unwrap_or(0.0),
+            })
+        }).map_err(|e| e.to_string())?.filter_map(|r| r.ok()).collect();
+
+        Ok(reports)
+    }
+
+    fn spent_since(&self, since: i64) -> Result<f64, String> {
+        self.conn.query_row(
+            "SELECT COALESCE(SUM(estimated_cost), 0) FROM ai_usage WHERE created_at >= ?1",
+            [since],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())
+    }
+
+    /// Whether the kiosk may still call the AI provider under the configured budgets
+    pub fn generation_status(&self) -> Result<GenerationStatus, String> {
+        let today = Local::now().date_naive();
+        let day_start = local_midnight(today)?;
+        let month_start = local_midnight(today.with_day(1).unwrap_or(today))?;
+
+        let spent_today = self.spent_since(day_start)?;
+        let spent_this_month = self.spent_since(month_start)?;
+
+        let reason = match (self.daily_budget, self.monthly_budget) {
+            (Some(budget), _) if spent_today >= budget => Some("Daily AI budget reached".to_string()),
+            (_, Some(budget)) if spent_this_month >= budget => Some("Monthly AI budget reached".to_string()),
+            _ => None,
+        };
+
+        Ok(GenerationStatus {
+            available: reason.is_none(),
+            reason,
+            spent_today,
+            daily_budget: self.daily_budget,
+            spent_this_month,
+            monthly_budget: self.monthly_budget,
+        })
+    }
+}
+
+fn local_midnight(date: chrono::NaiveDate) -> Result<i64, String> {
+    let midnight = date.and_hms_opt(0, 0, 0).ok_or("Invalid date")?;
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|dt| dt.timestamp())
+        .ok_or_else(|| "Invalid local time".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::Storage;
+
+    fn usage(conn: &Connection, daily_budget: Option<f64>, monthly_budget: Option<f64>) -> UsageService<'_> {
+        UsageService { conn, cost_per_call: 2.5, daily_budget, monthly_budget }
+    }
+
+    /// Move every recorded call to one second before `at`
+    fn backdate(conn: &Connection, at: i64) {
+        conn.execute("UPDATE ai_usage SET created_at = ?1", [at - 1]).unwrap();
+    }
+
+    #[test]
+    fn calls_are_recorded_with_their_cost() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let usage = usage(&conn, None, None);
+
+        let success = usage.record_call("s1", "cartoon-01", "minimax", 1200, &CallOutcome::Success).unwrap();
+        assert!(success.success);
+        assert_eq!(success.estimated_cost, 2.5);
+
+        let failed = usage.record_call("s1", "cartoon-01", "minimax", 300, &CallOutcome::Failed("HTTP 500".to_string())).unwrap();
+        assert!(!failed.success);
+        assert_eq!((failed.error.as_deref(), failed.estimated_cost), (Some("HTTP 500"), 0.0));
+
+        // May have been billed after we stopped waiting
+        let timed_out = usage.record_call("s1", "cartoon-01", "minimax", 30_000, &CallOutcome::TimedOut).unwrap();
+        assert_eq!((timed_out.error.as_deref(), timed_out.estimated_cost), (Some("Timed out"), 2.5));
+
+        let report = usage.daily_report(30).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].calls, report[0].successes, report[0].failures), (3, 1, 2));
+        assert_eq!(report[0].avg_latency_ms, 10_500);
+        assert_eq!(report[0].estimated_cost, 5.0);
+    }
+
+    #[test]
+    fn generation_stops_at_the_budget() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let usage = usage(&conn, Some(5.0), Some(100.0));
+
+        usage.record_call("s1", "anime-01", "minimax", 1000, &CallOutcome::Success).unwrap();
+        usage.record_call("s1", "anime-01", "minimax", 1000, &CallOutcome::Failed("rejected".to_string())).unwrap();
+        let status = usage.generation_status().unwrap();
+        assert!(status.available);
+        assert_eq!(status.spent_today, 2.5);
+
+        usage.record_call("s2", "anime-01", "minimax", 1000, &CallOutcome::Success).unwrap();
+        let status = usage.generation_status().unwrap();
+        assert!(!status.available);
+        assert_eq!(status.reason.as_deref(), Some("Daily AI budget reached"));
+
+        let status = UsageService { daily_budget: None, monthly_budget: Some(5.0), ..usage }.generation_status().unwrap();
+        assert_eq!(status.reason.as_deref(), Some("Monthly AI budget reached"));
+    }
+
+    #[test]
+    fn budgets_reset_with_the_period() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let usage = usage(&conn, Some(5.0), Some(5.0));
+        usage.record_call("s1", "anime-01", "minimax", 1000, &CallOutcome::Success).unwrap();
+        usage.record_call("s1", "anime-01", "minimax", 1000, &CallOutcome::Success).unwrap();
+        assert!(!usage.generation_status().unwrap().available);
+
+        let today = Local::now().date_naive();
+        backdate(&conn, local_midnight(today).unwrap());
+        let status = usage.generation_status().unwrap();
+        assert_eq!(status.spent_today, 0.0);
+        // Still counted this month unless today is the 1st
+        assert_eq!(status.available, today.day() == 1);
+
+        backdate(&conn, local_midnight(today.with_day(1).unwrap()).unwrap());
+        let status = usage.generation_status().unwrap();
+        assert!(status.available);
+        assert_eq!((status.spent_today, status.spent_this_month), (0.0, 0.0));
+
+        usage.record_call("s2", "anime-01", "minimax", 1000, &CallOutcome::Success).unwrap();
+        let report = usage.monthly_report(12).unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!((report[0].calls, report[1].calls), (1, 2));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::models::{Order, OrderStatus, PaymentProviderKind, Voucher, VoucherKind};
+use chrono::Utc;
+use rand::Rng;
+use rusqlite::{Connection, OptionalExtension};
+
+use super::session_service::OrderContents;
+use super::storage::Tx;
+use super::SessionService;
+
+// No 0/O or 1/I so codes survive being read out loud or typed from a card
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+const MAX_BATCH: u32 = 1000;
+
+/// Terms of a batch of vouchers issued together
+#[derive(Debug, Clone)]
+pub struct VoucherTerms {
+    pub kind: VoucherKind,
+    pub value: Option<i32>,
+    pub max_uses: u32,
+    pub mode_id: Option<String>,
+    pub expires_at: Option<i64>,
+    pub note: Option<String>,
+}
+
+pub struct VoucherService<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> VoucherService<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Issue `count` vouchers with the same terms
+    pub fn create_vouchers(&self, count: u32, terms: &VoucherTerms) -> Result<Vec<Voucher>, String> {
+        if count == 0 || count > MAX_BATCH {
+            return Err(format!("Voucher count must be between 1 and {}", MAX_BATCH));
+        }
+        if terms.max_uses == 0 {
+            return Err("A voucher must allow at least one use".to_string());
+        }
+        let value = match terms.kind {
+            VoucherKind::Free => None,
+            VoucherKind::Value => Some(terms.value.filter(|v| *v > 0).ok_or("Value vouchers need a positive value")?),
+        };
+
+        let now = Utc::now().timestamp();
+        let tx = Tx::begin(self.conn)?;
+        let mut vouchers = Vec::with_capacity(count as usize);
+        while vouchers.len() < count as usize {
+            let code = generate_code(CODE_LENGTH);
+            // Retry the rare collision with an existing code
+            let inserted = self.conn.execute(
+                "INSERT OR IGNORE INTO vouchers (code, kind, value, max_uses, mode_id, expires_at, note, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                rusqlite::params![code, terms.kind.to_string(), value, terms.max_uses, terms.mode_id, terms.expires_at, terms.note, now],
+            ).map_err(|e| e.to_string())?;
+            if inserted == 0 {
+                continue;
+            }
+
+            vouchers.push(Voucher {
+                code,
+                kind: terms.kind.clone(),
+                value,
+                max_uses: terms.max_uses,
+                used: 0,
+                mode_id: terms.mode_id.clone(),
+                expires_at: terms.expires_at,
+                note: terms.note.clone(),
+                disabled: false,
+                created_at: now,
+            });
+        }
+        tx.commit()?;
+
+        tracing::info!("[Voucher] Issued {} {} voucher(s)", count, terms.kind);
+        Ok(vouchers)
+    }
+
+    pub fn get_voucher(&self, code: &str) -> Result<Option<Voucher>, String> {
+        self.conn.query_row(
+            "SELECT code, kind, value, max_uses, used, mode_id, expires_at, note, disabled, created_at
+             FROM vouchers WHERE code = ?1",
+            [normalize_code(code)],
+            voucher_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    pub fn get_vouchers(&self) -> Result<Vec<Voucher>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT code, kind, value, max_uses, used, mode_id, expires_at, note, disabled, created_at
+             FROM vouchers ORDER BY created_at DESC, code"
+        ).map_err(|e| e.to_string())?;
+
+        let vouchers = stmt.query_map([], voucher_from_row)
+            .map_err(|e| e.to_string())?
+            .filter_map(|v| v.ok())
+            .collect();
+
+        Ok(vouchers)
+    }
+
+    /// Withdraw a voucher; orders it already settled stay paid
+    pub fn disable_voucher(&self, code: &str) -> Result<(), String> {
+        let updated = self.conn.execute(
+            "UPDATE vouchers SET disabled = 1 WHERE code = ?1",
+            [normalize_code(code)],
+        ).map_err(|e| e.to_string())?;
+        if updated == 0 {
+            return Err("Voucher not found".to_string());
+        }
+        Ok(())
+    }
+
+    /// Create an order for the session and settle it with a voucher instead of
+    /// a payment. The order is recorded with the voucher payment method and the
+    /// code as its transaction id.
+    pub fn redeem(&self, session_id: &str, contents: OrderContents, code: &str) -> Result<Order, String> {
+        let code = normalize_code(code);
+        let session_service = SessionService::new(self.conn);
+        let session = session_service
+            .get_session(session_id)?
+            .ok_or_else(|| "Session not found".to_string())?;
+        let voucher = self.get_voucher(&code)?.ok_or_else(|| "Invalid voucher code".to_string())?;
+
+        let now = Utc::now().timestamp();
+        if voucher.disabled {
+            return Err("Voucher has been withdrawn".to_string());
+        }
+        if voucher.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err("Voucher has expired".to_string());
+        }
+        if voucher.mode_id.as_ref().is_some_and(|mode_id| *mode_id != session.mode_id) {
+            return Err("Voucher is not valid for this mode".to_string());
+        }
+
+        // All or nothing: a failure anywhere leaves neither a used claim nor an order behind
+        let tx = Tx::begin(self.conn)?;
+
+        // The guard keeps two kiosks from sharing the last use
+        let claimed = self.conn.execute(
+            "UPDATE vouchers SET used = used + 1 WHERE code = ?1 AND used < max_uses AND disabled = 0",
+            [&code],
+        ).map_err(|e| e.to_string())?;
+        if claimed == 0 {
+            return Err("Voucher has been used up".to_string());
+        }
+
+        let order = session_service.create_order_from(session_id, contents, PaymentProviderKind::Voucher)?;
+        if let Some(value) = voucher.value.filter(|value| *value < order.amount) {
+            return Err(format!(
+                "Voucher is worth ¥{:.2} but the order costs ¥{:.2}",
+                value as f64 / 100.0,
+                order.amount as f64 / 100.0
+            ));
+        }
+        let detail = format!("Voucher {}", code);
+        session_service.transition_order(&order.id, OrderStatus::Paid, "voucher", Some(&code), Some(now), Some(&detail))?;
+        self.conn.execute(
+            "INSERT INTO voucher_redemptions (code, order_id, created_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![code, order.id, now],
+        ).map_err(|e| e.to_string())?;
+        tx.commit()?;
+
+        tracing::info!("[Voucher] {} settled order {}", code, order.id);
+        session_service.get_order(&order.id)?.ok_or_else(|| "Order not found".to_string())
+    }
+}
+
+/// Codes are matched case-insensitively, ignoring spaces and dashes
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+/// Random code over the unambiguous alphabet, shared with order codes
+pub fn generate_code(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+fn voucher_from_row(row: &rusqlite::Row) -> rusqlite::Result<Voucher> {
+    let kind: String = row.get(1)?;
+    Ok(Voucher {
+        code: row.get(0)?,
+        kind: kind.parse().unwrap_or(VoucherKind::Free),
+        value: row.get(2)?,
+        max_uses: row.get(3)?,
+        used: row.get(4)?,
+        mode_id: row.get(5)?,
+        expires_at: row.get(6)?,
+        note: row.get(7)?,
+        disabled: row.get(8)?,
+        created_at: row.get(9)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OrderType;
+    use crate::services::{ModeService, Storage};
+
+    fn single_use_voucher(conn: &Connection) -> String {
+        let terms = VoucherTerms { kind: VoucherKind::Free, value: None, max_uses: 1, mode_id: None, expires_at: None, note: None };
+        VoucherService::new(conn).create_vouchers(1, &terms).unwrap().remove(0).code
+    }
+
+    fn session_id(conn: &Connection) -> String {
+        let mode = ModeService::new(conn).get_all_modes().unwrap().remove(0);
+        SessionService::new(conn).create_session(&mode.id, &mode.effects[0].id).unwrap().id
+    }
+
+    fn order_count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM orders", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn redeem_settles_order() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let code = single_use_voucher(&conn);
+        let voucher_service = VoucherService::new(&conn);
+
+        let contents = || OrderContents::Single { order_type: OrderType::Download, amount: None };
+        let order = voucher_service.redeem(&session_id(&conn), contents(), &code).unwrap();
+        assert_eq!(order.status, OrderStatus::Paid);
+        assert_eq!(order.provider, PaymentProviderKind::Voucher);
+        assert_eq!(voucher_service.get_voucher(&code).unwrap().unwrap().used, 1);
+
+        let err = voucher_service.redeem(&session_id(&conn), contents(), &code).unwrap_err();
+        assert_eq!(err, "Voucher has been used up");
+    }
+
+    #[test]
+    fn failed_redemption_leaves_nothing_behind() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+        let code = single_use_voucher(&conn);
+        let session_id = session_id(&conn);
+        let voucher_service = VoucherService::new(&conn);
+        let contents = || OrderContents::Single { order_type: OrderType::Download, amount: None };
+
+        // Fails after the claim, the order and its transition to paid
+        conn.execute_batch(
+            "CREATE TEMP TRIGGER fail_redemption BEFORE INSERT ON voucher_redemptions
+             BEGIN SELECT RAISE(ABORT, 'disk I/O error'); END;",
+        ).unwrap();
+        assert!(voucher_service.redeem(&session_id, contents(), &code).is_err());
+        assert_eq!(voucher_service.get_voucher(&code).unwrap().unwrap().used, 0);
+        assert_eq!(order_count(&conn), 0);
+
+        conn.execute_batch("DROP TRIGGER fail_redemption").unwrap();
+        assert!(voucher_service.redeem(&session_id, contents(), &code).is_ok());
+        assert_eq!(order_count(&conn), 1);
+    }
+
+    #[test]
+    fn vouchers_issued_inside_a_transaction_join_it() {
+        let storage = Storage::temp();
+        let conn = storage.get_connection().unwrap();
+
+        let outer = Tx::begin(&conn).unwrap();
+        let code = single_use_voucher(&conn);
+        assert!(VoucherService::new(&conn).get_voucher(&code).unwrap().is_some());
+        drop(outer);
+
+        // Rolled back with the outer transaction
+        assert!(VoucherService::new(&conn).get_voucher(&code).unwrap().is_none());
+    }
+}
@@ -1,78 +1,725 @@
+use crate::models::{OrderStatus, PaymentProviderKind, RefundStatus};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::BlockDecrypt;
+use aes::Aes256;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::NaiveDate;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::env;
 
+use super::payment_provider::{
+    notify_path_from_url, parse_beijing_time, parse_cents, BillEntry, BillEntryKind, NotifyReply, NotifyRequest,
+    PaymentNotice, PaymentProvider, ProviderRefund, ProviderTrade, RefundError,
+};
+use super::wechat_v3::{Notification, V3RefundNotice, V3Transaction, WeChatSignatureHeaders, WeChatV3Client};
+
+const DEFAULT_API_BASE: &str = "https://api.mch.weixin.qq.com";
+const DEFAULT_NOTIFY_PATH: &str = "/wechat/notify";
+// Reported as the trade state of orders WeChat has no record of
+const ORDER_NOT_EXIST: &str = "ORDERNOTEXIST";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WeChatPayRequest {
     appid: String,
     mch_id: String,
     nonce_str: String,
-    sign: String,
+    sign_type: String,
     body: String,
     out_trade_no: String,
     total_fee: i32,
     spbill_create_ip: String,
     notify_url: String,
     trade_type: String,
+    product_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WeChatPayResponse {
     return_code: String,
-    return_msg: String,
-    result_code: String,
-    prepay_id: String,
-    qr_code: String,
+    return_msg: Option<String>,
+    result_code: Option<String>,
+    err_code: Option<String>,
+    err_code_des: Option<String>,
+    prepay_id: Option<String>,
+    code_url: Option<String>,
+}
+
+/// Provider-side state of an order, common to the v2 and v3 APIs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeChatTrade {
+    pub trade_state: String,
+    pub transaction_id: Option<String>,
+    pub paid_at: Option<i64>,
+    pub amount: Option<i32>,
+}
+
+impl WeChatTrade {
+    /// Our order status for a provider `trade_state`
+    pub fn order_status(&self) -> OrderStatus {
+        match self.trade_state.as_str() {
+            "SUCCESS" => OrderStatus::Paid,
+            "REFUND" => OrderStatus::Refunded,
+            "CLOSED" | "REVOKED" | "PAYERROR" => OrderStatus::Cancelled,
+            // NOTPAY, USERPAYING, ORDERNOTEXIST and anything new keep the order open
+            _ => OrderStatus::Pending,
+        }
+    }
+
+    /// An order WeChat never created, e.g. after a failed unified order.
+    /// Unpayable, so it can be closed once it expires.
+    pub fn not_exist() -> Self {
+        WeChatTrade { trade_state: ORDER_NOT_EXIST.to_string(), transaction_id: None, paid_at: None, amount: None }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeChatRefund {
+    pub refund_id: Option<String>,
+    pub out_refund_no: String,
+    pub status: String,
+}
+
+impl WeChatRefund {
+    /// Our refund status for a provider refund `status`
+    pub fn refund_status(&self) -> RefundStatus {
+        match self.status.as_str() {
+            "SUCCESS" => RefundStatus::Success,
+            "CLOSED" => RefundStatus::Closed,
+            "ABNORMAL" => RefundStatus::Abnormal,
+            _ => RefundStatus::Processing,
+        }
+    }
+}
+
+/// A verified notification pushed to `notify_url`
+#[derive(Debug, Clone)]
+pub enum WeChatNotice {
+    Payment { out_trade_no: String, trade: WeChatTrade },
+    Refund { out_trade_no: String, refund: WeChatRefund },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignType {
+    Md5,
+    HmacSha256,
+}
+
+impl SignType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SignType::Md5 => "MD5",
+            SignType::HmacSha256 => "HMAC-SHA256",
+        }
+    }
 }
 
 pub struct WeChatService {
+    client: Client,
     app_id: String,
     mch_id: String,
     api_key: String,
     notify_url: String,
+    api_base: String,
+    sign_type: SignType,
+    spbill_create_ip: String,
+    // v2 refunds go to /secapi, which requires the merchant certificate
+    cert_client: Option<Client>,
+    v3: Option<WeChatV3Client>,
+    use_mock: bool,
 }
 
 impl WeChatService {
     pub fn new() -> Result<Self, String> {
+        // Local stand-in provider for development and tests: orders stay unpaid,
+        // closes and refunds succeed immediately, no credentials needed
+        if env::var("WECHAT_MOCK").unwrap_or_default() == "true" {
+            tracing::info!("WeChat Pay service initialized: use_mock=true");
+            return Ok(Self {
+                client: Client::new(),
+                app_id: String::new(),
+                mch_id: String::new(),
+                api_key: String::new(),
+                notify_url: String::new(),
+                api_base: String::new(),
+                sign_type: SignType::Md5,
+                spbill_create_ip: String::new(),
+                cert_client: None,
+                v3: None,
+                use_mock: true,
+            });
+        }
+
         let app_id = env::var("WECHAT_APP_ID").map_err(|_| "WECHAT_APP_ID not set")?;
         let mch_id = env::var("WECHAT_MCH_ID").map_err(|_| "WECHAT_MCH_ID not set")?;
-        let api_key = env::var("WECHAT_API_KEY").map_err(|_| "WECHAT_API_KEY not set")?;
         let notify_url = env::var("WECHAT_NOTIFY_URL").unwrap_or_default();
+        // Overridable so a local mock server can stand in for WeChat
+        let api_base = env::var("WECHAT_API_BASE").unwrap_or_else(|_| DEFAULT_API_BASE.to_string());
+        let api_base = api_base.trim_end_matches('/').to_string();
+
+        // WECHAT_API_VERSION=v3 switches to the v3 client; v2 stays the default
+        let v3 = match env::var("WECHAT_API_VERSION").unwrap_or_default().as_str() {
+            "v3" => Some(WeChatV3Client::new(app_id.clone(), mch_id.clone(), notify_url.clone(), api_base.clone())?),
+            _ => None,
+        };
+        let api_key = match v3 {
+            Some(_) => env::var("WECHAT_API_KEY").unwrap_or_default(),
+            None => env::var("WECHAT_API_KEY").map_err(|_| "WECHAT_API_KEY not set")?,
+        };
+        let sign_type = match env::var("WECHAT_SIGN_TYPE").unwrap_or_default().as_str() {
+            "HMAC-SHA256" => SignType::HmacSha256,
+            _ => SignType::Md5,
+        };
+        let spbill_create_ip = env::var("WECHAT_SPBILL_CREATE_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let cert_client = match v3 {
+            Some(_) => None,
+            None => merchant_cert_client()?,
+        };
 
         Ok(Self {
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()
+                .map_err(|e| format!("Failed to create HTTP client: {}", e))?,
             app_id,
             mch_id,
             api_key,
             notify_url,
+            api_base,
+            sign_type,
+            spbill_create_ip,
+            cert_client,
+            v3,
+            use_mock: false,
         })
     }
 
-    pub fn create_qr_code_url(&self, prepay_id: &str) -> String {
-        format!(
-            "weixin://wxpay/bizpayurl?pr={}",
-            prepay_id
-        )
+    pub fn is_mock(&self) -> bool {
+        self.use_mock
+    }
+
+    pub fn v3(&self) -> Option<&WeChatV3Client> {
+        self.v3.as_ref()
     }
 
-    // Stub: In production, this would call the actual WeChat Pay API
+    /// Create a Native (scan-to-pay) order. Returns `(prepay_id, code_url)`;
+    /// `code_url` is the `weixin://wxpay/bizpayurl` link to render as a QR code.
     pub async fn create_order(&self, order_id: &str, amount: i32, description: &str) -> Result<(String, String), String> {
-        // Return mock data for development
-        // In production, this would call WeChat's unifiedorder API
-        let prepay_id = format!("prepay_{}_{}", order_id, chrono::Utc::now().timestamp_millis());
-        let qr_code = self.create_qr_code_url(&prepay_id);
+        let out_trade_no = out_trade_no(order_id);
+
+        if self.use_mock {
+            tracing::info!("Mock WeChat order created: {} for {} cents", order_id, amount);
+            return Ok((format!("mock_{}", out_trade_no), format!("weixin://wxpay/bizpayurl?pr=mock{}", out_trade_no)));
+        }
+
+        if let Some(v3) = &self.v3 {
+            // v3 Native orders have no prepay_id; the trade number identifies the order
+            let code_url = v3.create_native_order(&out_trade_no, amount, description).await?;
+            tracing::info!("WeChat v3 order created: {} for {} cents", order_id, amount);
+            return Ok((out_trade_no, code_url));
+        }
+
+        let request = WeChatPayRequest {
+            appid: self.app_id.clone(),
+            mch_id: self.mch_id.clone(),
+            nonce_str: nonce_str(),
+            sign_type: self.sign_type.as_str().to_string(),
+            body: description.to_string(),
+            out_trade_no: out_trade_no.clone(),
+            total_fee: amount,
+            spbill_create_ip: self.spbill_create_ip.clone(),
+            notify_url: self.notify_url.clone(),
+            trade_type: "NATIVE".to_string(),
+            product_id: out_trade_no,
+        };
+
+        let params = to_params(&request)?;
+        let response = self.post("/pay/unifiedorder", params).await?;
+        let response: WeChatPayResponse = serde_json::from_value(serde_json::to_value(&response).map_err(|e| e.to_string())?)
+            .map_err(|e| format!("Unexpected WeChat response: {}", e))?;
+
+        if response.result_code.as_deref() != Some("SUCCESS") {
+            return Err(format!(
+                "WeChat unified order failed: {} {}",
+                response.err_code.unwrap_or_default(),
+                response.err_code_des.unwrap_or_default()
+            ));
+        }
+
+        let prepay_id = response.prepay_id.ok_or("WeChat response missing prepay_id")?;
+        let code_url = response.code_url.ok_or("WeChat response missing code_url")?;
 
         tracing::info!("WeChat order created: {} for {} cents", order_id, amount);
 
-        Ok((prepay_id, qr_code))
+        Ok((prepay_id, code_url))
+    }
+
+    /// Query an order by our order id. `trade_state` is one of
+    /// SUCCESS, NOTPAY, CLOSED, REFUND, USERPAYING, PAYERROR, REVOKED.
+    pub async fn query_order(&self, order_id: &str) -> Result<WeChatTrade, String> {
+        if self.use_mock {
+            return Ok(WeChatTrade { trade_state: "NOTPAY".to_string(), transaction_id: None, paid_at: None, amount: None });
+        }
+
+        if let Some(v3) = &self.v3 {
+            return v3.query_order(&out_trade_no(order_id)).await;
+        }
+
+        let response = self.post("/pay/orderquery", self.order_params(order_id)).await?;
+        if response.get("err_code").map(String::as_str) == Some(ORDER_NOT_EXIST) {
+            return Ok(WeChatTrade::not_exist());
+        }
+        if response.get("result_code").map(String::as_str) != Some("SUCCESS") {
+            return Err(format!(
+                "WeChat order query failed: {} {}",
+                response.get("err_code").cloned().unwrap_or_default(),
+                response.get("err_code_des").cloned().unwrap_or_default()
+            ));
+        }
+
+        let trade_state = response
+            .get("trade_state")
+            .cloned()
+            .ok_or("WeChat response missing trade_state")?;
+
+        tracing::info!("WeChat order queried: {} -> {}", order_id, trade_state);
+        Ok(WeChatTrade {
+            trade_state,
+            transaction_id: response.get("transaction_id").cloned(),
+            paid_at: response.get("time_end").and_then(|t| parse_time_end(t)),
+            amount: response.get("total_fee").and_then(|v| v.parse().ok()),
+        })
     }
 
-    // Stub: In production, this would query the actual WeChat Pay API
-    pub async fn query_order(&self, wechat_order_id: &str) -> Result<String, String> {
-        // Return mock data for development
-        // In production, this would call WeChat's orderquery API
-        tracing::info!("WeChat order queried: {}", wechat_order_id);
+    /// Close an unpaid order so it can no longer be paid
+    pub async fn close_order(&self, order_id: &str) -> Result<(), String> {
+        if self.use_mock {
+            return Ok(());
+        }
+
+        if let Some(v3) = &self.v3 {
+            return v3.close_order(&out_trade_no(order_id)).await;
+        }
+
+        let response = self.post("/pay/closeorder", self.order_params(order_id)).await?;
+        match response.get("result_code").map(String::as_str) {
+            Some("SUCCESS") => Ok(()),
+            // Already closed or never created counts as closed
+            _ if matches!(response.get("err_code").map(String::as_str), Some("ORDERCLOSED") | Some(ORDER_NOT_EXIST)) => Ok(()),
+            _ => Err(format!(
+                "WeChat close order failed: {} {}",
+                response.get("err_code").cloned().unwrap_or_default(),
+                response.get("err_code_des").cloned().unwrap_or_default()
+            )),
+        }
+    }
+
+    /// Refund all or part of a paid order
+    pub async fn refund(
+        &self,
+        order_id: &str,
+        refund_id: &str,
+        refund_amount: i32,
+        total_amount: i32,
+        reason: &str,
+    ) -> Result<WeChatRefund, RefundError> {
+        if self.use_mock {
+            tracing::info!("Mock WeChat refund {} of {} cents on order {} ({})", refund_id, refund_amount, order_id, reason);
+            return Ok(WeChatRefund {
+                refund_id: Some(format!("mock_{}", out_trade_no(refund_id))),
+                out_refund_no: out_trade_no(refund_id),
+                status: "SUCCESS".to_string(),
+            });
+        }
+
+        if let Some(v3) = &self.v3 {
+            return v3
+                .refund(&out_trade_no(order_id), &out_trade_no(refund_id), refund_amount, total_amount, reason)
+                .await;
+        }
+
+        let cert_client = self
+            .cert_client
+            .as_ref()
+            .ok_or_else(|| RefundError::Rejected("v2 refunds need the merchant certificate (WECHAT_MCH_CERT_PATH)".to_string()))?;
+        let out_refund_no = out_trade_no(refund_id);
+        let mut params = self.order_params(order_id);
+        params.insert("out_refund_no".to_string(), out_refund_no.clone());
+        params.insert("total_fee".to_string(), total_amount.to_string());
+        params.insert("refund_fee".to_string(), refund_amount.to_string());
+        params.insert("refund_desc".to_string(), reason.to_string());
+        if !self.notify_url.is_empty() {
+            params.insert("notify_url".to_string(), self.notify_url.clone());
+        }
+
+        // Sending the same out_refund_no again is accepted without refunding twice
+        let response = self.post_via(cert_client, "/secapi/pay/refund", params).await?;
+        if response.get("result_code").map(String::as_str) != Some("SUCCESS") {
+            let err_code = response.get("err_code").cloned().unwrap_or_default();
+            let message = format!(
+                "WeChat refund failed: {} {}",
+                err_code,
+                response.get("err_code_des").cloned().unwrap_or_default()
+            );
+            return Err(match err_code.as_str() {
+                "SYSTEMERROR" | "BIZERR_NEED_RETRY" | "FREQUENCY_LIMITED" => RefundError::Unknown(message),
+                _ => RefundError::Rejected(message),
+            });
+        }
+        let refund_id = response.get("refund_id").cloned();
+
+        // Acceptance says nothing about the outcome; a resend after a restart learns it here
+        let status = match self.query_refund(&out_refund_no).await {
+            Ok(status) => status,
+            Err(e) => {
+                tracing::warn!("WeChat refund {} accepted, status unknown: {}", out_refund_no, e);
+                "PROCESSING".to_string()
+            }
+        };
+        tracing::info!("WeChat refund {} on order {}: {}", out_refund_no, order_id, status);
+        Ok(WeChatRefund { refund_id, out_refund_no, status })
+    }
+
+    /// Status of a v2 refund in the terms of `WeChatRefund::status`
+    async fn query_refund(&self, out_refund_no: &str) -> Result<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.app_id.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), nonce_str());
+        params.insert("sign_type".to_string(), self.sign_type.as_str().to_string());
+        params.insert("out_refund_no".to_string(), out_refund_no.to_string());
+
+        let response = self.post("/pay/refundquery", params).await?;
+        if response.get("result_code").map(String::as_str) != Some("SUCCESS") {
+            return Err(format!(
+                "WeChat refund query failed: {} {}",
+                response.get("err_code").cloned().unwrap_or_default(),
+                response.get("err_code_des").cloned().unwrap_or_default()
+            ));
+        }
+        Ok(v2_refund_status(response.get("refund_status_0").map(String::as_str)))
+    }
 
-        // Simulate: return "SUCCESS" meaning paid
-        Ok("SUCCESS".to_string())
+    fn order_params(&self, order_id: &str) -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.app_id.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("out_trade_no".to_string(), out_trade_no(order_id));
+        params.insert("nonce_str".to_string(), nonce_str());
+        params.insert("sign_type".to_string(), self.sign_type.as_str().to_string());
+        params
+    }
+
+    /// Sign, send and verify one v2 API call
+    async fn post(&self, path: &str, params: BTreeMap<String, String>) -> Result<BTreeMap<String, String>, String> {
+        self.post_via(&self.client, path, params).await
+    }
+
+    async fn post_via(&self, client: &Client, path: &str, params: BTreeMap<String, String>) -> Result<BTreeMap<String, String>, String> {
+        let body = self.send_via(client, path, params).await?;
+        let fields = parse_xml(&body)?;
+
+        if fields.get("return_code").map(String::as_str) != Some("SUCCESS") {
+            return Err(format!(
+                "WeChat Pay error: {}",
+                fields.get("return_msg").cloned().unwrap_or_default()
+            ));
+        }
+
+        if !self.verify(&fields) {
+            return Err("WeChat response signature verification failed".to_string());
+        }
+
+        Ok(fields)
+    }
+
+    /// Sign and send one v2 API call, returning the raw response body
+    async fn send(&self, path: &str, params: BTreeMap<String, String>) -> Result<String, String> {
+        self.send_via(&self.client, path, params).await
+    }
+
+    async fn send_via(&self, client: &Client, path: &str, mut params: BTreeMap<String, String>) -> Result<String, String> {
+        let sign = self.sign(&params);
+        params.insert("sign".to_string(), sign);
+
+        let response = client
+            .post(format!("{}{}", self.api_base, path))
+            .header("Content-Type", "text/xml; charset=utf-8")
+            .body(to_xml(&params))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach WeChat Pay: {}", e))?;
+
+        if !response.status().is_success() {
+            return Err(format!("WeChat Pay HTTP error: {}", response.status()));
+        }
+
+        response.text().await.map_err(|e| format!("Failed to read WeChat response: {}", e))
+    }
+
+    /// Raw trade bill (ALL) of one day. Empty when there were no transactions.
+    pub async fn download_bill_file(&self, date: NaiveDate) -> Result<Vec<u8>, String> {
+        if self.use_mock {
+            return Err("Mock WeChat Pay has no bills".to_string());
+        }
+
+        if let Some(v3) = &self.v3 {
+            return v3.download_trade_bill(&date.format("%Y-%m-%d").to_string()).await;
+        }
+
+        let mut params = BTreeMap::new();
+        params.insert("appid".to_string(), self.app_id.clone());
+        params.insert("mch_id".to_string(), self.mch_id.clone());
+        params.insert("nonce_str".to_string(), nonce_str());
+        params.insert("sign_type".to_string(), self.sign_type.as_str().to_string());
+        params.insert("bill_date".to_string(), date.format("%Y%m%d").to_string());
+        params.insert("bill_type".to_string(), "ALL".to_string());
+
+        // The bill comes back as plain CSV text; errors come back as XML
+        let body = self.send("/pay/downloadbill", params).await?;
+        if !body.trim_start().starts_with("<xml>") {
+            return Ok(body.into_bytes());
+        }
+        let fields = parse_xml(&body)?;
+        let message = fields.get("return_msg").cloned().unwrap_or_default();
+        if message.contains("No Bill Exist") {
+            return Ok(Vec::new());
+        }
+        Err(format!("WeChat bill download failed: {}", message))
+    }
+
+    /// Verify and decode a payment or refund callback. Returns `None` for
+    /// well-formed notifications we have nothing to do with.
+    pub fn parse_notification(&self, headers: &WeChatSignatureHeaders, body: &str) -> Result<Option<WeChatNotice>, String> {
+        if self.use_mock {
+            return Err("Mock WeChat Pay does not send notifications".to_string());
+        }
+        if let Some(v3) = &self.v3 {
+            v3.verify_signature(headers, body)?;
+            let notification: Notification = serde_json::from_str(body)
+                .map_err(|e| format!("Invalid WeChat notification: {}", e))?;
+            let event_type = notification.event_type.as_str();
+            if event_type != "TRANSACTION.SUCCESS" && !event_type.starts_with("REFUND.") {
+                tracing::info!("[Notify] Ignoring WeChat event {} ({})", notification.event_type, notification.id);
+                return Ok(None);
+            }
+
+            let plaintext = v3.decrypt_resource(&notification.resource)?;
+            if event_type.starts_with("REFUND.") {
+                let refund: V3RefundNotice = serde_json::from_str(&plaintext)
+                    .map_err(|e| format!("Invalid WeChat refund notification: {}", e))?;
+                return Ok(Some(WeChatNotice::Refund {
+                    out_trade_no: refund.out_trade_no,
+                    refund: WeChatRefund {
+                        refund_id: refund.refund_id,
+                        out_refund_no: refund.out_refund_no,
+                        status: refund.refund_status,
+                    },
+                }));
+            }
+
+            let transaction: V3Transaction = serde_json::from_str(&plaintext)
+                .map_err(|e| format!("Invalid WeChat transaction: {}", e))?;
+            let out_trade_no = transaction
+                .out_trade_no
+                .clone()
+                .ok_or("WeChat notification missing out_trade_no")?;
+            return Ok(Some(WeChatNotice::Payment { out_trade_no, trade: transaction.into() }));
+        }
+
+        let fields = parse_xml(body)?;
+        if fields.get("return_code").map(String::as_str) != Some("SUCCESS") {
+            return Err(format!(
+                "WeChat notification error: {}",
+                fields.get("return_msg").cloned().unwrap_or_default()
+            ));
+        }
+        // Refund results carry no signature; req_info only decrypts with our API key
+        if let Some(req_info) = fields.get("req_info") {
+            return self.parse_refund_notification(&fields, req_info).map(Some);
+        }
+        if !self.verify(&fields) {
+            return Err("WeChat notification signature verification failed".to_string());
+        }
+        if fields.get("result_code").map(String::as_str) != Some("SUCCESS") {
+            return Ok(None);
+        }
+
+        let out_trade_no = fields
+            .get("out_trade_no")
+            .cloned()
+            .ok_or("WeChat notification missing out_trade_no")?;
+        Ok(Some(WeChatNotice::Payment {
+            out_trade_no,
+            trade: WeChatTrade {
+                trade_state: "SUCCESS".to_string(),
+                transaction_id: fields.get("transaction_id").cloned(),
+                paid_at: fields.get("time_end").and_then(|t| parse_time_end(t)),
+                amount: fields.get("total_fee").and_then(|v| v.parse().ok()),
+            },
+        }))
+    }
+
+    /// v2 refund result, whose `req_info` is AES-256-ECB encrypted with the MD5 of the API key
+    fn parse_refund_notification(&self, fields: &BTreeMap<String, String>, req_info: &str) -> Result<WeChatNotice, String> {
+        if fields.get("mch_id") != Some(&self.mch_id) {
+            return Err("WeChat refund notification is for another merchant".to_string());
+        }
+        let plaintext = decrypt_req_info(req_info, &self.api_key)?;
+        let info = parse_xml_root(&plaintext, "root")?;
+        let field = |k: &str| info.get(k).cloned().ok_or_else(|| format!("WeChat refund notification missing {}", k));
+
+        Ok(WeChatNotice::Refund {
+            out_trade_no: field("out_trade_no")?,
+            refund: WeChatRefund {
+                refund_id: info.get("refund_id").cloned(),
+                out_refund_no: field("out_refund_no")?,
+                status: v2_refund_status(info.get("refund_status").map(String::as_str)),
+            },
+        })
+    }
+
+    /// v2 signature: sorted non-empty `k=v` pairs joined with `&`, then `&key=API_KEY`
+    pub fn sign(&self, params: &BTreeMap<String, String>) -> String {
+        let mut payload = params
+            .iter()
+            .filter(|(k, v)| k.as_str() != "sign" && !v.is_empty())
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        payload.push_str(&format!("&key={}", self.api_key));
+
+        let digest = match self.sign_type {
+            SignType::Md5 => hex::encode(Md5::digest(payload.as_bytes())),
+            SignType::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(self.api_key.as_bytes())
+                    .expect("HMAC accepts keys of any length");
+                mac.update(payload.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+        };
+        digest.to_uppercase()
+    }
+
+    /// Check the `sign` field of a message received from WeChat
+    pub fn verify(&self, fields: &BTreeMap<String, String>) -> bool {
+        match fields.get("sign") {
+            Some(sign) => constant_time_eq(sign.as_bytes(), self.sign(fields).as_bytes()),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl WeChatService {
+    /// v2 merchant of the signing guide, talking to `api_base`
+    pub fn test_v2(api_base: &str, api_key: &str, sign_type: SignType) -> Self {
+        WeChatService {
+            client: Client::new(),
+            app_id: "wxd930ea5d5a258f4f".to_string(),
+            mch_id: "10000100".to_string(),
+            api_key: api_key.to_string(),
+            notify_url: "https://example.com/wechat/notify".to_string(),
+            api_base: api_base.to_string(),
+            sign_type,
+            spbill_create_ip: "127.0.0.1".to_string(),
+            // Mock servers are plain HTTP, so no certificate is presented
+            cert_client: Some(Client::new()),
+            v3: None,
+            use_mock: false,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for WeChatService {
+    fn kind(&self) -> PaymentProviderKind {
+        PaymentProviderKind::Wechat
+    }
+
+    async fn create(&self, order_id: &str, amount: i32, description: &str) -> Result<String, String> {
+        self.create_order(order_id, amount, description).await.map(|(_, code_url)| code_url)
+    }
+
+    async fn query(&self, order_id: &str) -> Result<ProviderTrade, String> {
+        let trade = self.query_order(order_id).await?;
+        Ok(trade.into())
+    }
+
+    async fn close(&self, order_id: &str) -> Result<(), String> {
+        self.close_order(order_id).await
+    }
+
+    async fn refund(
+        &self,
+        order_id: &str,
+        refund_id: &str,
+        refund_amount: i32,
+        total_amount: i32,
+        reason: &str,
+    ) -> Result<ProviderRefund, RefundError> {
+        let refund = WeChatService::refund(self, order_id, refund_id, refund_amount, total_amount, reason).await?;
+        Ok(ProviderRefund { status: refund.refund_status(), refund_id: refund.refund_id })
+    }
+
+    fn verify_notification(&self, request: &NotifyRequest) -> Result<Option<PaymentNotice>, String> {
+        let headers = WeChatSignatureHeaders {
+            timestamp: request.header("Wechatpay-Timestamp"),
+            nonce: request.header("Wechatpay-Nonce"),
+            signature: request.header("Wechatpay-Signature"),
+            serial: request.header("Wechatpay-Serial"),
+        };
+
+        Ok(self.parse_notification(&headers, &request.body)?.map(|notice| match notice {
+            WeChatNotice::Payment { out_trade_no, trade } => PaymentNotice::Payment { out_trade_no, trade: trade.into() },
+            WeChatNotice::Refund { refund, .. } => PaymentNotice::Refund {
+                out_refund_no: refund.out_refund_no.clone(),
+                refund: ProviderRefund { status: refund.refund_status(), refund_id: refund.refund_id },
+            },
+        }))
+    }
+
+    fn notification_reply(&self, error: Option<&str>) -> NotifyReply {
+        let (status, content_type, body) = match (&self.v3, error) {
+            (Some(_), None) => (200, "application/json", json!({ "code": "SUCCESS", "message": "OK" }).to_string()),
+            (Some(_), Some(message)) => (500, "application/json", json!({ "code": "FAIL", "message": message }).to_string()),
+            (None, error) => {
+                let mut params = BTreeMap::new();
+                params.insert("return_code".to_string(), if error.is_some() { "FAIL" } else { "SUCCESS" }.to_string());
+                params.insert("return_msg".to_string(), error.unwrap_or("OK").to_string());
+                (200, "text/xml; charset=utf-8", to_xml(&params))
+            }
+        };
+        NotifyReply { status, content_type, body }
+    }
+
+    fn notify_path(&self) -> String {
+        notify_path_from_url(&self.notify_url, DEFAULT_NOTIFY_PATH)
+    }
+
+    async fn download_bill(&self, date: NaiveDate) -> Result<Vec<BillEntry>, String> {
+        parse_bill(&self.download_bill_file(date).await?)
+    }
+}
+
+impl From<WeChatTrade> for ProviderTrade {
+    fn from(trade: WeChatTrade) -> Self {
+        ProviderTrade {
+            status: trade.order_status(),
+            needs_close: trade.trade_state == "PAYERROR",
+            state: trade.trade_state,
+            transaction_id: trade.transaction_id,
+            paid_at: trade.paid_at,
+            amount: trade.amount,
+        }
     }
 }
 
@@ -81,3 +728,456 @@ impl Default for WeChatService {
         Self::new().expect("Failed to create WeChatService")
     }
 }
+
+/// HTTP client presenting the merchant API certificate, from `WECHAT_MCH_CERT_PATH`
+/// and `WECHAT_MCH_PRIVATE_KEY_PATH`. `None` when no certificate is configured.
+fn merchant_cert_client() -> Result<Option<Client>, String> {
+    let Ok(cert_path) = env::var("WECHAT_MCH_CERT_PATH") else {
+        tracing::info!("WECHAT_MCH_CERT_PATH not set, WeChat v2 refunds disabled");
+        return Ok(None);
+    };
+    let key_path = env::var("WECHAT_MCH_PRIVATE_KEY_PATH").map_err(|_| "WECHAT_MCH_PRIVATE_KEY_PATH not set")?;
+    let cert = std::fs::read(&cert_path).map_err(|e| format!("Failed to read merchant certificate: {}", e))?;
+    let key = std::fs::read(&key_path).map_err(|e| format!("Failed to read merchant private key: {}", e))?;
+    let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+        .map_err(|e| format!("Invalid merchant certificate: {}", e))?;
+
+    Client::builder()
+        .identity(identity)
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map(Some)
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// WeChat limits out_trade_no to 32 characters; our UUIDs fit once the dashes are dropped
+pub fn out_trade_no(order_id: &str) -> String {
+    order_id.replace('-', "")
+}
+
+/// Parse a trade bill of type ALL. Every field is prefixed with a backtick;
+/// the bill ends with a totals section.
+pub fn parse_bill(data: &[u8]) -> Result<Vec<BillEntry>, String> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.trim_start_matches('\u{feff}').lines().map(str::trim).filter(|l| !l.is_empty());
+    let Some(header) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let header: Vec<&str> = header.split(',').map(str::trim).collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| *h == name)
+            .ok_or_else(|| format!("WeChat bill has no {} column", name))
+    };
+    let traded_at = column("交易时间")?;
+    let transaction_id = column("微信订单号")?;
+    let out_trade_no = column("商户订单号")?;
+    let state = column("交易状态")?;
+    // Newer bills add the pre-discount 订单金额 and the requested refund amount
+    let amount = column("订单金额").or_else(|_| column("应结订单金额"))?;
+    let refund_amount = column("申请退款金额").or_else(|_| column("退款金额"))?;
+
+    let mut entries = Vec::new();
+    for line in lines {
+        // Start of the totals section
+        if line.starts_with("总交易单数") {
+            break;
+        }
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim().trim_start_matches('`')).collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+        let (kind, amount) = match field(state) {
+            "SUCCESS" => (BillEntryKind::Payment, field(amount)),
+            "REFUND" => (BillEntryKind::Refund, field(refund_amount)),
+            // REVOKED: reversed the same day, no money moved
+            _ => continue,
+        };
+        entries.push(BillEntry {
+            kind,
+            out_trade_no: field(out_trade_no).to_string(),
+            transaction_id: field(transaction_id).to_string(),
+            amount: parse_cents(amount).ok_or_else(|| format!("Invalid amount in WeChat bill: {}", amount))?.abs(),
+            time: parse_beijing_time(field(traded_at)),
+        });
+    }
+    Ok(entries)
+}
+
+/// A v2 refund status in the terms of `WeChatRefund::status`
+fn v2_refund_status(status: Option<&str>) -> String {
+    match status {
+        Some("SUCCESS") => "SUCCESS",
+        Some("REFUNDCLOSE") => "CLOSED",
+        Some("CHANGE") => "ABNORMAL",
+        _ => "PROCESSING",
+    }
+    .to_string()
+}
+
+/// Decrypt the `req_info` of a v2 refund notification: base64 of AES-256-ECB
+/// with PKCS#7 padding, keyed with the lowercase hex MD5 of the API key
+fn decrypt_req_info(req_info: &str, api_key: &str) -> Result<String, String> {
+    let mut data = STANDARD
+        .decode(req_info.trim())
+        .map_err(|e| format!("Invalid WeChat req_info: {}", e))?;
+    if data.is_empty() || data.len() % 16 != 0 {
+        return Err("Invalid WeChat req_info length".to_string());
+    }
+
+    let key = hex::encode(Md5::digest(api_key.as_bytes()));
+    // KeyInit stays unimported: it would make Hmac::new_from_slice ambiguous
+    let cipher = <Aes256 as aes::cipher::KeyInit>::new(GenericArray::from_slice(key.as_bytes()));
+    for block in data.chunks_exact_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    // A wrong key shows up as bad padding
+    let pad = *data.last().unwrap_or(&0) as usize;
+    if !(1..=16).contains(&pad) || !data[data.len() - pad..].iter().all(|&b| b as usize == pad) {
+        return Err("WeChat req_info could not be decrypted".to_string());
+    }
+    data.truncate(data.len() - pad);
+    String::from_utf8(data).map_err(|_| "WeChat req_info could not be decrypted".to_string())
+}
+
+/// v2 `time_end` is `yyyyMMddHHmmss` in Beijing time
+fn parse_time_end(time_end: &str) -> Option<i64> {
+    let naive = chrono::NaiveDateTime::parse_from_str(time_end, "%Y%m%d%H%M%S").ok()?;
+    let beijing = chrono::FixedOffset::east_opt(8 * 3600)?;
+    naive.and_local_timezone(beijing).single().map(|t| t.timestamp())
+}
+
+fn nonce_str() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn to_params<T: Serialize>(request: &T) -> Result<BTreeMap<String, String>, String> {
+    let value = serde_json::to_value(request).map_err(|e| e.to_string())?;
+    let object = value.as_object().ok_or("Request must serialize to an object")?;
+    Ok(object
+        .iter()
+        .map(|(k, v)| {
+            let v = match v {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (k.clone(), v)
+        })
+        .collect())
+}
+
+pub fn to_xml(params: &BTreeMap<String, String>) -> String {
+    let mut xml = String::from("<xml>");
+    for (k, v) in params {
+        xml.push_str(&format!("<{0}><![CDATA[{1}]]></{0}>", k, v.replace("]]>", "]]]]><![CDATA[>")));
+    }
+    xml.push_str("</xml>");
+    xml
+}
+
+/// Parse the flat `<xml><key>value</key>...</xml>` documents used by the v2 API
+pub fn parse_xml(xml: &str) -> Result<BTreeMap<String, String>, String> {
+    parse_xml_root(xml, "xml")
+}
+
+/// `parse_xml` for a document whose root element is `root`
+fn parse_xml_root(xml: &str, root: &str) -> Result<BTreeMap<String, String>, String> {
+    let root_open = format!("<{}>", root);
+    let root_close = format!("</{}>", root);
+    let start = xml.find(&root_open).ok_or_else(|| format!("Invalid WeChat XML: missing {}", root_open))? + root_open.len();
+    let end = xml.rfind(&root_close).ok_or_else(|| format!("Invalid WeChat XML: missing {}", root_close))?;
+    let mut rest = &xml[start..end];
+    let mut fields = BTreeMap::new();
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let open_end = rest.find('>').ok_or("Invalid WeChat XML: unterminated tag")?;
+        let tag = rest
+            .strip_prefix('<')
+            .map(|r| &r[..open_end - 1])
+            .ok_or("Invalid WeChat XML: expected tag")?;
+        let close = format!("</{}>", tag);
+        let body_start = open_end + 1;
+        let body_len = rest[body_start..].find(&close).ok_or("Invalid WeChat XML: unclosed tag")?;
+        let raw = &rest[body_start..body_start + body_len];
+
+        let value = match raw.trim().strip_prefix("<![CDATA[").and_then(|v| v.strip_suffix("]]>")) {
+            // `to_xml` splits a literal "]]>" across two sections
+            Some(cdata) => cdata.replace("]]]]><![CDATA[>", "]]>"),
+            None => unescape_xml(raw.trim()),
+        };
+        fields.insert(tag.to_string(), value);
+        rest = &rest[body_start + body_len + close.len()..];
+    }
+
+    Ok(fields)
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tiny_http::{Response, Server};
+
+    // Key and expected signatures from the WeChat Pay v2 signing guide
+    const KEY: &str = "192006250b4c09247ec02edce69f6a2d";
+    const CODE_URL: &str = "weixin://wxpay/bizpayurl?pr=8q2GAoW";
+
+    fn service(api_base: &str, api_key: &str, sign_type: SignType) -> WeChatService {
+        WeChatService::test_v2(api_base, api_key, sign_type)
+    }
+
+    fn guide_params() -> BTreeMap<String, String> {
+        [("appid", "wxd930ea5d5a258f4f"), ("mch_id", "10000100"), ("device_info", "1000"), ("body", "test"), ("nonce_str", "ibuaiVcKdpRxkhJA")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// Local stand-in for the v2 API. Rejects requests with a bad signature
+    /// and answers unified orders with a code_url and refunds by their
+    /// out_refund_no, signed with `reply_key`.
+    fn mock_wechat(sign_type: SignType, reply_key: &'static str) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let api_base = format!("http://{}", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            let merchant = service("", KEY, sign_type);
+            let wechat = service("", reply_key, sign_type);
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let fields = parse_xml(&body).unwrap();
+
+                let field = |k: &str| fields.get(k).map(String::as_str).unwrap_or_default();
+                let refund_result = match field("out_refund_no") {
+                    "refundnotenough" => [("result_code", "FAIL"), ("err_code", "NOTENOUGH")],
+                    "refundbusy" => [("result_code", "FAIL"), ("err_code", "SYSTEMERROR")],
+                    _ => [("result_code", "SUCCESS"), ("refund_id", "50000408012016040101234567890")],
+                };
+                let result: &[(&str, &str)] = match request.url() {
+                    "/pay/unifiedorder" => &[
+                        ("result_code", "SUCCESS"),
+                        ("trade_type", "NATIVE"),
+                        ("prepay_id", "wx201410272009395522657a690389285100"),
+                        ("code_url", CODE_URL),
+                    ],
+                    "/secapi/pay/refund" if field("refund_fee") == "300" && field("total_fee") == "500" => &refund_result,
+                    "/pay/refundquery" => &[("result_code", "SUCCESS"), ("refund_count", "1"), ("refund_status_0", "SUCCESS")],
+                    _ => &[],
+                };
+
+                let mut reply = BTreeMap::new();
+                let sign_type_matches = field("sign_type") == sign_type.as_str();
+                if result.is_empty() || !sign_type_matches || !merchant.verify(&fields) {
+                    reply.insert("return_code".to_string(), "FAIL".to_string());
+                    reply.insert("return_msg".to_string(), "签名错误".to_string());
+                } else {
+                    reply.insert("return_code".to_string(), "SUCCESS".to_string());
+                    reply.insert("nonce_str".to_string(), "IITRi8Iabbblz1Jc".to_string());
+                    for (k, v) in result {
+                        reply.insert(k.to_string(), v.to_string());
+                    }
+                    let sign = wechat.sign(&reply);
+                    reply.insert("sign".to_string(), sign);
+                }
+                request.respond(Response::from_string(to_xml(&reply))).unwrap();
+            }
+        });
+        api_base
+    }
+
+    #[test]
+    fn sign_matches_reference() {
+        let params = guide_params();
+        assert_eq!(service("", KEY, SignType::Md5).sign(&params), "9A0A8659F005D6984697E2CA0A9CF3B7");
+        assert_eq!(
+            service("", KEY, SignType::HmacSha256).sign(&params),
+            "6A9AE1657590FD6257D693A078E1C3E4BB6BA4DC30B23E0EE2496E54170DACD6"
+        );
+    }
+
+    #[test]
+    fn verify_rejects_tampered_messages() {
+        for sign_type in [SignType::Md5, SignType::HmacSha256] {
+            let wechat = service("", KEY, sign_type);
+            let mut fields = guide_params();
+            assert!(!wechat.verify(&fields), "unsigned message accepted");
+
+            fields.insert("sign".to_string(), wechat.sign(&fields));
+            assert!(wechat.verify(&fields));
+
+            fields.insert("body".to_string(), "test2".to_string());
+            assert!(!wechat.verify(&fields), "tampered message accepted");
+            assert!(!service("", "another_key", sign_type).verify(&fields));
+        }
+    }
+
+    #[test]
+    fn parse_xml_round_trip() {
+        let mut params = guide_params();
+        params.insert("attach".to_string(), "a<b> & \"c\" ]]> d".to_string());
+        params.insert("total_fee".to_string(), "300".to_string());
+        assert_eq!(parse_xml(&to_xml(&params)).unwrap(), params);
+
+        let plain = parse_xml("<xml>\n  <return_code>SUCCESS</return_code>\n  <return_msg>a &amp; b</return_msg>\n</xml>").unwrap();
+        assert_eq!(plain.get("return_code").map(String::as_str), Some("SUCCESS"));
+        assert_eq!(plain.get("return_msg").map(String::as_str), Some("a & b"));
+
+        assert!(parse_xml("<xml><return_code>SUCCESS</xml>").is_err());
+        assert!(parse_xml("return_code=SUCCESS").is_err());
+    }
+
+    #[tokio::test]
+    async fn create_order_returns_code_url() {
+        for sign_type in [SignType::Md5, SignType::HmacSha256] {
+            let wechat = service(&mock_wechat(sign_type, KEY), KEY, sign_type);
+            let (prepay_id, code_url) = wechat.create_order("0b8f-42", 300, "AI Photo Download").await.unwrap();
+            assert_eq!(prepay_id, "wx201410272009395522657a690389285100");
+            assert_eq!(code_url, CODE_URL);
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_signatures_are_rejected() {
+        // Reply signed with a different key
+        let wechat = service(&mock_wechat(SignType::Md5, "forged_key"), KEY, SignType::Md5);
+        let err = wechat.create_order("0b8f-42", 300, "AI Photo Download").await.unwrap_err();
+        assert!(err.contains("signature verification failed"), "{}", err);
+
+        // Request signed with the wrong key is refused by WeChat
+        let wechat = service(&mock_wechat(SignType::Md5, KEY), "wrong_key", SignType::Md5);
+        let err = wechat.create_order("0b8f-42", 300, "AI Photo Download").await.unwrap_err();
+        assert!(err.contains("签名错误"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn refund_is_sent_with_the_merchant_certificate() {
+        let wechat = service(&mock_wechat(SignType::Md5, KEY), KEY, SignType::Md5);
+        let refund = wechat.refund("0b8f-42", "refund-1", 300, 500, "Order cancelled").await.unwrap();
+        assert_eq!(refund.out_refund_no, "refund1");
+        assert_eq!(refund.refund_id.as_deref(), Some("50000408012016040101234567890"));
+        assert_eq!(refund.refund_status(), RefundStatus::Success);
+
+        let without_cert = WeChatService { cert_client: None, ..service(&mock_wechat(SignType::Md5, KEY), KEY, SignType::Md5) };
+        let err = without_cert.refund("0b8f-42", "refund-1", 300, 500, "Order cancelled").await.unwrap_err();
+        assert!(matches!(err, RefundError::Rejected(ref e) if e.contains("WECHAT_MCH_CERT_PATH")), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn refund_errors_are_classified() {
+        let wechat = service(&mock_wechat(SignType::Md5, KEY), KEY, SignType::Md5);
+        let err = wechat.refund("0b8f-42", "refund-notenough", 300, 500, "Order cancelled").await.unwrap_err();
+        assert!(matches!(err, RefundError::Rejected(ref e) if e.contains("NOTENOUGH")), "{}", err);
+
+        // May still go through; the poller sends it again
+        let err = wechat.refund("0b8f-42", "refund-busy", 300, 500, "Order cancelled").await.unwrap_err();
+        assert!(matches!(err, RefundError::Unknown(ref e) if e.contains("SYSTEMERROR")), "{}", err);
+
+        let forged = service(&mock_wechat(SignType::Md5, "forged_key"), KEY, SignType::Md5);
+        let err = forged.refund("0b8f-42", "refund-1", 300, 500, "Order cancelled").await.unwrap_err();
+        assert!(matches!(err, RefundError::Unknown(ref e) if e.contains("signature verification failed")), "{}", err);
+    }
+
+    #[test]
+    fn parse_bill_reads_payments_and_refunds() {
+        let entries = parse_bill(include_bytes!("../../tests/fixtures/wechat_bill.csv")).unwrap();
+        // The REVOKED line and the totals section are skipped
+        assert_eq!(entries.len(), 2);
+
+        let payment = &entries[0];
+        assert_eq!(payment.kind, BillEntryKind::Payment);
+        assert_eq!(payment.out_trade_no, "6f1c2a9e0b2d4c0e9a1b2c3d4e5f6a7b");
+        assert_eq!(payment.transaction_id, "4200002301202610181234567890");
+        assert_eq!(payment.amount, 300);
+        assert_eq!(payment.time, Some(chrono::Utc.with_ymd_and_hms(2026, 10, 18, 2, 15, 2).unwrap().timestamp()));
+
+        let refund = &entries[1];
+        assert_eq!(refund.kind, BillEntryKind::Refund);
+        assert_eq!(refund.out_trade_no, payment.out_trade_no);
+        assert_eq!(refund.amount, 300);
+    }
+
+    #[test]
+    fn parse_bill_rejects_unknown_layouts() {
+        assert!(parse_bill(b"").unwrap().is_empty());
+        let err = parse_bill("交易时间,微信订单号\n`2026-10-18 10:15:02,`42000023".as_bytes()).unwrap_err();
+        assert!(err.contains("商户订单号"), "{}", err);
+    }
+
+    /// v2 refund result for `out_refund_no`, encrypted the way WeChat does with `key`
+    fn refund_notification(key: &str, out_refund_no: &str, refund_status: &str) -> NotifyRequest {
+        use aes::cipher::BlockEncrypt;
+
+        let info = format!(
+            "<root><out_refund_no><![CDATA[{}]]></out_refund_no><out_trade_no><![CDATA[0b8f42]]></out_trade_no>\
+             <refund_id><![CDATA[50000408012016040101234567890]]></refund_id><refund_fee><![CDATA[300]]></refund_fee>\
+             <refund_status><![CDATA[{}]]></refund_status></root>",
+            out_refund_no, refund_status
+        );
+        let mut data = info.into_bytes();
+        let pad = 16 - data.len() % 16;
+        data.extend(std::iter::repeat_n(pad as u8, pad));
+        let cipher = <Aes256 as aes::cipher::KeyInit>::new(GenericArray::from_slice(hex::encode(Md5::digest(key.as_bytes())).as_bytes()));
+        for block in data.chunks_exact_mut(16) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        let mut fields = BTreeMap::new();
+        fields.insert("return_code".to_string(), "SUCCESS".to_string());
+        fields.insert("appid".to_string(), "wxd930ea5d5a258f4f".to_string());
+        fields.insert("mch_id".to_string(), "10000100".to_string());
+        fields.insert("nonce_str".to_string(), "TeqClE3i0mvn3DrK".to_string());
+        fields.insert("req_info".to_string(), STANDARD.encode(data));
+        NotifyRequest { body: to_xml(&fields), ..NotifyRequest::default() }
+    }
+
+    #[test]
+    fn refund_notification_is_decrypted() {
+        let wechat = service("", KEY, SignType::Md5);
+        for (wechat_status, status) in [
+            ("SUCCESS", RefundStatus::Success),
+            ("REFUNDCLOSE", RefundStatus::Closed),
+            ("CHANGE", RefundStatus::Abnormal),
+        ] {
+            let notice = wechat.verify_notification(&refund_notification(KEY, "refund42", wechat_status)).unwrap();
+            match notice {
+                Some(PaymentNotice::Refund { out_refund_no, refund }) => {
+                    assert_eq!(out_refund_no, "refund42");
+                    assert_eq!(refund.status, status);
+                    assert_eq!(refund.refund_id.as_deref(), Some("50000408012016040101234567890"));
+                }
+                other => panic!("expected a refund notice, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn refund_notification_under_another_key_is_rejected() {
+        let wechat = service("", KEY, SignType::Md5);
+        let err = wechat.verify_notification(&refund_notification("forged_key", "refund42", "SUCCESS")).unwrap_err();
+        assert!(err.contains("could not be decrypted"), "{}", err);
+
+        let mut request = refund_notification(KEY, "refund42", "SUCCESS");
+        request.body = request.body.replace("10000100", "10000999");
+        assert!(wechat.verify_notification(&request).is_err());
+    }
+}
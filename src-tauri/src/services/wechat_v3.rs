@@ -0,0 +1,597 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{Client, Method, StatusCode};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::Certificate;
+
+use super::payment_provider::RefundError;
+use super::wechat_service::{WeChatRefund, WeChatTrade};
+
+// Reject responses and notifications whose signed timestamp is too far off
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Headers WeChat attaches to signed responses and notifications
+#[derive(Debug, Clone, Default)]
+pub struct WeChatSignatureHeaders {
+    pub timestamp: String,
+    pub nonce: String,
+    pub signature: String,
+    pub serial: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationResource {
+    pub algorithm: String,
+    pub ciphertext: String,
+    pub associated_data: Option<String>,
+    pub nonce: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Notification {
+    pub id: String,
+    pub event_type: String,
+    pub resource: NotificationResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionAmount {
+    total: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct V3Transaction {
+    pub out_trade_no: Option<String>,
+    pub transaction_id: Option<String>,
+    pub trade_state: String,
+    pub success_time: Option<String>,
+    amount: Option<TransactionAmount>,
+}
+
+impl From<V3Transaction> for WeChatTrade {
+    fn from(tx: V3Transaction) -> Self {
+        WeChatTrade {
+            trade_state: tx.trade_state,
+            transaction_id: tx.transaction_id,
+            paid_at: tx
+                .success_time
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(&t).ok())
+                .map(|t| t.timestamp()),
+            amount: tx.amount.and_then(|a| a.total),
+        }
+    }
+}
+
+/// Decrypted resource of a REFUND.* notification
+#[derive(Debug, Deserialize)]
+pub struct V3RefundNotice {
+    pub out_trade_no: String,
+    pub out_refund_no: String,
+    pub refund_id: Option<String>,
+    pub refund_status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct V3Refund {
+    refund_id: Option<String>,
+    out_refund_no: String,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct V3Bill {
+    hash_type: String,
+    hash_value: String,
+    download_url: String,
+}
+
+/// WeChat Pay API v3: JSON bodies signed with the merchant RSA key,
+/// responses verified against the platform certificates or public key
+pub struct WeChatV3Client {
+    client: Client,
+    app_id: String,
+    mch_id: String,
+    notify_url: String,
+    api_base: String,
+    api_v3_key: Vec<u8>,
+    merchant_serial: String,
+    signing_key: SigningKey<Sha256>,
+    // By serial; more than one while WeChat rotates certificates
+    platform_keys: HashMap<String, VerifyingKey<Sha256>>,
+}
+
+impl WeChatV3Client {
+    pub fn new(app_id: String, mch_id: String, notify_url: String, api_base: String) -> Result<Self, String> {
+        let api_v3_key = env::var("WECHAT_API_V3_KEY").map_err(|_| "WECHAT_API_V3_KEY not set")?;
+        if api_v3_key.len() != 32 {
+            return Err("WECHAT_API_V3_KEY must be 32 bytes".to_string());
+        }
+        let merchant_serial = env::var("WECHAT_MCH_SERIAL_NO").map_err(|_| "WECHAT_MCH_SERIAL_NO not set")?;
+        let key_path = env::var("WECHAT_MCH_PRIVATE_KEY_PATH").map_err(|_| "WECHAT_MCH_PRIVATE_KEY_PATH not set")?;
+        let key_pem = std::fs::read_to_string(&key_path)
+            .map_err(|e| format!("Failed to read merchant private key: {}", e))?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&key_pem)
+            .map_err(|e| format!("Invalid merchant private key: {}", e))?;
+
+        let platform_keys = load_platform_keys()?;
+
+        Ok(Self {
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()
+                .map_err(|e| format!("Failed to create HTTP client: {}", e))?,
+            app_id,
+            mch_id,
+            notify_url,
+            api_base,
+            api_v3_key: api_v3_key.into_bytes(),
+            merchant_serial,
+            signing_key: SigningKey::<Sha256>::new(private_key),
+            platform_keys,
+        })
+    }
+
+    /// Native order; returns the `code_url` to render as a QR code
+    pub async fn create_native_order(&self, out_trade_no: &str, amount: i32, description: &str) -> Result<String, String> {
+        let body = json!({
+            "appid": self.app_id,
+            "mchid": self.mch_id,
+            "description": description,
+            "out_trade_no": out_trade_no,
+            "notify_url": self.notify_url,
+            "amount": { "total": amount, "currency": "CNY" },
+        });
+
+        let response = self.request(Method::POST, "/v3/pay/transactions/native", Some(body)).await?;
+        response
+            .get("code_url")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| "WeChat response missing code_url".to_string())
+    }
+
+    pub async fn query_order(&self, out_trade_no: &str) -> Result<WeChatTrade, String> {
+        let path = format!("/v3/pay/transactions/out-trade-no/{}?mchid={}", out_trade_no, self.mch_id);
+        let (status, response) = self.exchange(Method::GET, &path, None).await?;
+        if is_order_not_exist(status, &response) {
+            return Ok(WeChatTrade::not_exist());
+        }
+        if !status.is_success() {
+            return Err(api_error(status, &response));
+        }
+        let tx: V3Transaction = serde_json::from_value(response)
+            .map_err(|e| format!("Unexpected WeChat response: {}", e))?;
+        Ok(tx.into())
+    }
+
+    pub async fn close_order(&self, out_trade_no: &str) -> Result<(), String> {
+        let path = format!("/v3/pay/transactions/out-trade-no/{}/close", out_trade_no);
+        let (status, response) = self.exchange(Method::POST, &path, Some(json!({ "mchid": self.mch_id }))).await?;
+        // Never created: nothing to close
+        if status.is_success() || is_order_not_exist(status, &response) {
+            return Ok(());
+        }
+        Err(api_error(status, &response))
+    }
+
+    pub async fn refund(
+        &self,
+        out_trade_no: &str,
+        out_refund_no: &str,
+        refund_amount: i32,
+        total_amount: i32,
+        reason: &str,
+    ) -> Result<WeChatRefund, RefundError> {
+        let body = json!({
+            "out_trade_no": out_trade_no,
+            "out_refund_no": out_refund_no,
+            "reason": reason,
+            "notify_url": self.notify_url,
+            "amount": { "refund": refund_amount, "total": total_amount, "currency": "CNY" },
+        });
+
+        let (status, response) = self.exchange(Method::POST, "/v3/refund/domestic/refunds", Some(body)).await?;
+        // 4xx answers are refusals (NOT_ENOUGH, INVALID_REQUEST, ...); 429 and 5xx
+        // (FREQUENCY_LIMITED, SYSTEM_ERROR) leave the outcome open
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            return Err(RefundError::Rejected(api_error(status, &response)));
+        }
+        if !status.is_success() {
+            return Err(RefundError::Unknown(api_error(status, &response)));
+        }
+        let refund: V3Refund = serde_json::from_value(response)
+            .map_err(|e| format!("Unexpected WeChat response: {}", e))?;
+
+        Ok(WeChatRefund {
+            refund_id: refund.refund_id,
+            out_refund_no: refund.out_refund_no,
+            status: refund.status,
+        })
+    }
+
+    /// Raw trade bill (ALL) of one day (`yyyy-MM-dd`). Empty when there were no transactions.
+    pub async fn download_trade_bill(&self, bill_date: &str) -> Result<Vec<u8>, String> {
+        let path = format!("/v3/bill/tradebill?bill_date={}&bill_type=ALL", bill_date);
+        let response = match self.request(Method::GET, &path, None).await {
+            Ok(response) => response,
+            Err(e) if e.contains("NO_STATEMENT_EXIST") => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let bill: V3Bill = serde_json::from_value(response)
+            .map_err(|e| format!("Unexpected WeChat response: {}", e))?;
+
+        // The file download is signed like any request but its response is not;
+        // the hash from the signed response above vouches for it instead
+        let url = reqwest::Url::parse(&bill.download_url).map_err(|e| format!("Invalid bill download URL: {}", e))?;
+        let download_path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let response = self
+            .client
+            .get(url)
+            .header("Authorization", self.authorization("GET", &download_path, ""))
+            .header("User-Agent", "ai-photobooth")
+            .timeout(std::time::Duration::from_secs(60))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to download WeChat bill: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("WeChat bill download error: {}", response.status()));
+        }
+        let bytes = response.bytes().await.map_err(|e| format!("Failed to download WeChat bill: {}", e))?;
+
+        if !bill.hash_type.eq_ignore_ascii_case("SHA1") || !hex::encode(Sha1::digest(&bytes)).eq_ignore_ascii_case(&bill.hash_value) {
+            return Err("WeChat bill hash mismatch".to_string());
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Verify a signed response or notification against the platform key it names
+    pub fn verify_signature(&self, headers: &WeChatSignatureHeaders, body: &str) -> Result<(), String> {
+        let platform_key = self
+            .platform_keys
+            .get(&headers.serial)
+            .ok_or_else(|| format!("Unknown WeChat platform serial: {}", headers.serial))?;
+
+        let timestamp: i64 = headers.timestamp.parse().map_err(|_| "Invalid WeChat timestamp")?;
+        if (chrono::Utc::now().timestamp() - timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+            return Err("WeChat signature timestamp expired".to_string());
+        }
+
+        let message = format!("{}\n{}\n{}\n", headers.timestamp, headers.nonce, body);
+        let signature_bytes = STANDARD
+            .decode(&headers.signature)
+            .map_err(|_| "Invalid WeChat signature encoding")?;
+        let signature = Signature::try_from(signature_bytes.as_slice())
+            .map_err(|_| "Invalid WeChat signature")?;
+
+        platform_key
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| "WeChat signature verification failed".to_string())
+    }
+
+    /// Decrypt the AES-256-GCM `resource` of a payment or refund notification
+    pub fn decrypt_resource(&self, resource: &NotificationResource) -> Result<String, String> {
+        if resource.algorithm != "AEAD_AES_256_GCM" {
+            return Err(format!("Unsupported notification algorithm: {}", resource.algorithm));
+        }
+
+        let cipher = Aes256Gcm::new_from_slice(&self.api_v3_key).map_err(|e| e.to_string())?;
+        if resource.nonce.len() != 12 {
+            return Err("Invalid notification nonce".to_string());
+        }
+        let ciphertext = STANDARD
+            .decode(&resource.ciphertext)
+            .map_err(|_| "Invalid notification ciphertext")?;
+
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(resource.nonce.as_bytes()),
+                Payload {
+                    msg: &ciphertext,
+                    aad: resource.associated_data.as_deref().unwrap_or("").as_bytes(),
+                },
+            )
+            .map_err(|_| "Failed to decrypt WeChat notification".to_string())?;
+
+        String::from_utf8(plaintext).map_err(|_| "Notification is not UTF-8".to_string())
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<serde_json::Value, String> {
+        let (status, response) = self.exchange(method, path, body).await?;
+        if !status.is_success() {
+            return Err(api_error(status, &response));
+        }
+        Ok(response)
+    }
+
+    /// Send a signed request. Error answers come back with their status; only
+    /// successful responses are signature-checked.
+    async fn exchange(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<(StatusCode, serde_json::Value), String> {
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let authorization = self.authorization(method.as_str(), path, &body);
+
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.api_base, path))
+            .header("Authorization", authorization)
+            .header("Accept", "application/json")
+            .header("User-Agent", "ai-photobooth");
+        if !body.is_empty() {
+            request = request.header("Content-Type", "application/json").body(body);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach WeChat Pay: {}", e))?;
+
+        let status = response.status();
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let headers = WeChatSignatureHeaders {
+            timestamp: header("Wechatpay-Timestamp"),
+            nonce: header("Wechatpay-Nonce"),
+            signature: header("Wechatpay-Signature"),
+            serial: header("Wechatpay-Serial"),
+        };
+        let text = response.text().await.map_err(|e| format!("Failed to read WeChat response: {}", e))?;
+
+        if !status.is_success() {
+            return Ok((status, serde_json::from_str(&text).unwrap_or_default()));
+        }
+
+        self.verify_signature(&headers, &text)?;
+
+        if text.is_empty() {
+            // e.g. 204 No Content from close order
+            return Ok((status, serde_json::Value::Null));
+        }
+        let response = serde_json::from_str(&text).map_err(|e| format!("Failed to parse WeChat response: {}", e))?;
+        Ok((status, response))
+    }
+
+    fn authorization(&self, method: &str, path: &str, body: &str) -> String {
+        let timestamp = chrono::Utc::now().timestamp();
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        let message = format!("{}\n{}\n{}\n{}\n{}\n", method, path, timestamp, nonce, body);
+        let signature = STANDARD.encode(self.signing_key.sign(message.as_bytes()).to_bytes());
+
+        format!(
+            "WECHATPAY2-SHA256-RSA2048 mchid=\"{}\",nonce_str=\"{}\",signature=\"{}\",timestamp=\"{}\",serial_no=\"{}\"",
+            self.mch_id, nonce, signature, timestamp, self.merchant_serial
+        )
+    }
+}
+
+fn is_order_not_exist(status: StatusCode, error: &serde_json::Value) -> bool {
+    status == StatusCode::NOT_FOUND && error.get("code").and_then(|v| v.as_str()) == Some("ORDER_NOT_EXIST")
+}
+
+fn api_error(status: StatusCode, error: &serde_json::Value) -> String {
+    format!(
+        "WeChat Pay error {}: {} {}",
+        status,
+        error.get("code").and_then(|v| v.as_str()).unwrap_or_default(),
+        error.get("message").and_then(|v| v.as_str()).unwrap_or_default()
+    )
+}
+
+/// Platform verification keys by serial. `WECHATPAY_SERIAL` names the key in
+/// `WECHATPAY_PUBLIC_KEY_PATH` or `WECHATPAY_PLATFORM_CERT_PATH`;
+/// `WECHATPAY_PLATFORM_KEYS` adds `serial=path` pairs, e.g. a certificate being
+/// rotated in next to the current one.
+fn load_platform_keys() -> Result<HashMap<String, VerifyingKey<Sha256>>, String> {
+    let mut keys = HashMap::new();
+    if let Ok(serial) = env::var("WECHATPAY_SERIAL") {
+        let path = env::var("WECHATPAY_PUBLIC_KEY_PATH")
+            .or_else(|_| env::var("WECHATPAY_PLATFORM_CERT_PATH"))
+            .map_err(|_| "WECHATPAY_PUBLIC_KEY_PATH or WECHATPAY_PLATFORM_CERT_PATH must be set")?;
+        keys.insert(serial, VerifyingKey::<Sha256>::new(read_platform_key(&path)?));
+    }
+
+    for pair in env::var("WECHATPAY_PLATFORM_KEYS").unwrap_or_default().split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (serial, path) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("WECHATPAY_PLATFORM_KEYS entry must be serial=path: {}", pair))?;
+        keys.insert(serial.trim().to_string(), VerifyingKey::<Sha256>::new(read_platform_key(path.trim())?));
+    }
+
+    if keys.is_empty() {
+        return Err("WECHATPAY_SERIAL or WECHATPAY_PLATFORM_KEYS must be set".to_string());
+    }
+    Ok(keys)
+}
+
+/// Either the WeChat Pay public key or the public key of a downloaded platform certificate
+fn read_platform_key(path: &str) -> Result<RsaPublicKey, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("Failed to read WeChat Pay key {}: {}", path, e))?;
+    if !String::from_utf8_lossy(&pem).contains("BEGIN CERTIFICATE") {
+        let pem = String::from_utf8(pem).map_err(|_| format!("Invalid WeChat Pay public key: {}", path))?;
+        return RsaPublicKey::from_public_key_pem(&pem)
+            .map_err(|e| format!("Invalid WeChat Pay public key: {}", e));
+    }
+
+    let cert = Certificate::from_pem(&pem).map_err(|e| format!("Invalid platform certificate: {}", e))?;
+
+    let validity = &cert.tbs_certificate.validity;
+    let now = std::time::SystemTime::now();
+    if now < validity.not_before.to_system_time() || now > validity.not_after.to_system_time() {
+        return Err("WeChat Pay platform certificate is not currently valid".to_string());
+    }
+
+    let spki = cert
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(|e| format!("Invalid platform certificate key: {}", e))?;
+    RsaPublicKey::from_public_key_der(&spki).map_err(|e| format!("Invalid platform certificate key: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+
+    const API_V3_KEY: &str = "a7cde1ef41e6a2b7ab1c0f1b3e9d9a52";
+    const MERCHANT_SERIAL: &str = "3775B6A45ACD588826D15E583A95F5DD";
+    const SERIAL: &str = "5157F09EFDC096DE15EBE81A47057A72";
+    // A platform certificate being rotated in
+    const NEXT_SERIAL: &str = "6D0F9A3C2B1E4F5A8B7C6D5E4F3A2B1C";
+
+    // Short keys keep generation quick; signing works the same
+    static MERCHANT_KEY: Lazy<RsaPrivateKey> = Lazy::new(|| RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+    static PLATFORM_KEY: Lazy<RsaPrivateKey> = Lazy::new(|| RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+    static NEXT_PLATFORM_KEY: Lazy<RsaPrivateKey> = Lazy::new(|| RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+
+    fn client() -> WeChatV3Client {
+        WeChatV3Client {
+            client: Client::new(),
+            app_id: "wxd930ea5d5a258f4f".to_string(),
+            mch_id: "1900009191".to_string(),
+            notify_url: "https://example.com/wechat/notify".to_string(),
+            api_base: String::new(),
+            api_v3_key: API_V3_KEY.as_bytes().to_vec(),
+            merchant_serial: MERCHANT_SERIAL.to_string(),
+            signing_key: SigningKey::<Sha256>::new(MERCHANT_KEY.clone()),
+            platform_keys: [(SERIAL, &*PLATFORM_KEY), (NEXT_SERIAL, &*NEXT_PLATFORM_KEY)]
+                .into_iter()
+                .map(|(serial, key)| (serial.to_string(), VerifyingKey::<Sha256>::new(key.to_public_key())))
+                .collect(),
+        }
+    }
+
+    /// Headers WeChat would send with `body`, signed by `key`
+    fn signed(key: &RsaPrivateKey, serial: &str, timestamp: i64, body: &str) -> WeChatSignatureHeaders {
+        let nonce = "593BEC0C930BF1AFEB40B4A08C8FB242";
+        let message = format!("{}\n{}\n{}\n", timestamp, nonce, body);
+        let signature = SigningKey::<Sha256>::new(key.clone()).sign(message.as_bytes());
+        WeChatSignatureHeaders {
+            timestamp: timestamp.to_string(),
+            nonce: nonce.to_string(),
+            signature: STANDARD.encode(signature.to_bytes()),
+            serial: serial.to_string(),
+        }
+    }
+
+    fn encrypt(plaintext: &str, associated_data: &str) -> NotificationResource {
+        let nonce = "fdasflkja484";
+        let cipher = Aes256Gcm::new_from_slice(API_V3_KEY.as_bytes()).unwrap();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(nonce.as_bytes()), Payload { msg: plaintext.as_bytes(), aad: associated_data.as_bytes() })
+            .unwrap();
+        NotificationResource {
+            algorithm: "AEAD_AES_256_GCM".to_string(),
+            ciphertext: STANDARD.encode(ciphertext),
+            associated_data: Some(associated_data.to_string()),
+            nonce: nonce.to_string(),
+        }
+    }
+
+    #[test]
+    fn requests_are_signed_with_the_merchant_key() {
+        let body = r#"{"mchid":"1900009191"}"#;
+        let authorization = client().authorization("POST", "/v3/pay/transactions/native", body);
+        let fields: HashMap<&str, &str> = authorization
+            .strip_prefix("WECHATPAY2-SHA256-RSA2048 ")
+            .unwrap()
+            .split(',')
+            .map(|field| {
+                let (name, value) = field.split_once('=').unwrap();
+                (name, value.trim_matches('"'))
+            })
+            .collect();
+        assert_eq!(fields["mchid"], "1900009191");
+        assert_eq!(fields["serial_no"], MERCHANT_SERIAL);
+
+        let message = format!("POST\n/v3/pay/transactions/native\n{}\n{}\n{}\n", fields["timestamp"], fields["nonce_str"], body);
+        let signature = Signature::try_from(STANDARD.decode(fields["signature"]).unwrap().as_slice()).unwrap();
+        let merchant = VerifyingKey::<Sha256>::new(MERCHANT_KEY.to_public_key());
+        assert!(merchant.verify(message.as_bytes(), &signature).is_ok());
+        assert!(merchant.verify(message.replace("POST", "GET").as_bytes(), &signature).is_err());
+    }
+
+    #[test]
+    fn verify_signature_accepts_every_configured_serial() {
+        let client = client();
+        let body = r#"{"code_url":"weixin://wxpay/bizpayurl?pr=p4lpSuKzz"}"#;
+        let now = chrono::Utc::now().timestamp();
+        client.verify_signature(&signed(&PLATFORM_KEY, SERIAL, now, body), body).unwrap();
+        client.verify_signature(&signed(&NEXT_PLATFORM_KEY, NEXT_SERIAL, now, body), body).unwrap();
+        client.verify_signature(&signed(&PLATFORM_KEY, SERIAL, now - 290, body), body).unwrap();
+    }
+
+    #[test]
+    fn verify_signature_rejects_bad_responses() {
+        let client = client();
+        let body = r#"{"code_url":"weixin://wxpay/bizpayurl?pr=p4lpSuKzz"}"#;
+        let now = chrono::Utc::now().timestamp();
+
+        let tampered = body.replace("p4lpSuKzz", "attacker00");
+        let err = client.verify_signature(&signed(&PLATFORM_KEY, SERIAL, now, body), &tampered).unwrap_err();
+        assert!(err.contains("verification failed"), "{}", err);
+
+        let err = client.verify_signature(&signed(&PLATFORM_KEY, SERIAL, now - 310, body), body).unwrap_err();
+        assert!(err.contains("expired"), "{}", err);
+
+        // Signed by one platform key but naming the other
+        let err = client.verify_signature(&signed(&PLATFORM_KEY, NEXT_SERIAL, now, body), body).unwrap_err();
+        assert!(err.contains("verification failed"), "{}", err);
+
+        let err = client.verify_signature(&signed(&PLATFORM_KEY, MERCHANT_SERIAL, now, body), body).unwrap_err();
+        assert!(err.contains("Unknown WeChat platform serial"), "{}", err);
+
+        let forged_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let err = client.verify_signature(&signed(&forged_key, SERIAL, now, body), body).unwrap_err();
+        assert!(err.contains("verification failed"), "{}", err);
+    }
+
+    #[test]
+    fn decrypt_resource_round_trip() {
+        let plaintext = r#"{"out_trade_no":"1217752501201407033233368018","trade_state":"SUCCESS"}"#;
+        assert_eq!(client().decrypt_resource(&encrypt(plaintext, "transaction")).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn decrypt_resource_rejects_tampered_resources() {
+        let client = client();
+        let plaintext = r#"{"out_trade_no":"1217752501201407033233368018","trade_state":"SUCCESS"}"#;
+
+        let mut resource = encrypt(plaintext, "transaction");
+        let mut ciphertext = STANDARD.decode(&resource.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        resource.ciphertext = STANDARD.encode(ciphertext);
+        assert!(client.decrypt_resource(&resource).is_err());
+
+        let mut resource = encrypt(plaintext, "transaction");
+        resource.associated_data = Some("refund".to_string());
+        assert!(client.decrypt_resource(&resource).is_err());
+
+        let mut resource = encrypt(plaintext, "transaction");
+        resource.nonce = "short".to_string();
+        assert!(client.decrypt_resource(&resource).is_err());
+
+        let mut resource = encrypt(plaintext, "transaction");
+        resource.algorithm = "AEAD_SM4_GCM".to_string();
+        let err = client.decrypt_resource(&resource).unwrap_err();
+        assert!(err.contains("Unsupported"), "{}", err);
+    }
+}
@@ -1,6 +1,6 @@
-import { useState } from 'react';
+import { useState, useEffect } from 'react';
 import './styles/global.css';
-import type { PhotoMode, Effect, PhotoSession, Order } from './types';
+import type { PhotoMode, Effect, PhotoSession, Order, LatePayment } from './types';
 import { api } from './services/api';
 import ModeSelect from './components/ModeSelect';
 import EffectSelect from './components/EffectSelect';
@@ -21,6 +21,15 @@ function App() {
   const [capturedPhoto, setCapturedPhoto] = useState<string | null>(null);
   const [, setOrder] = useState<Order | null>(null);
   const [loading, setLoading] = useState(false);
+  const [latePayments, setLatePayments] = useState<LatePayment[]>([]);
+
+  // Payments for cancelled orders stay on screen until someone acknowledges them
+  useEffect(() => {
+    const unlisten = api.onLatePayment((late) => setLatePayments((list) => [...list, late]));
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
 
   const loadModes = async () => {
     setLoading(true);
@@ -104,7 +113,7 @@ function App() {
     setLoading(true);
     try {
       // Use the same style_id if provided, otherwise generate without style
-      const updatedSession = await api.generatePhoto(session.id, photoBase64, styleId || session.style_id);
+      const updatedSession = await api.generatePhoto(session.id, photoBase64, styleId || session.style_id, true);
       setSession(updatedSession);
     } catch (error) {
       console.error('Failed to generate photo:', error);
@@ -214,6 +223,7 @@ function App() {
       case 'download':
         return (
           <OrderList
+            paidOrder={order}
             onBack={handleBackToHome}
             onNewPhoto={handleBackToHome}
           />
@@ -227,6 +237,19 @@ function App() {
   return (
     <div className="app">
       <main className="main-content">
+        {latePayments.map((late) => (
+          <div key={late.refund_id} className="card mb-4" style={{ borderColor: 'var(--color-warning)' }}>
+            <p style={{ color: 'var(--color-warning)', fontWeight: 600 }}>
+              订单 {late.code ?? late.order_id.slice(0, 8)} 取消后收到付款 ¥{(late.amount / 100).toFixed(2)}，正在自动退款
+            </p>
+            <button
+              className="btn btn-secondary mt-4"
+              onClick={() => setLatePayments((list) => list.filter((l) => l.refund_id !== late.refund_id))}
+            >
+              知道了
+            </button>
+          </div>
+        ))}
         {renderStep()}
       </main>
     </div>
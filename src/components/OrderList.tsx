@@ -1,16 +1,23 @@
 import { useState, useEffect } from 'react';
-import type { Order } from '../types';
+import type { DownloadLink, Order, OrderLookup } from '../types';
 import { api } from '../services/api';
 
 interface OrderListProps {
+  paidOrder?: Order | null; // Just paid; its code is the customer's only way back to the photo
   onBack: () => void;
   onNewPhoto: () => void;
 }
 
-function OrderList({ onBack, onNewPhoto }: OrderListProps) {
+function OrderList({ paidOrder, onBack, onNewPhoto }: OrderListProps) {
   const [orders, setOrders] = useState<Order[]>([]);
   const [loading, setLoading] = useState(true);
   const [downloadingOrderId, setDownloadingOrderId] = useState<string | null>(null);
+  const [link, setLink] = useState<DownloadLink | null>(null);
+  const [linkError, setLinkError] = useState('');
+  const [code, setCode] = useState('');
+  const [lookup, setLookup] = useState<OrderLookup | null>(null);
+  const [lookupError, setLookupError] = useState('');
+  const [searching, setSearching] = useState(false);
 
   useEffect(() => {
     loadOrders();
@@ -20,10 +27,12 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
     setLoading(true);
     try {
       // For demo, get orders from session storage
-      const savedOrders = sessionStorage.getItem('orders');
-      if (savedOrders) {
-        setOrders(JSON.parse(savedOrders));
+      const savedOrders: Order[] = JSON.parse(sessionStorage.getItem('orders') ?? '[]');
+      if (paidOrder && !savedOrders.some((o) => o.id === paidOrder.id)) {
+        savedOrders.unshift(paidOrder);
+        sessionStorage.setItem('orders', JSON.stringify(savedOrders));
       }
+      setOrders(savedOrders);
     } catch (error) {
       console.error('Failed to load orders:', error);
     } finally {
@@ -34,14 +43,12 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
   const handleDownload = async (order: Order) => {
     setDownloadingOrderId(order.id);
     try {
-      const session = await api.getSession(order.session_id);
-      if (session?.generated_photo) {
-        // Create download link
-        const link = document.createElement('a');
-        link.href = `data:image/jpeg;base64,${session.generated_photo}`;
-        link.download = `ai-photo-${order.id}.jpg`;
-        link.click();
-      }
+      // Upscaled to print resolution by the backend
+      const photo = await api.getOrderPhoto(order.id);
+      const link = document.createElement('a');
+      link.href = `data:image/jpeg;base64,${photo}`;
+      link.download = `ai-photo-${order.id}.jpg`;
+      link.click();
     } catch (error) {
       console.error('Failed to download:', error);
     } finally {
@@ -49,6 +56,36 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
     }
   };
 
+  const handlePhoneDownload = async (orderId: string) => {
+    setDownloadingOrderId(orderId);
+    setLinkError('');
+    try {
+      setLink(await api.createDownloadLink(orderId));
+    } catch (error) {
+      setLinkError(String(error));
+    } finally {
+      setDownloadingOrderId(null);
+    }
+  };
+
+  const handleLookup = async () => {
+    setSearching(true);
+    setLookupError('');
+    setLookup(null);
+    try {
+      const found = await api.lookupOrderByCode(code);
+      if (found) {
+        setLookup(found);
+      } else {
+        setLookupError('未找到该订单，请核对小票上的订单号');
+      }
+    } catch (error) {
+      setLookupError(String(error));
+    } finally {
+      setSearching(false);
+    }
+  };
+
   const formatDate = (timestamp: number) => {
     return new Date(timestamp * 1000).toLocaleString('zh-CN');
   };
@@ -66,6 +103,59 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
         </button>
       </div>
 
+      {paidOrder?.code && (
+        <div className="card mb-4" style={{ textAlign: 'center' }}>
+          <h3 style={{ color: 'var(--color-success)' }}>支付成功！</h3>
+          <p className="mt-4 text-light">您的订单号</p>
+          <p style={{ fontSize: '2rem', fontWeight: 700, letterSpacing: '0.2em' }}>{paidOrder.code}</p>
+          <p className="text-light">请拍照或记下订单号，凭订单号可重新下载或补打照片</p>
+        </div>
+      )}
+
+      <div className="card mb-4">
+        <div className="flex gap-4">
+          <input
+            value={code}
+            onChange={(e) => setCode(e.target.value)}
+            placeholder="输入小票上的订单号"
+            style={{
+              flex: 1,
+              padding: '0.75rem 1rem',
+              border: '1px solid var(--color-border)',
+              borderRadius: 'var(--radius-md)',
+              fontSize: '1rem',
+              textTransform: 'uppercase',
+            }}
+          />
+          <button className="btn btn-primary" onClick={handleLookup} disabled={searching || !code.trim()}>
+            {searching ? '查询中...' : '查询'}
+          </button>
+        </div>
+        {lookup && (
+          <div className="flex justify-between items-center mt-4">
+            <div>
+              <p style={{ fontWeight: 600 }}>订单号: {lookup.code}</p>
+              <p className="text-light" style={{ fontSize: '0.875rem' }}>
+                {formatDate(lookup.created_at)} | {formatPrice(lookup.amount)} |{' '}
+                {lookup.status === 'paid' ? '已支付' : '未支付'}
+              </p>
+            </div>
+            {lookup.downloadable && (
+              <button
+                className="btn btn-secondary"
+                onClick={() => handlePhoneDownload(lookup.order_id)}
+                disabled={downloadingOrderId === lookup.order_id}
+              >
+                手机下载
+              </button>
+            )}
+          </div>
+        )}
+        {lookupError && (
+          <p className="mt-4" style={{ color: 'var(--color-error)' }}>{lookupError}</p>
+        )}
+      </div>
+
       <div className="card">
         {loading ? (
           <div className="loading">
@@ -92,7 +182,7 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
                 }}
               >
                 <div>
-                  <p style={{ fontWeight: 600 }}>订单号: {order.id.slice(0, 8)}...</p>
+                  <p style={{ fontWeight: 600 }}>订单号: {order.code ?? `${order.id.slice(0, 8)}...`}</p>
                   <p className="text-light" style={{ fontSize: '0.875rem' }}>
                     {formatDate(order.created_at)} | {formatPrice(order.amount)}
                   </p>
@@ -109,13 +199,22 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
                   </p>
                 </div>
                 {order.status === 'paid' && (
-                  <button
-                    className="btn btn-primary"
-                    onClick={() => handleDownload(order)}
-                    disabled={downloadingOrderId === order.id}
-                  >
-                    {downloadingOrderId === order.id ? '下载中...' : '下载'}
-                  </button>
+                  <div className="flex gap-4">
+                    <button
+                      className="btn btn-secondary"
+                      onClick={() => handlePhoneDownload(order.id)}
+                      disabled={downloadingOrderId === order.id}
+                    >
+                      手机下载
+                    </button>
+                    <button
+                      className="btn btn-primary"
+                      onClick={() => handleDownload(order)}
+                      disabled={downloadingOrderId === order.id}
+                    >
+                      {downloadingOrderId === order.id ? '下载中...' : '下载'}
+                    </button>
+                  </div>
                 )}
               </div>
             ))}
@@ -123,6 +222,26 @@ function OrderList({ onBack, onNewPhoto }: OrderListProps) {
         )}
       </div>
 
+      {link && (
+        <div className="card mt-4" style={{ textAlign: 'center' }}>
+          <h3 className="mb-4">扫码保存到手机</h3>
+          {link.code && <p className="mb-4" style={{ fontWeight: 600 }}>订单号: {link.code}</p>}
+          <div style={{ padding: '1rem', backgroundColor: 'white', display: 'inline-block', borderRadius: 'var(--radius-md)' }}>
+            <img src={`data:image/png;base64,${link.png_base64}`} alt="下载二维码" width={200} height={200} />
+          </div>
+          <p className="mt-4 text-light">
+            请连接店内 Wi-Fi 后扫码，链接在 {formatDate(link.expires_at)} 前可重复打开
+            {link.code && '；之后可凭订单号重新获取'}
+          </p>
+          <button className="btn btn-secondary mt-4" onClick={() => setLink(null)}>
+            关闭
+          </button>
+        </div>
+      )}
+      {linkError && (
+        <p className="mt-4 text-center" style={{ color: 'var(--color-error)' }}>{linkError}</p>
+      )}
+
       <div className="text-center mt-4">
         <button className="btn btn-primary btn-lg" onClick={onNewPhoto}>
           继续拍照
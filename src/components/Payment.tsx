@@ -1,6 +1,5 @@
-import { useState } from 'react';
-import { QRCodeSVG } from 'qrcode.react';
-import type { PhotoSession, Order } from '../types';
+import { useEffect, useRef, useState } from 'react';
+import type { PhotoSession, Order, PaymentProviderKind, Product, PrinterStatus } from '../types';
 import { api } from '../services/api';
 
 interface PaymentProps {
@@ -9,52 +8,125 @@ interface PaymentProps {
   onBack: () => void;
 }
 
-const DOWNLOAD_PRICE = 300; // 3元
+type ScanProvider = Exclude<PaymentProviderKind, 'voucher'>;
+
+const PROVIDER_NAMES: Record<ScanProvider, string> = {
+  wechat: '微信',
+  alipay: '支付宝',
+};
 
 function Payment({ session, onSuccess, onBack }: PaymentProps) {
   const [qrCode, setQrCode] = useState<string>('');
-  const [, setOrderId] = useState<string>('');
+  const [orderId, setOrderId] = useState<string>('');
+  const [provider, setProvider] = useState<ScanProvider>('wechat');
+  const [products, setProducts] = useState<Product[]>([]);
+  const [selected, setSelected] = useState<Product | null>(null);
+  const [printer, setPrinter] = useState<PrinterStatus | null>(null);
+  const [voucherCode, setVoucherCode] = useState('');
+  const [voucherError, setVoucherError] = useState('');
   const [status, setStatus] = useState<'pending' | 'paid' | 'checking'>('pending');
   const [loading, setLoading] = useState(false);
+  const [paidCode, setPaidCode] = useState('');
+  const settled = useRef(false);
+
+  const completePayment = async (paidOrderId: string) => {
+    if (settled.current) return;
+    settled.current = true;
+    setStatus('paid');
+    const order = await api.getOrder(paidOrderId);
+    if (order) {
+      setPaidCode(order.code ?? '');
+      onSuccess(order);
+    }
+  };
+
+  // Prints drop out of the list when the media runs out, so reload on every alert
+  useEffect(() => {
+    const loadProducts = () =>
+      api.getProducts(session.effect_id)
+        .then((list) => {
+          setProducts(list);
+          setSelected((current) => list.find((p) => p.id === current?.id) ?? list[0] ?? null);
+        })
+        .catch((error) => console.error('Failed to load products:', error));
+    loadProducts();
+    const unlisten = api.onMediaLow(loadProducts);
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, [session.effect_id]);
+
+  // Prints cannot be sold while the printer is down; the backend refuses them too
+  useEffect(() => {
+    api.getPrinterStatus()
+      .then(setPrinter)
+      .catch((error) => console.error('Failed to load printer status:', error));
+    const unlisten = api.onPrinterStatus(setPrinter);
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, []);
+
+  const needsPrinter = (product: Product) =>
+    product.kind === 'print' ||
+    product.components.some((c) => products.find((p) => p.id === c.product_id)?.kind === 'print');
+  // Like the backend, a status nobody has refreshed for two minutes is ignored
+  const printerDown = printer !== null && printer.state !== 'ready' && Date.now() / 1000 - printer.checked_at <= 120;
+  const unavailable = (product: Product) => printerDown && needsPrinter(product);
+  const canPay = selected !== null && !unavailable(selected);
 
-  const createPayment = async () => {
+  // The backend prices every order; a voucher order is refused if the price shown changed
+  const orderItems = (product: Product) => [{ product_id: product.id, quantity: 1 }];
+
+  // The backend poller and payment notifications report every status change
+  useEffect(() => {
+    if (!orderId) return;
+    const unlisten = api.onPaymentStatus((event) => {
+      if (event.order_id !== orderId) return;
+      if (event.status === 'paid') {
+        completePayment(orderId);
+      } else if (event.status === 'cancelled') {
+        // Expired unpaid; the customer can generate a new code
+        setQrCode('');
+        setOrderId('');
+      }
+    });
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, [orderId]);
+
+  // Prepaid events: a voucher settles the order without any QR code
+  const redeemVoucher = async () => {
+    if (!selected || !canPay) return;
     setLoading(true);
+    setVoucherError('');
     try {
-      const [newOrderId, qr] = await api.createPayment(session.id, 'download', DOWNLOAD_PRICE);
-      setQrCode(qr);
-      setOrderId(newOrderId);
-      setStatus('pending');
-
-      // Poll for payment status
-      pollPaymentStatus(newOrderId);
+      const order = await api.redeemVoucher(session.id, orderItems(selected), selected.price, voucherCode);
+      settled.current = true;
+      setStatus('paid');
+      onSuccess(order);
     } catch (error) {
-      console.error('Failed to create payment:', error);
+      setVoucherError(String(error));
     } finally {
       setLoading(false);
     }
   };
 
-  const pollPaymentStatus = async (orderId: string) => {
-    const checkStatus = async () => {
-      try {
-        const result = await api.queryPayment(orderId);
-        if (result === 'SUCCESS') {
-          setStatus('paid');
-          const order = await api.getOrder(orderId);
-          if (order) {
-            onSuccess(order);
-          }
-        } else {
-          // Continue polling
-          setTimeout(checkStatus, 2000);
-        }
-      } catch (error) {
-        console.error('Failed to query payment:', error);
-        setTimeout(checkStatus, 2000);
-      }
-    };
-
-    checkStatus();
+  const createPayment = async (chosen: ScanProvider) => {
+    if (!selected || !canPay) return;
+    setLoading(true);
+    setProvider(chosen);
+    try {
+      const payment = await api.createPayment(session.id, orderItems(selected), chosen);
+      setQrCode(`data:image/png;base64,${payment.png_base64}`);
+      setOrderId(payment.order_id);
+      setStatus('pending');
+    } catch (error) {
+      console.error('Failed to create payment:', error);
+    } finally {
+      setLoading(false);
+    }
   };
 
   return (
@@ -69,27 +141,72 @@ function Payment({ session, onSuccess, onBack }: PaymentProps) {
       <div className="card" style={{ maxWidth: '500px', margin: '0 auto', textAlign: 'center' }}>
         {!qrCode ? (
           <>
-            <h3 className="mb-4">下载照片</h3>
-            <p className="text-light mb-4">价格: ¥{(DOWNLOAD_PRICE / 100).toFixed(2)}</p>
-            <button
-              className="btn btn-primary btn-lg"
-              onClick={createPayment}
-              disabled={loading}
-            >
-              {loading ? '生成中...' : '生成支付二维码'}
-            </button>
+            <h3 className="mb-4">选择商品</h3>
+            <div className="flex justify-center gap-4 mb-4">
+              {products.map((product) => (
+                <button
+                  key={product.id}
+                  className={`btn ${selected?.id === product.id ? 'btn-primary' : 'btn-secondary'}`}
+                  onClick={() => setSelected(product)}
+                  disabled={loading || unavailable(product)}
+                  title={product.description}
+                >
+                  {product.name} ¥{(product.price / 100).toFixed(2)}
+                </button>
+              ))}
+            </div>
+            {printerDown && (
+              <p className="mb-4 text-light">打印机暂不可用，暂时无法购买打印</p>
+            )}
+            <div className="flex justify-center gap-4">
+              {(Object.keys(PROVIDER_NAMES) as ScanProvider[]).map((kind) => (
+                <button
+                  key={kind}
+                  className="btn btn-primary btn-lg"
+                  onClick={() => createPayment(kind)}
+                  disabled={loading || !canPay}
+                >
+                  {loading && provider === kind ? '生成中...' : `${PROVIDER_NAMES[kind]}支付`}
+                </button>
+              ))}
+            </div>
+
+            <div className="flex justify-center gap-4 mt-4">
+              <input
+                value={voucherCode}
+                onChange={(e) => setVoucherCode(e.target.value)}
+                placeholder="兑换码"
+                style={{
+                  padding: '0.75rem 1rem',
+                  border: '1px solid var(--color-border)',
+                  borderRadius: 'var(--radius-md)',
+                  fontSize: '1rem',
+                  textTransform: 'uppercase',
+                }}
+              />
+              <button
+                className="btn btn-secondary"
+                onClick={redeemVoucher}
+                disabled={loading || !canPay || !voucherCode.trim()}
+              >
+                使用兑换码
+              </button>
+            </div>
+            {voucherError && (
+              <p className="mt-4" style={{ color: 'var(--color-error)' }}>{voucherError}</p>
+            )}
           </>
         ) : (
           <>
             <h3 className="mb-4">扫码支付</h3>
             <div style={{ padding: '1rem', backgroundColor: 'white', display: 'inline-block', borderRadius: 'var(--radius-md)' }}>
-              <QRCodeSVG value={qrCode} size={200} />
+              <img src={qrCode} alt="支付二维码" width={200} height={200} />
             </div>
-            <p className="mt-4 text-light">请使用微信扫描二维码完成支付</p>
+            <p className="mt-4 text-light">请使用{PROVIDER_NAMES[provider]}扫描二维码完成支付</p>
 
             {status === 'paid' && (
               <div className="mt-4" style={{ color: 'var(--color-success)' }}>
-                支付成功！
+                支付成功！{paidCode && `订单号: ${paidCode}`}
               </div>
             )}
 
@@ -1,5 +1,12 @@
 import { invoke } from '@tauri-apps/api/core';
-import type { PhotoMode, PhotoSession, Order } from '../types';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
+import type { PhotoMode, PhotoSession, Order, FaceDetection, GenerationStatus, UsageReport, PaymentStatusEvent, LatePayment, OrderEvent, Refund, PaymentQr, PaymentProviderKind, ReconciliationReport, Voucher, VoucherKind, RevenueReport, Product, OrderItem, Fulfillment, FulfillmentStatus, LineItemRequest, PrintJob, PrintStatusEvent, PrintTemplate, PrinterStatus, MediaKind, MediaSupply, DownloadLink, OrderLookup } from '../types';
+
+// An order is a single download/print or a list of catalog items
+type OrderContents = string | LineItemRequest[];
+
+const orderArgs = (contents: OrderContents) =>
+  typeof contents === 'string' ? { orderType: contents } : { items: contents };
 
 export const api = {
   // Mode operations
@@ -25,38 +32,200 @@ export const api = {
   },
 
   // Photo operations
-  async generatePhoto(sessionId: string, photoBase64: string, styleId?: string): Promise<PhotoSession> {
-    console.log('[API] generatePhoto called, sessionId:', sessionId, 'photo length:', photoBase64.length, 'styleId:', styleId);
-    const result = await invoke<PhotoSession>('generate_photo', { sessionId, photoBase64, styleId });
+  async generatePhoto(sessionId: string, photoBase64: string, styleId?: string, regenerate?: boolean): Promise<PhotoSession> {
+    console.log('[API] generatePhoto called, sessionId:', sessionId, 'photo length:', photoBase64.length, 'styleId:', styleId, 'regenerate:', regenerate);
+    const result = await invoke<PhotoSession>('generate_photo', { sessionId, photoBase64, styleId, regenerate });
     console.log('[API] generatePhoto completed, generated photo length:', result.generated_photo?.length || 0);
     return result;
   },
 
+  async detectFaces(sessionId: string, photoBase64: string): Promise<FaceDetection> {
+    return invoke<FaceDetection>('detect_faces', { sessionId, photoBase64 });
+  },
+
   async saveOriginalPhoto(sessionId: string, photoBase64: string): Promise<PhotoSession> {
     console.log('[API] saveOriginalPhoto called, sessionId:', sessionId, 'photo length:', photoBase64.length);
     return invoke<PhotoSession>('save_original_photo', { sessionId, photoBase64 });
   },
 
-  async saveGeneratedPhoto(sessionId: string, photoBase64: string): Promise<void> {
-    return invoke<void>('save_generated_photo', { sessionId, photoBase64 });
+  // AI usage
+  async getGenerationStatus(): Promise<GenerationStatus> {
+    return invoke<GenerationStatus>('get_generation_status');
+  },
+
+  async getUsageReport(pin: string, period: 'daily' | 'monthly', limit?: number): Promise<UsageReport[]> {
+    return invoke<UsageReport[]>('get_usage_report', { pin, period, limit });
+  },
+
+  // Settled orders per period and payment method; voucher rows are not money received
+  async getRevenueReport(pin: string, period: 'daily' | 'monthly', limit?: number): Promise<RevenueReport[]> {
+    return invoke<RevenueReport[]>('get_revenue_report', { pin, period, limit });
   },
 
   // Order operations
-  async createOrder(sessionId: string, orderType: string, amount: number): Promise<Order> {
-    return invoke<Order>('create_order', { sessionId, orderType, amount });
+  async createOrder(sessionId: string, contents: OrderContents, amount: number, provider?: PaymentProviderKind): Promise<Order> {
+    return invoke<Order>('create_order', { sessionId, ...orderArgs(contents), amount, provider });
+  },
+
+  async getOrderItems(orderId: string): Promise<OrderItem[]> {
+    return invoke<OrderItem[]>('get_order_items', { orderId });
+  },
+
+  // Base64 JPEG of a paid order's photo at print resolution
+  async getOrderPhoto(orderId: string): Promise<string> {
+    return invoke<string>('get_order_photo', { orderId });
+  },
+
+  // QR code the customer scans to save a paid photo to their phone
+  async createDownloadLink(orderId: string): Promise<DownloadLink> {
+    return invoke<DownloadLink>('create_download_link', { orderId });
+  },
+
+  // Catalog, priced for the session's effect
+  async getProducts(effectId?: string): Promise<Product[]> {
+    return invoke<Product[]>('get_products', { effectId });
+  },
+
+  async getFulfillments(orderId: string): Promise<Fulfillment[]> {
+    return invoke<Fulfillment[]>('get_fulfillments', { orderId });
+  },
+
+  // Operator: mark a unit delivered or failed
+  async updateFulfillmentStatus(pin: string, fulfillmentId: number, status: FulfillmentStatus, detail?: string): Promise<void> {
+    return invoke<void>('update_fulfillment_status', { pin, fulfillmentId, status, detail });
   },
 
   async getOrder(orderId: string): Promise<Order | null> {
     return invoke<Order | null>('get_order', { orderId });
   },
 
-  // Payment operations
-  async createPayment(sessionId: string, orderType: string, amount: number): Promise<[string, string]> {
-    console.log('[API] createPayment called:', { sessionId, orderType, amount });
-    return invoke<[string, string]>('create_payment', { sessionId, orderType, amount });
+  // Case, spaces and dashes in the typed code do not matter
+  async lookupOrderByCode(code: string): Promise<OrderLookup | null> {
+    return invoke<OrderLookup | null>('lookup_order_by_code', { code });
+  },
+
+  async getOrderEvents(orderId: string): Promise<OrderEvent[]> {
+    return invoke<OrderEvent[]>('get_order_events', { orderId });
+  },
+
+  // Payment operations; the backend prices the order and charges that amount
+  async createPayment(sessionId: string, contents: OrderContents, provider: PaymentProviderKind = 'wechat'): Promise<PaymentQr> {
+    console.log('[API] createPayment called:', { sessionId, contents, provider });
+    return invoke<PaymentQr>('create_payment', { sessionId, ...orderArgs(contents), provider });
+  },
+
+  async queryPayment(orderId: string): Promise<Order> {
+    return invoke<Order>('query_payment', { orderId });
+  },
+
+  // Operator: refund all (amount omitted) or part of a paid order
+  async refundOrder(pin: string, orderId: string, amount?: number, reason?: string): Promise<Refund> {
+    return invoke<Refund>('refund_order', { pin, orderId, amount, reason });
+  },
+
+  async getRefunds(orderId: string): Promise<Refund[]> {
+    return invoke<Refund[]>('get_refunds', { orderId });
+  },
+
+  // Settle a new order with a voucher code instead of a QR payment
+  async redeemVoucher(sessionId: string, contents: OrderContents, amount: number, code: string): Promise<Order> {
+    return invoke<Order>('redeem_voucher', { sessionId, ...orderArgs(contents), amount, code });
+  },
+
+  // Operator: issue vouchers; value in 分 for value vouchers, maxUses defaults to 1
+  async createVouchers(
+    pin: string,
+    count: number,
+    kind: VoucherKind,
+    options: { value?: number; maxUses?: number; modeId?: string; expiresAt?: number; note?: string } = {}
+  ): Promise<Voucher[]> {
+    return invoke<Voucher[]>('create_vouchers', { pin, count, kind, ...options });
+  },
+
+  async getVouchers(pin: string): Promise<Voucher[]> {
+    return invoke<Voucher[]>('get_vouchers', { pin });
+  },
+
+  async disableVoucher(pin: string, code: string): Promise<void> {
+    return invoke('disable_voucher', { pin, code });
+  },
+
+  // Operator: check a day's provider bill (YYYY-MM-DD, Beijing time) against the orders
+  async reconcilePayments(
+    pin: string,
+    provider: PaymentProviderKind,
+    billDate: string,
+    autoCorrect?: boolean
+  ): Promise<ReconciliationReport> {
+    return invoke<ReconciliationReport>('reconcile_payments', { pin, provider, billDate, autoCorrect });
+  },
+
+  async importBill(
+    pin: string,
+    provider: PaymentProviderKind,
+    billDate: string,
+    filePath: string,
+    autoCorrect?: boolean
+  ): Promise<ReconciliationReport> {
+    return invoke<ReconciliationReport>('import_bill', { pin, provider, billDate, filePath, autoCorrect });
+  },
+
+  async getReconciliationReports(pin: string, limit?: number): Promise<ReconciliationReport[]> {
+    return invoke<ReconciliationReport[]>('get_reconciliation_reports', { pin, limit });
+  },
+
+  // Pushed by the backend when a payment notification settles an order
+  async onPaymentStatus(handler: (event: PaymentStatusEvent) => void): Promise<UnlistenFn> {
+    return listen<PaymentStatusEvent>('payment-status', (event) => handler(event.payload));
+  },
+
+  // Print jobs of an order, or the latest jobs when orderId is omitted
+  async getPrintJobs(orderId?: string, limit?: number): Promise<PrintJob[]> {
+    return invoke<PrintJob[]>('get_print_jobs', { orderId, limit });
+  },
+
+  async getPrintTemplates(): Promise<PrintTemplate[]> {
+    return invoke<PrintTemplate[]>('get_print_templates');
+  },
+
+  // Base64 JPEG of the session's photo on a template; defaults to the configured one
+  async renderPrintPreview(sessionId: string, templateId?: string): Promise<string> {
+    return invoke<string>('render_print_preview', { sessionId, templateId });
+  },
+
+  async onPrintStatus(handler: (event: PrintStatusEvent) => void): Promise<UnlistenFn> {
+    return listen<PrintStatusEvent>('print-status', (event) => handler(event.payload));
+  },
+
+  // Pushed when a cancelled order gets paid anyway
+  async onLatePayment(handler: (late: LatePayment) => void): Promise<UnlistenFn> {
+    return listen<LatePayment>('late-payment', (event) => handler(event.payload));
+  },
+
+  // Operator commands take the OPERATOR_PIN; check it once to unlock the operator screens
+  async verifyOperatorPin(pin: string): Promise<void> {
+    return invoke<void>('verify_operator_pin', { pin });
+  },
+
+  // Last polled printer health; null when printing is not set up
+  async getPrinterStatus(): Promise<PrinterStatus | null> {
+    return invoke<PrinterStatus | null>('get_printer_status');
+  },
+
+  async onPrinterStatus(handler: (status: PrinterStatus) => void): Promise<UnlistenFn> {
+    return listen<PrinterStatus>('printer-status', (event) => handler(event.payload));
+  },
+
+  async getMediaInventory(): Promise<MediaSupply[]> {
+    return invoke<MediaSupply[]>('get_media_inventory');
+  },
+
+  // Sets the count after loading media; the threshold is kept when omitted
+  async restockMedia(pin: string, kind: MediaKind, remaining: number, lowThreshold?: number): Promise<MediaSupply> {
+    return invoke<MediaSupply>('restock_media', { pin, kind, remaining, lowThreshold });
   },
 
-  async queryPayment(orderId: string): Promise<string> {
-    return invoke<string>('query_payment', { orderId });
+  async onMediaLow(handler: (supply: MediaSupply) => void): Promise<UnlistenFn> {
+    return listen<MediaSupply>('media-low', (event) => handler(event.payload));
   },
 };
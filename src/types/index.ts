@@ -3,6 +3,8 @@ export interface PhotoMode {
   name: string;
   description: string;
   icon: string;
+  min_persons: number;
+  max_persons: number;
   effects: Effect[];
 }
 
@@ -22,12 +24,30 @@ export interface PhotoSession {
   effect_id: string;
   style_id?: string;
   original_photo?: string;
-  generated_photo?: string;
+  generated_photo?: string; // watermarked preview; the clean photo needs a paid order (getOrderPhoto)
+  fallback: boolean;
+  photo_width?: number;
+  photo_height?: number;
+  photo_format?: string;
+  face_count?: number;
   status: SessionStatus;
   created_at: number;
   updated_at: number;
 }
 
+export interface FaceBox {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+  score: number;
+}
+
+export interface FaceDetection {
+  face_count: number;
+  faces: FaceBox[];
+}
+
 export type SessionStatus =
   | 'selecting_mode'
   | 'selecting_effect'
@@ -45,7 +65,260 @@ export interface Order {
   wechat_order_id?: string;
   payment_time?: number;
   created_at: number;
+  provider: PaymentProviderKind;
+  code?: string; // 6-character code printed on receipts
+}
+
+// Result of looking an order up by its code
+export interface OrderLookup {
+  order_id: string;
+  code: string;
+  order_type: OrderType;
+  status: OrderStatus;
+  amount: number;
+  created_at: number;
+  downloadable: boolean;
+}
+
+export interface UsageReport {
+  period: string;
+  calls: number;
+  successes: number;
+  failures: number;
+  avg_latency_ms: number;
+  estimated_cost: number;
+}
+
+export interface GenerationStatus {
+  available: boolean;
+  reason?: string;
+  spent_today: number;
+  daily_budget?: number;
+  spent_this_month: number;
+  monthly_budget?: number;
 }
 
-export type OrderType = 'download' | 'print';
+export type OrderType = 'download' | 'print' | 'bundle';
 export type OrderStatus = 'pending' | 'paid' | 'cancelled' | 'refunded';
+export type PaymentProviderKind = 'wechat' | 'alipay' | 'voucher';
+
+export interface OrderEvent {
+  id: number;
+  order_id: string;
+  from_status?: OrderStatus;
+  to_status: OrderStatus;
+  source: string;
+  detail?: string;
+  created_at: number;
+}
+
+export interface BundleComponent {
+  product_id: string;
+  quantity: number;
+}
+
+export interface Product {
+  id: string;
+  name: string;
+  description: string;
+  kind: OrderType;
+  price: number; // 分
+  components: BundleComponent[];
+}
+
+export interface LineItemRequest {
+  product_id: string;
+  quantity: number;
+}
+
+// Bundle components are child lines (parent_id) at a unit price of 0
+export interface OrderItem {
+  id: number;
+  order_id: string;
+  parent_id?: number;
+  product_id: string;
+  name: string;
+  kind: OrderType;
+  quantity: number;
+  unit_price: number;
+}
+
+export type FulfillmentStatus = 'pending' | 'fulfilled' | 'failed';
+
+export interface Fulfillment {
+  id: number;
+  order_id: string;
+  order_item_id?: number;
+  kind: OrderType;
+  status: FulfillmentStatus;
+  detail?: string;
+  created_at: number;
+  updated_at: number;
+}
+
+export type RefundStatus = 'processing' | 'success' | 'closed' | 'abnormal' | 'failed';
+
+export interface Refund {
+  id: string;
+  order_id: string;
+  amount: number;
+  reason?: string;
+  status: RefundStatus;
+  provider_refund_id?: string;
+  created_at: number;
+  updated_at: number;
+}
+
+export interface PaymentQr {
+  order_id: string;
+  png_base64: string;
+  svg: string;
+}
+
+export type VoucherKind = 'free' | 'value';
+
+export interface Voucher {
+  code: string;
+  kind: VoucherKind;
+  value?: number;
+  max_uses: number;
+  used: number;
+  mode_id?: string;
+  expires_at?: number;
+  note?: string;
+  disabled: boolean;
+  created_at: number;
+}
+
+export interface RevenueReport {
+  period: string;
+  provider: PaymentProviderKind;
+  orders: number;
+  amount: number;
+  refunded: number;
+}
+
+export type DiscrepancyKind = 'missing' | 'extra' | 'mismatched' | 'refund_required';
+
+export interface ReconciliationItem {
+  kind: DiscrepancyKind;
+  out_trade_no: string;
+  order_id?: string;
+  bill_amount?: number;
+  order_amount?: number;
+  order_status?: OrderStatus;
+  detail: string;
+  corrected: boolean;
+}
+
+export interface ReconciliationReport {
+  id: number;
+  provider: PaymentProviderKind;
+  bill_date: string;
+  source: 'download' | 'import';
+  transactions: number;
+  matched: number;
+  items: ReconciliationItem[];
+  created_at: number;
+}
+
+// Single-use link to a paid photo for the customer's phone
+export interface DownloadLink {
+  order_id: string;
+  code?: string;
+  url: string;
+  filename: string;
+  expires_at: number;
+  png_base64: string;
+}
+
+export interface PaymentStatusEvent {
+  order_id: string;
+  status: OrderStatus;
+}
+
+// Payload of the late-payment event: a cancelled order was paid anyway and is being refunded
+export interface LatePayment {
+  order_id: string;
+  code?: string;
+  amount: number;
+  transaction_id?: string;
+  refund_id: string;
+}
+
+export type PrintJobStatus = 'queued' | 'printing' | 'completed' | 'failed';
+
+export interface PrintJob {
+  id: string;
+  order_id: string;
+  fulfillment_id: number;
+  printer: string;
+  status: PrintJobStatus;
+  printer_job_id?: string;
+  error?: string;
+  created_at: number;
+  updated_at: number;
+}
+
+// Print layout; sizes and positions in inches from the trimmed page's top-left, text in points
+export interface PrintBox {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+export interface PrintText {
+  x: number;
+  y: number;
+  width: number;
+  size: number;
+  color: string;
+  align: 'left' | 'center' | 'right';
+  text?: string;
+  format?: string;
+}
+
+export interface PrintTemplate {
+  id: string;
+  name: string;
+  width: number;
+  height: number;
+  bleed: number;
+  dpi: number;
+  background: string;
+  border?: { width: number; color: string };
+  photos: PrintBox[];
+  logo?: PrintBox;
+  caption?: PrintText;
+  date?: PrintText;
+  order_code?: PrintText; // {code} in text is replaced with the order code
+  qr?: { x: number; y: number; size: number };
+}
+
+// Sent when a print job completes or fails
+export interface PrintStatusEvent {
+  job_id: string;
+  order_id: string;
+  status: PrintJobStatus;
+  error?: string;
+}
+
+export type PrinterState = 'ready' | 'out_of_paper' | 'out_of_ribbon' | 'offline' | 'jammed' | 'error';
+
+export interface PrinterStatus {
+  printer: string;
+  state: PrinterState;
+  message?: string;
+  checked_at: number;
+}
+
+export type MediaKind = 'paper' | 'ribbon';
+
+// A print consumable, counted down on every completed print; also the media-low event payload
+export interface MediaSupply {
+  kind: MediaKind;
+  remaining: number;
+  low_threshold: number;
+  updated_at: number;
+}